@@ -1,47 +1,170 @@
+mod admin;
 pub mod auth;
+pub mod auth_lockout;
 pub mod auth_middleware;
+pub mod aws_chunked;
+pub mod batch;
+pub mod bootstrap;
+pub mod bucket_logging;
+pub mod bucket_usage;
+mod bucket_policy;
+mod bucket_subresource;
+pub mod byte_range;
+pub mod cache_control;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(not(feature = "client"))]
+mod client;
+pub mod clock_skew;
+mod clone_bucket;
+pub mod cluster;
+pub mod commit;
+mod compose_object;
+mod copy_object;
+pub mod compression;
+pub mod connections;
+pub mod cpu_pool;
 mod create_bucket;
 mod create_general_bucket;
+mod create_presigned_url;
 mod delete_bucket;
 mod delete_object;
+mod delete_prefix;
+pub mod disk_space;
 pub mod encryption;
 pub mod etag;
+mod key_locks;
 mod get_object;
+pub mod idempotency;
+pub mod lifecycle;
 mod list_buckets;
+pub mod mapped_read;
+mod ownership_controls;
+pub mod logging;
+pub mod maintenance;
+pub mod manifest;
 pub mod metadata;
+pub mod mfa_delete;
+pub mod migrations;
+pub mod mirror;
+pub mod notifications;
+pub mod object_search;
+pub mod operation;
+mod patch_metadata;
 pub mod path_security;
+pub mod policy_condition;
+pub mod presigned_registry;
+mod public_access_block;
 mod put_object;
+pub mod request_info;
+pub mod request_log;
+pub mod request_path;
+mod rename_object;
+pub mod replica;
+pub mod resumable_upload;
+mod revoke_presigned_url;
 pub mod s3_app_error;
+pub mod scrub;
 mod search_bucket;
+pub mod self_test;
+pub mod sync;
+pub mod telemetry;
+pub mod tenancy;
+pub mod timeouts;
+pub mod timestamp;
+mod unimplemented;
+pub mod verified_body;
+pub mod webdav;
 
 use std::sync::Arc;
 
 use axum::{
-    routing::{delete, get, put},
+    handler::Handler,
+    routing::{any, delete, get, head, patch, post, put},
     Extension, Router,
 };
 use serde::Deserialize;
 use tokio::signal;
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
 
-use auth::{AwsCredentials, AwsSignatureV4Validator};
+use auth::{AwsCredentials, AwsSignatureV4Validator, PresignPolicy, MAX_PRESIGNED_EXPIRES};
+use auth_lockout::AuthLockout;
 use auth_middleware::AuthLayer;
+use bucket_logging::AccessLogs;
+use bucket_usage::UsageCounters;
+use clock_skew::ClockSkewMonitor;
+use cluster::Cluster;
+use compression::CompressionConfig;
+use cpu_pool::{CpuPool, CpuPoolConfig};
+use disk_space::{DiskSpaceMonitor, DiskWatermarkConfig};
+use lifecycle::ShutdownHandle;
+use maintenance::{MaintenanceMode, ReadOnlyConfig};
+use mfa_delete::MfaDelete;
+use notifications::Notifier;
+use presigned_registry::PresignedUrlRegistry;
+use request_log::SamplingConfig;
+use resumable_upload::ResumableUploads;
+use scrub::Scrubber;
+use sync::BucketSync;
+use tenancy::TenantNamespaces;
+use timeouts::TimeoutConfig;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Clone)]
 pub struct EncryptionConfig {
     pub enabled: bool,
     pub master_key: Option<String>,
 }
 
+impl std::fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("enabled", &self.enabled)
+            .field("master_key", &self.master_key.as_ref().map(|_| logging::REDACTED))
+            .finish()
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
+pub struct PresignedRegistryConfig {
+    pub enabled: bool,
+    pub require_token: bool,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct AwsCredentialConfig {
     pub access_key_id: String,
     pub secret_access_key: String,
     pub region: String,
+    // Methods this key may pre-sign (all methods when unset)
+    #[serde(default)]
+    pub presign_methods: Option<Vec<String>>,
+    // Maximum pre-signed URL lifetime in seconds (7 days when unset)
+    #[serde(default)]
+    pub presign_max_expires: Option<u64>,
+    // Account owning the buckets this key creates (unrestricted when unset)
+    #[serde(default)]
+    pub account: Option<String>,
+    // Buckets this key may use and list (every bucket when unset)
+    #[serde(default)]
+    pub buckets: Option<Vec<String>>,
 }
 
-#[derive(Debug)]
+impl std::fmt::Debug for AwsCredentialConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentialConfig")
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &logging::REDACTED)
+            .field("region", &self.region)
+            .field("presign_methods", &self.presign_methods)
+            .field("presign_max_expires", &self.presign_max_expires)
+            .field("account", &self.account)
+            .field("buckets", &self.buckets)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub location: String,
     pub port: String,
@@ -51,94 +174,648 @@ pub struct Config {
     pub aws_credentials: Vec<AwsCredentialConfig>,
     // Encryption configuration
     pub encryption: Option<EncryptionConfig>,
+    // Server-side registry for single-use and revocable pre-signed URLs
+    pub presigned_registry: Option<PresignedRegistryConfig>,
+    // Access keys allowed to call /_fily/admin endpoints (all keys when empty)
+    pub admin_access_keys: Vec<String>,
+    // Sampling of successful per-request log lines
+    pub request_log_sampling: SamplingConfig,
+    // Domain whose sub-domains map to account namespaces (disabled when unset)
+    pub tenant_domain: Option<String>,
+    // Read-only mode the server starts in
+    pub read_only: ReadOnlyConfig,
+    // Low free space thresholds for the storage volume
+    pub disk_watermarks: DiskWatermarkConfig,
+    // Recompute the SHA-256 of objects on GET and refuse to serve mismatches
+    pub verify_on_get: bool,
+    // Response compression negotiated via Accept-Encoding
+    pub compression: CompressionConfig,
+    // Memory-mapped reads of medium-sized objects that are decrypted before being served
+    pub mapped_reads: mapped_read::MappedReadConfig,
+    // Bounded thread pool for hashing and encryption of object bodies
+    pub cpu_pool: CpuPoolConfig,
+    // Requests taking at least this long are logged under `fily::slow_request`
+    pub slow_request_threshold: Option<std::time::Duration>,
+    // Per-request, header read and idle request body timeouts
+    pub timeouts: TimeoutConfig,
+    // Proxies whose forwarding headers supply the client address and protocol for policy conditions
+    pub trusted_proxies: Vec<policy_condition::IpCidr>,
+    // Remote buckets periodically pulled into local buckets
+    pub sync: Vec<sync::SyncConfig>,
+    // Buckets created or updated at startup
+    pub buckets: Vec<bootstrap::BucketDeclaration>,
+    // Lockout of source IPs and access keys failing signature checks
+    pub auth_lockout: auth_lockout::AuthLockoutConfig,
+    // Warning when most clients' x-amz-date disagrees with the server clock in the same direction
+    pub clock_skew: clock_skew::ClockSkewConfig,
+    // fily extension for appending to an upload across requests before committing it
+    pub resumable_uploads: resumable_upload::ResumableUploadConfig,
+    // Signing key for bucket integrity manifests
+    pub manifest: manifest::ManifestConfig,
+    // Webhook receiving operational events such as lockouts and low disk space
+    pub notifications: notifications::NotificationConfig,
+    // fily extension for storing and reading many small objects per request
+    pub batch: batch::BatchConfig,
+    // WebDAV endpoint mapping basic-auth users onto access keys
+    pub webdav: webdav::WebDavConfig,
+    // Cache-Control and surrogate keys added to GET responses of matching objects
+    pub cache_rules: Vec<cache_control::CacheRule>,
+    // Key signing listing continuation tokens; random per process when unset
+    pub list_token_key: Option<String>,
+    // Upgrading stores written by older versions at startup
+    pub migrations: migrations::MigrationConfig,
+    // Where object metadata and bucket settings are kept
+    pub metadata_layout: path_security::MetadataLayout,
+    // Scratch directory for staged writes and resumable uploads; next to the buckets when unset
+    pub staging_root: Option<String>,
+    // Background re-verification of stored checksums
+    pub scrub: scrub::ScrubConfig,
+    // Serving a store another instance writes to, without writing to it
+    pub replica: replica::ReplicaConfig,
+    // Nodes sharing buckets by consistent hashing, proxying requests to the owning node
+    pub cluster: cluster::ClusterConfig,
+    // Authenticator codes required for bucket and, optionally, object deletes
+    pub mfa_delete: mfa_delete::MfaDeleteConfig,
+    // Canary, encryption key and clock checks run before serving
+    pub self_test: self_test::SelfTestConfig,
+    // Grant keys nothing a bucket allowlist, bucket owner or grant does not give them explicitly
+    pub deny_by_default: bool,
+    // Operations refused for every bucket or for some, whatever the credentials allow
+    pub disabled_operations: operation::DisabledOperations,
+    // Second storage root every write to the mirrored buckets is committed to before the primary
+    pub mirror: mirror::MirrorConfig,
+}
+
+/// Serves `./data` on `0.0.0.0:8333` with no credentials and every optional feature off, the
+/// base for configurations built in code: set the fields that matter and `..Default::default()`
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            location: "./data".to_string(),
+            port: "8333".to_string(),
+            address: "0.0.0.0".to_string(),
+            log_level: "info".to_string(),
+            aws_credentials: vec![],
+            encryption: None,
+            presigned_registry: None,
+            admin_access_keys: vec![],
+            request_log_sampling: Default::default(),
+            tenant_domain: None,
+            read_only: Default::default(),
+            disk_watermarks: Default::default(),
+            verify_on_get: false,
+            compression: Default::default(),
+            mapped_reads: Default::default(),
+            cpu_pool: Default::default(),
+            slow_request_threshold: None,
+            timeouts: Default::default(),
+            trusted_proxies: vec![],
+            sync: vec![],
+            buckets: vec![],
+            auth_lockout: Default::default(),
+            clock_skew: Default::default(),
+            resumable_uploads: Default::default(),
+            manifest: Default::default(),
+            notifications: Default::default(),
+            batch: Default::default(),
+            webdav: Default::default(),
+            cache_rules: vec![],
+            list_token_key: None,
+            migrations: Default::default(),
+            metadata_layout: Default::default(),
+            staging_root: None,
+            scrub: Default::default(),
+            replica: Default::default(),
+            cluster: Default::default(),
+            mfa_delete: Default::default(),
+            self_test: Default::default(),
+            deny_by_default: false,
+            disabled_operations: Default::default(),
+            mirror: Default::default(),
+        }
+    }
+}
+
+/// A fily server whose storage and credentials have been validated, ready to bind and serve
+pub struct Server {
+    config: Arc<Config>,
+    app: Router,
+    disk_monitor: Option<Arc<DiskSpaceMonitor>>,
+    syncs: Vec<Arc<BucketSync>>,
+    scrubber: Option<Arc<Scrubber>>,
+    cluster: Option<Arc<Cluster>>,
+    access_logs: Option<Arc<AccessLogs>>,
+    notifier: Option<Arc<Notifier>>,
+    shutdown: ShutdownHandle,
+    handle_signals: bool,
 }
 
+/// Runs fily until Ctrl+C or SIGTERM
 pub async fn run(config: Config) -> anyhow::Result<()> {
-    let config_state = Arc::new(config);
-
-    let port = config_state.port.clone();
-    let address = config_state.address.clone();
-
-    // Setup AWS SigV4 authentication
-    let mut validator = AwsSignatureV4Validator::new();
-    let mut credentials_added = 0;
-
-    // Add all configured AWS credentials
-    for (index, aws_config) in config_state.aws_credentials.iter().enumerate() {
-        match AwsCredentials::new(
-            aws_config.access_key_id.clone(),
-            aws_config.secret_access_key.clone(),
-            aws_config.region.clone(),
-        ) {
-            Ok(credentials) => {
-                match validator.add_credentials(aws_config.access_key_id.clone(), credentials) {
-                    Ok(()) => {
-                        info!(
-                            "Added AWS credentials #{} for access key: {} (region: {})",
-                            index + 1,
-                            aws_config.access_key_id,
-                            aws_config.region
-                        );
-                        credentials_added += 1;
+    Server::init(config).await?.serve().await
+}
+
+impl Server {
+    /// Validates the storage location and credentials and builds the router without binding a port
+    pub async fn init(config: Config) -> anyhow::Result<Self> {
+        let config_state = Arc::new(config);
+
+        if config_state.replica.enabled {
+            replica::open(&config_state.location, &config_state.replica)?;
+            info!(
+                "Serving {} as a read-only replica, metadata at most {}s stale",
+                config_state.location,
+                config_state.replica.max_staleness.as_secs()
+            );
+        } else {
+            lifecycle::validate_storage(&config_state.location, config_state.staging_root.as_deref()).await?;
+            migrations::migrate(
+                std::path::Path::new(&config_state.location),
+                &config_state.migrations,
+                &config_state.metadata_layout,
+                true,
+            )
+            .await?;
+            mirror::open(std::path::Path::new(&config_state.location), &config_state.mirror).await?;
+            if let Some(root) = &config_state.mirror.root {
+                info!(
+                    "Mirroring writes to {} for {}",
+                    root,
+                    if config_state.mirror.buckets.is_empty() {
+                        "every bucket".to_string()
+                    } else {
+                        config_state.mirror.buckets.join(", ")
+                    }
+                );
+            }
+        }
+
+        if config_state.self_test.enabled {
+            let report = self_test::run(&config_state).await;
+            if !report.passed {
+                match config_state.self_test.on_failure {
+                    self_test::SelfTestFailureMode::Refuse => {
+                        return Err(anyhow::anyhow!("Startup self-test failed: {}", report.failures()));
                     }
-                    Err(e) => {
-                        return Err(anyhow::anyhow!(
-                            "Failed to add AWS credentials #{}: {}",
-                            index + 1,
-                            e
-                        ));
+                    self_test::SelfTestFailureMode::Warn => {
+                        warn!("Serving despite failed startup self-test: {}", report.failures());
                     }
                 }
             }
-            Err(e) => {
-                return Err(anyhow::anyhow!(
-                    "Invalid AWS credentials format for credential #{}: {}",
-                    index + 1,
-                    e
-                ));
+        }
+
+        // Setup AWS SigV4 authentication
+        let mut validator = AwsSignatureV4Validator::new();
+        validator.set_future_skew_grace(config_state.clock_skew.future_grace);
+        let mut credentials_added = 0;
+
+        // Add all configured AWS credentials
+        for (index, aws_config) in config_state.aws_credentials.iter().enumerate() {
+            match AwsCredentials::new(
+                aws_config.access_key_id.clone(),
+                aws_config.secret_access_key.clone(),
+                aws_config.region.clone(),
+            ) {
+                Ok(credentials) => {
+                    let allowed_methods = aws_config
+                        .presign_methods
+                        .as_ref()
+                        .map(|methods| {
+                            methods
+                                .iter()
+                                .map(|m| m.to_ascii_uppercase().parse::<hyper::Method>())
+                                .collect::<Result<Vec<_>, _>>()
+                        })
+                        .transpose()
+                        .map_err(|e| {
+                            anyhow::anyhow!(
+                                "Invalid presign methods for credential #{}: {}",
+                                index + 1,
+                                e
+                            )
+                        })?;
+                    let credentials = credentials.with_presign_policy(PresignPolicy {
+                        allowed_methods,
+                        max_expires: aws_config
+                            .presign_max_expires
+                            .unwrap_or(MAX_PRESIGNED_EXPIRES),
+                    });
+
+                    match validator.add_credentials(aws_config.access_key_id.clone(), credentials) {
+                        Ok(()) => {
+                            info!(
+                                "Added AWS credentials #{} for access key: {} (region: {})",
+                                index + 1,
+                                aws_config.access_key_id,
+                                aws_config.region
+                            );
+                            credentials_added += 1;
+                        }
+                        Err(e) => {
+                            return Err(anyhow::anyhow!(
+                                "Failed to add AWS credentials #{}: {}",
+                                index + 1,
+                                e
+                            ));
+                        }
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid AWS credentials format for credential #{}: {}",
+                        index + 1,
+                        e
+                    ));
+                }
+            }
+        }
+
+        if credentials_added == 0 {
+            info!("No AWS credentials provided - authentication will be disabled");
+        } else {
+            info!(
+                "Successfully loaded {} AWS credential set(s)",
+                credentials_added
+            );
+        }
+
+        if let Some(registry_config) = config_state
+            .presigned_registry
+            .as_ref()
+            .filter(|c| c.enabled)
+        {
+            info!(
+                "Pre-signed URL registry enabled (token required: {})",
+                registry_config.require_token
+            );
+            validator.set_presigned_registry(Arc::new(PresignedUrlRegistry::new(
+                registry_config.require_token,
+            )));
+        }
+
+        let tenant_namespaces = TenantNamespaces::new(&config_state).map(Arc::new);
+        if let Some(namespaces) = &tenant_namespaces {
+            if !config_state.replica.enabled {
+                namespaces.prepare().await?;
+            }
+            info!(
+                "Sub-domain tenant routing enabled for *.{}",
+                config_state.tenant_domain.as_deref().unwrap_or_default()
+            );
+        }
+
+        let maintenance = Arc::new(MaintenanceMode::new(config_state.read_only.clone()));
+        if config_state.read_only.enabled {
+            info!("Starting in read-only mode");
+        }
+
+        if !config_state.replica.enabled {
+            bootstrap::bootstrap_buckets(
+                std::path::Path::new(&config_state.location),
+                &config_state.buckets,
+                Some(&maintenance),
+            )
+            .await?;
+        }
+
+        let notifier = if config_state.notifications.is_enabled() {
+            info!("Sending operational notifications to the configured webhook");
+            Some(Arc::new(Notifier::new(config_state.notifications.clone())))
+        } else {
+            None
+        };
+
+        let disk_monitor = if config_state.disk_watermarks.is_enabled() && !config_state.replica.enabled {
+            let mut monitor = DiskSpaceMonitor::new(config_state.disk_watermarks.clone(), &config_state.location);
+            if let Some(notifier) = &notifier {
+                monitor = monitor.with_notifier(notifier.clone());
+            }
+            let monitor = Arc::new(monitor);
+            monitor.check().await?;
+            info!(
+                "Disk space watermarks enabled for {}",
+                config_state.location
+            );
+            Some(monitor)
+        } else {
+            None
+        };
+
+        let auth_validator = Arc::new(validator);
+        let mut auth_layer = AuthLayer::new(auth_validator.clone(), config_state.clone());
+        let lockout = if config_state.auth_lockout.enabled {
+            info!(
+                "Locking out source IPs after {} failed signature checks within {}s",
+                config_state.auth_lockout.ip_threshold,
+                config_state.auth_lockout.window.as_secs()
+            );
+            let mut lockout = AuthLockout::new(config_state.auth_lockout.clone());
+            if let Some(notifier) = &notifier {
+                lockout = lockout.with_notifier(notifier.clone());
+            }
+            let lockout = Arc::new(lockout);
+            auth_layer = auth_layer.with_lockout(lockout.clone());
+            Some(lockout)
+        } else {
+            None
+        };
+
+        if config_state.clock_skew.enabled {
+            let mut monitor = ClockSkewMonitor::new(config_state.clock_skew.clone());
+            if let Some(notifier) = &notifier {
+                monitor = monitor.with_notifier(notifier.clone());
+            }
+            auth_layer = auth_layer.with_clock_skew(Arc::new(monitor));
+        }
+
+        let mut get_object_route = get(get_object::handle.layer(operation::tag("GetObject")))
+            .head(get_object::handle.layer(operation::tag("HeadObject")));
+        if !config_state.cache_rules.is_empty() {
+            info!("Adding caching headers for {} cache rule(s)", config_state.cache_rules.len());
+            get_object_route = get_object_route.layer(axum::middleware::from_fn(cache_control::apply));
+        }
+
+        // build our application with routes
+        let mut protected_routes = Router::new()
+            .route("/", get(list_buckets::handle.layer(operation::tag("ListBuckets"))))
+            .route("/", put(create_general_bucket::handle.layer(operation::tag("CreateBucket"))))
+            .route("/{bucket}", put(bucket_subresource::put))
+            .route("/{bucket}", get(bucket_subresource::get))
+            .route("/{bucket}", delete(bucket_subresource::delete))
+            .route("/{bucket}/{file}", get_object_route)
+            .route("/{bucket}/{file}", put(copy_object::put_or_copy))
+            .route("/{bucket}/{file}", delete(delete_object::handle.layer(operation::tag("DeleteObject"))))
+            .route(
+                "/_fily/buckets/{bucket}/policy",
+                get(bucket_policy::get_policy).put(bucket_policy::put_policy),
+            )
+            .route("/_fily/compose/{bucket}/{file}", post(compose_object::handle.layer(operation::tag("ComposeObject"))))
+            .route(
+                "/_fily/metadata/{bucket}/{file}",
+                patch(patch_metadata::handle.layer(operation::tag("PatchObjectMetadata"))),
+            )
+            .route("/_fily/presigned-urls", post(create_presigned_url::handle))
+            .route("/_fily/search/{bucket}", get(object_search::handle.layer(operation::tag("SearchObjects"))))
+            .route(
+                "/_fily/presigned-urls/{token}",
+                delete(revoke_presigned_url::handle),
+            )
+            .route("/_fily/admin/config", get(admin::get_config))
+            .route("/_fily/admin/manifests/{bucket}", get(admin::get_manifest))
+            .route("/_fily/admin/manifests/{bucket}/verify", post(admin::verify_manifest))
+            .route("/_fily/admin/scrub", get(admin::get_scrub_progress))
+            .route("/_fily/admin/cluster", get(admin::get_cluster))
+            .route(
+                "/_fily/admin/logging/sampling",
+                get(admin::get_log_sampling).put(admin::put_log_sampling),
+            )
+            .route(
+                "/_fily/admin/read-only",
+                get(admin::get_read_only).put(admin::put_read_only),
+            )
+            .route(
+                "/_fily/admin/read-only/buckets/{bucket}",
+                put(admin::put_bucket_read_only).delete(admin::delete_bucket_read_only),
+            )
+            .route(
+                "/_fily/admin/logging/level",
+                get(admin::get_log_level)
+                    .put(admin::put_log_level)
+                    .delete(admin::reset_log_level),
+            );
+        if config_state.resumable_uploads.enabled {
+            info!(
+                "Resumable uploads enabled for objects up to {} bytes",
+                config_state.resumable_uploads.max_size
+            );
+            protected_routes = protected_routes
+                .route("/_fily/uploads/{bucket}/{file}", post(resumable_upload::create))
+                .route(
+                    "/_fily/uploads/{bucket}/{file}/{upload_id}",
+                    head(resumable_upload::status)
+                        .patch(resumable_upload::append)
+                        .post(resumable_upload::commit)
+                        .delete(resumable_upload::abort),
+                )
+                .layer(Extension(Arc::new(ResumableUploads::new(
+                    config_state.resumable_uploads.clone(),
+                ))));
+        }
+        if config_state.batch.enabled {
+            info!(
+                "Batch requests enabled for up to {} objects of {} bytes",
+                config_state.batch.max_objects, config_state.batch.max_object_size
+            );
+            protected_routes = protected_routes
+                .route("/_fily/batch/put/{bucket}", post(batch::put.layer(operation::tag("BatchPutObjects"))))
+                .route("/_fily/batch/get/{bucket}", post(batch::get.layer(operation::tag("BatchGetObjects"))));
+        }
+        let protected_routes = protected_routes
+            .layer(axum::middleware::from_fn(unimplemented::reject_unimplemented))
+            .layer(axum::middleware::from_fn(disk_space::reject_uploads))
+            .layer(axum::middleware::from_fn(maintenance::reject_writes))
+            .layer(axum::middleware::from_fn(mfa_delete::require_mfa))
+            .layer(axum::middleware::from_fn(tenancy::enforce_bucket_access))
+            .layer(Extension(auth_validator))
+            .layer(auth_layer) // Add AWS SigV4 authentication layer
+            .layer(axum::middleware::from_fn(bucket_logging::record_access));
+
+        let mut app = Router::new()
+            .route("/_fily/metrics", get(telemetry::handle))
+            .route(cluster::GOSSIP_PATH, get(cluster::gossip))
+            .merge(protected_routes);
+        if config_state.webdav.enabled {
+            info!(
+                "WebDAV enabled under {} for {} user(s)",
+                webdav::WEBDAV_PATH_PREFIX,
+                config_state.webdav.users.len()
+            );
+            // Basic auth takes the place of SigV4; the bucket middleware applies as for S3 requests
+            let mut dav_routes = Router::new()
+                .route(webdav::WEBDAV_PATH_PREFIX, any(webdav::handle))
+                .route(&format!("{}/", webdav::WEBDAV_PATH_PREFIX), any(webdav::handle))
+                .route(&format!("{}/{{*path}}", webdav::WEBDAV_PATH_PREFIX), any(webdav::handle))
+                .layer(axum::middleware::from_fn(disk_space::reject_uploads))
+                .layer(axum::middleware::from_fn(maintenance::reject_writes))
+                .layer(axum::middleware::from_fn(mfa_delete::require_mfa))
+                .layer(axum::middleware::from_fn(tenancy::enforce_bucket_access))
+                .layer(axum::middleware::from_fn(webdav::authenticate));
+            if let Some(lockout) = &lockout {
+                dav_routes = dav_routes.layer(Extension(lockout.clone()));
+            }
+            app = app.merge(dav_routes);
+        }
+        let mut app = app.layer(axum::middleware::from_fn(tenancy::route_tenant));
+        if let Some(namespaces) = tenant_namespaces {
+            app = app.layer(Extension(namespaces));
+        }
+        if let Some(monitor) = &disk_monitor {
+            app = app.layer(Extension(monitor.clone()));
+        }
+        if let Some(mfa) = MfaDelete::new(&config_state.mfa_delete)? {
+            info!(
+                "Requiring {} codes for bucket{} deletes",
+                mfa_delete::MFA_HEADER,
+                if config_state.mfa_delete.objects { " and object" } else { "" }
+            );
+            app = app.layer(Extension(Arc::new(mfa)));
+        }
+        if config_state.replica.enabled {
+            app = app.layer(axum::middleware::from_fn(replica::reject_writes));
+        }
+        let cluster = Cluster::new(&config_state.cluster).map(Arc::new);
+        if let Some(cluster) = &cluster {
+            info!(
+                "Cluster node {} of {}, forwarding requests for buckets owned by other nodes",
+                cluster.node_id(),
+                config_state.cluster.peers.len()
+            );
+            app = app
+                .layer(axum::middleware::from_fn(cluster::forward_to_owner))
+                .layer(Extension(cluster.clone()));
+        }
+        if config_state.compression.enabled {
+            info!(
+                "Response compression enabled for bodies of at least {} bytes",
+                config_state.compression.min_size
+            );
+            app = app.layer(compression::layer(&config_state.compression));
+        }
+        let cpu_pool = Arc::new(CpuPool::new(&config_state.cpu_pool));
+        info!(
+            "CPU pool sized to {} threads with a queue of {}",
+            config_state.cpu_pool.threads, config_state.cpu_pool.max_queue
+        );
+        let mut syncs = Vec::new();
+        for rule in &config_state.sync {
+            let mut sync = BucketSync::new(rule.clone(), config_state.clone(), cpu_pool.clone())?
+                .with_write_guards(maintenance.clone(), disk_monitor.clone());
+            if let Some(notifier) = &notifier {
+                sync = sync.with_notifier(notifier.clone());
             }
+            info!(
+                "Pulling bucket {} from {} every {}s",
+                rule.bucket, rule.endpoint, rule.interval_secs
+            );
+            syncs.push(Arc::new(sync));
         }
+        let scrubber = if config_state.scrub.enabled {
+            let mut scrubber = Scrubber::new(config_state.clone(), cpu_pool.clone());
+            if let Some(notifier) = &notifier {
+                scrubber = scrubber.with_notifier(notifier.clone());
+            }
+            info!(
+                "Scrubbing stored checksums at {} bytes/s, every {}s per bucket",
+                config_state.scrub.rate,
+                config_state.scrub.interval.as_secs()
+            );
+            Some(Arc::new(scrubber))
+        } else {
+            None
+        };
+        // Replicas write nothing, so requests they serve go unlogged
+        let access_logs = (!config_state.replica.enabled)
+            .then(|| Arc::new(AccessLogs::new(config_state.clone(), cpu_pool.clone())));
+        if let Some(access_logs) = &access_logs {
+            app = app.layer(Extension(access_logs.clone()));
+        }
+        let app = app
+            .layer(Extension(Arc::new(UsageCounters::new())))
+            .layer(axum::middleware::from_fn(timeouts::enforce))
+            .layer(Extension(cpu_pool))
+            .layer(Extension(maintenance))
+            .layer(TraceLayer::new_for_http())
+            .layer(axum::middleware::from_fn(request_log::log_request))
+            // Outside the request log so it can read the slow request threshold
+            .layer(Extension(config_state.clone()))
+            // Outermost, so every layer and handler shares the request's context
+            .layer(axum::middleware::from_fn(request_info::attach));
+
+        Ok(Self {
+            config: config_state,
+            app,
+            disk_monitor,
+            syncs,
+            scrubber,
+            cluster,
+            access_logs,
+            notifier,
+            shutdown: ShutdownHandle::new(),
+            handle_signals: true,
+        })
+    }
+
+    /// Handle that stops the server gracefully once triggered
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
-    if credentials_added == 0 {
-        info!("No AWS credentials provided - authentication will be disabled");
-    } else {
-        info!("Successfully loaded {} AWS credential set(s)", credentials_added);
+    /// Leaves Ctrl+C, SIGTERM and SIGUSR1 to the embedding application
+    pub fn without_signal_handlers(mut self) -> Self {
+        self.handle_signals = false;
+        self
     }
 
-    let auth_validator = Arc::new(validator);
-    let auth_layer = AuthLayer::new(auth_validator, config_state.clone());
-
-    // build our application with routes
-    let protected_routes = Router::new()
-        .route("/", get(list_buckets::handle))
-        .route("/", put(create_general_bucket::handle))
-        .route("/{bucket}", put(create_bucket::handle))
-        .route("/{bucket}", get(search_bucket::handle))
-        .route("/{bucket}", delete(delete_bucket::handle))
-        .route("/{bucket}/{file}", get(get_object::handle))
-        .route("/{bucket}/{file}", put(put_object::handle))
-        .route("/{bucket}/{file}", delete(delete_object::handle))
-        .layer(auth_layer); // Add AWS SigV4 authentication layer
-
-    let app = Router::new()
-        .merge(protected_routes)
-        .layer(Extension(config_state))
-        .layer(TraceLayer::new_for_http());
-
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", &address, &port))
-        .await
-        .unwrap();
-
-    info!("running fily server on {}:{}", &address, &port);
-
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
-
-    Ok(())
+    /// Binds the configured address and port and serves until shut down
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let address = format!("{}:{}", self.config.address, self.config.port);
+        let listener = tokio::net::TcpListener::bind(&address)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to bind {}: {}", address, e))?;
+        self.serve_with_listener(listener).await
+    }
+
+    /// Serves on an already bound listener until shut down
+    pub async fn serve_with_listener(
+        self,
+        listener: tokio::net::TcpListener,
+    ) -> anyhow::Result<()> {
+        info!("running fily server on {}", listener.local_addr()?);
+
+        let mut background = Vec::new();
+        if let Some(notifier) = self.notifier {
+            background.push(tokio::spawn(notifier.deliver()));
+        }
+        if let Some(monitor) = self.disk_monitor {
+            background.push(tokio::spawn(monitor.watch()));
+        }
+        for sync in self.syncs {
+            background.push(tokio::spawn(sync.watch()));
+        }
+        if let Some(scrubber) = self.scrubber {
+            background.push(tokio::spawn(scrubber.watch()));
+        }
+        if let Some(cluster) = self.cluster {
+            background.push(tokio::spawn(cluster.watch()));
+        }
+        if let Some(access_logs) = self.access_logs {
+            background.push(tokio::spawn(access_logs.watch()));
+        }
+        #[cfg(unix)]
+        if self.handle_signals {
+            background.push(tokio::spawn(logging::watch_sigusr1()));
+        }
+
+        let shutdown = self.shutdown.clone();
+        let handle_signals = self.handle_signals;
+        lifecycle::serve_connections(listener, self.app, &self.config.timeouts, async move {
+            if handle_signals {
+                tokio::select! {
+                    _ = shutdown.wait() => {},
+                    _ = shutdown_signal() => {},
+                }
+            } else {
+                shutdown.wait().await;
+            }
+            info!("shutting down fily server");
+        })
+        .await;
+
+        for task in background {
+            task.abort();
+        }
+        Ok(())
+    }
 }
 
 async fn shutdown_signal() {
@@ -0,0 +1,677 @@
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use crc::{Crc, CRC_32_ISCSI, CRC_32_ISO_HDLC, CRC_64_NVME};
+use hmac::{Hmac, Mac};
+use hyper::HeaderMap;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tracing::debug;
+
+/// Payload hash value sent by SDKs that stream the body as aws-chunked with trailing checksums
+pub const STREAMING_UNSIGNED_PAYLOAD_TRAILER: &str = "STREAMING-UNSIGNED-PAYLOAD-TRAILER";
+/// Payload hash values of aws-chunked bodies whose chunks each carry a signature
+pub const STREAMING_AWS4_HMAC_SHA256_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+pub const STREAMING_AWS4_HMAC_SHA256_PAYLOAD_TRAILER: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER";
+
+const X_AMZ_CONTENT_SHA256_HEADER: &str = "x-amz-content-sha256";
+const X_AMZ_TRAILER_HEADER: &str = "x-amz-trailer";
+const X_AMZ_DECODED_CONTENT_LENGTH_HEADER: &str = "x-amz-decoded-content-length";
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+const CRC64NVME: Crc<u64> = Crc::<u64>::new(&CRC_64_NVME);
+
+/// Longest chunk header or trailer line accepted while verifying chunk signatures
+const MAX_CHUNK_LINE: usize = 4096;
+const CHUNK_SIGNATURE_EXTENSION: &str = "chunk-signature=";
+const X_AMZ_TRAILER_SIGNATURE: &str = "x-amz-trailer-signature";
+
+#[derive(Debug, Error)]
+pub enum AwsChunkedError {
+    #[error("Malformed aws-chunked body: {0}")]
+    MalformedChunk(String),
+    #[error("Unsupported checksum algorithm: {0}")]
+    UnsupportedChecksumAlgorithm(String),
+    #[error("Missing trailing header: {0}")]
+    MissingTrailer(String),
+    #[error("Checksum mismatch for {0}")]
+    ChecksumMismatch(String),
+    #[error("Decoded content length mismatch: expected {expected}, got {actual}")]
+    DecodedLengthMismatch { expected: u64, actual: u64 },
+    #[error("Chunk signature does not match")]
+    SignatureMismatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    Crc32c,
+    Crc64Nvme,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// Resolves an algorithm from its checksum header name, e.g. `x-amz-checksum-crc32`
+    pub fn from_header_name(name: &str) -> Result<Self, AwsChunkedError> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "x-amz-checksum-crc32" => Ok(Self::Crc32),
+            "x-amz-checksum-crc32c" => Ok(Self::Crc32c),
+            "x-amz-checksum-crc64nvme" => Ok(Self::Crc64Nvme),
+            "x-amz-checksum-sha1" => Ok(Self::Sha1),
+            "x-amz-checksum-sha256" => Ok(Self::Sha256),
+            other => Err(AwsChunkedError::UnsupportedChecksumAlgorithm(other.to_string())),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Crc32 => "CRC32",
+            Self::Crc32c => "CRC32C",
+            Self::Crc64Nvme => "CRC64NVME",
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA256",
+        }
+    }
+
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            Self::Crc32 => "x-amz-checksum-crc32",
+            Self::Crc32c => "x-amz-checksum-crc32c",
+            Self::Crc64Nvme => "x-amz-checksum-crc64nvme",
+            Self::Sha1 => "x-amz-checksum-sha1",
+            Self::Sha256 => "x-amz-checksum-sha256",
+        }
+    }
+
+    /// Computes the base64-encoded checksum of `data` as S3 represents it on the wire
+    pub fn compute(&self, data: &[u8]) -> String {
+        let digest = match self {
+            Self::Crc32 => CRC32.checksum(data).to_be_bytes().to_vec(),
+            Self::Crc32c => CRC32C.checksum(data).to_be_bytes().to_vec(),
+            Self::Crc64Nvme => CRC64NVME.checksum(data).to_be_bytes().to_vec(),
+            Self::Sha1 => Sha1::digest(data).to_vec(),
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+        };
+        general_purpose::STANDARD.encode(digest)
+    }
+}
+
+/// A decoded aws-chunked request body along with any trailing headers
+#[derive(Debug)]
+pub struct DecodedBody {
+    pub data: Bytes,
+    pub trailers: Vec<(String, String)>,
+}
+
+impl DecodedBody {
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Checksum verified against the trailer of an aws-chunked upload
+#[derive(Debug, Clone)]
+pub struct VerifiedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String,
+}
+
+/// Returns true when the request body is aws-chunked, with or without signed chunks and trailing
+/// headers
+pub fn is_aws_chunked_request(headers: &HeaderMap) -> bool {
+    headers
+        .get(X_AMZ_CONTENT_SHA256_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            matches!(
+                v,
+                STREAMING_UNSIGNED_PAYLOAD_TRAILER
+                    | STREAMING_AWS4_HMAC_SHA256_PAYLOAD
+                    | STREAMING_AWS4_HMAC_SHA256_PAYLOAD_TRAILER
+            )
+        })
+}
+
+/// Decodes an aws-chunked body: `<hex-size>[;ext]\r\n<data>\r\n ... 0\r\n<trailers>\r\n`, where
+/// signed chunks carry their `chunk-signature` as an extension
+pub fn decode_aws_chunked(body: &[u8]) -> Result<DecodedBody, AwsChunkedError> {
+    let mut data = Vec::with_capacity(body.len());
+    let mut pos = 0;
+
+    loop {
+        let line = read_line(body, &mut pos)?;
+        let size_str = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| {
+            AwsChunkedError::MalformedChunk(format!("invalid chunk size '{}'", size_str))
+        })?;
+
+        if size == 0 {
+            break;
+        }
+
+        let end = pos
+            .checked_add(size)
+            .filter(|end| *end <= body.len())
+            .ok_or_else(|| AwsChunkedError::MalformedChunk("chunk exceeds body length".to_string()))?;
+        data.extend_from_slice(&body[pos..end]);
+        pos = end;
+
+        if body.get(pos..pos + 2) != Some(b"\r\n") {
+            return Err(AwsChunkedError::MalformedChunk(
+                "chunk data is not terminated by CRLF".to_string(),
+            ));
+        }
+        pos += 2;
+    }
+
+    let mut trailers = Vec::new();
+    while pos < body.len() {
+        let line = read_line(body, &mut pos)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| {
+            AwsChunkedError::MalformedChunk(format!("invalid trailer line '{}'", line))
+        })?;
+        trailers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    debug!(
+        "Decoded aws-chunked body: {} bytes, {} trailer(s)",
+        data.len(),
+        trailers.len()
+    );
+
+    Ok(DecodedBody {
+        data: Bytes::from(data),
+        trailers,
+    })
+}
+
+/// Verifies the decoded length and the checksum announced in `x-amz-trailer`
+pub fn verify_trailing_checksum(
+    headers: &HeaderMap,
+    decoded: &DecodedBody,
+) -> Result<Option<VerifiedChecksum>, AwsChunkedError> {
+    if let Some(expected) = headers
+        .get(X_AMZ_DECODED_CONTENT_LENGTH_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        let actual = decoded.data.len() as u64;
+        if expected != actual {
+            return Err(AwsChunkedError::DecodedLengthMismatch { expected, actual });
+        }
+    }
+
+    let Some(trailer_name) = headers
+        .get(X_AMZ_TRAILER_HEADER)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Ok(None);
+    };
+
+    let algorithm = ChecksumAlgorithm::from_header_name(trailer_name)?;
+    let provided = decoded
+        .trailer(algorithm.header_name())
+        .ok_or_else(|| AwsChunkedError::MissingTrailer(algorithm.header_name().to_string()))?;
+
+    let calculated = algorithm.compute(&decoded.data);
+    if calculated != provided {
+        return Err(AwsChunkedError::ChecksumMismatch(
+            algorithm.header_name().to_string(),
+        ));
+    }
+
+    Ok(Some(VerifiedChecksum {
+        algorithm,
+        value: calculated,
+    }))
+}
+
+enum ChunkState {
+    /// Reading a `<hex-size>;chunk-signature=<signature>` line
+    Header,
+    /// Hashing the data of a chunk
+    Data { remaining: usize, signature: String },
+    /// Expecting the CRLF that ends the data of a chunk, the final one when `last`
+    DataEnd { signature: String, last: bool },
+    /// Reading trailer lines after the final chunk, up to an empty line
+    Trailers,
+    Done,
+}
+
+/// Checks the signature chain of a `STREAMING-AWS4-HMAC-SHA256-PAYLOAD[-TRAILER]` body as it
+/// streams past, without holding it: every chunk is signed over the signature before it, the
+/// first over the request's own, so chunks cannot be altered, dropped or reordered
+pub struct ChunkSignatureVerifier {
+    signing_key: [u8; 32],
+    timestamp: String,
+    scope: String,
+    previous: String,
+    with_trailer: bool,
+    state: ChunkState,
+    line: Vec<u8>,
+    hasher: Sha256,
+    trailers: String,
+    trailer_signature: Option<String>,
+}
+
+impl ChunkSignatureVerifier {
+    /// `seed_signature` is the signature of the request, `timestamp` its `x-amz-date` and `scope`
+    /// its credential scope; `with_trailer` expects signed trailing headers after the final chunk
+    pub fn new(
+        signing_key: [u8; 32],
+        timestamp: String,
+        scope: String,
+        seed_signature: String,
+        with_trailer: bool,
+    ) -> Self {
+        Self {
+            signing_key,
+            timestamp,
+            scope,
+            previous: seed_signature,
+            with_trailer,
+            state: ChunkState::Header,
+            line: Vec::new(),
+            hasher: Sha256::new(),
+            trailers: String::new(),
+            trailer_signature: None,
+        }
+    }
+
+    /// Feeds the next bytes of the body, failing as soon as a chunk does not verify
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), AwsChunkedError> {
+        while !data.is_empty() {
+            match &mut self.state {
+                ChunkState::Header | ChunkState::Trailers => {
+                    let Some(line) = self.take_line(&mut data)? else {
+                        return Ok(());
+                    };
+                    if matches!(self.state, ChunkState::Header) {
+                        self.start_chunk(&line)?;
+                    } else {
+                        self.trailer_line(&line)?;
+                    }
+                }
+                ChunkState::Data { remaining, signature } => {
+                    let n = (*remaining).min(data.len());
+                    self.hasher.update(&data[..n]);
+                    data = &data[n..];
+                    *remaining -= n;
+                    if *remaining == 0 {
+                        let signature = std::mem::take(signature);
+                        self.state = ChunkState::DataEnd { signature, last: false };
+                    }
+                }
+                ChunkState::DataEnd { .. } => {
+                    let Some(line) = self.take_line(&mut data)? else {
+                        return Ok(());
+                    };
+                    if !line.is_empty() {
+                        return Err(AwsChunkedError::MalformedChunk(
+                            "chunk data is not terminated by CRLF".to_string(),
+                        ));
+                    }
+                    let ChunkState::DataEnd { signature, last } = std::mem::replace(&mut self.state, ChunkState::Header) else {
+                        unreachable!()
+                    };
+                    let data_hash = hex::encode(self.hasher.finalize_reset());
+                    self.check_chunk(&signature, &data_hash)?;
+                    if last {
+                        self.state = ChunkState::Done;
+                    }
+                }
+                ChunkState::Done => {
+                    return Err(AwsChunkedError::MalformedChunk(
+                        "data after the final chunk".to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Called at the end of the body, which must have ended with its final chunk
+    pub fn finish(&self) -> Result<(), AwsChunkedError> {
+        match self.state {
+            ChunkState::Done => Ok(()),
+            _ => Err(AwsChunkedError::MalformedChunk(
+                "body ends before its final chunk".to_string(),
+            )),
+        }
+    }
+
+    /// Moves bytes from `data` into the current line, returning it once its CRLF arrived
+    fn take_line(&mut self, data: &mut &[u8]) -> Result<Option<String>, AwsChunkedError> {
+        let end = data.iter().position(|b| *b == b'\n').map_or(data.len(), |i| i + 1);
+        self.line.extend_from_slice(&data[..end]);
+        *data = &data[end..];
+        if self.line.len() > MAX_CHUNK_LINE {
+            return Err(AwsChunkedError::MalformedChunk("chunk line too long".to_string()));
+        }
+        let Some(line) = self.line.strip_suffix(b"\r\n") else {
+            if self.line.ends_with(b"\n") {
+                return Err(AwsChunkedError::MalformedChunk("missing CRLF".to_string()));
+            }
+            return Ok(None);
+        };
+        let line = std::str::from_utf8(line)
+            .map_err(|_| AwsChunkedError::MalformedChunk("non UTF-8 chunk header".to_string()))?
+            .to_string();
+        self.line.clear();
+        Ok(Some(line))
+    }
+
+    fn start_chunk(&mut self, line: &str) -> Result<(), AwsChunkedError> {
+        let (size, signature) = line
+            .split_once(';')
+            .and_then(|(size, ext)| Some((size, ext.strip_prefix(CHUNK_SIGNATURE_EXTENSION)?)))
+            .ok_or_else(|| AwsChunkedError::MalformedChunk("chunk without a chunk-signature".to_string()))?;
+        let size = usize::from_str_radix(size, 16).map_err(|_| {
+            AwsChunkedError::MalformedChunk(format!("invalid chunk size '{}'", size))
+        })?;
+        let signature = signature.to_string();
+        if size > 0 {
+            self.state = ChunkState::Data { remaining: size, signature };
+        } else if self.with_trailer {
+            // The final chunk of a body with trailers is followed by the trailers, not a CRLF
+            self.check_chunk(&signature, EMPTY_SHA256)?;
+            self.state = ChunkState::Trailers;
+        } else {
+            self.state = ChunkState::DataEnd { signature, last: true };
+        }
+        Ok(())
+    }
+
+    fn trailer_line(&mut self, line: &str) -> Result<(), AwsChunkedError> {
+        if !line.is_empty() {
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                AwsChunkedError::MalformedChunk(format!("invalid trailer line '{}'", line))
+            })?;
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            if name == X_AMZ_TRAILER_SIGNATURE {
+                self.trailer_signature = Some(value.to_string());
+            } else {
+                self.trailers.push_str(&format!("{}:{}\n", name, value));
+            }
+            return Ok(());
+        }
+
+        let signature = self
+            .trailer_signature
+            .take()
+            .ok_or_else(|| AwsChunkedError::MissingTrailer(X_AMZ_TRAILER_SIGNATURE.to_string()))?;
+        let trailers_hash = hex::encode(Sha256::digest(self.trailers.as_bytes()));
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-TRAILER\n{}\n{}\n{}\n{}",
+            self.timestamp, self.scope, self.previous, trailers_hash
+        );
+        self.check(&signature, &string_to_sign)?;
+        self.state = ChunkState::Done;
+        Ok(())
+    }
+
+    /// Checks the signature of a chunk whose data hashes to `data_hash`
+    fn check_chunk(&mut self, signature: &str, data_hash: &str) -> Result<(), AwsChunkedError> {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            self.timestamp, self.scope, self.previous, EMPTY_SHA256, data_hash
+        );
+        self.check(signature, &string_to_sign)
+    }
+
+    fn check(&mut self, signature: &str, string_to_sign: &str) -> Result<(), AwsChunkedError> {
+        let expected = chunk_signature(&self.signing_key, string_to_sign);
+        if !bool::from(expected.as_bytes().ct_eq(signature.as_bytes())) {
+            return Err(AwsChunkedError::SignatureMismatch);
+        }
+        self.previous = expected;
+        Ok(())
+    }
+}
+
+/// SHA-256 of the empty string, hashed into every chunk signature in place of chunk headers
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn chunk_signature(signing_key: &[u8; 32], string_to_sign: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key).expect("HMAC can take key of any size");
+    mac.update(string_to_sign.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn read_line<'a>(body: &'a [u8], pos: &mut usize) -> Result<&'a str, AwsChunkedError> {
+    let remaining = &body[*pos..];
+    let line_len = remaining
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or_else(|| AwsChunkedError::MalformedChunk("missing CRLF".to_string()))?;
+    let line = std::str::from_utf8(&remaining[..line_len])
+        .map_err(|_| AwsChunkedError::MalformedChunk("non UTF-8 chunk header".to_string()))?;
+    *pos += line_len + 2;
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked_body(chunks: &[&[u8]], trailers: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in chunks {
+            body.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            body.extend_from_slice(chunk);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"0\r\n");
+        for (name, value) in trailers {
+            body.extend_from_slice(format!("{}:{}\r\n", name, value).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body
+    }
+
+    #[test]
+    fn test_checksum_known_values() {
+        assert_eq!(ChecksumAlgorithm::Crc32.compute(b"Hello, world!"), "6+bG5g==");
+        assert_eq!(ChecksumAlgorithm::Crc32c.compute(b""), "AAAAAA==");
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.compute(b""),
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
+
+    #[test]
+    fn test_decode_with_trailer() {
+        let checksum = ChecksumAlgorithm::Crc32.compute(b"Hello, world!");
+        let body = chunked_body(
+            &[b"Hello, ", b"world!"],
+            &[("x-amz-checksum-crc32", checksum.as_str())],
+        );
+
+        let decoded = decode_aws_chunked(&body).unwrap();
+        assert_eq!(decoded.data.as_ref(), b"Hello, world!");
+        assert_eq!(decoded.trailer("x-amz-checksum-crc32"), Some(checksum.as_str()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-trailer", "x-amz-checksum-crc32".parse().unwrap());
+        headers.insert("x-amz-decoded-content-length", "13".parse().unwrap());
+
+        let verified = verify_trailing_checksum(&headers, &decoded).unwrap().unwrap();
+        assert_eq!(verified.algorithm, ChecksumAlgorithm::Crc32);
+        assert_eq!(verified.value, checksum);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let body = chunked_body(&[b"data"], &[("x-amz-checksum-crc32", "AAAAAA==")]);
+        let decoded = decode_aws_chunked(&body).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-trailer", "x-amz-checksum-crc32".parse().unwrap());
+
+        assert!(matches!(
+            verify_trailing_checksum(&headers, &decoded),
+            Err(AwsChunkedError::ChecksumMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_signed_chunks_with_trailer() {
+        let checksum = ChecksumAlgorithm::Crc32c.compute(b"Hello, world!");
+        let signature = "0".repeat(64);
+        let body = format!(
+            "d;chunk-signature={signature}\r\nHello, world!\r\n0;chunk-signature={signature}\r\n\
+             x-amz-checksum-crc32c:{checksum}\r\nx-amz-trailer-signature:{signature}\r\n\r\n"
+        );
+
+        let decoded = decode_aws_chunked(body.as_bytes()).unwrap();
+        assert_eq!(decoded.data.as_ref(), b"Hello, world!");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-content-sha256", STREAMING_AWS4_HMAC_SHA256_PAYLOAD_TRAILER.parse().unwrap());
+        headers.insert("x-amz-trailer", "x-amz-checksum-crc32c".parse().unwrap());
+        assert!(is_aws_chunked_request(&headers));
+
+        let verified = verify_trailing_checksum(&headers, &decoded).unwrap().unwrap();
+        assert_eq!(verified.algorithm, ChecksumAlgorithm::Crc32c);
+        assert_eq!(verified.value, checksum);
+    }
+
+    #[test]
+    fn test_aws_chunked_request_types() {
+        let chunked = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-amz-content-sha256", value.parse().unwrap());
+            is_aws_chunked_request(&headers)
+        };
+        assert!(chunked(STREAMING_UNSIGNED_PAYLOAD_TRAILER));
+        assert!(chunked(STREAMING_AWS4_HMAC_SHA256_PAYLOAD));
+        assert!(chunked(STREAMING_AWS4_HMAC_SHA256_PAYLOAD_TRAILER));
+        assert!(!chunked("UNSIGNED-PAYLOAD"));
+        assert!(!is_aws_chunked_request(&HeaderMap::new()));
+    }
+
+    /// A verifier with the key, date and seed signature of the SigV4 streaming examples in the S3
+    /// documentation
+    fn example_verifier(seed_signature: &str, with_trailer: bool) -> ChunkSignatureVerifier {
+        let hmac = |key: &[u8], data: &str| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data.as_bytes());
+            mac.finalize().into_bytes()
+        };
+        let key = hmac(b"AWS4wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY", "20130524");
+        let key = hmac(&hmac(&hmac(&key, "us-east-1"), "s3"), "aws4_request");
+        ChunkSignatureVerifier::new(
+            key.into(),
+            "20130524T000000Z".to_string(),
+            "20130524/us-east-1/s3/aws4_request".to_string(),
+            seed_signature.to_string(),
+            with_trailer,
+        )
+    }
+
+    /// The example body: 64 KiB of `a`, then 1 KiB of `a`, then the final chunk and `trailer`
+    fn example_body(signatures: [&str; 3], trailer: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (data, signature) in [vec![b'a'; 65536], vec![b'a'; 1024], vec![]].iter().zip(signatures) {
+            body.extend_from_slice(format!("{:x};chunk-signature={}\r\n", data.len(), signature).as_bytes());
+            body.extend_from_slice(data);
+            if !data.is_empty() || trailer.is_empty() {
+                body.extend_from_slice(b"\r\n");
+            }
+        }
+        body.extend_from_slice(trailer.as_bytes());
+        body
+    }
+
+    #[test]
+    fn test_chunk_signatures_match_aws_example() {
+        let seed = "4f232c4386841ef735655705268965c44a0e4690baa4adea153f7db9fa80a0a9";
+        let body = example_body(
+            [
+                "ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648",
+                "0055627c9e194cb4542bae2aa5492e3c1575bbb81b612b7d234b86a503ef5497",
+                "b6c6ea8a5354eaf15b3cb7646744f4275b71ea724fed81ceb9323e279d449df9",
+            ],
+            "",
+        );
+        assert_eq!(decode_aws_chunked(&body).unwrap().data.len(), 66560);
+
+        let mut verifier = example_verifier(seed, false);
+        verifier.update(&body).unwrap();
+        verifier.finish().unwrap();
+
+        // However the body is split up as it arrives
+        let mut verifier = example_verifier(seed, false);
+        for piece in body.chunks(7) {
+            verifier.update(piece).unwrap();
+        }
+        verifier.finish().unwrap();
+
+        // A changed byte breaks its chunk's signature
+        let mut tampered = body.clone();
+        tampered[100] = b'b';
+        let mut verifier = example_verifier(seed, false);
+        assert!(matches!(verifier.update(&tampered), Err(AwsChunkedError::SignatureMismatch)));
+
+        // So does a different request signature to chain from
+        let mut verifier = example_verifier(&"0".repeat(64), false);
+        assert!(matches!(verifier.update(&body), Err(AwsChunkedError::SignatureMismatch)));
+
+        // A body cut off before its final chunk is incomplete
+        let final_chunk = body.len() - 86;
+        let mut verifier = example_verifier(seed, false);
+        verifier.update(&body[..final_chunk]).unwrap();
+        assert!(matches!(verifier.finish(), Err(AwsChunkedError::MalformedChunk(_))));
+
+        // And nothing may follow the final chunk
+        let mut verifier = example_verifier(seed, false);
+        verifier.update(&body).unwrap();
+        assert!(verifier.update(b"1;chunk-signature=").is_err());
+    }
+
+    #[test]
+    fn test_trailer_signature_matches_aws_example() {
+        let seed = "106e2a8a18243abcf37539882f36619c00e2dfc72633413f02d3b74544bfeb8e";
+        let signatures = [
+            "b474d8862b1487a5145d686f57f013e54db672cee1c953b3010fb58501ef5aa2",
+            "1c1344b170168f8e65b41376b44b20fe354e373826ccbbe2c1d40a8cae51e5c7",
+            "2ca2aba2005185cf7159c6277faf83795951dd77a3a99e6e65d5c9f85863f992",
+        ];
+        let trailer = "x-amz-checksum-crc32c:sOO8/Q==\r\n\
+                       x-amz-trailer-signature:d81f82fc3505edab99d459891051a732e8730629a2e4a59689829ca17fe2e435\r\n\r\n";
+
+        let mut verifier = example_verifier(seed, true);
+        verifier.update(&example_body(signatures, trailer)).unwrap();
+        verifier.finish().unwrap();
+
+        let mut verifier = example_verifier(seed, true);
+        let tampered = trailer.replace("sOO8/Q==", "AAAAAA==");
+        assert!(matches!(
+            verifier.update(&example_body(signatures, &tampered)),
+            Err(AwsChunkedError::SignatureMismatch)
+        ));
+
+        let mut verifier = example_verifier(seed, true);
+        let unsigned = "x-amz-checksum-crc32c:sOO8/Q==\r\n\r\n";
+        assert!(matches!(
+            verifier.update(&example_body(signatures, unsigned)),
+            Err(AwsChunkedError::MissingTrailer(_))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_chunks() {
+        assert!(decode_aws_chunked(b"zz\r\ndata\r\n0\r\n\r\n").is_err());
+        assert!(decode_aws_chunked(b"10\r\nshort\r\n0\r\n\r\n").is_err());
+        assert!(decode_aws_chunked(b"4\r\ndata").is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::extract::{Path, Query, Request};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::auth_middleware::Principal;
+use super::cpu_pool::CpuPool;
+use super::get_object;
+use super::policy_condition::RequestContext;
+use super::put_object;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::tenancy::{authorize_bucket_access, BucketAccess};
+use super::Config;
+
+/// Room for the key and JSON framing of one entry on top of its base64 data
+const ENTRY_OVERHEAD: usize = 4096;
+
+/// Limits of the batch extension
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchConfig {
+    pub enabled: bool,
+    /// Most objects one batch request may read or write
+    pub max_objects: usize,
+    /// Largest object in bytes a batch may carry; bigger objects need a regular PUT or GET
+    pub max_object_size: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_objects: 1000,
+            max_object_size: 64 * 1024,
+        }
+    }
+}
+
+impl BatchConfig {
+    /// Largest request body a batch of `max_objects` entries of `max_object_size` can need
+    fn max_request_size(&self) -> usize {
+        let entry = self.max_object_size.div_ceil(3) * 4 + ENTRY_OVERHEAD;
+        self.max_objects.saturating_mul(entry)
+    }
+}
+
+/// One line of a batch PUT
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PutEntry {
+    key: String,
+    /// Object data, base64 encoded
+    data: String,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+/// One line of a batch GET
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetEntry {
+    key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EntryError {
+    code: &'static str,
+    message: String,
+}
+
+/// Outcome of one entry, answered on the line matching the entry's line in the request
+#[derive(Debug, Default, Serialize)]
+struct EntryResult {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// Object data, base64 encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<EntryError>,
+}
+
+impl EntryResult {
+    fn failed(key: String, error: S3AppError) -> Self {
+        let message = error.message.unwrap_or_else(|| error.code.default_message().to_string());
+        Self {
+            key,
+            error: Some(EntryError {
+                code: error.code.as_str(),
+                message,
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+fn header(response: &Response, name: &str) -> Option<String> {
+    response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+}
+
+fn invalid_argument(message: String) -> S3AppError {
+    S3AppError::with_message(S3ErrorCode::InvalidArgument, message)
+}
+
+/// Entries of an NDJSON request body, one JSON document per non-empty line
+fn parse_entries<T: serde::de::DeserializeOwned>(body: &[u8], max_objects: usize) -> Result<Vec<T>, S3AppError> {
+    let mut entries = Vec::new();
+    for (index, line) in body.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let entry = serde_json::from_slice(line)
+            .map_err(|e| invalid_argument(format!("Invalid batch entry on line {}: {}", index + 1, e)))?;
+        entries.push(entry);
+    }
+    if entries.is_empty() || entries.len() > max_objects {
+        return Err(invalid_argument(format!(
+            "A batch request needs between 1 and {} entries",
+            max_objects
+        )));
+    }
+    Ok(entries)
+}
+
+/// Reads the body of a batch request into its entries after checking the bucket exists
+async fn read_request<T: serde::de::DeserializeOwned>(
+    config: &Config,
+    bucket: &str,
+    req: Request,
+) -> Result<Vec<T>, S3AppError> {
+    let body = axum::body::to_bytes(req.into_body(), config.batch.max_request_size())
+        .await
+        .map_err(|e| invalid_argument(format!("Invalid batch request: {}", e)))?;
+    if !FsPath::new(&config.location).join(bucket).is_dir() {
+        return Err(S3AppError::no_such_bucket(bucket));
+    }
+    parse_entries(&body, config.batch.max_objects)
+}
+
+fn ndjson_response(results: &[EntryResult]) -> Result<Response, S3AppError> {
+    let mut body = Vec::new();
+    for result in results {
+        serde_json::to_writer(&mut body, result).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+        body.push(b'\n');
+    }
+    Ok((StatusCode::OK, [("content-type", "application/x-ndjson")], body).into_response())
+}
+
+fn record(operation: &'static str, results: &[EntryResult]) -> usize {
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    metrics::counter!("fily_batch_objects_total", "operation" => operation, "result" => "ok")
+        .increment((results.len() - failed) as u64);
+    metrics::counter!("fily_batch_objects_total", "operation" => operation, "result" => "error")
+        .increment(failed as u64);
+    failed
+}
+
+/// `POST /_fily/batch/put/{bucket}`: stores many small objects from one NDJSON request, each line
+/// `{"key", "data" (base64), "content_type"?}`. Every entry is stored like a PUT, and answered
+/// by a line with its ETag or its error, so one failing key does not fail the rest.
+pub async fn put(
+    config: Extension<Arc<Config>>,
+    Extension(cpu_pool): Extension<Arc<CpuPool>>,
+    principal: Principal,
+    Path(bucket): Path<String>,
+    req: Request,
+) -> Result<Response, S3AppError> {
+    // Every entry is a PutObject, so the batch is refused whole where that is disabled
+    config.disabled_operations.check("PutObject", Some(&bucket))?;
+    let entries: Vec<PutEntry> = read_request(&config, &bucket, req).await?;
+
+    // Validate the whole batch before storing anything
+    let mut objects = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.into_iter().enumerate() {
+        let data = general_purpose::STANDARD
+            .decode(&entry.data)
+            .map_err(|e| invalid_argument(format!("Invalid data for {} (entry {}): {}", entry.key, index + 1, e)))?;
+        if data.len() > config.batch.max_object_size {
+            return Err(S3AppError::with_message(
+                S3ErrorCode::EntityTooLarge,
+                format!(
+                    "{} is larger than the batch limit of {} bytes, use PutObject instead",
+                    entry.key, config.batch.max_object_size
+                ),
+            ));
+        }
+        objects.push((entry.key, entry.content_type, data));
+    }
+
+    let mut results = Vec::with_capacity(objects.len());
+    for (key, content_type, data) in objects {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = content_type.and_then(|ct| ct.parse().ok()) {
+            headers.insert("content-type", value);
+        }
+        let size = data.len() as u64;
+        let stored = put_object::handle(
+            config.clone(),
+            Extension(cpu_pool.clone()),
+            principal.clone(),
+            headers,
+            Path((bucket.clone(), key.clone())),
+            Bytes::from(data),
+        )
+        .await;
+        results.push(match stored {
+            Ok(response) => EntryResult {
+                etag: header(&response, "etag"),
+                size: Some(size),
+                key,
+                ..Default::default()
+            },
+            Err(e) => EntryResult::failed(key, e),
+        });
+    }
+
+    let failed = record("put", &results);
+    info!(
+        "Stored {} of {} batched objects in {} for {}",
+        results.len() - failed,
+        results.len(),
+        bucket,
+        principal
+    );
+    ndjson_response(&results)
+}
+
+/// `POST /_fily/batch/get/{bucket}`: reads many small objects in one NDJSON request, each line
+/// `{"key"}`. Every entry is read like a GET and answered by a line with its base64 data, ETag and
+/// content type, or its error.
+pub async fn get(
+    config: Extension<Arc<Config>>,
+    Extension(cpu_pool): Extension<Arc<CpuPool>>,
+    principal: Principal,
+    Path(bucket): Path<String>,
+    req: Request,
+) -> Result<Response, S3AppError> {
+    config.disabled_operations.check("GetObject", Some(&bucket))?;
+    // The bucket middleware only checks batch writes; reads are authorised here
+    let context = RequestContext::from_request(&req, &config.trusted_proxies);
+    authorize_bucket_access(&config, &principal.access_key(), &bucket, BucketAccess::Read, &context).await?;
+    let entries: Vec<GetEntry> = read_request(&config, &bucket, req).await?;
+
+    let mut results = Vec::with_capacity(entries.len());
+    for GetEntry { key } in entries {
+        // Tag conditions of the bucket policy depend on the object read
+        let entry_context = RequestContext {
+            object_key: Some(key.clone()),
+            ..context.clone()
+        };
+        let access_key = principal.access_key();
+        if let Err(e) = authorize_bucket_access(&config, &access_key, &bucket, BucketAccess::Read, &entry_context).await {
+            results.push(EntryResult::failed(key, e));
+            continue;
+        }
+        let response = get_object::handle(
+            config.clone(),
+            Extension(cpu_pool.clone()),
+            principal.clone(),
+            Path((bucket.clone(), key.clone())),
+            Query(HashMap::new()),
+            HeaderMap::new(),
+        )
+        .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                results.push(EntryResult::failed(key, e));
+                continue;
+            }
+        };
+        let (etag, content_type) = (header(&response, "etag"), header(&response, "content-type"));
+        match axum::body::to_bytes(response.into_body(), config.batch.max_object_size).await {
+            Ok(data) => results.push(EntryResult {
+                etag,
+                size: Some(data.len() as u64),
+                content_type,
+                data: Some(general_purpose::STANDARD.encode(&data)),
+                key,
+                ..Default::default()
+            }),
+            Err(_) => results.push(EntryResult::failed(
+                key,
+                S3AppError::with_message(
+                    S3ErrorCode::EntityTooLarge,
+                    format!(
+                        "The object is larger than the batch limit of {} bytes, use GetObject instead",
+                        config.batch.max_object_size
+                    ),
+                ),
+            )),
+        }
+    }
+
+    let failed = record("get", &results);
+    info!(
+        "Served {} of {} batched objects from {} to {}",
+        results.len() - failed,
+        results.len(),
+        bucket,
+        principal
+    );
+    ndjson_response(&results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entries() {
+        let body = b"{\"key\":\"a\"}\r\n\n{\"key\":\"b/c\"}";
+        let entries: Vec<GetEntry> = parse_entries(body, 10).unwrap();
+        assert_eq!(entries.iter().map(|e| e.key.as_str()).collect::<Vec<_>>(), vec!["a", "b/c"]);
+
+        assert!(parse_entries::<GetEntry>(b"", 10).is_err());
+        assert!(parse_entries::<GetEntry>(b"{\"key\":\"a\"}\n{\"key\":\"b\"}", 1).is_err());
+        let error = parse_entries::<GetEntry>(b"{\"key\":\"a\"}\n{\"name\":\"b\"}", 10).unwrap_err();
+        assert!(error.message.unwrap().contains("line 2"));
+    }
+
+    #[test]
+    fn test_max_request_size() {
+        let config = BatchConfig {
+            enabled: true,
+            max_objects: 10,
+            max_object_size: 3,
+        };
+        assert_eq!(config.max_request_size(), 10 * (4 + ENTRY_OVERHEAD));
+    }
+}
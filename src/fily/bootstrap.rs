@@ -0,0 +1,206 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use tracing::info;
+
+use super::create_bucket::is_valid_bucket_name;
+use super::maintenance::MaintenanceMode;
+use super::ownership_controls::{load_object_ownership, save_object_ownership, ObjectOwnership};
+use super::public_access_block::{
+    delete_public_access_block, load_public_access_block, save_public_access_block, PublicAccessBlock,
+};
+use super::tenancy::{load_bucket_policy, save_bucket_policy, BucketGrant, BucketPolicy};
+
+/// Public access block applied to buckets declared with `"public": false`
+const BLOCK_ALL: PublicAccessBlock = PublicAccessBlock {
+    block_public_acls: true,
+    ignore_public_acls: true,
+    block_public_policy: true,
+    restrict_public_buckets: true,
+};
+
+/// A bucket that must exist at startup, with the settings fily enforces on it. Settings left
+/// out are not touched, so changes made through the API to them survive a restart.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BucketDeclaration {
+    pub name: String,
+    /// Account owning the bucket
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Grants to other accounts, replacing those already on the bucket
+    #[serde(default)]
+    pub grants: Option<Vec<BucketGrant>>,
+    #[serde(default)]
+    pub object_ownership: Option<ObjectOwnership>,
+    /// `false` blocks public ACLs and policies entirely, `true` removes the public access block
+    #[serde(default)]
+    pub public: Option<bool>,
+    /// Starts the bucket in read-only mode
+    #[serde(default)]
+    pub read_only: bool,
+    // Accepted so that declarations written for other S3 servers fail with a clear error
+    #[serde(default)]
+    pub versioning: Option<serde_json::Value>,
+    #[serde(default)]
+    pub quota: Option<serde_json::Value>,
+    #[serde(default)]
+    pub lifecycle: Option<serde_json::Value>,
+}
+
+/// What bootstrapping did to a declared bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootstrapOutcome {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+impl BucketDeclaration {
+    pub fn validate(&self) -> Result<()> {
+        if !is_valid_bucket_name(&self.name) {
+            return Err(anyhow!("Invalid declared bucket name: {}", self.name));
+        }
+        let unsupported = [
+            ("versioning", self.versioning.is_some()),
+            ("quota", self.quota.is_some()),
+            ("lifecycle", self.lifecycle.is_some()),
+        ];
+        if let Some((setting, _)) = unsupported.iter().find(|(_, declared)| *declared) {
+            return Err(anyhow!(
+                "Bucket {} declares {}, which fily does not support",
+                self.name,
+                setting
+            ));
+        }
+        Ok(())
+    }
+
+    /// Creates the bucket if needed and brings its declared settings up to date; running it
+    /// again with the same declaration changes nothing
+    pub async fn apply(&self, storage_root: &Path, maintenance: Option<&MaintenanceMode>) -> Result<BootstrapOutcome> {
+        self.validate()?;
+        let bucket = self.name.as_str();
+        let bucket_path = storage_root.join(bucket);
+        let created = !bucket_path.is_dir();
+        if created {
+            tokio::fs::create_dir_all(&bucket_path).await?;
+        }
+        let mut updated = false;
+
+        if self.owner.is_some() || self.grants.is_some() {
+            let current = load_bucket_policy(storage_root, bucket).await?;
+            let mut policy = current.clone().unwrap_or_else(|| BucketPolicy::owned_by(None));
+            if let Some(owner) = &self.owner {
+                policy.owner = Some(owner.clone());
+            }
+            if let Some(grants) = &self.grants {
+                policy.grants = grants.clone();
+            }
+            if current.as_ref() != Some(&policy) {
+                save_bucket_policy(storage_root, bucket, &policy).await?;
+                updated = true;
+            }
+        }
+
+        if let Some(ownership) = self.object_ownership {
+            if load_object_ownership(storage_root, bucket).await? != Some(ownership) {
+                save_object_ownership(storage_root, bucket, ownership).await?;
+                updated = true;
+            }
+        }
+
+        match (self.public, load_public_access_block(storage_root, bucket).await?) {
+            (Some(false), current) if current != Some(BLOCK_ALL) => {
+                save_public_access_block(storage_root, bucket, &BLOCK_ALL).await?;
+                updated = true;
+            }
+            (Some(true), Some(_)) => {
+                delete_public_access_block(storage_root, bucket).await?;
+                updated = true;
+            }
+            _ => {}
+        }
+
+        if let Some(maintenance) = maintenance.filter(|_| self.read_only) {
+            maintenance.set_bucket(storage_root, bucket, true);
+        }
+
+        Ok(match (created, updated) {
+            (true, _) => BootstrapOutcome::Created,
+            (false, true) => BootstrapOutcome::Updated,
+            (false, false) => BootstrapOutcome::Unchanged,
+        })
+    }
+}
+
+/// Applies every declaration in order, stopping at the first one that fails
+pub async fn bootstrap_buckets(
+    storage_root: &Path,
+    declarations: &[BucketDeclaration],
+    maintenance: Option<&MaintenanceMode>,
+) -> Result<()> {
+    for declaration in declarations {
+        let outcome = declaration
+            .apply(storage_root, maintenance)
+            .await
+            .map_err(|e| anyhow!("Failed to bootstrap bucket {}: {}", declaration.name, e))?;
+        info!("Declared bucket {}: {:?}", declaration.name, outcome);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fily::maintenance::ReadOnlyConfig;
+    use crate::fily::tenancy::BucketAccess;
+
+    fn declaration(json: &str) -> BucketDeclaration {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_is_idempotent() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let maintenance = MaintenanceMode::new(ReadOnlyConfig::default());
+        let photos = declaration(
+            r#"{"name": "photos", "owner": "acme", "grants": [{"account": "partner", "access": "read"}],
+                "object_ownership": "BucketOwnerEnforced", "public": false, "read_only": true}"#,
+        );
+
+        assert_eq!(photos.apply(root, Some(&maintenance)).await.unwrap(), BootstrapOutcome::Created);
+        assert_eq!(photos.apply(root, Some(&maintenance)).await.unwrap(), BootstrapOutcome::Unchanged);
+
+        let policy = load_bucket_policy(root, "photos").await.unwrap().unwrap();
+        assert_eq!(policy.owner.as_deref(), Some("acme"));
+        assert!(policy.allows(Some("partner"), BucketAccess::Read));
+        assert!(!policy.allows(Some("partner"), BucketAccess::Write));
+        assert_eq!(
+            load_object_ownership(root, "photos").await.unwrap(),
+            Some(ObjectOwnership::BucketOwnerEnforced)
+        );
+        assert_eq!(load_public_access_block(root, "photos").await.unwrap(), Some(BLOCK_ALL));
+        assert_eq!(maintenance.buckets(root), vec!["photos".to_string()]);
+
+        // Objects already in the bucket survive, and only declared settings change
+        std::fs::write(root.join("photos/a.jpg"), b"jpeg").unwrap();
+        let public = declaration(r#"{"name": "photos", "public": true}"#);
+        assert_eq!(public.apply(root, None).await.unwrap(), BootstrapOutcome::Updated);
+        assert_eq!(load_public_access_block(root, "photos").await.unwrap(), None);
+        assert_eq!(load_bucket_policy(root, "photos").await.unwrap(), Some(policy));
+        assert!(root.join("photos/a.jpg").exists());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(declaration(r#"{"name": "photos"}"#).validate().is_ok());
+        assert!(declaration(r#"{"name": "Photos"}"#).validate().is_err());
+        let versioned = declaration(r#"{"name": "photos", "versioning": "Enabled"}"#);
+        assert!(versioned.validate().unwrap_err().to_string().contains("versioning"));
+        assert!(declaration(r#"{"name": "photos", "quota": {"bytes": 1024}}"#).validate().is_err());
+        assert!(serde_json::from_str::<BucketDeclaration>(r#"{"name": "photos", "colour": "red"}"#).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+//! Server access logging: `PUT /{bucket}?logging` names a target bucket and prefix, and requests
+//! to the bucket are then delivered there as log objects in the S3 server access log format.
+//! Like S3, delivery is best effort: lines are buffered and written out every few minutes, and
+//! those buffered when the server stops are lost.
+
+use std::collections::HashMap;
+use std::path::Path as FsPath;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::{Path, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::auth_middleware::{AuthenticatedAccessKey, Principal};
+use super::bucket_policy::manageable_policy;
+use super::cpu_pool::CpuPool;
+use super::path_security::{bucket_metadata_dir, construct_safe_path, sanitize_bucket_name};
+use super::policy_condition::RequestContext;
+use super::put_object;
+use super::request_info::RequestInfo;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::Config;
+
+/// Stored next to the bucket policy, under a name no object key maps to
+const LOGGING_FILE: &str = "logging";
+const S3_XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// How often buffered lines are written out as log objects
+const FLUSH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long the request path trusts a cached logging configuration; changes made through this
+/// node apply at once, those made elsewhere (another cluster node) within this long
+const TARGET_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Lines buffered per source bucket between flushes; further requests go unlogged
+const MAX_BUFFERED_LINES: usize = 100_000;
+
+/// Recorded as the writer of log objects
+const LOG_DELIVERY_PRINCIPAL: &str = "fily-log-delivery";
+
+/// Where the access logs of a bucket are delivered
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LoggingTarget {
+    pub target_bucket: String,
+    #[serde(default)]
+    pub target_prefix: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BucketLoggingStatus {
+    #[serde(rename = "@xmlns", default, skip_deserializing)]
+    xmlns: String,
+    #[serde(rename = "LoggingEnabled", default, skip_serializing_if = "Option::is_none")]
+    logging_enabled: Option<LoggingEnabled>,
+}
+
+/// Grants and the object key format are accepted and ignored; log objects are always keyed
+/// `<prefix>YYYY-mm-DD-HH-MM-SS-<unique>` and owned by the target bucket's owner
+#[derive(Debug, Serialize, Deserialize)]
+struct LoggingEnabled {
+    #[serde(rename = "TargetBucket")]
+    target_bucket: String,
+    #[serde(rename = "TargetPrefix", default)]
+    target_prefix: String,
+}
+
+fn logging_path(storage_root: &FsPath, bucket: &str) -> anyhow::Result<std::path::PathBuf> {
+    let bucket = sanitize_bucket_name(bucket)
+        .map_err(|e| anyhow::anyhow!("Bucket logging path security violation: {}", e))?;
+    Ok(bucket_metadata_dir(storage_root, &bucket).join(LOGGING_FILE))
+}
+
+pub async fn load_logging_target(storage_root: &FsPath, bucket: &str) -> anyhow::Result<Option<LoggingTarget>> {
+    let path = logging_path(storage_root, bucket)?;
+    match super::replica::read_store_file(storage_root, &path).await? {
+        Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        None => Ok(None),
+    }
+}
+
+/// Saves the logging target of `bucket`, or disables logging when `None`
+pub async fn save_logging_target(
+    storage_root: &FsPath,
+    bucket: &str,
+    target: Option<&LoggingTarget>,
+) -> anyhow::Result<()> {
+    let path = logging_path(storage_root, bucket)?;
+    let Some(target) = target else {
+        return match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    };
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&path, serde_json::to_string(target)?).await?;
+    Ok(())
+}
+
+struct Pending {
+    target: LoggingTarget,
+    lines: Vec<String>,
+}
+
+/// Buffers access log lines per source bucket and delivers them to the configured targets
+pub struct AccessLogs {
+    config: Arc<Config>,
+    cpu_pool: Arc<CpuPool>,
+    targets: Mutex<HashMap<String, (Option<LoggingTarget>, Instant)>>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl AccessLogs {
+    pub fn new(config: Arc<Config>, cpu_pool: Arc<CpuPool>) -> Self {
+        Self {
+            config,
+            cpu_pool,
+            targets: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Logging target of `bucket`, from the cache while it is fresh
+    async fn target(&self, bucket: &str) -> Option<LoggingTarget> {
+        let cached = self.targets.lock().unwrap().get(bucket).cloned();
+        if let Some((target, loaded)) = cached {
+            if loaded.elapsed() < TARGET_CACHE_TTL {
+                return target;
+            }
+        }
+        let target = load_logging_target(FsPath::new(&self.config.location), bucket)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to load logging configuration of bucket {}: {}", bucket, e);
+                None
+            });
+        self.targets
+            .lock()
+            .unwrap()
+            .insert(bucket.to_string(), (target.clone(), Instant::now()));
+        target
+    }
+
+    /// Drops the cached configuration of `bucket` after it changed
+    fn forget(&self, bucket: &str) {
+        self.targets.lock().unwrap().remove(bucket);
+    }
+
+    fn record(&self, bucket: &str, target: LoggingTarget, line: String) {
+        let mut pending = self.pending.lock().unwrap();
+        let entry = pending.entry(bucket.to_string()).or_insert_with(|| Pending {
+            target: target.clone(),
+            lines: Vec::new(),
+        });
+        if entry.lines.len() >= MAX_BUFFERED_LINES {
+            metrics::counter!("fily_access_log_dropped_lines_total").increment(1);
+            return;
+        }
+        entry.target = target;
+        entry.lines.push(line);
+    }
+
+    /// Writes every buffered line out as one log object per source bucket
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (bucket, Pending { target, lines }) in pending {
+            let key = log_object_key(&target.target_prefix, Utc::now());
+            let mut body = lines.join("\n");
+            body.push('\n');
+            let mut headers = HeaderMap::new();
+            headers.insert("content-type", "text/plain".parse().unwrap());
+            let stored = put_object::store(
+                Extension(self.config.clone()),
+                self.cpu_pool.clone(),
+                Principal(LOG_DELIVERY_PRINCIPAL.to_string()),
+                headers,
+                target.target_bucket.clone(),
+                key.clone(),
+                Bytes::from(body),
+                |_| {},
+            )
+            .await;
+            match stored {
+                Ok(_) => {
+                    metrics::counter!("fily_access_log_objects_total").increment(1);
+                    info!(
+                        "Delivered {} access log line(s) of bucket {} to {}/{}",
+                        lines.len(),
+                        bucket,
+                        target.target_bucket,
+                        key
+                    );
+                }
+                Err(e) => {
+                    metrics::counter!("fily_access_log_delivery_failures_total").increment(1);
+                    warn!(
+                        "Failed to deliver {} access log line(s) of bucket {} to {}: {:?} {}",
+                        lines.len(),
+                        bucket,
+                        target.target_bucket,
+                        e.code,
+                        e.message.unwrap_or_default()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Delivers buffered lines every `FLUSH_INTERVAL`
+    pub async fn watch(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            self.flush().await;
+        }
+    }
+}
+
+/// `<prefix>YYYY-mm-DD-HH-MM-SS-<unique>`, as S3 names its log objects
+fn log_object_key(prefix: &str, now: DateTime<Utc>) -> String {
+    let unique: [u8; 8] = rand::random();
+    format!(
+        "{}{}-{}",
+        prefix,
+        now.format("%Y-%m-%d-%H-%M-%S"),
+        hex::encode_upper(unique)
+    )
+}
+
+/// S3 names an operation `REST.<METHOD>.<RESOURCE>`: OBJECT, BUCKET, or the subresource
+fn operation_name(method: &str, has_key: bool, query: Option<&str>) -> String {
+    let resource = if has_key {
+        "OBJECT".to_string()
+    } else {
+        url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .find(|(_, value)| value.is_empty())
+            .map(|(name, _)| name.to_uppercase())
+            .unwrap_or_else(|| "BUCKET".to_string())
+    };
+    format!("REST.{}.{}", method, resource)
+}
+
+/// Quotes a field that may contain spaces, `-` standing in for a missing value
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('"', "\\\"")),
+        None => "-".to_string(),
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+}
+
+/// Middleware recording requests to buckets with logging enabled. It wraps the auth layer, so
+/// the request's context names who made the request once it returns, and runs after cluster
+/// forwarding, so only the node that served a request logs it.
+pub async fn record_access(req: Request, next: Next) -> Response {
+    let Some(logs) = req.extensions().get::<Arc<AccessLogs>>().cloned() else {
+        return next.run(req).await;
+    };
+    let info = RequestInfo::of(&req);
+    let Some(bucket) = info.target.bucket().map(str::to_string) else {
+        return next.run(req).await;
+    };
+    let key = info.target.key().map(str::to_string);
+    let Some(target) = logs.target(&bucket).await else {
+        return next.run(req).await;
+    };
+
+    let remote_ip = RequestContext::from_request(&req, &logs.config.trusted_proxies).source_ip;
+    let method = req.method().to_string();
+    let operation = operation_name(&method, key.is_some(), req.uri().query());
+    let request_uri = format!("{} {} {:?}", method, req.uri(), req.version());
+    let referer = header(req.headers(), "referer");
+    let user_agent = header(req.headers(), "user-agent");
+    let host = header(req.headers(), "host");
+    let auth_type = if req.headers().contains_key("authorization") {
+        "AuthHeader"
+    } else if req.uri().query().is_some_and(|q| q.contains("X-Amz-Signature")) {
+        "QueryString"
+    } else {
+        "-"
+    };
+
+    let response = next.run(req).await;
+
+    let elapsed = info.started.elapsed().as_millis();
+    let bytes_sent = header(response.headers(), "content-length");
+    let line = [
+        "-".to_string(),
+        bucket.clone(),
+        info.received.format("[%d/%b/%Y:%H:%M:%S %z]").to_string(),
+        remote_ip.map_or("-".to_string(), |ip| ip.to_string()),
+        info.principal().unwrap_or("-").to_string(),
+        info.request_id.clone(),
+        operation,
+        key.unwrap_or_else(|| "-".to_string()),
+        quoted(Some(&request_uri)),
+        response.status().as_u16().to_string(),
+        info.error_code().unwrap_or("-").to_string(),
+        bytes_sent.unwrap_or_else(|| "-".to_string()),
+        "-".to_string(),
+        elapsed.to_string(),
+        "-".to_string(),
+        quoted(referer.as_deref()),
+        quoted(user_agent.as_deref()),
+        "-".to_string(),
+        "-".to_string(),
+        if auth_type == "-" { "-" } else { "SigV4" }.to_string(),
+        "-".to_string(),
+        auth_type.to_string(),
+        host.unwrap_or_else(|| "-".to_string()),
+        "-".to_string(),
+    ]
+    .join(" ");
+    logs.record(&bucket, target, line);
+    response
+}
+
+fn xml_response(target: Option<LoggingTarget>) -> Result<Response, S3AppError> {
+    let status = BucketLoggingStatus {
+        xmlns: S3_XMLNS.to_string(),
+        logging_enabled: target.map(|target| LoggingEnabled {
+            target_bucket: target.target_bucket,
+            target_prefix: target.target_prefix,
+        }),
+    };
+    let xml = quick_xml::se::to_string(&status).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    let mut resp = Response::new(Body::from(xml));
+    resp.headers_mut()
+        .insert("content-type", "application/xml".parse().unwrap());
+    Ok(resp)
+}
+
+/// GET /{bucket}?logging
+pub async fn get(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let target = load_logging_target(FsPath::new(&config.location), &bucket)
+        .await
+        .map_err(|e| {
+            error!("Failed to load logging configuration for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to load logging configuration: {}", e))
+        })?;
+    xml_response(target)
+}
+
+/// PUT /{bucket}?logging; an empty `BucketLoggingStatus` disables logging
+pub async fn put(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    logs: Option<Extension<Arc<AccessLogs>>>,
+    Path(bucket): Path<String>,
+    body: Bytes,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let status: BucketLoggingStatus = std::str::from_utf8(&body)
+        .ok()
+        .and_then(|xml| quick_xml::de::from_str(xml).ok())
+        .ok_or_else(|| S3AppError::new(S3ErrorCode::MalformedXML))?;
+    let storage_root = FsPath::new(&config.location);
+    let target = match status.logging_enabled {
+        Some(enabled) => {
+            // The caller must be able to manage the target as well, or logging would let anyone
+            // write objects into a bucket they cannot otherwise touch
+            let target_resource = format!("/{}", enabled.target_bucket);
+            manageable_policy(&config, &access_key, &enabled.target_bucket)
+                .await
+                .map_err(|_| {
+                    S3AppError::with_resource(S3ErrorCode::InvalidTargetBucketForLogging, target_resource)
+                })?;
+            construct_safe_path(storage_root, &enabled.target_bucket, &log_object_key(&enabled.target_prefix, Utc::now()))
+                .map_err(|e| {
+                    S3AppError::with_message(S3ErrorCode::InvalidArgument, format!("Invalid target prefix: {}", e))
+                })?;
+            Some(LoggingTarget {
+                target_bucket: enabled.target_bucket,
+                target_prefix: enabled.target_prefix,
+            })
+        }
+        None => None,
+    };
+
+    save_logging_target(storage_root, &bucket, target.as_ref())
+        .await
+        .map_err(|e| {
+            error!("Failed to save logging configuration for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to save logging configuration: {}", e))
+        })?;
+    if let Some(Extension(logs)) = logs {
+        logs.forget(&bucket);
+    }
+
+    match &target {
+        Some(target) => info!(
+            "Access logs of bucket {} delivered to {}/{} as set by {}",
+            bucket, target.target_bucket, target.target_prefix, access_key.0
+        ),
+        None => info!("Access logging of bucket {} disabled by {}", bucket, access_key.0),
+    }
+    Ok(StatusCode::OK.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fily::cpu_pool::CpuPoolConfig;
+
+    fn config(location: &FsPath) -> Arc<Config> {
+        Arc::new(Config {
+            location: location.to_string_lossy().into_owned(),
+            address: "127.0.0.1".to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_logging_status_xml() {
+        let xml = r#"<BucketLoggingStatus xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><LoggingEnabled><TargetBucket>logs</TargetBucket><TargetPrefix>photos/</TargetPrefix></LoggingEnabled></BucketLoggingStatus>"#;
+        let status: BucketLoggingStatus = quick_xml::de::from_str(xml).unwrap();
+        let enabled = status.logging_enabled.unwrap();
+        assert_eq!((enabled.target_bucket.as_str(), enabled.target_prefix.as_str()), ("logs", "photos/"));
+
+        let target = LoggingTarget {
+            target_bucket: "logs".to_string(),
+            target_prefix: "photos/".to_string(),
+        };
+        let response = xml_response(Some(target)).unwrap();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, xml.as_bytes());
+
+        // An empty status, as sent to disable logging, is what an unconfigured bucket returns
+        let disabled = r#"<BucketLoggingStatus xmlns="http://s3.amazonaws.com/doc/2006-03-01/"/>"#;
+        let status: BucketLoggingStatus = quick_xml::de::from_str(disabled).unwrap();
+        assert!(status.logging_enabled.is_none());
+        let response = xml_response(None).unwrap();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, disabled.as_bytes());
+    }
+
+    #[test]
+    fn test_operation_name() {
+        assert_eq!(operation_name("GET", true, None), "REST.GET.OBJECT");
+        assert_eq!(operation_name("GET", false, Some("list-type=2&prefix=a")), "REST.GET.BUCKET");
+        assert_eq!(operation_name("PUT", false, Some("logging")), "REST.PUT.LOGGING");
+        assert!(log_object_key("logs/", Utc::now()).starts_with("logs/20"));
+    }
+
+    #[tokio::test]
+    async fn test_lines_delivered_to_target() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("photos")).unwrap();
+        std::fs::create_dir_all(root.join("logs")).unwrap();
+        let target = LoggingTarget {
+            target_bucket: "logs".to_string(),
+            target_prefix: "photos/".to_string(),
+        };
+        save_logging_target(root, "photos", Some(&target)).await.unwrap();
+
+        let config = config(root);
+        let logs = AccessLogs::new(config.clone(), Arc::new(CpuPool::new(&CpuPoolConfig::default())));
+        assert_eq!(logs.target("photos").await, Some(target.clone()));
+        assert_eq!(logs.target("logs").await, None);
+        logs.record("photos", target.clone(), "first".to_string());
+        logs.record("photos", target, "second".to_string());
+        logs.flush().await;
+
+        let delivered: Vec<_> = std::fs::read_dir(root.join("logs").join("photos"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(std::fs::read_to_string(&delivered[0]).unwrap(), "first\nsecond\n");
+
+        // Nothing is left to deliver, and disabling logging takes effect once forgotten
+        logs.flush().await;
+        assert_eq!(std::fs::read_dir(root.join("logs").join("photos")).unwrap().count(), 1);
+        save_logging_target(root, "photos", None).await.unwrap();
+        assert!(logs.target("photos").await.is_some());
+        logs.forget("photos");
+        assert_eq!(logs.target("photos").await, None);
+    }
+}
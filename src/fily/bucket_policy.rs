@@ -0,0 +1,90 @@
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use hyper::StatusCode;
+use tracing::{error, info};
+
+use super::admin::require_admin;
+use super::auth_middleware::AuthenticatedAccessKey;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::tenancy::{account_for, load_bucket_policy, save_bucket_policy, BucketPolicy};
+use super::Config;
+
+/// Policy of an existing bucket, provided the caller may manage it
+pub(crate) async fn manageable_policy(
+    config: &Config,
+    access_key: &AuthenticatedAccessKey,
+    bucket: &str,
+) -> Result<(Option<String>, BucketPolicy), S3AppError> {
+    let storage_root = FsPath::new(&config.location);
+    if !storage_root.join(bucket).is_dir() {
+        return Err(S3AppError::no_such_bucket(bucket));
+    }
+
+    let policy = load_bucket_policy(storage_root, bucket)
+        .await
+        .map_err(|e| {
+            error!("Failed to load policy for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to load bucket policy: {}", e))
+        })?
+        .unwrap_or_default();
+
+    let account = account_for(config, &access_key.0);
+    // In deny-by-default mode only the owning account and admin keys manage a policy
+    let allowed = if config.deny_by_default {
+        (account.is_some() && policy.owner == account) || require_admin(config, access_key).is_ok()
+    } else {
+        policy.can_manage(account.as_deref())
+    };
+    if !allowed {
+        return Err(S3AppError::access_denied(&format!("/{}", bucket)));
+    }
+
+    Ok((account, policy))
+}
+
+fn json_response(policy: &BucketPolicy) -> Result<Response, S3AppError> {
+    let body = serde_json::to_string(policy).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    Ok((StatusCode::OK, [("content-type", "application/json")], body).into_response())
+}
+
+pub async fn get_policy(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    let (_, policy) = manageable_policy(&config, &access_key, &bucket).await?;
+    json_response(&policy)
+}
+
+pub async fn put_policy(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+    body: Bytes,
+) -> Result<Response, S3AppError> {
+    let (account, current) = manageable_policy(&config, &access_key, &bucket).await?;
+
+    let mut policy: BucketPolicy = serde_json::from_slice(&body).map_err(|e| {
+        S3AppError::with_message(S3ErrorCode::InvalidArgument, format!("Invalid bucket policy: {}", e))
+    })?;
+
+    // Account keys manage grants; only operator keys may assign or change the owner
+    if account.is_some() {
+        policy.owner = current.owner;
+    }
+
+    save_bucket_policy(FsPath::new(&config.location), &bucket, &policy)
+        .await
+        .map_err(|e| {
+            error!("Failed to save policy for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to save bucket policy: {}", e))
+        })?;
+
+    info!("Bucket policy for {} updated by {}", bucket, access_key.0);
+    json_response(&policy)
+}
@@ -0,0 +1,82 @@
+use axum::extract::Request;
+use axum::handler::Handler;
+use axum::response::Response;
+use hyper::Method;
+
+use super::operation::tag;
+use super::clone_bucket::{self, CLONE_SOURCE_HEADER};
+use super::{bucket_logging, bucket_usage, create_bucket, delete_bucket, ownership_controls, public_access_block, search_bucket};
+
+/// Whether the query string names the subresource, e.g. `?ownershipControls`
+pub(crate) fn has_subresource(req: &Request, name: &str) -> bool {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).any(|(key, _)| key == name)
+}
+
+/// GET /{bucket}: a bucket subresource, or ListObjects
+pub async fn get(req: Request) -> Response {
+    if has_subresource(&req, "ownershipControls") {
+        ownership_controls::get.layer(tag("GetBucketOwnershipControls")).call(req, ()).await
+    } else if has_subresource(&req, "publicAccessBlock") {
+        public_access_block::get.layer(tag("GetPublicAccessBlock")).call(req, ()).await
+    } else if has_subresource(&req, "policyStatus") {
+        public_access_block::get_policy_status.layer(tag("GetBucketPolicyStatus")).call(req, ()).await
+    } else if has_subresource(&req, "logging") {
+        bucket_logging::get.layer(tag("GetBucketLogging")).call(req, ()).await
+    } else if req.method() == Method::HEAD {
+        bucket_usage::head.layer(tag("HeadBucket")).call(req, ()).await
+    } else {
+        let operation = if url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .any(|(key, value)| key == "list-type" && value == "2")
+        {
+            "ListObjectsV2"
+        } else {
+            "ListObjects"
+        };
+        search_bucket::handle.layer(tag(operation)).call(req, ()).await
+    }
+}
+
+/// PUT /{bucket}: a bucket subresource, a clone of another bucket, or CreateBucket
+pub async fn put(req: Request) -> Response {
+    if has_subresource(&req, "ownershipControls") {
+        ownership_controls::put.layer(tag("PutBucketOwnershipControls")).call(req, ()).await
+    } else if has_subresource(&req, "publicAccessBlock") {
+        public_access_block::put.layer(tag("PutPublicAccessBlock")).call(req, ()).await
+    } else if has_subresource(&req, "logging") {
+        bucket_logging::put.layer(tag("PutBucketLogging")).call(req, ()).await
+    } else if has_subresource(&req, "acl") {
+        ownership_controls::put_acl.layer(tag("PutBucketAcl")).call(req, ()).await
+    } else if req.headers().contains_key(CLONE_SOURCE_HEADER) {
+        clone_bucket::handle.layer(tag("CloneBucket")).call(req, ()).await
+    } else {
+        create_bucket::handle.layer(tag("CreateBucket")).call(req, ()).await
+    }
+}
+
+/// DELETE /{bucket}: a bucket subresource, or DeleteBucket
+pub async fn delete(req: Request) -> Response {
+    if has_subresource(&req, "ownershipControls") {
+        ownership_controls::delete.layer(tag("DeleteBucketOwnershipControls")).call(req, ()).await
+    } else if has_subresource(&req, "publicAccessBlock") {
+        public_access_block::delete.layer(tag("DeletePublicAccessBlock")).call(req, ()).await
+    } else if has_subresource(&req, "prefix") {
+        delete_bucket::handle.layer(tag("DeletePrefix")).call(req, ()).await
+    } else {
+        delete_bucket::handle.layer(tag("DeleteBucket")).call(req, ()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    #[test]
+    fn test_has_subresource() {
+        let req = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert!(has_subresource(&req("/photos?ownershipControls"), "ownershipControls"));
+        assert!(has_subresource(&req("/photos?acl="), "acl"));
+        assert!(!has_subresource(&req("/photos?prefix=acl"), "acl"));
+        assert!(!has_subresource(&req("/photos"), "acl"));
+    }
+}
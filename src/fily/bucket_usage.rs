@@ -0,0 +1,164 @@
+//! Per-bucket usage counters: object count, total size and newest modification, returned as
+//! `x-fily-*` headers on HEAD bucket so dashboards can poll a bucket without listing it.
+//!
+//! A bucket is counted by walking it once; the counts are then served from memory and recounted
+//! in the background once they are older than [`USAGE_REFRESH`], so a HEAD never waits on a walk
+//! except the first for a bucket.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::Path;
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use super::metadata::load_metadata;
+use super::path_security::sanitize_bucket_name;
+use super::s3_app_error::S3AppError;
+use super::search_bucket::{Listed, ObjectWalker};
+use super::timestamp::http_date;
+use super::Config;
+
+pub const OBJECT_COUNT_HEADER: &str = "x-fily-object-count";
+pub const TOTAL_SIZE_HEADER: &str = "x-fily-total-size";
+pub const LAST_MODIFIED_HEADER: &str = "x-fily-last-modified";
+
+/// How old counts may get before a HEAD triggers a recount
+pub const USAGE_REFRESH: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketUsage {
+    pub objects: u64,
+    /// Sum of the objects' sizes as uploaded, before compression or encryption
+    pub bytes: u64,
+    /// Newest modification of any object, `None` for an empty bucket
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl BucketUsage {
+    /// Walks `bucket` and counts its objects
+    pub async fn count(storage_root: &FsPath, bucket: &str) -> std::io::Result<Self> {
+        let mut usage = BucketUsage::default();
+        let mut walker = ObjectWalker::new(&storage_root.join(bucket), "", None);
+        while let Some(listed) = walker.next_listed().await? {
+            let Listed::Object(object) = listed else { continue };
+            let metadata = load_metadata(storage_root, bucket, &object.key).await.ok().flatten();
+            usage.objects += 1;
+            usage.bytes += metadata.map_or(object.size, |m| m.content_length);
+            usage.last_modified = usage.last_modified.max(Some(object.last_modified));
+        }
+        Ok(usage)
+    }
+}
+
+/// Usage of every bucket HEAD has been asked about, shared by all requests. Buckets are keyed by
+/// storage root as well as name, since tenant namespaces each have their own root.
+#[derive(Default)]
+pub struct UsageCounters {
+    counted: Mutex<HashMap<PathBuf, (BucketUsage, Instant)>>,
+    recounting: Mutex<HashSet<PathBuf>>,
+}
+
+impl UsageCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Usage of `bucket`: the last count, recounted in the background once stale
+    pub async fn get(self: &Arc<Self>, storage_root: &FsPath, bucket: &str) -> std::io::Result<BucketUsage> {
+        let bucket_path = storage_root.join(bucket);
+        let cached = self.counted.lock().unwrap().get(&bucket_path).copied();
+        match cached {
+            Some((usage, counted)) => {
+                if counted.elapsed() >= USAGE_REFRESH && self.recounting.lock().unwrap().insert(bucket_path.clone()) {
+                    let counters = self.clone();
+                    let storage_root = storage_root.to_path_buf();
+                    let bucket = bucket.to_string();
+                    tokio::spawn(async move {
+                        if let Err(e) = counters.recount(&storage_root, &bucket).await {
+                            warn!("Failed to count usage of bucket {}: {}", bucket, e);
+                        }
+                        counters.recounting.lock().unwrap().remove(&bucket_path);
+                    });
+                }
+                Ok(usage)
+            }
+            None => self.recount(storage_root, bucket).await,
+        }
+    }
+
+    async fn recount(&self, storage_root: &FsPath, bucket: &str) -> std::io::Result<BucketUsage> {
+        let usage = BucketUsage::count(storage_root, bucket).await?;
+        self.counted
+            .lock()
+            .unwrap()
+            .insert(storage_root.join(bucket), (usage, Instant::now()));
+        Ok(usage)
+    }
+}
+
+/// HEAD /{bucket}: whether the bucket exists, with its usage in `x-fily-*` headers
+pub async fn head(
+    config: Extension<Arc<Config>>,
+    counters: Option<Extension<Arc<UsageCounters>>>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    let bucket = sanitize_bucket_name(&bucket).map_err(|_| S3AppError::invalid_bucket_name(&bucket))?;
+    let storage_root = FsPath::new(&config.location);
+    if !storage_root.join(&bucket).is_dir() {
+        return Err(S3AppError::no_such_bucket(&bucket));
+    }
+
+    let mut response = ().into_response();
+    let Some(Extension(counters)) = counters else {
+        return Ok(response);
+    };
+    // The headers are a convenience; a bucket that cannot be counted still exists
+    let usage = match counters.get(storage_root, &bucket).await {
+        Ok(usage) => usage,
+        Err(e) => {
+            warn!("Failed to count usage of bucket {}: {}", bucket, e);
+            return Ok(response);
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert(OBJECT_COUNT_HEADER, HeaderValue::from(usage.objects));
+    headers.insert(TOTAL_SIZE_HEADER, HeaderValue::from(usage.bytes));
+    if let Some(modified) = usage.last_modified {
+        headers.insert(LAST_MODIFIED_HEADER, http_date(modified).parse().unwrap());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_usage_counted_and_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket_path = dir.path().join("photos");
+        std::fs::create_dir_all(bucket_path.join("2024")).unwrap();
+        std::fs::write(bucket_path.join("a.jpg"), b"12345").unwrap();
+        std::fs::write(bucket_path.join("2024/b.jpg"), b"123").unwrap();
+
+        let counters = Arc::new(UsageCounters::new());
+        let usage = counters.get(dir.path(), "photos").await.unwrap();
+        assert_eq!(usage.objects, 2);
+        assert_eq!(usage.bytes, 8);
+        assert!(usage.last_modified.is_some());
+
+        // Fresh counts are served without walking the bucket again
+        std::fs::write(bucket_path.join("c.jpg"), b"1").unwrap();
+        assert_eq!(counters.get(dir.path(), "photos").await.unwrap(), usage);
+        assert_eq!(BucketUsage::count(dir.path(), "photos").await.unwrap().objects, 3);
+
+        std::fs::create_dir_all(dir.path().join("empty")).unwrap();
+        assert_eq!(counters.get(dir.path(), "empty").await.unwrap(), BucketUsage::default());
+    }
+}
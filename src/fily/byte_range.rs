@@ -0,0 +1,203 @@
+//! HTTP `Range` requests (RFC 7233) for object GETs: byte ranges resolved against the object
+//! length, overlapping and adjacent ranges coalesced, and several ranges served as
+//! `multipart/byteranges`.
+
+use std::ops::Range;
+
+use hyper::header::{IF_RANGE, RANGE};
+use hyper::HeaderMap;
+
+use super::timestamp::parse_http_date;
+
+/// Ranges served as one multipart response at most; more are answered with the whole object,
+/// as a request for many tiny ranges costs more to frame than to send in full
+pub const MAX_RANGES: usize = 100;
+
+/// Why a `Range` header could not be served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeError {
+    /// Every range starts past the end of the object
+    Unsatisfiable,
+}
+
+/// The byte ranges of a `len` byte object a request asks for, sorted and coalesced, or `None` to
+/// serve the whole object: without a `Range` header, when it is malformed or in another unit
+/// (which RFC 7233 lets servers ignore), or when it asks for more than [`MAX_RANGES`]
+pub fn requested_ranges(headers: &HeaderMap, len: u64) -> Result<Option<Vec<Range<u64>>>, RangeError> {
+    let Some(value) = headers.get(RANGE).and_then(|v| v.to_str().ok()) else {
+        return Ok(None);
+    };
+    parse_ranges(value, len)
+}
+
+/// Parses a `bytes=` range header; see [`requested_ranges`]
+pub fn parse_ranges(value: &str, len: u64) -> Result<Option<Vec<Range<u64>>>, RangeError> {
+    let Some((unit, specs)) = value.trim().split_once('=') else {
+        return Ok(None);
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ok(None);
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Ok(None);
+        };
+        let (start, end) = (start.trim(), end.trim());
+        let range = if start.is_empty() {
+            // `-n` is the last n bytes
+            let Ok(suffix) = end.parse::<u64>() else {
+                return Ok(None);
+            };
+            len.saturating_sub(suffix)..len
+        } else {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let end = match end {
+                "" => len,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.saturating_add(1).min(len),
+                    _ => return Ok(None),
+                },
+            };
+            start..end
+        };
+        // Ranges starting past the end, and empty suffixes, are unsatisfiable on their own
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+
+    let ranges = coalesce(ranges);
+    if ranges.len() > MAX_RANGES {
+        return Ok(None);
+    }
+    Ok(Some(ranges))
+}
+
+/// Sorts `ranges` and merges those that overlap or touch, so no byte is sent twice
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Whether the `If-Range` precondition, if any, still holds for the object: its strong ETag or
+/// its exact `Last-Modified` date. A range of a changed object is not served.
+pub fn if_range_holds(headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+    let Some(condition) = headers.get(IF_RANGE).and_then(|v| v.to_str().ok()).map(str::trim) else {
+        return true;
+    };
+    if condition.starts_with('"') {
+        return condition == etag;
+    }
+    if condition.starts_with("W/") {
+        return false;
+    }
+    match (parse_http_date(condition), last_modified.and_then(parse_http_date)) {
+        (Some(condition), Some(modified)) => condition == modified,
+        _ => false,
+    }
+}
+
+/// `Content-Range` of `range` within a `len` byte object
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// `multipart/byteranges` body with one part per range, each carrying the object's content type
+/// and its own `Content-Range`, delimited by `boundary`
+pub fn multipart_byteranges(contents: &[u8], ranges: &[Range<u64>], content_type: &str, boundary: &str) -> Vec<u8> {
+    let len = contents.len() as u64;
+    let size = ranges.iter().map(|r| (r.end - r.start) as usize + 128).sum::<usize>() + boundary.len() + 8;
+    let mut body = Vec::with_capacity(size);
+    for range in ranges {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                content_range(range, len)
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&contents[range.start as usize..range.end as usize]);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_parse_ranges() {
+        assert_eq!(parse_ranges("bytes=0-9", 100), Ok(Some(vec![0..10])));
+        assert_eq!(parse_ranges("bytes=90-", 100), Ok(Some(vec![90..100])));
+        assert_eq!(parse_ranges("bytes=-10", 100), Ok(Some(vec![90..100])));
+        assert_eq!(parse_ranges("bytes=-500", 100), Ok(Some(vec![0..100])));
+        assert_eq!(parse_ranges("bytes=50-500", 100), Ok(Some(vec![50..100])));
+        assert_eq!(parse_ranges("Bytes = 0-0 , 99-99", 100), Ok(Some(vec![0..1, 99..100])));
+
+        // Overlapping and adjacent ranges are merged, out of order ranges sorted
+        assert_eq!(
+            parse_ranges("bytes=40-49,0-9,5-19,20-29,80-", 100),
+            Ok(Some(vec![0..30, 40..50, 80..100]))
+        );
+        // Unsatisfiable ranges are dropped while others remain
+        assert_eq!(parse_ranges("bytes=200-300,0-1", 100), Ok(Some(vec![0..2])));
+
+        assert_eq!(parse_ranges("bytes=100-", 100), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_ranges("bytes=-0", 100), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_ranges("bytes=0-", 0), Err(RangeError::Unsatisfiable));
+
+        // Malformed headers and other units are ignored
+        for header in ["bytes=9-0", "bytes=a-b", "bytes=", "bytes=1", "items=0-9", "0-9"] {
+            assert_eq!(parse_ranges(header, 100), Ok(None), "{}", header);
+        }
+        let many = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>().join(",");
+        assert_eq!(parse_ranges(&format!("bytes={}", many), 1000), Ok(None));
+    }
+
+    #[test]
+    fn test_if_range() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(IF_RANGE, value.parse().unwrap());
+            headers
+        };
+        let modified = Some("Tue, 15 Oct 2024 10:00:00 GMT");
+        assert!(if_range_holds(&HeaderMap::new(), "\"abc\"", modified));
+        assert!(if_range_holds(&headers("\"abc\""), "\"abc\"", modified));
+        assert!(!if_range_holds(&headers("\"def\""), "\"abc\"", modified));
+        assert!(!if_range_holds(&headers("W/\"abc\""), "\"abc\"", modified));
+        assert!(if_range_holds(&headers("Tue, 15 Oct 2024 10:00:00 GMT"), "\"abc\"", modified));
+        assert!(!if_range_holds(&headers("Tue, 15 Oct 2024 09:00:00 GMT"), "\"abc\"", modified));
+        assert!(!if_range_holds(&headers("Tue, 15 Oct 2024 10:00:00 GMT"), "\"abc\"", None));
+    }
+
+    #[test]
+    fn test_multipart_byteranges() {
+        let body = multipart_byteranges(b"0123456789", &[0..2, 8..10], "text/plain", "b0undary");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "--b0undary\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --b0undary\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+             --b0undary--\r\n"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::extract::{Path, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use hyper::header::{CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use hyper::{HeaderMap, StatusCode};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+
+use super::create_bucket::is_valid_bucket_name;
+use super::metadata::load_metadata;
+use super::timestamp::parse_http_date;
+use super::Config;
+
+/// Header CDNs such as Fastly purge by; keys are separated by spaces
+pub const SURROGATE_KEY_HEADER: &str = "surrogate-key";
+
+/// Characters escaped in an object's surrogate key, which must be one space-free token
+const SURROGATE_KEY: &AsciiSet = &CONTROLS.add(b' ').add(b'%').add(b'"');
+
+/// Caching headers added to GET responses for the objects of a bucket, optionally only to keys
+/// under `prefix`. The most specific matching rule applies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    /// `max-age` in seconds, for browsers and any cache without `s_maxage`
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// `s-maxage` in seconds, for shared caches such as CDNs
+    #[serde(default)]
+    pub s_maxage: Option<u64>,
+    /// Seconds a cache may serve the object stale while it revalidates it in the background
+    #[serde(default)]
+    pub stale_while_revalidate: Option<u64>,
+    /// Seconds a cache may serve the object stale while fily answers with errors
+    #[serde(default)]
+    pub stale_if_error: Option<u64>,
+    /// Marks responses `public`, so shared caches store them although the request was signed
+    #[serde(default)]
+    pub public: bool,
+    /// Tags responses with surrogate keys for the bucket and the object, for targeted purges
+    #[serde(default)]
+    pub surrogate_keys: bool,
+}
+
+impl CacheRule {
+    pub fn validate(&self) -> Result<()> {
+        if !is_valid_bucket_name(&self.bucket) {
+            return Err(anyhow!("Invalid bucket name: {}", self.bucket));
+        }
+        if self.max_age.is_none() && self.s_maxage.is_none() {
+            return Err(anyhow!(
+                "Rule for bucket {} needs max_age or s_maxage",
+                self.bucket
+            ));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, bucket: &str, key: &str) -> bool {
+        self.bucket == bucket && key.starts_with(&self.prefix)
+    }
+
+    /// Value of the Cache-Control header
+    fn directives(&self) -> String {
+        let mut directives = Vec::new();
+        if self.public {
+            directives.push("public".to_string());
+        }
+        let seconds = [
+            ("max-age", self.max_age),
+            ("s-maxage", self.s_maxage),
+            ("stale-while-revalidate", self.stale_while_revalidate),
+            ("stale-if-error", self.stale_if_error),
+        ];
+        for (directive, value) in seconds {
+            if let Some(value) = value {
+                directives.push(format!("{}={}", directive, value));
+            }
+        }
+        directives.join(", ")
+    }
+}
+
+/// The rule with the longest prefix among those matching the object
+fn find_rule<'a>(rules: &'a [CacheRule], bucket: &str, key: &str) -> Option<&'a CacheRule> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(bucket, key))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// Surrogate keys of an object: one for its bucket and one for the object itself
+fn surrogate_keys(bucket: &str, key: &str) -> String {
+    format!("{} {}/{}", bucket, bucket, utf8_percent_encode(key, SURROGATE_KEY))
+}
+
+/// Whether a conditional request's copy of the object is still current. As in RFC 9110,
+/// `If-None-Match` takes precedence and `If-Modified-Since` is only used without it.
+fn not_modified(headers: &HeaderMap, etag: &str, last_modified: &str) -> bool {
+    if let Some(value) = headers.get(IF_NONE_MATCH) {
+        let Ok(value) = value.to_str() else {
+            return false;
+        };
+        // Weak comparison, as for GET
+        return value
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_http_date);
+    let modified = parse_http_date(last_modified);
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = value.parse() {
+        headers.insert(name, value);
+    }
+}
+
+/// Middleware on GET /{bucket}/{file} adding the headers of the matching cache rule, and
+/// answering revalidations of objects under a rule with 304 Not Modified
+pub async fn apply(
+    Extension(config): Extension<Arc<Config>>,
+    Path((bucket, key)): Path<(String, String)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(rule) = find_rule(&config.cache_rules, &bucket, &key).cloned() else {
+        return next.run(req).await;
+    };
+
+    let metadata = load_metadata(FsPath::new(&config.location), &bucket, &key)
+        .await
+        .ok()
+        .flatten();
+    let mut headers = HeaderMap::new();
+    insert_header(&mut headers, CACHE_CONTROL.as_str(), &rule.directives());
+    if rule.surrogate_keys {
+        insert_header(&mut headers, SURROGATE_KEY_HEADER, &surrogate_keys(&bucket, &key));
+    }
+    if let Some(metadata) = &metadata {
+        insert_header(&mut headers, LAST_MODIFIED.as_str(), &metadata.last_modified);
+    }
+
+    // Answered from the metadata, so a revalidation neither reads nor decrypts the object.
+    // Parts have ETags of their own and are always served.
+    let whole_object = !req.uri().query().is_some_and(|q| q.contains("partNumber="));
+    if let Some(metadata) = metadata.filter(|_| whole_object) {
+        if not_modified(req.headers(), &metadata.etag, &metadata.last_modified) {
+            metrics::counter!("fily_cache_revalidations_total", "result" => "not_modified").increment(1);
+            insert_header(&mut headers, ETAG.as_str(), &metadata.etag);
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+        if req.headers().contains_key(IF_NONE_MATCH) || req.headers().contains_key(IF_MODIFIED_SINCE) {
+            metrics::counter!("fily_cache_revalidations_total", "result" => "modified").increment(1);
+        }
+    }
+
+    let mut response = next.run(req).await;
+    if matches!(response.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT) {
+        response.headers_mut().extend(headers);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(bucket: &str, prefix: &str) -> CacheRule {
+        CacheRule {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            max_age: Some(60),
+            s_maxage: None,
+            stale_while_revalidate: None,
+            stale_if_error: None,
+            public: false,
+            surrogate_keys: false,
+        }
+    }
+
+    #[test]
+    fn test_find_rule() {
+        let rules = vec![rule("assets", ""), rule("assets", "img/"), rule("photos", "")];
+        assert_eq!(find_rule(&rules, "assets", "img/a.png").unwrap().prefix, "img/");
+        assert_eq!(find_rule(&rules, "assets", "css/a.css").unwrap().prefix, "");
+        assert_eq!(find_rule(&rules, "photos", "a.jpg").unwrap().bucket, "photos");
+        assert!(find_rule(&rules, "backups", "a.tar").is_none());
+    }
+
+    #[test]
+    fn test_directives() {
+        let mut rule = rule("assets", "");
+        assert_eq!(rule.directives(), "max-age=60");
+        rule.public = true;
+        rule.s_maxage = Some(86400);
+        rule.stale_while_revalidate = Some(30);
+        rule.stale_if_error = Some(600);
+        assert_eq!(
+            rule.directives(),
+            "public, max-age=60, s-maxage=86400, stale-while-revalidate=30, stale-if-error=600"
+        );
+
+        rule.max_age = None;
+        assert!(rule.validate().is_ok());
+        rule.s_maxage = None;
+        assert!(rule.validate().is_err());
+    }
+
+    #[test]
+    fn test_surrogate_keys() {
+        assert_eq!(surrogate_keys("assets", "img/a b%.png"), "assets assets/img/a%20b%25.png");
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = "\"5d41402abc4b2a76b9719d911017c592\"";
+        let last_modified = "Tue, 15 Oct 2024 10:00:00 GMT";
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        assert!(not_modified(&headers(&[("if-none-match", etag)]), etag, last_modified));
+        assert!(not_modified(&headers(&[("if-none-match", &format!("\"x\", W/{}", etag))]), etag, last_modified));
+        assert!(not_modified(&headers(&[("if-none-match", "*")]), etag, last_modified));
+        assert!(!not_modified(&headers(&[("if-none-match", "\"x\"")]), etag, last_modified));
+        assert!(not_modified(&headers(&[("if-modified-since", last_modified)]), etag, last_modified));
+        // The obsolete RFC 850 and asctime forms are accepted too
+        assert!(not_modified(&headers(&[("if-modified-since", "Tuesday, 15-Oct-24 10:00:00 GMT")]), etag, last_modified));
+        assert!(not_modified(&headers(&[("if-modified-since", "Tue Oct 15 10:00:00 2024")]), etag, last_modified));
+        assert!(!not_modified(
+            &headers(&[("if-modified-since", "Mon, 14 Oct 2024 10:00:00 GMT")]),
+            etag,
+            last_modified
+        ));
+        // A changed ETag wins over an unchanged date
+        assert!(!not_modified(
+            &headers(&[("if-none-match", "\"x\""), ("if-modified-since", last_modified)]),
+            etag,
+            last_modified
+        ));
+        assert!(!not_modified(&HeaderMap::new(), etag, last_modified));
+    }
+}
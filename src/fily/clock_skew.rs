@@ -0,0 +1,269 @@
+//! Tracks how far the `x-amz-date` of signed requests is from this server's clock. One skewed
+//! client is its own problem, but when most clients are off in the same direction the server
+//! clock is the likelier culprit, and every RequestTimeTooSkewed it causes is ours.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hyper::HeaderMap;
+use tracing::{error, info};
+
+use super::notifications::{Event, EventKind, Notifier, Severity};
+
+const X_AMZ_DATE_HEADER: &str = "x-amz-date";
+
+/// Clients seen within this long count towards the verdict
+const WINDOW: Duration = Duration::from_secs(600);
+
+/// The verdict is recomputed at most this often, not on every request
+const EVALUATE_EVERY: Duration = Duration::from_secs(1);
+
+/// How often the warning is repeated while the server clock stays suspect
+const WARN_EVERY: Duration = Duration::from_secs(600);
+
+/// Share of clients that must be skewed in the same direction for the server to be suspect
+const AGREEMENT: f64 = 0.75;
+
+/// Clients remembered at most; new clients are ignored until stale ones expire
+const MAX_CLIENTS: usize = 10_000;
+
+/// Detection of a wrong server clock from the skew of its clients, and how far ahead of the
+/// server clock clients may be
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockSkewConfig {
+    pub enabled: bool,
+    /// Skew beyond which a client counts as skewed
+    pub threshold: Duration,
+    /// Distinct clients needed before the server clock is suspected
+    pub min_clients: usize,
+    /// How far a request's or pre-signed URL's `X-Amz-Date` may be ahead of the server clock;
+    /// applies whether or not detection is enabled
+    pub future_grace: Duration,
+}
+
+impl Default for ClockSkewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: Duration::from_secs(60),
+            min_clients: 3,
+            future_grace: Duration::from_secs(900),
+        }
+    }
+}
+
+/// Seconds the request's `x-amz-date` is ahead of `now` (negative when behind)
+pub fn request_skew(headers: &HeaderMap, now: DateTime<Utc>) -> Option<f64> {
+    let value = headers.get(X_AMZ_DATE_HEADER)?.to_str().ok()?;
+    let request_time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ").ok()?.and_utc();
+    Some(request_time.signed_duration_since(now).num_milliseconds() as f64 / 1000.0)
+}
+
+struct Sample {
+    skew: f64,
+    seen: Instant,
+}
+
+/// What the recent clients say about the server clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSkewStatus {
+    /// Clients seen within the window
+    pub clients: usize,
+    /// Of those, clients skewed beyond the threshold in the direction of the median
+    pub skewed: usize,
+    /// Median skew of the clients; positive when they are ahead of the server
+    pub median_skew: f64,
+    /// Whether the server clock, rather than the clients, is probably wrong
+    pub server_suspect: bool,
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<String, Sample>,
+    evaluated: Option<Instant>,
+    suspect: bool,
+    warned: Option<Instant>,
+}
+
+/// Latest skew per client (source IP or access key), checked for a consensus that the server
+/// clock is off
+pub struct ClockSkewMonitor {
+    config: ClockSkewConfig,
+    state: Mutex<State>,
+    notifier: Option<Arc<Notifier>>,
+}
+
+impl ClockSkewMonitor {
+    pub fn new(config: ClockSkewConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+            notifier: None,
+        }
+    }
+
+    /// Reports the server clock becoming suspect, and recovering, to the operators' webhook
+    pub fn with_notifier(mut self, notifier: Arc<Notifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Records the skew of a request from `client`
+    pub fn record(&self, client: &str, skew: f64, now: Instant) {
+        metrics::histogram!("fily_client_clock_skew_seconds").record(skew.abs());
+
+        let mut state = self.state.lock().unwrap();
+        if state.clients.len() >= MAX_CLIENTS && !state.clients.contains_key(client) {
+            state.clients.retain(|_, sample| now.duration_since(sample.seen) < WINDOW);
+            if state.clients.len() >= MAX_CLIENTS {
+                return;
+            }
+        }
+        state.clients.insert(client.to_string(), Sample { skew, seen: now });
+
+        if state.evaluated.is_some_and(|at| now.duration_since(at) < EVALUATE_EVERY) {
+            return;
+        }
+        state.evaluated = Some(now);
+        let status = self.evaluate(&state, now);
+        self.report(&mut state, status, now);
+    }
+
+    /// The verdict over the clients seen within the window as of `now`
+    pub fn status(&self, now: Instant) -> ClockSkewStatus {
+        self.evaluate(&self.state.lock().unwrap(), now)
+    }
+
+    fn evaluate(&self, state: &State, now: Instant) -> ClockSkewStatus {
+        let mut skews: Vec<f64> = state
+            .clients
+            .values()
+            .filter(|sample| now.duration_since(sample.seen) < WINDOW)
+            .map(|sample| sample.skew)
+            .collect();
+        if skews.is_empty() {
+            return ClockSkewStatus { clients: 0, skewed: 0, median_skew: 0.0, server_suspect: false };
+        }
+        skews.sort_by(f64::total_cmp);
+        let median_skew = skews[skews.len() / 2];
+
+        let threshold = self.config.threshold.as_secs_f64();
+        let skewed = skews
+            .iter()
+            .filter(|skew| skew.abs() > threshold && skew.signum() == median_skew.signum())
+            .count();
+        let server_suspect = skews.len() >= self.config.min_clients
+            && median_skew.abs() > threshold
+            && skewed as f64 >= AGREEMENT * skews.len() as f64;
+        ClockSkewStatus { clients: skews.len(), skewed, median_skew, server_suspect }
+    }
+
+    fn report(&self, state: &mut State, status: ClockSkewStatus, now: Instant) {
+        metrics::gauge!("fily_clock_skew_clients").set(status.clients as f64);
+        metrics::gauge!("fily_clock_skewed_clients").set(status.skewed as f64);
+        metrics::gauge!("fily_client_clock_skew_median_seconds").set(status.median_skew);
+        metrics::gauge!("fily_server_clock_suspect").set(status.server_suspect as u8 as f64);
+
+        if status.server_suspect {
+            let became_suspect = !state.suspect;
+            state.suspect = true;
+            if !became_suspect && state.warned.is_some_and(|at| now.duration_since(at) < WARN_EVERY) {
+                return;
+            }
+            state.warned = Some(now);
+            let direction = if status.median_skew > 0.0 { "behind" } else { "ahead of" };
+            let message = format!(
+                "SERVER CLOCK LOOKS WRONG: {} of {} recent clients agree it is about {:.0}s {} theirs; \
+                 RequestTimeTooSkewed errors are likely this server's fault, check NTP on this host",
+                status.skewed,
+                status.clients,
+                status.median_skew.abs(),
+                direction
+            );
+            error!(
+                clients = status.clients,
+                skewed = status.skewed,
+                median_skew_secs = status.median_skew,
+                "{}",
+                message
+            );
+            if became_suspect {
+                self.notify(Severity::Critical, message, status);
+            }
+        } else if state.suspect {
+            state.suspect = false;
+            state.warned = None;
+            let message = format!(
+                "Server clock agrees with its clients again (median skew {:.0}s over {} clients)",
+                status.median_skew, status.clients
+            );
+            info!("{}", message);
+            self.notify(Severity::Resolved, message, status);
+        }
+    }
+
+    fn notify(&self, severity: Severity, message: String, status: ClockSkewStatus) {
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(Event::new(
+                EventKind::ClockSkew,
+                severity,
+                message,
+                serde_json::json!({
+                    "clients": status.clients,
+                    "skewed": status.skewed,
+                    "median_skew_secs": status.median_skew,
+                }),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_skew() {
+        let now = DateTime::parse_from_rfc3339("2024-10-15T10:00:00Z").unwrap().with_timezone(&Utc);
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_skew(&headers, now), None);
+        headers.insert(X_AMZ_DATE_HEADER, "20241015T100130Z".parse().unwrap());
+        assert_eq!(request_skew(&headers, now), Some(90.0));
+        headers.insert(X_AMZ_DATE_HEADER, "20241015T095500Z".parse().unwrap());
+        assert_eq!(request_skew(&headers, now), Some(-300.0));
+    }
+
+    #[test]
+    fn test_server_suspected_when_clients_agree() {
+        let monitor = ClockSkewMonitor::new(ClockSkewConfig::default());
+        let now = Instant::now();
+
+        // A single badly skewed client is not enough
+        monitor.record("10.0.0.1", 3600.0, now);
+        monitor.record("10.0.0.2", 1.0, now);
+        monitor.record("10.0.0.3", -2.0, now);
+        assert!(!monitor.status(now).server_suspect);
+
+        // Most clients agree the server is an hour behind them
+        for client in ["10.0.0.2", "10.0.0.3", "10.0.0.4"] {
+            monitor.record(client, 3600.0, now);
+        }
+        let status = monitor.status(now);
+        assert_eq!(status.clients, 4);
+        assert_eq!(status.skewed, 4);
+        assert!(status.server_suspect);
+
+        // Clients skewed in opposite directions point at the clients instead
+        let monitor = ClockSkewMonitor::new(ClockSkewConfig::default());
+        for (client, skew) in [("a", 900.0), ("b", -900.0), ("c", 900.0), ("d", -900.0)] {
+            monitor.record(client, skew, now);
+        }
+        assert!(!monitor.status(now).server_suspect);
+
+        // Clients not seen within the window no longer count
+        let later = now + WINDOW;
+        assert_eq!(monitor.status(later).clients, 0);
+    }
+}
@@ -0,0 +1,147 @@
+//! PUT /{bucket} with `x-fily-clone-source: {source}`: creates the bucket as a server-side clone of
+//! another, every object with its metadata and tags, for refreshing e.g. a staging environment
+//! without pulling the data through a client.
+//!
+//! Objects are hard linked into the new bucket where the filesystem allows, so a clone costs
+//! directory entries rather than copies of the data; a later write to either bucket replaces the
+//! key's file instead of changing it, so the buckets never see each other's writes.
+
+use std::collections::BTreeMap;
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::extract::{Path, Request};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use hyper::StatusCode;
+use serde::Serialize;
+use tracing::{error, info};
+
+use super::auth_middleware::{AuthenticatedAccessKey, Principal};
+use super::commit::{commit_object, ObjectData};
+use super::copy_object::{stage_copy, CopySource};
+use super::cpu_pool::CpuPool;
+use super::create_bucket;
+use super::key_locks;
+use super::metadata::load_metadata;
+use super::path_security::{construct_safe_path, sanitize_bucket_name};
+use super::policy_condition::RequestContext;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::search_bucket::{Listed, ObjectWalker};
+use super::tenancy::{authorize_bucket_access, BucketAccess, Tenant};
+use super::Config;
+
+/// Header naming the bucket to clone, which turns CreateBucket into a clone
+pub const CLONE_SOURCE_HEADER: &str = "x-fily-clone-source";
+
+#[derive(Serialize, Debug, Default)]
+struct CloneResult {
+    source: String,
+    bucket: String,
+    objects: u64,
+    /// Size of the cloned objects as uploaded
+    bytes: u64,
+    /// Objects by how their data was cloned: `hard_link`, `copy` or `rewrite`
+    methods: BTreeMap<&'static str, u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    Extension(cpu_pool): Extension<Arc<CpuPool>>,
+    principal: Principal,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    tenant: Option<Extension<Tenant>>,
+    Path(bucket): Path<String>,
+    req: Request,
+) -> Result<Response, S3AppError> {
+    let source = req
+        .headers()
+        .get(CLONE_SOURCE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+            S3AppError::with_message(S3ErrorCode::InvalidArgument, format!("Invalid {} header", CLONE_SOURCE_HEADER))
+        })?;
+    let source = sanitize_bucket_name(source).map_err(|_| S3AppError::invalid_bucket_name(source))?;
+    let storage_root = FsPath::new(&config.location);
+    let source_path = storage_root.join(&source);
+    if !source_path.is_dir() {
+        return Err(S3AppError::no_such_bucket(&source));
+    }
+
+    // The middleware checked creating the bucket; reading every object of the source needs its own permission
+    let context = RequestContext::from_request(&req, &config.trusted_proxies);
+    authorize_bucket_access(&config, &access_key, &source, BucketAccess::Read, &context).await?;
+
+    create_bucket::handle(
+        config.clone(),
+        Extension(access_key),
+        tenant,
+        Path(bucket.clone()),
+        req.headers().clone(),
+        Bytes::new(),
+    )
+    .await?;
+
+    let mut result = CloneResult {
+        source: source.clone(),
+        bucket: bucket.clone(),
+        ..Default::default()
+    };
+    let mut walker = ObjectWalker::new(&source_path, "", None);
+    let cloned = async {
+        while let Some(listed) = walker.next_listed().await.map_err(|e| {
+            S3AppError::internal_error(&format!("Failed to list bucket {}: {}", source, e))
+        })? {
+            let Listed::Object(object) = listed else { continue };
+            let path = construct_safe_path(storage_root, &source, &object.key)
+                .map_err(|e| S3AppError::internal_error(&format!("Invalid key {}: {}", object.key, e)))?;
+            // The source must not change between reading its metadata and its data
+            let _lock = key_locks::lock_all(storage_root, &[(&source, &object.key), (&bucket, &object.key)]).await;
+            if !tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+                // Deleted since the walk listed it
+                continue;
+            }
+            let copy_source = CopySource {
+                bucket: &source,
+                key: &object.key,
+                path: &path,
+                metadata: load_metadata(storage_root, &source, &object.key).await.ok().flatten(),
+            };
+            let (staged, mut metadata, method) = stage_copy(&config, &cpu_pool, copy_source, &bucket).await?;
+            // Keeps the object's ETag and dates; the clone's keys are new, so they are the caller's
+            metadata.record_write(&principal.0, None);
+            commit_object(storage_root, &bucket, &object.key, ObjectData::Staged(&staged), &metadata)
+                .await
+                .map_err(|e| S3AppError::internal_error(&format!("Failed to clone {}: {}", object.key, e)))?;
+
+            metrics::counter!("fily_clone_bucket_objects_total", "method" => method).increment(1);
+            result.objects += 1;
+            result.bytes += metadata.content_length;
+            *result.methods.entry(method).or_default() += 1;
+        }
+        Ok::<_, S3AppError>(())
+    }
+    .await;
+
+    if let Err(mut e) = cloned {
+        error!(
+            "Clone of bucket {} into {} stopped after {} objects: {:?}",
+            source, bucket, result.objects, e
+        );
+        let cause = e.message.take().unwrap_or_else(|| e.code.default_message().to_string());
+        e.message = Some(format!(
+            "Clone stopped after {} objects, delete bucket {} before retrying: {}",
+            result.objects, bucket, cause
+        ));
+        return Err(e);
+    }
+
+    info!(
+        "Cloned bucket {} into {} ({} objects, {} bytes) for {}",
+        source, bucket, result.objects, result.bytes, principal
+    );
+    let body = serde_json::to_string(&result).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    Ok((StatusCode::OK, [("content-type", "application/json")], body).into_response())
+}
@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use hmac::{Hmac, Mac};
+use http_body_util::BodyExt;
+use hyper::header::{HeaderName, CONNECTION, TE, TRAILER, UPGRADE};
+use hyper::{Method, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::{debug, info, warn};
+
+use super::logging::REDACTED;
+use super::request_info::RequestInfo;
+use super::s3_app_error::S3AppError;
+use super::tenancy::BucketAccess;
+
+/// Set on requests a node forwards to the bucket's owner, naming the forwarding node; such
+/// requests are always served where they arrive so a disagreement about the ring cannot loop
+pub const FORWARDED_HEADER: &str = "x-fily-forwarded-by";
+
+/// Proves a request carrying [`FORWARDED_HEADER`] was forwarded by that node rather than sent by
+/// a client: `<unix-time>:<hex HMAC-SHA256>` of the node, time, method and path under the cluster
+/// secret
+pub const FORWARDED_SIGNATURE_HEADER: &str = "x-fily-forwarded-signature";
+
+/// Seconds a forwarding signature's time may be off from this node's clock
+const FORWARDED_SIGNATURE_WINDOW: u64 = 300;
+
+/// Unauthenticated endpoint through which nodes exchange heartbeats
+pub const GOSSIP_PATH: &str = "/_fily/cluster/gossip";
+
+/// Headers describing a single connection rather than the request, never forwarded
+const HOP_BY_HOP_HEADERS: [HeaderName; 4] = [CONNECTION, TE, TRAILER, UPGRADE];
+
+/// A fily node of the cluster
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Peer {
+    pub id: String,
+    /// `http://host:port` the other nodes reach this node at
+    pub endpoint: String,
+}
+
+/// Nodes sharing buckets between them; every node must be given the same peer list
+#[derive(Clone, PartialEq)]
+pub struct ClusterConfig {
+    /// This node's ID in `peers`
+    pub node_id: Option<String>,
+    /// Every node of the cluster, including this one; clustering is disabled when empty
+    pub peers: Vec<Peer>,
+    /// Secret shared by every node, signing the requests they forward to each other
+    pub secret: Option<String>,
+    /// Points each node takes on the ring, evening out the share of buckets per node
+    pub vnodes: usize,
+    /// Time between two rounds of heartbeat exchange with the other nodes
+    pub health_interval: Duration,
+    /// Time without a new heartbeat after which a node counts as down
+    pub failure_timeout: Duration,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            node_id: None,
+            peers: vec![],
+            secret: None,
+            vnodes: 128,
+            health_interval: Duration::from_secs(2),
+            failure_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl fmt::Debug for ClusterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClusterConfig")
+            .field("node_id", &self.node_id)
+            .field("peers", &self.peers)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("vnodes", &self.vnodes)
+            .field("health_interval", &self.health_interval)
+            .field("failure_timeout", &self.failure_timeout)
+            .finish()
+    }
+}
+
+impl ClusterConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut ids = HashSet::new();
+        for peer in &self.peers {
+            if peer.id.is_empty() || !ids.insert(peer.id.as_str()) {
+                return Err(format!("peer IDs must be unique and not empty, found {:?}", peer.id));
+            }
+            let url = url::Url::parse(&peer.endpoint)
+                .map_err(|e| format!("invalid endpoint of peer {}: {}", peer.id, e))?;
+            if url.scheme() != "http" || url.host_str().is_none() {
+                return Err(format!("the endpoint of peer {} must be an http:// URL", peer.id));
+            }
+        }
+        match &self.node_id {
+            Some(node_id) if ids.contains(node_id.as_str()) => {}
+            Some(node_id) => return Err(format!("node ID {} is not one of the peers", node_id)),
+            None => return Err("a node ID is required when peers are configured".to_string()),
+        }
+        if self.secret.as_deref().is_none_or(str::is_empty) {
+            return Err("a cluster secret is required when peers are configured".to_string());
+        }
+        if self.vnodes == 0 {
+            return Err("vnodes must be greater than 0".to_string());
+        }
+        if self.health_interval.is_zero() || self.failure_timeout <= self.health_interval {
+            return Err("the failure timeout must be longer than the health check interval, which must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Position on the ring; SHA-256 rather than `DefaultHasher`, which may differ between builds
+/// and so between nodes
+fn ring_hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Consistent hash ring assigning each bucket to one node; adding or removing a node only
+/// moves the buckets on its share of the ring
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    points: BTreeMap<u64, usize>,
+    peers: Vec<Peer>,
+}
+
+impl HashRing {
+    pub fn new(peers: &[Peer], vnodes: usize) -> Self {
+        let mut points = BTreeMap::new();
+        for (index, peer) in peers.iter().enumerate() {
+            for vnode in 0..vnodes {
+                points.insert(ring_hash(&format!("{}#{}", peer.id, vnode)), index);
+            }
+        }
+        Self {
+            points,
+            peers: peers.to_vec(),
+        }
+    }
+
+    /// Node owning `bucket`: the first point at or after the bucket's hash, wrapping around
+    pub fn owner(&self, bucket: &str) -> &Peer {
+        self.preference_list(bucket)
+            .next()
+            .expect("a cluster has at least one peer")
+    }
+
+    /// Every node in the order they take over `bucket`: its owner, then its replica node, and so on
+    pub fn preference_list(&self, bucket: &str) -> impl Iterator<Item = &Peer> {
+        let hash = ring_hash(bucket);
+        let mut seen = HashSet::new();
+        self.points
+            .range(hash..)
+            .chain(self.points.range(..hash))
+            .filter(move |(_, index)| seen.insert(**index))
+            .map(|(_, index)| &self.peers[*index])
+    }
+}
+
+/// Last heartbeat of a node and when this node learned of it
+struct PeerHealth {
+    heartbeat: u64,
+    updated: Instant,
+}
+
+/// Heartbeats a node knows of, exchanged between nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gossip {
+    pub node_id: String,
+    pub heartbeats: BTreeMap<String, u64>,
+}
+
+/// Where a request for a bucket is served
+#[derive(Debug, PartialEq)]
+enum Route<'a> {
+    Local,
+    Remote(&'a Peer),
+    /// Neither the owner nor, for reads, its replica node is up
+    Unavailable(&'a Peer),
+}
+
+type HttpClient = Client<HttpConnector, Body>;
+
+/// This node's view of the cluster, shared with the forwarding middleware
+pub struct Cluster {
+    config: ClusterConfig,
+    node_id: String,
+    ring: HashRing,
+    client: HttpClient,
+    heartbeat: AtomicU64,
+    /// Other nodes, each assumed up until it misses `failure_timeout`
+    health: RwLock<HashMap<String, PeerHealth>>,
+}
+
+impl Cluster {
+    /// Ring of a validated cluster configuration, `None` when clustering is disabled
+    pub fn new(config: &ClusterConfig) -> Option<Self> {
+        let node_id = config.node_id.clone().filter(|_| config.is_enabled())?;
+        let health = config
+            .peers
+            .iter()
+            .filter(|peer| peer.id != node_id)
+            .map(|peer| (peer.id.clone(), PeerHealth { heartbeat: 0, updated: Instant::now() }))
+            .collect();
+        Some(Self {
+            config: config.clone(),
+            node_id,
+            ring: HashRing::new(&config.peers, config.vnodes),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            heartbeat: AtomicU64::new(0),
+            health: RwLock::new(health),
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn is_up(&self, id: &str) -> bool {
+        id == self.node_id
+            || self
+                .health
+                .read()
+                .unwrap()
+                .get(id)
+                .is_some_and(|health| health.updated.elapsed() < self.config.failure_timeout)
+    }
+
+    /// Serves a bucket on its owner while that is up, and reads on the owner's replica node,
+    /// the next on the ring, while it is not. Writes wait for the owner so the copies never
+    /// diverge.
+    fn route(&self, bucket: &str, access: BucketAccess) -> Route<'_> {
+        let mut candidates = self.ring.preference_list(bucket);
+        let owner = candidates.next().expect("a cluster has at least one peer");
+        let target = if self.is_up(&owner.id) {
+            owner
+        } else {
+            match candidates.next() {
+                Some(replica) if access == BucketAccess::Read && self.is_up(&replica.id) => replica,
+                _ => return Route::Unavailable(owner),
+            }
+        };
+        if target.id == self.node_id {
+            Route::Local
+        } else {
+            Route::Remote(target)
+        }
+    }
+
+    /// Heartbeats known to this node, its own included
+    pub fn gossip(&self) -> Gossip {
+        let mut heartbeats: BTreeMap<String, u64> = self
+            .health
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, health)| (id.clone(), health.heartbeat))
+            .collect();
+        heartbeats.insert(self.node_id.clone(), self.heartbeat.load(Ordering::Relaxed));
+        Gossip {
+            node_id: self.node_id.clone(),
+            heartbeats,
+        }
+    }
+
+    /// Takes every heartbeat newer than the one known, logging nodes going down or coming back
+    fn merge(&self, gossip: &Gossip) {
+        let mut health = self.health.write().unwrap();
+        for (id, &heartbeat) in &gossip.heartbeats {
+            let Some(peer) = health.get_mut(id) else {
+                continue;
+            };
+            if heartbeat > peer.heartbeat {
+                if peer.updated.elapsed() >= self.config.failure_timeout {
+                    info!("Cluster node {} is up again", id);
+                }
+                peer.heartbeat = heartbeat;
+                peer.updated = Instant::now();
+            }
+        }
+    }
+
+    async fn fetch_gossip(&self, peer: &Peer) -> anyhow::Result<Gossip> {
+        let uri = format!("{}{}", peer.endpoint.trim_end_matches('/'), GOSSIP_PATH).parse::<Uri>()?;
+        let request = Request::get(uri)
+            .header(FORWARDED_HEADER, self.node_id.as_str())
+            .body(Body::empty())?;
+        let response = tokio::time::timeout(self.config.health_interval, async {
+            let response = self.client.request(request).await?;
+            if !response.status().is_success() {
+                anyhow::bail!("status {}", response.status());
+            }
+            Ok(response.into_body().collect().await?.to_bytes())
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out"))??;
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    /// Exchanges heartbeats with every other node each `health_interval`, for the lifetime of
+    /// the server. Heartbeats a node learns from others keep a peer up even when this node
+    /// cannot reach it directly.
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.health_interval);
+        let mut was_up: HashMap<String, bool> = HashMap::new();
+        loop {
+            interval.tick().await;
+            self.heartbeat.fetch_add(1, Ordering::Relaxed);
+            for peer in self.config.peers.iter().filter(|peer| peer.id != self.node_id) {
+                match self.fetch_gossip(peer).await {
+                    Ok(gossip) => self.merge(&gossip),
+                    Err(e) => debug!("Failed to exchange heartbeats with node {}: {}", peer.id, e),
+                }
+            }
+            for peer in self.config.peers.iter().filter(|peer| peer.id != self.node_id) {
+                let up = self.is_up(&peer.id);
+                metrics::gauge!("fily_cluster_peer_up", "node" => peer.id.clone()).set(up as u8 as f64);
+                if was_up.insert(peer.id.clone(), up) == Some(true) && !up {
+                    warn!("Cluster node {} is down, serving reads of its buckets from their replica nodes", peer.id);
+                }
+            }
+        }
+    }
+
+    /// State of every node as this node sees it
+    pub fn state(&self) -> serde_json::Value {
+        let health = self.health.read().unwrap();
+        let nodes: Vec<_> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer| {
+                let (heartbeat, last_heartbeat_secs) = match health.get(&peer.id) {
+                    Some(h) => (h.heartbeat, Some(h.updated.elapsed().as_secs())),
+                    None => (self.heartbeat.load(Ordering::Relaxed), None),
+                };
+                serde_json::json!({
+                    "id": peer.id,
+                    "endpoint": peer.endpoint,
+                    "up": self.is_up(&peer.id),
+                    "heartbeat": heartbeat,
+                    "last_heartbeat_secs": last_heartbeat_secs,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "node_id": self.node_id,
+            "nodes": nodes,
+        })
+    }
+
+    /// Signature of a request `node_id` forwards at `timestamp`, `None` without a cluster secret
+    fn forwarding_signature(&self, node_id: &str, timestamp: u64, method: &Method, path_and_query: &str) -> Option<String> {
+        let secret = self.config.secret.as_deref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+        mac.update(format!("{}\n{}\n{}\n{}", node_id, timestamp, method, path_and_query).as_bytes());
+        Some(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Whether another node of the cluster forwarded `req`, proven by its signature; the
+    /// forwarding header alone is not trusted, or any client could skip routing and have writes
+    /// served while the bucket's owner is down
+    fn is_forwarded(&self, req: &Request) -> bool {
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let (Some(node_id), Some(signature)) = (header(FORWARDED_HEADER), header(FORWARDED_SIGNATURE_HEADER)) else {
+            return false;
+        };
+        let Some((timestamp, signature)) = signature.split_once(':') else {
+            return false;
+        };
+        let Ok(timestamp) = timestamp.parse::<u64>() else {
+            return false;
+        };
+        if node_id == self.node_id
+            || !self.config.peers.iter().any(|peer| peer.id == node_id)
+            || unix_time().abs_diff(timestamp) > FORWARDED_SIGNATURE_WINDOW
+        {
+            return false;
+        }
+        let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        self.forwarding_signature(node_id, timestamp, req.method(), path_and_query)
+            .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(signature.as_bytes())))
+    }
+
+    /// Sends the request unchanged to `peer`, which checks its signature as if it had received
+    /// it directly: the Host header and the path the client signed are kept
+    async fn forward(&self, peer: &Peer, req: Request) -> Result<Response, S3AppError> {
+        let path = req.uri().path().to_string();
+        let (mut parts, body) = req.into_parts();
+        let path_and_query = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        parts.uri = format!("{}{}", peer.endpoint.trim_end_matches('/'), path_and_query)
+            .parse::<Uri>()
+            .map_err(|e| S3AppError::internal_error(&format!("Invalid endpoint of peer {}: {}", peer.id, e)))?;
+        for name in &HOP_BY_HOP_HEADERS {
+            parts.headers.remove(name);
+        }
+        parts.headers.insert(
+            FORWARDED_HEADER,
+            self.node_id
+                .parse()
+                .map_err(|_| S3AppError::internal_error("Node ID is not a valid header value"))?,
+        );
+        let timestamp = unix_time();
+        let path_and_query = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        match self.forwarding_signature(&self.node_id, timestamp, &parts.method, path_and_query) {
+            Some(signature) => {
+                let value = format!("{}:{}", timestamp, signature);
+                parts.headers.insert(FORWARDED_SIGNATURE_HEADER, value.parse().expect("hex is a valid header value"));
+            }
+            None => {
+                parts.headers.remove(FORWARDED_SIGNATURE_HEADER);
+            }
+        }
+
+        let response = self
+            .client
+            .request(Request::from_parts(parts, body))
+            .await
+            .map_err(|e| {
+                warn!("Failed to forward {} to node {}: {}", path, peer.id, e);
+                metrics::counter!("fily_cluster_forwarded_total", "node" => peer.id.clone(), "result" => "error")
+                    .increment(1);
+                S3AppError::service_unavailable(&format!("The node owning this bucket ({}) is unreachable.", peer.id), &path)
+            })?;
+        metrics::counter!("fily_cluster_forwarded_total", "node" => peer.id.clone(), "result" => "ok").increment(1);
+
+        let (mut parts, body) = response.into_parts();
+        for name in &HOP_BY_HOP_HEADERS {
+            parts.headers.remove(name);
+        }
+        Ok(Response::from_parts(parts, Body::new(body)))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Middleware proxying requests for buckets owned by another node to that node, or to its
+/// replica node while the owner is down
+pub async fn forward_to_owner(req: Request, next: Next) -> Response {
+    let Some(cluster) = req.extensions().get::<Arc<Cluster>>().cloned() else {
+        return next.run(req).await;
+    };
+    if cluster.is_forwarded(&req) {
+        return next.run(req).await;
+    }
+    let info = RequestInfo::of(&req);
+    let Some(bucket) = info.target.bucket() else {
+        return next.run(req).await;
+    };
+    let peer = match cluster.route(bucket, BucketAccess::for_method(req.method())) {
+        Route::Local => return next.run(req).await,
+        Route::Remote(peer) => peer.clone(),
+        Route::Unavailable(owner) => {
+            metrics::counter!("fily_cluster_forwarded_total", "node" => owner.id.clone(), "result" => "unavailable")
+                .increment(1);
+            return S3AppError::service_unavailable(
+                &format!("The node owning this bucket ({}) is down.", owner.id),
+                req.uri().path(),
+            )
+            .into_response();
+        }
+    };
+    debug!("Forwarding {} {} to node {}", req.method(), req.uri().path(), peer.id);
+    match cluster.forward(&peer, req).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /_fily/cluster/gossip: the heartbeats this node knows of
+pub async fn gossip(cluster: Option<Extension<Arc<Cluster>>>) -> Result<Response, S3AppError> {
+    let Some(Extension(cluster)) = cluster else {
+        return Err(S3AppError::not_implemented("cluster mode"));
+    };
+    let body = serde_json::to_string(&cluster.gossip()).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    Ok(([("content-type", "application/json")], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(ids: &[&str]) -> Vec<Peer> {
+        ids.iter()
+            .map(|id| Peer {
+                id: id.to_string(),
+                endpoint: format!("http://{}:8333", id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ring_spreads_buckets_and_moves_few() {
+        let buckets: Vec<String> = (0..1000).map(|i| format!("bucket-{}", i)).collect();
+        let three = HashRing::new(&peers(&["a", "b", "c"]), 128);
+        for id in ["a", "b", "c"] {
+            let owned = buckets.iter().filter(|b| three.owner(b).id == id).count();
+            assert!((200..=466).contains(&owned), "{} owns {} buckets", id, owned);
+        }
+
+        // A fourth node only takes buckets over, it never shuffles them between the others
+        let four = HashRing::new(&peers(&["a", "b", "c", "d"]), 128);
+        let mut moved = 0;
+        for bucket in &buckets {
+            let (before, after) = (&three.owner(bucket).id, &four.owner(bucket).id);
+            if before != after {
+                assert_eq!(after, "d");
+                moved += 1;
+            }
+        }
+        assert!((150..=350).contains(&moved), "{} buckets moved", moved);
+    }
+
+    #[test]
+    fn test_validate_cluster_config() {
+        let config = |node_id: Option<&str>, peers: Vec<Peer>| ClusterConfig {
+            node_id: node_id.map(str::to_string),
+            peers,
+            secret: Some("cluster secret".to_string()),
+            ..Default::default()
+        };
+        assert!(ClusterConfig::default().validate().is_ok());
+        assert!(config(Some("a"), peers(&["a", "b"])).validate().is_ok());
+        assert!(config(None, peers(&["a", "b"])).validate().is_err());
+        assert!(config(Some("c"), peers(&["a", "b"])).validate().is_err());
+        assert!(config(Some("a"), peers(&["a", "a"])).validate().is_err());
+
+        let mut unsigned = config(Some("a"), peers(&["a", "b"]));
+        unsigned.secret = None;
+        assert!(unsigned.validate().is_err());
+
+        let mut https = peers(&["a"]);
+        https[0].endpoint = "https://a:8333".to_string();
+        assert!(config(Some("a"), https).validate().is_err());
+
+        let mut eager = config(Some("a"), peers(&["a", "b"]));
+        eager.failure_timeout = eager.health_interval;
+        assert!(eager.validate().is_err());
+    }
+
+    #[test]
+    fn test_routes_to_the_first_node_up() {
+        let config = ClusterConfig {
+            node_id: Some("a".to_string()),
+            peers: peers(&["a", "b", "c"]),
+            secret: None,
+            vnodes: 64,
+            failure_timeout: Duration::from_millis(100),
+            health_interval: Duration::from_millis(10),
+        };
+        let cluster = Cluster::new(&config).unwrap();
+        let ring = HashRing::new(&config.peers, 64);
+        let bucket = (0..)
+            .map(|i| format!("bucket-{}", i))
+            .find(|bucket| {
+                let order: Vec<_> = ring.preference_list(bucket).map(|p| p.id.as_str()).collect();
+                order == ["b", "a", "c"]
+            })
+            .unwrap();
+
+        assert_eq!(cluster.route(&bucket, BucketAccess::Read), Route::Remote(&config.peers[1]));
+        assert_eq!(cluster.route(&bucket, BucketAccess::Write), Route::Remote(&config.peers[1]));
+
+        // Node b misses its heartbeats while c keeps beating
+        std::thread::sleep(Duration::from_millis(120));
+        cluster.merge(&Gossip {
+            node_id: "c".to_string(),
+            heartbeats: BTreeMap::from([("c".to_string(), 1)]),
+        });
+        assert!(!cluster.is_up("b"));
+        assert!(cluster.is_up("c"));
+        assert_eq!(cluster.route(&bucket, BucketAccess::Read), Route::Local);
+        assert_eq!(cluster.route(&bucket, BucketAccess::Write), Route::Unavailable(&config.peers[1]));
+
+        // A newer heartbeat of b, even one learned through c, brings it back
+        cluster.merge(&Gossip {
+            node_id: "c".to_string(),
+            heartbeats: BTreeMap::from([("b".to_string(), 5), ("c".to_string(), 1)]),
+        });
+        assert_eq!(cluster.route(&bucket, BucketAccess::Read), Route::Remote(&config.peers[1]));
+        assert!(Cluster::new(&ClusterConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_only_signed_forwards_are_trusted() {
+        let config = |node_id: &str| ClusterConfig {
+            node_id: Some(node_id.to_string()),
+            peers: peers(&["a", "b"]),
+            secret: Some("cluster secret".to_string()),
+            ..Default::default()
+        };
+        let (a, b) = (Cluster::new(&config("a")).unwrap(), Cluster::new(&config("b")).unwrap());
+        let request = |headers: &[(&'static str, String)]| {
+            let mut req = Request::put("/photos/a.jpg?tagging").body(Body::empty()).unwrap();
+            for (name, value) in headers {
+                req.headers_mut().insert(*name, value.parse().unwrap());
+            }
+            req
+        };
+        let signed = |cluster: &Cluster, node_id: &str, timestamp: u64, path: &str| {
+            let signature = cluster.forwarding_signature(node_id, timestamp, &Method::PUT, path).unwrap();
+            [
+                (FORWARDED_HEADER, node_id.to_string()),
+                (FORWARDED_SIGNATURE_HEADER, format!("{}:{}", timestamp, signature)),
+            ]
+        };
+        let now = unix_time();
+
+        assert!(b.is_forwarded(&request(&signed(&a, "a", now, "/photos/a.jpg?tagging"))));
+
+        // A client naming a node, or replaying a signature for another request or much later
+        assert!(!b.is_forwarded(&request(&[(FORWARDED_HEADER, "a".to_string())])));
+        assert!(!b.is_forwarded(&request(&signed(&a, "a", now, "/photos/b.jpg"))));
+        assert!(!b.is_forwarded(&request(&signed(&a, "a", now - 3600, "/photos/a.jpg?tagging"))));
+        // Only peers forward, and only under the cluster's secret
+        assert!(!b.is_forwarded(&request(&signed(&a, "c", now, "/photos/a.jpg?tagging"))));
+        let other = ClusterConfig {
+            secret: Some("another secret".to_string()),
+            ..config("a")
+        };
+        let other = Cluster::new(&other).unwrap();
+        assert!(!b.is_forwarded(&request(&signed(&other, "a", now, "/photos/a.jpg?tagging"))));
+    }
+}
@@ -0,0 +1,571 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::key_locks;
+use super::mirror;
+use super::metadata::{delete_metadata, stage_metadata, ObjectMetadata};
+use super::path_security::{
+    construct_safe_metadata_path, construct_safe_path, construct_staging_path, metadata_staging_dir, scratch_dir,
+    staging_dir,
+};
+
+/// Extension of intent records; staged files are bare UUIDs, so the two never collide
+const INTENT_EXTENSION: &str = "commit";
+
+/// Renames fail with this on Linux when source and destination are on different filesystems
+const EXDEV: i32 = 18;
+
+/// Staged files without an intent are left alone this long, as a large upload may still be
+/// writing one when a server embedded in the same process starts on the storage root
+const ORPHAN_AGE: Duration = Duration::from_secs(3600);
+
+/// Where the new data of an object comes from
+pub(crate) enum ObjectData<'a> {
+    /// A file under the bucket's staging directory
+    Staged(&'a Path),
+    /// Another key of the same bucket, whose data file moves and whose metadata goes
+    Renamed(&'a str),
+}
+
+/// Record of a commit in progress, written before anything visible changes so the recovery pass
+/// can tell which half of a commit reached the disk
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Intent {
+    key: String,
+    data: IntentData,
+    /// File name of the staged metadata
+    metadata: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum IntentData {
+    /// File name in the staging directory
+    Staged(String),
+    Renamed(String),
+}
+
+/// Writes a file and flushes it to disk before returning
+pub(crate) async fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+/// Moves a file like a rename, copying it when the destination is on another filesystem. The copy
+/// is synced and renamed into place, so `dest` is never seen partially written, then the source
+/// is removed.
+pub(crate) async fn move_file(source: &Path, dest: &Path) -> std::io::Result<()> {
+    match tokio::fs::rename(source, dest).await {
+        Err(e) if is_cross_device(&e) => {}
+        result => return result,
+    }
+    let copy = dest.with_file_name(format!(".{}.{}", file_name(dest), uuid::Uuid::new_v4().simple()));
+    let copied = async {
+        let mut reader = tokio::fs::File::open(source).await?;
+        let mut writer = tokio::fs::File::create(&copy).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.sync_all().await?;
+        tokio::fs::rename(&copy, dest).await
+    }
+    .await;
+    if let Err(e) = copied {
+        let _ = tokio::fs::remove_file(&copy).await;
+        return Err(e);
+    }
+    sync_dir(dest).await;
+    tokio::fs::remove_file(source).await
+}
+
+fn is_cross_device(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::CrossesDevices || e.raw_os_error() == Some(EXDEV)
+}
+
+/// Makes renames into a directory durable. Directories cannot be opened for syncing everywhere,
+/// so this is best effort.
+async fn sync_dir(path: &Path) {
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = tokio::fs::File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+/// Replaces an object's data and metadata so that after a crash at any point either both are
+/// the old ones or both are the new ones. Both are staged and an intent is recorded, then the
+/// data is renamed into place before the metadata; `recover` finishes or undoes a commit
+/// interrupted between the two. The caller holds the key's lock. A staged data file is consumed
+/// whether or not the commit succeeds.
+pub(crate) async fn commit_object(
+    storage_root: &Path,
+    bucket: &str,
+    key: &str,
+    data: ObjectData<'_>,
+    metadata: &ObjectMetadata,
+) -> anyhow::Result<()> {
+    let result = match data {
+        ObjectData::Staged(staged) => match localize(storage_root, bucket, staged).await {
+            Ok(local) => {
+                let mut result = commit_mirror(storage_root, bucket, key, &local, None, metadata).await;
+                if result.is_ok() {
+                    result = commit(storage_root, bucket, key, &ObjectData::Staged(&local), metadata).await;
+                }
+                if result.is_err() {
+                    let _ = tokio::fs::remove_file(&local).await;
+                }
+                result
+            }
+            Err(e) => Err(e),
+        },
+        ObjectData::Renamed(source_key) => {
+            let source = construct_safe_path(storage_root, bucket, source_key)
+                .map_err(|e| anyhow::anyhow!("Path security violation: {}", e))?;
+            match commit_mirror(storage_root, bucket, key, &source, Some(source_key), metadata).await {
+                Ok(()) => commit(storage_root, bucket, key, &data, metadata).await,
+                Err(e) => Err(e),
+            }
+        }
+    };
+    if let (Err(_), ObjectData::Staged(staged)) = (&result, data) {
+        let _ = tokio::fs::remove_file(staged).await;
+    }
+    result
+}
+
+/// Commits the new data to the bucket's mirror, if it has one, before the primary changes. The
+/// mirror gets a copy of `source`, the primary's staged data or the data of a renamed key, which
+/// the primary commit then consumes as usual.
+async fn commit_mirror(
+    storage_root: &Path,
+    bucket: &str,
+    key: &str,
+    source: &Path,
+    renamed_from: Option<&str>,
+    metadata: &ObjectMetadata,
+) -> anyhow::Result<()> {
+    let Some(mirror_root) = mirror::mirror_root(storage_root, bucket) else {
+        return Ok(());
+    };
+    let mirrored = async {
+        let staged = mirror::stage_copy(&mirror_root, bucket, key, source).await?;
+        if let Err(e) = commit(&mirror_root, bucket, key, &ObjectData::Staged(&staged), metadata).await {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(e);
+        }
+        if let Some(source_key) = renamed_from {
+            mirror::remove(storage_root, bucket, source_key).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = mirrored {
+        metrics::counter!("fily_mirror_failures_total").increment(1);
+        warn!("Failed to mirror {}/{} to {}: {}", bucket, key, mirror_root.display(), e);
+        return Err(anyhow::anyhow!("Mirror write failed: {}", e));
+    }
+    Ok(())
+}
+
+/// Brings data staged on the scratch volume next to the bucket before anything is recorded, so
+/// the commit itself only renames within one filesystem
+async fn localize(storage_root: &Path, bucket: &str, staged: &Path) -> anyhow::Result<std::path::PathBuf> {
+    let local_dir = staging_dir(storage_root, bucket);
+    if staged.parent() == Some(local_dir.as_path()) {
+        return Ok(staged.to_path_buf());
+    }
+    let local = construct_staging_path(storage_root, bucket)
+        .map_err(|e| anyhow::anyhow!("Path security violation: {}", e))?;
+    move_file(staged, &local).await?;
+    Ok(local)
+}
+
+async fn commit(
+    storage_root: &Path,
+    bucket: &str,
+    key: &str,
+    data: &ObjectData<'_>,
+    metadata: &ObjectMetadata,
+) -> anyhow::Result<()> {
+    let security = |e| anyhow::anyhow!("Path security violation: {}", e);
+    let dest = construct_safe_path(storage_root, bucket, key).map_err(security)?;
+    let metadata_path = construct_safe_metadata_path(storage_root, bucket, key).map_err(security)?;
+    let (source, intent_data) = match data {
+        ObjectData::Staged(staged) => (staged.to_path_buf(), IntentData::Staged(file_name(staged))),
+        ObjectData::Renamed(source_key) => (
+            construct_safe_path(storage_root, bucket, source_key).map_err(security)?,
+            IntentData::Renamed(source_key.to_string()),
+        ),
+    };
+
+    let staged_metadata = stage_metadata(storage_root, bucket, metadata).await?;
+    let intent = Intent {
+        key: key.to_string(),
+        data: intent_data,
+        metadata: file_name(&staged_metadata),
+    };
+    let intent_path = construct_staging_path(storage_root, bucket)
+        .map_err(security)?
+        .with_extension(INTENT_EXTENSION);
+    if let Err(e) = write_synced(&intent_path, &serde_json::to_vec(&intent)?).await {
+        let _ = tokio::fs::remove_file(&intent_path).await;
+        let _ = tokio::fs::remove_file(&staged_metadata).await;
+        return Err(e.into());
+    }
+
+    // Until the data is in place nothing has changed, and a failure is simply undone
+    if let Err(e) = tokio::fs::rename(&source, &dest).await {
+        let _ = tokio::fs::remove_file(&staged_metadata).await;
+        let _ = tokio::fs::remove_file(&intent_path).await;
+        return Err(e.into());
+    }
+    sync_dir(&dest).await;
+
+    // From here on the commit is rolled forward, now or by the recovery pass
+    tokio::fs::rename(&staged_metadata, &metadata_path).await?;
+    sync_dir(&metadata_path).await;
+    if let ObjectData::Renamed(source_key) = data {
+        delete_metadata(storage_root, bucket, source_key).await?;
+    }
+    tokio::fs::remove_file(&intent_path).await?;
+    Ok(())
+}
+
+/// What the recovery pass found
+#[derive(Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Commits whose data was in place, finished by moving their metadata in too
+    pub rolled_forward: usize,
+    /// Commits whose data never moved, discarded with the old object left as it was
+    pub rolled_back: usize,
+    /// Staged files of writes that never reached their commit
+    pub orphans_removed: usize,
+}
+
+/// Resolves the commits of every bucket that a crash or kill interrupted, and removes staged
+/// files they left behind, in the store and its tenant namespaces. Run at startup, before
+/// requests are served.
+pub async fn recover(storage_root: &Path) -> std::io::Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+    for root in super::tenancy::namespace_roots(storage_root)? {
+        let mut buckets = tokio::fs::read_dir(&root).await?;
+        while let Some(entry) = buckets.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            recover_bucket(&root, &name, &mut report).await?;
+        }
+    }
+
+    if report != RecoveryReport::default() {
+        info!(
+            "Recovered interrupted writes: {} rolled forward, {} rolled back, {} staged files removed",
+            report.rolled_forward, report.rolled_back, report.orphans_removed
+        );
+    }
+    metrics::counter!("fily_commit_recoveries_total", "outcome" => "rolled_forward")
+        .increment(report.rolled_forward as u64);
+    metrics::counter!("fily_commit_recoveries_total", "outcome" => "rolled_back")
+        .increment(report.rolled_back as u64);
+    Ok(report)
+}
+
+async fn recover_bucket(storage_root: &Path, bucket: &str, report: &mut RecoveryReport) -> std::io::Result<()> {
+    let dir = staging_dir(storage_root, bucket);
+    let metadata_dir = metadata_staging_dir(storage_root, bucket);
+    let mut intents = Vec::new();
+    let mut staged = Vec::new();
+    // Metadata is staged apart from the data when it is kept outside the buckets, and new data on
+    // the scratch volume when there is one
+    let mut dirs = vec![dir.clone()];
+    dirs.extend(scratch_dir(storage_root, bucket).map(|scratch| scratch.join("staging")));
+    if metadata_dir != dir {
+        dirs.push(metadata_dir.clone());
+    }
+    for staging in &dirs {
+        let mut entries = match tokio::fs::read_dir(staging).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == INTENT_EXTENSION) {
+                intents.push(path);
+            } else {
+                staged.push(path);
+            }
+        }
+    }
+
+    for intent_path in intents {
+        let intent = match tokio::fs::read(&intent_path).await {
+            Ok(json) => serde_json::from_slice::<Intent>(&json).ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        match intent {
+            Some(intent) => {
+                let forward = recover_commit(storage_root, bucket, (&dir, &metadata_dir), &intent_path, &intent).await?;
+                if let Some(forward) = forward {
+                    *if forward { &mut report.rolled_forward } else { &mut report.rolled_back } += 1;
+                }
+            }
+            // Written last before the renames and synced, so a torn record means nothing moved
+            None => {
+                warn!("Discarding unreadable commit record {}", intent_path.display());
+                remove_if_present(&intent_path).await?;
+            }
+        }
+    }
+
+    for path in staged {
+        let age = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.modified()?.elapsed().unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if age >= ORPHAN_AGE && remove_if_present(&path).await? {
+            report.orphans_removed += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Finishes or undoes one commit; `Some(true)` when it was rolled forward, `None` when a running
+/// writer finished it first
+async fn recover_commit(
+    storage_root: &Path,
+    bucket: &str,
+    (dir, metadata_dir): (&Path, &Path),
+    intent_path: &Path,
+    intent: &Intent,
+) -> std::io::Result<Option<bool>> {
+    let mut keys = vec![(bucket, intent.key.as_str())];
+    if let IntentData::Renamed(source_key) = &intent.data {
+        keys.push((bucket, source_key.as_str()));
+    }
+    let _lock = key_locks::lock_all(storage_root, &keys).await;
+    if !tokio::fs::try_exists(intent_path).await? {
+        return Ok(None);
+    }
+
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}", e));
+    let data = match &intent.data {
+        IntentData::Staged(name) => dir.join(name),
+        IntentData::Renamed(source_key) => construct_safe_path(storage_root, bucket, source_key).map_err(invalid)?,
+    };
+    let staged_metadata = metadata_dir.join(&intent.metadata);
+
+    // The data is renamed first, so while it is still at its source nothing visible changed
+    let forward = !tokio::fs::try_exists(&data).await?;
+    if forward {
+        let metadata_path = construct_safe_metadata_path(storage_root, bucket, &intent.key).map_err(invalid)?;
+        if tokio::fs::try_exists(&staged_metadata).await? {
+            tokio::fs::rename(&staged_metadata, &metadata_path).await?;
+            sync_dir(&metadata_path).await;
+        }
+        if let IntentData::Renamed(source_key) = &intent.data {
+            delete_metadata(storage_root, bucket, source_key).await.map_err(std::io::Error::other)?;
+        }
+        info!("Rolled forward interrupted write of {}/{}", bucket, intent.key);
+    } else {
+        if matches!(intent.data, IntentData::Staged(_)) {
+            remove_if_present(&data).await?;
+        }
+        remove_if_present(&staged_metadata).await?;
+        info!("Rolled back interrupted write of {}/{}", bucket, intent.key);
+    }
+    remove_if_present(intent_path).await?;
+    Ok(Some(forward))
+}
+
+/// Whether the file was there to remove
+async fn remove_if_present(path: &Path) -> std::io::Result<bool> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fily::metadata::load_metadata;
+    use std::path::PathBuf;
+
+    fn metadata(etag: &str) -> ObjectMetadata {
+        ObjectMetadata::with_content_sha256(None, 3, etag.to_string(), "a.txt", String::new())
+    }
+
+    /// Leaves a commit as a crash after its intent was recorded would, with the data already
+    /// renamed when `data_moved` is set
+    async fn interrupted(root: &Path, data_moved: bool) -> PathBuf {
+        let staged = construct_staging_path(root, "docs").unwrap();
+        write_synced(&staged, b"new").await.unwrap();
+        let staged_metadata = stage_metadata(root, "docs", &metadata("\"new\"")).await.unwrap();
+        let intent = Intent {
+            key: "a.txt".to_string(),
+            data: IntentData::Staged(file_name(&staged)),
+            metadata: file_name(&staged_metadata),
+        };
+        let intent_path = construct_staging_path(root, "docs").unwrap().with_extension(INTENT_EXTENSION);
+        write_synced(&intent_path, &serde_json::to_vec(&intent).unwrap()).await.unwrap();
+        if data_moved {
+            tokio::fs::rename(&staged, root.join("docs/a.txt")).await.unwrap();
+        }
+        staged
+    }
+
+    async fn setup() -> tempfile::TempDir {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/a.txt"), b"old").unwrap();
+        crate::fily::metadata::save_metadata(dir.path(), "docs", "a.txt", &metadata("\"old\"")).await.unwrap();
+        dir
+    }
+
+    fn staging_is_empty(root: &Path) -> bool {
+        std::fs::read_dir(staging_dir(root, "docs")).unwrap().next().is_none()
+    }
+
+    #[tokio::test]
+    async fn test_commit_object() {
+        let dir = setup().await;
+        let staged = construct_staging_path(dir.path(), "docs").unwrap();
+        write_synced(&staged, b"new").await.unwrap();
+
+        commit_object(dir.path(), "docs", "a.txt", ObjectData::Staged(&staged), &metadata("\"new\""))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("docs/a.txt")).unwrap(), b"new");
+        let stored = load_metadata(dir.path(), "docs", "a.txt").await.unwrap().unwrap();
+        assert_eq!(stored.etag, "\"new\"");
+        assert!(staging_is_empty(dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_commit_object_from_scratch() {
+        let dir = setup().await;
+        let scratch = tempfile::TempDir::new().unwrap();
+        crate::fily::path_security::register_staging_root(dir.path(), Some(scratch.path().to_path_buf()));
+        let staged = crate::fily::path_security::construct_scratch_path(dir.path(), "docs").unwrap();
+        assert!(staged.starts_with(scratch.path()));
+        write_synced(&staged, b"new").await.unwrap();
+
+        commit_object(dir.path(), "docs", "a.txt", ObjectData::Staged(&staged), &metadata("\"new\""))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("docs/a.txt")).unwrap(), b"new");
+        assert!(!staged.exists());
+        assert!(staging_is_empty(dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_commit_object_mirrored() {
+        use crate::fily::mirror::{self, MirrorConfig};
+
+        let dir = setup().await;
+        let secondary = tempfile::TempDir::new().unwrap();
+        let mirror_root = secondary.path().join("mirror");
+        let config = MirrorConfig {
+            root: Some(mirror_root.to_string_lossy().into_owned()),
+            buckets: vec![],
+        };
+        mirror::open(dir.path(), &config).await.unwrap();
+
+        let staged = construct_staging_path(dir.path(), "docs").unwrap();
+        write_synced(&staged, b"new").await.unwrap();
+        commit_object(dir.path(), "docs", "2024/a.txt", ObjectData::Staged(&staged), &metadata("\"new\""))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(mirror_root.join("docs/2024/a.txt")).unwrap(), b"new");
+        let mirrored = load_metadata(&mirror_root, "docs", "2024/a.txt").await.unwrap().unwrap();
+        assert_eq!(mirrored.etag, "\"new\"");
+
+        // Renames move the key in the mirror too
+        commit_object(dir.path(), "docs", "b.txt", ObjectData::Renamed("2024/a.txt"), &metadata("\"new\""))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(mirror_root.join("docs/b.txt")).unwrap(), b"new");
+        assert!(!mirror_root.join("docs/2024/a.txt").exists());
+        assert!(load_metadata(&mirror_root, "docs", "2024/a.txt").await.unwrap().is_none());
+
+        // A write the mirror cannot take leaves the primary as it was
+        std::fs::remove_dir_all(mirror_root.join("docs")).unwrap();
+        std::fs::write(mirror_root.join("docs"), b"not a directory").unwrap();
+        let staged = construct_staging_path(dir.path(), "docs").unwrap();
+        write_synced(&staged, b"newer").await.unwrap();
+        assert!(commit_object(dir.path(), "docs", "a.txt", ObjectData::Staged(&staged), &metadata("\"newer\""))
+            .await
+            .is_err());
+        assert_eq!(std::fs::read(dir.path().join("docs/a.txt")).unwrap(), b"old");
+        assert!(staging_is_empty(dir.path()));
+
+        mirror::open(dir.path(), &MirrorConfig::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_move_file_replaces_destination() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("a"), b"new").unwrap();
+        std::fs::write(dir.path().join("b"), b"old").unwrap();
+
+        move_file(&dir.path().join("a"), &dir.path().join("b")).await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b"new");
+        assert!(!dir.path().join("a").exists());
+        assert!(is_cross_device(&std::io::Error::from_raw_os_error(EXDEV)));
+    }
+
+    #[tokio::test]
+    async fn test_recover_rolls_forward_once_data_moved() {
+        let dir = setup().await;
+        interrupted(dir.path(), true).await;
+
+        let report = recover(dir.path()).await.unwrap();
+
+        assert_eq!(report.rolled_forward, 1);
+        assert_eq!(std::fs::read(dir.path().join("docs/a.txt")).unwrap(), b"new");
+        let stored = load_metadata(dir.path(), "docs", "a.txt").await.unwrap().unwrap();
+        assert_eq!(stored.etag, "\"new\"");
+        assert!(staging_is_empty(dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_recover_rolls_back_before_data_moved() {
+        let dir = setup().await;
+        interrupted(dir.path(), false).await;
+
+        let report = recover(dir.path()).await.unwrap();
+
+        assert_eq!(report.rolled_back, 1);
+        assert_eq!(std::fs::read(dir.path().join("docs/a.txt")).unwrap(), b"old");
+        let stored = load_metadata(dir.path(), "docs", "a.txt").await.unwrap().unwrap();
+        assert_eq!(stored.etag, "\"old\"");
+        assert!(staging_is_empty(dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_recover_keeps_recent_orphans() {
+        let dir = setup().await;
+        let staged = construct_staging_path(dir.path(), "docs").unwrap();
+        write_synced(&staged, b"upload in progress").await.unwrap();
+
+        let report = recover(dir.path()).await.unwrap();
+
+        assert_eq!(report, RecoveryReport::default());
+        assert!(staged.exists());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeSet;
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::extract::{Path, Request};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::auth_middleware::Principal;
+use super::cpu_pool::CpuPool;
+use super::etag::{generate_etag, generate_multipart_etag};
+use super::get_object::decrypt_object;
+use super::metadata::load_metadata;
+use super::path_security::construct_safe_path;
+use super::policy_condition::RequestContext;
+use super::put_object;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::tenancy::{authorize_bucket_access, BucketAccess};
+use super::Config;
+
+/// Most sources one compose request may concatenate, the number of parts GET ?partNumber= serves
+const MAX_COMPOSE_SOURCES: usize = 10000;
+
+/// Largest accepted compose request document
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComposeRequest {
+    sources: Vec<ComposeSource>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComposeSource {
+    /// Bucket of the source, the destination bucket when unset
+    #[serde(default)]
+    bucket: Option<String>,
+    key: String,
+    /// Bytes of the source to use, `bytes=first-last` or `bytes=first-`; the whole object when unset
+    #[serde(default)]
+    range: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeResult {
+    bucket: String,
+    key: String,
+    etag: String,
+    size: u64,
+    parts: usize,
+}
+
+fn invalid_argument(message: String) -> S3AppError {
+    S3AppError::with_message(S3ErrorCode::InvalidArgument, message)
+}
+
+/// Start and end (exclusive) of `range` within an object of `len` bytes
+fn parse_range(range: &str, len: u64) -> Result<(u64, u64), S3AppError> {
+    let invalid = || invalid_argument(format!("Invalid source range {}, expected bytes=first-last", range));
+    let (first, last) = range
+        .strip_prefix("bytes=")
+        .and_then(|r| r.split_once('-'))
+        .ok_or_else(invalid)?;
+    let first = first.parse::<u64>().map_err(|_| invalid())?;
+    let end = match last {
+        "" => len,
+        last => last.parse::<u64>().map_err(|_| invalid())?.saturating_add(1).min(len),
+    };
+    if first >= end {
+        return Err(invalid_argument(format!(
+            "Source range {} is not satisfiable for an object of {} bytes",
+            range, len
+        )));
+    }
+    Ok((first, end))
+}
+
+/// `POST /_fily/compose/{bucket}/{key}`: stores the concatenation of existing objects, or ranges
+/// of them, as a new object without the client transferring any of the data. Each source becomes
+/// a part of the new object, so its ETag and `?partNumber=` reads work as for a multipart upload.
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    Extension(cpu_pool): Extension<Arc<CpuPool>>,
+    principal: Principal,
+    Path((bucket, file)): Path<(String, String)>,
+    req: Request,
+) -> Result<Response, S3AppError> {
+    let context = RequestContext::from_request(&req, &config.trusted_proxies);
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, MAX_REQUEST_SIZE)
+        .await
+        .map_err(|e| invalid_argument(format!("Invalid compose request: {}", e)))?;
+    let request: ComposeRequest = serde_json::from_slice(&body)
+        .map_err(|e| invalid_argument(format!("Invalid compose request: {}", e)))?;
+    if request.sources.is_empty() || request.sources.len() > MAX_COMPOSE_SOURCES {
+        return Err(invalid_argument(format!(
+            "A compose request needs between 1 and {} sources",
+            MAX_COMPOSE_SOURCES
+        )));
+    }
+
+    let storage_root = FsPath::new(&config.location);
+    construct_safe_path(storage_root, &bucket, &file)
+        .map_err(|e| invalid_argument(format!("Invalid bucket or object name: {}", e)))?;
+
+    // The middleware only checked the destination; reading each source bucket needs its own permission
+    let source_buckets: BTreeSet<&str> = request
+        .sources
+        .iter()
+        .map(|source| source.bucket.as_deref().unwrap_or(&bucket))
+        .collect();
+    for name in std::iter::once(bucket.as_str()).chain(source_buckets.iter().copied()) {
+        if !storage_root.join(name).is_dir() {
+            return Err(S3AppError::no_such_bucket(name));
+        }
+    }
+    for source in &request.sources {
+        let source_context = RequestContext {
+            object_key: Some(source.key.clone()),
+            ..context.clone()
+        };
+        let source_bucket = source.bucket.as_deref().unwrap_or(&bucket);
+        authorize_bucket_access(&config, &principal.access_key(), source_bucket, BucketAccess::Read, &source_context)
+            .await?;
+    }
+
+    let mut data = Vec::new();
+    let mut part_sizes = Vec::with_capacity(request.sources.len());
+    let mut part_etags = Vec::with_capacity(request.sources.len());
+    let mut content_type = None;
+    for source in &request.sources {
+        let source_bucket = source.bucket.as_deref().unwrap_or(&bucket);
+        let path = construct_safe_path(storage_root, source_bucket, &source.key)
+            .map_err(|e| invalid_argument(format!("Invalid compose source: {}", e)))?;
+        let stored = match tokio::fs::read(&path).await {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(S3AppError::no_such_key(source_bucket, &source.key));
+            }
+            Err(e) => {
+                error!("Failed to read compose source {}/{}: {}", source_bucket, source.key, e);
+                return Err(S3AppError::internal_error(&format!("Failed to read compose source: {}", e)));
+            }
+        };
+        let metadata = load_metadata(storage_root, source_bucket, &source.key).await.ok().flatten();
+        let encryption_id = metadata.as_ref().and_then(|m| m.encryption_id.clone());
+        content_type = content_type.or_else(|| metadata.map(|m| m.content_type));
+        let plaintext = decrypt_object(&config, &cpu_pool, source_bucket, &source.key, encryption_id, stored).await?;
+
+        let (start, end) = match &source.range {
+            Some(range) => parse_range(range, plaintext.len() as u64)?,
+            None => (0, plaintext.len() as u64),
+        };
+        let piece = &plaintext[start as usize..end as usize];
+        part_etags.push(generate_etag(piece));
+        part_sizes.push(piece.len() as u64);
+        data.extend_from_slice(piece);
+    }
+
+    // Stored like a PUT with the request's headers, taking the first source's type unless one is given
+    let mut headers: HeaderMap = parts.headers;
+    if let (false, Some(content_type)) = (headers.contains_key("content-type"), content_type) {
+        if let Ok(value) = content_type.parse() {
+            headers.insert("content-type", value);
+        }
+    }
+    let size = data.len() as u64;
+    let multipart_etag = generate_multipart_etag(&part_etags);
+    let stored = put_object::store(
+        config.clone(),
+        cpu_pool,
+        principal.clone(),
+        headers,
+        bucket.clone(),
+        file.clone(),
+        Bytes::from(data),
+        |metadata| {
+            if let Some(etag) = multipart_etag {
+                metadata.etag = etag;
+            }
+            metadata.part_sizes = part_sizes;
+        },
+    )
+    .await?;
+    let etag = stored
+        .headers()
+        .get("etag")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    metrics::counter!("fily_compose_object_total").increment(1);
+    info!(
+        "Composed {}/{} from {} source(s) ({} bytes) for {}",
+        bucket,
+        file,
+        part_etags.len(),
+        size,
+        principal
+    );
+    let result = ComposeResult {
+        bucket,
+        key: file,
+        etag,
+        size,
+        parts: part_etags.len(),
+    };
+    let body = serde_json::to_string(&result).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    Ok((StatusCode::OK, [("content-type", "application/json")], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100).unwrap(), (0, 10));
+        assert_eq!(parse_range("bytes=90-", 100).unwrap(), (90, 100));
+        assert_eq!(parse_range("bytes=90-200", 100).unwrap(), (90, 100));
+        assert!(parse_range("bytes=100-", 100).is_err());
+        assert!(parse_range("bytes=5-2", 100).is_err());
+        assert!(parse_range("bytes=-10", 100).is_err());
+        assert!(parse_range("0-9", 100).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use axum::http::Response;
+use hyper::body::Body;
+use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use hyper::StatusCode;
+use tower_http::compression::predicate::Predicate;
+use tower_http::compression::CompressionLayer;
+
+/// On-the-fly gzip/zstd compression of responses negotiated via Accept-Encoding
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub min_size: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: 1024,
+        }
+    }
+}
+
+/// Text-like content types that shrink well; media and archives are already compressed
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/x-ndjson"
+                | "application/ndjson"
+                | "application/jsonl"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-javascript"
+                | "application/csv"
+                | "application/x-yaml"
+                | "application/yaml"
+                | "application/toml"
+                | "image/svg+xml"
+        )
+}
+
+/// Compresses complete, compressible responses of at least `min_size` bytes
+#[derive(Debug, Clone, Copy)]
+pub struct CompressResponse {
+    min_size: u64,
+}
+
+impl Predicate for CompressResponse {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: Body,
+    {
+        // Partial content must keep byte offsets that refer to the stored object
+        if response.status() != StatusCode::OK || response.headers().contains_key(CONTENT_RANGE) {
+            return false;
+        }
+
+        let compressible = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(is_compressible);
+        if !compressible {
+            return false;
+        }
+
+        let size = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .or_else(|| response.body().size_hint().exact());
+        // An empty body would only grow, and clients expect `Content-Length: 0` for empty objects
+        size.is_some_and(|size| size > 0 && size >= self.min_size)
+    }
+}
+
+pub fn layer(config: &CompressionConfig) -> CompressionLayer<CompressResponse> {
+    CompressionLayer::new()
+        .no_br()
+        .no_deflate()
+        .compress_when(CompressResponse {
+            min_size: config.min_size,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content_type: &str, len: usize) -> Response<String> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body("x".repeat(len))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("application/x-ndjson; charset=utf-8"));
+        assert!(is_compressible("text/csv"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+
+    #[test]
+    fn test_predicate() {
+        let predicate = CompressResponse { min_size: 1024 };
+        assert!(predicate.should_compress(&response("application/json", 4096)));
+        assert!(!predicate.should_compress(&response("application/json", 100)));
+        assert!(!predicate.should_compress(&response("video/mp4", 4096)));
+
+        let mut partial = response("application/json", 4096);
+        *partial.status_mut() = StatusCode::PARTIAL_CONTENT;
+        assert!(!predicate.should_compress(&partial));
+
+        let unlimited = CompressResponse { min_size: 0 };
+        assert!(unlimited.should_compress(&response("application/json", 1)));
+        assert!(!unlimited.should_compress(&response("application/json", 0)));
+    }
+}
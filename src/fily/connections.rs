@@ -0,0 +1,56 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Lifetime statistics of one client connection, reported when the connection closes
+#[derive(Debug)]
+pub struct ConnectionStats {
+    opened: Instant,
+    requests: AtomicU64,
+}
+
+impl ConnectionStats {
+    fn open() -> Self {
+        metrics::counter!("fily_connections_total").increment(1);
+        metrics::gauge!("fily_connections_active").increment(1.0);
+        Self {
+            opened: Instant::now(),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ConnectionStats {
+    fn drop(&mut self) {
+        metrics::gauge!("fily_connections_active").decrement(1.0);
+        metrics::histogram!("fily_connection_requests").record(self.requests() as f64);
+        metrics::histogram!("fily_connection_duration_seconds")
+            .record(self.opened.elapsed().as_secs_f64());
+    }
+}
+
+/// Peer of a request, available as `ConnectInfo<ClientConnection>` to handlers and middleware
+#[derive(Debug, Clone)]
+pub struct ClientConnection {
+    pub addr: SocketAddr,
+    // Shared by every request on the connection; dropped with the connection's service
+    pub stats: Arc<ConnectionStats>,
+}
+
+impl ClientConnection {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            stats: Arc::new(ConnectionStats::open()),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+
+/// Limits for CPU-bound work such as hashing and encryption of object bodies
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuPoolConfig {
+    /// Jobs running at the same time, each on its own blocking thread
+    pub threads: usize,
+    /// Jobs allowed to wait for a thread before requests are rejected with SlowDown
+    pub max_queue: usize,
+}
+
+impl Default for CpuPoolConfig {
+    fn default() -> Self {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        Self {
+            threads,
+            max_queue: threads * 16,
+        }
+    }
+}
+
+/// Bounded pool keeping CPU-heavy work off the async runtime threads
+pub struct CpuPool {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queue: usize,
+}
+
+impl CpuPool {
+    pub fn new(config: &CpuPoolConfig) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.threads.max(1))),
+            queued: AtomicUsize::new(0),
+            max_queue: config.max_queue,
+        }
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Runs `job` on a blocking thread once one of the pool's slots is free
+    pub async fn run<F, T>(&self, job: F) -> Result<T, S3AppError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let depth = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+                if depth > self.max_queue {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    metrics::counter!("fily_cpu_pool_rejections_total").increment(1);
+                    warn!("CPU pool queue is full ({} waiting), rejecting request", self.max_queue);
+                    return Err(S3AppError::new(S3ErrorCode::SlowDown));
+                }
+                metrics::gauge!("fily_cpu_pool_queue_depth").set(depth as f64);
+
+                let permit = self.permits.clone().acquire_owned().await;
+                let depth = self.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+                metrics::gauge!("fily_cpu_pool_queue_depth").set(depth as f64);
+                permit.map_err(|_| S3AppError::internal_error("CPU pool is closed"))?
+            }
+        };
+
+        metrics::gauge!("fily_cpu_pool_active").increment(1.0);
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        })
+        .await;
+        metrics::gauge!("fily_cpu_pool_active").decrement(1.0);
+
+        result.map_err(|e| S3AppError::internal_error(&format!("CPU pool job failed: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_returns_job_result() {
+        let pool = CpuPool::new(&CpuPoolConfig {
+            threads: 2,
+            max_queue: 4,
+        });
+
+        let sum = pool.run(|| (1..=10u64).sum::<u64>()).await.unwrap();
+
+        assert_eq!(sum, 55);
+        assert_eq!(pool.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_when_queue_is_full() {
+        let pool = Arc::new(CpuPool::new(&CpuPoolConfig {
+            threads: 1,
+            max_queue: 1,
+        }));
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+
+        // Occupy the only thread, then fill the queue
+        let running = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || blocked.recv().unwrap()).await }
+        });
+        while pool.permits.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+        let queued = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| ()).await }
+        });
+        while pool.queue_depth() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let rejected = pool.run(|| ()).await;
+        assert!(rejected.is_err_and(|e| matches!(e.code, S3ErrorCode::SlowDown)));
+
+        release.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        queued.await.unwrap().unwrap();
+        assert_eq!(pool.queue_depth(), 0);
+    }
+}
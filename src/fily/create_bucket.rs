@@ -4,13 +4,16 @@ use axum::extract::Path;
 use axum::response::IntoResponse;
 use axum::Extension;
 use bytes::Bytes;
-use hyper::StatusCode;
+use hyper::{HeaderMap, StatusCode};
 use tracing::{debug, info, error};
 
-use super::s3_app_error::S3AppError;
+use super::auth_middleware::AuthenticatedAccessKey;
+use super::ownership_controls::{save_object_ownership, sets_acl, ObjectOwnership, OBJECT_OWNERSHIP_HEADER};
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::tenancy::{account_for, save_bucket_policy, BucketPolicy, Tenant};
 use super::Config;
 
-fn is_valid_bucket_name(bucket: &str) -> bool {
+pub(crate) fn is_valid_bucket_name(bucket: &str) -> bool {
     // S3 bucket naming rules (simplified)
     if bucket.len() < 3 || bucket.len() > 63 {
         return false;
@@ -43,7 +46,10 @@ fn is_valid_bucket_name(bucket: &str) -> bool {
 
 pub async fn handle(
     config: Extension<Arc<Config>>, 
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    tenant: Option<Extension<Tenant>>,
     Path(bucket): Path<String>, 
+    headers: HeaderMap,
     body: Bytes
 ) -> Result<impl IntoResponse, S3AppError> {
     info!("Creating bucket: {}", bucket);
@@ -54,6 +60,26 @@ pub async fn handle(
         return Err(S3AppError::invalid_bucket_name(&bucket));
     }
 
+    // Account keys may only create buckets in their own tenant namespace
+    let account = account_for(&config, &access_key.0);
+    let tenant = tenant.map(|Extension(Tenant(tenant))| tenant);
+    if let (Some(account), Some(tenant)) = (&account, &tenant) {
+        if account != tenant {
+            return Err(S3AppError::access_denied(&format!("/{}", bucket)));
+        }
+    }
+
+    let ownership = headers
+        .get(OBJECT_OWNERSHIP_HEADER)
+        .map(|v| v.to_str().unwrap_or_default().parse::<ObjectOwnership>())
+        .transpose()?;
+    if ownership == Some(ObjectOwnership::BucketOwnerEnforced) && sets_acl(&headers, false) {
+        return Err(S3AppError::with_resource(
+            S3ErrorCode::AccessControlListNotSupported,
+            format!("/{}", bucket),
+        ));
+    }
+
     let bucket_path = format!("{}/{}", config.location, bucket);
     let path = std::path::Path::new(&bucket_path);
     
@@ -66,6 +92,28 @@ pub async fn handle(
     // Create the bucket directory
     match tokio::fs::create_dir_all(&bucket_path).await {
         Ok(_) => {
+            // Buckets belong to the creating key's account, or to the tenant they were created in
+            if let Some(owner) = account.or(tenant) {
+                let policy = BucketPolicy::owned_by(Some(owner));
+                if let Err(e) = save_bucket_policy(std::path::Path::new(&config.location), &bucket, &policy).await {
+                    error!("Failed to record owner of bucket {}: {}", bucket, e);
+                    let _ = tokio::fs::remove_dir_all(&bucket_path).await;
+                    return Err(S3AppError::internal_error(&format!(
+                        "Failed to create bucket: {}", e
+                    )));
+                }
+            }
+
+            if let Some(ownership) = ownership {
+                if let Err(e) = save_object_ownership(std::path::Path::new(&config.location), &bucket, ownership).await {
+                    error!("Failed to record object ownership of bucket {}: {}", bucket, e);
+                    let _ = tokio::fs::remove_dir_all(&bucket_path).await;
+                    return Err(S3AppError::internal_error(&format!(
+                        "Failed to create bucket: {}", e
+                    )));
+                }
+            }
+
             info!("Successfully created bucket: {}", bucket);
             Ok(StatusCode::OK)
         }
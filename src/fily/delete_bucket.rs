@@ -1,15 +1,136 @@
 use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-use axum::extract::Path;
-use axum::response::IntoResponse;
+use axum::extract::{Path, Query};
+use axum::response::{IntoResponse, Response};
 use axum::Extension;
 use hyper::StatusCode;
-use tracing::{info, error};
+use serde::{Deserialize, Serialize};
+use tracing::{info, error, warn};
 
+use super::auth_middleware::Principal;
+use super::delete_prefix::delete_prefix;
+use super::maintenance::MaintenanceMode;
+use super::mirror;
+use super::path_security::{
+    metadata_dir_name, metadata_layout, sanitize_bucket_name, scratch_dir, staging_dir, MetadataLayout,
+};
 use super::s3_app_error::S3AppError;
 use super::Config;
 
-async fn is_bucket_empty(bucket_path: &std::path::Path) -> std::io::Result<bool> {
+/// Directory under a storage root that deleted buckets are moved to before being removed
+pub(crate) const TRASH_DIR: &str = ".fily-trash";
+
+/// Entries removed between progress reports while purging a deleted bucket
+const PURGE_BATCH: u64 = 10_000;
+
+/// Suffix of the file next to a trashed bucket recording how far its purge got
+const PROGRESS_SUFFIX: &str = ".progress";
+
+/// How far the purge of one trashed directory got, kept next to it so a purge interrupted by a
+/// restart reports its progress from where it stopped
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct PurgeProgress {
+    /// Bucket the directory held, unknown for directories trashed by migration rollbacks
+    bucket: Option<String>,
+    /// Files and directories removed so far
+    removed: u64,
+}
+
+fn progress_path(trash_path: &std::path::Path) -> PathBuf {
+    let mut path = trash_path.as_os_str().to_owned();
+    path.push(PROGRESS_SUFFIX);
+    PathBuf::from(path)
+}
+
+async fn load_progress(trash_path: &std::path::Path) -> PurgeProgress {
+    match tokio::fs::read(progress_path(trash_path)).await {
+        Ok(json) => serde_json::from_slice(&json).unwrap_or_default(),
+        Err(_) => PurgeProgress::default(),
+    }
+}
+
+async fn save_progress(trash_path: &std::path::Path, progress: &PurgeProgress) -> std::io::Result<()> {
+    tokio::fs::write(progress_path(trash_path), serde_json::to_vec(progress)?).await
+}
+
+/// Removes a trashed directory depth-first, one entry at a time rather than with a single
+/// `remove_dir_all`, reporting progress every `PURGE_BATCH` entries. Only the directories on the
+/// current path are held open, and whatever an interruption leaves is picked up at the next start.
+async fn purge(trash_path: &std::path::Path) -> std::io::Result<()> {
+    let mut progress = load_progress(trash_path).await;
+    let label = match &progress.bucket {
+        Some(bucket) => format!("bucket {}", bucket),
+        None => trash_path.display().to_string(),
+    };
+    let resumed = progress.removed;
+    if resumed > 0 {
+        info!("Resuming purge of deleted {} after {} entries", label, resumed);
+    }
+
+    let mut dirs = vec![trash_path.to_path_buf()];
+    let mut stack = match tokio::fs::read_dir(trash_path).await {
+        Ok(entries) => vec![entries],
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    while let Some(entries) = stack.last_mut() {
+        match entries.next_entry().await? {
+            Some(entry) if entry.file_type().await?.is_dir() => {
+                stack.push(tokio::fs::read_dir(entry.path()).await?);
+                dirs.push(entry.path());
+                continue;
+            }
+            Some(entry) => match tokio::fs::remove_file(entry.path()).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            },
+            None => {
+                stack.pop();
+                if let Some(dir) = dirs.pop() {
+                    tokio::fs::remove_dir(dir).await?;
+                }
+            }
+        }
+
+        progress.removed += 1;
+        if progress.removed % PURGE_BATCH == 0 {
+            save_progress(trash_path, &progress).await?;
+            metrics::counter!("fily_purged_entries_total").increment(PURGE_BATCH);
+            info!("Purging deleted {}: {} entries removed", label, progress.removed);
+        }
+    }
+
+    metrics::counter!("fily_purged_entries_total").increment(progress.removed % PURGE_BATCH);
+    match tokio::fs::remove_file(progress_path(trash_path)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if progress.removed > resumed {
+        info!("Purged deleted {} ({} entries)", label, progress.removed);
+    }
+    Ok(())
+}
+
+/// Moves `path` into the trash directory `trash_dir`, recording the bucket it held for the purge
+async fn move_to_trash(path: &std::path::Path, trash_dir: &std::path::Path, bucket: &str) -> std::io::Result<PathBuf> {
+    let trash_path = trash_dir.join(uuid::Uuid::new_v4().simple().to_string());
+    tokio::fs::create_dir_all(trash_dir).await?;
+    let progress = PurgeProgress {
+        bucket: Some(bucket.to_string()),
+        removed: 0,
+    };
+    save_progress(&trash_path, &progress).await?;
+    if let Err(e) = tokio::fs::rename(path, &trash_path).await {
+        let _ = tokio::fs::remove_file(progress_path(&trash_path)).await;
+        return Err(e);
+    }
+    Ok(trash_path)
+}
+
+async fn is_bucket_empty(storage_root: &std::path::Path, bucket_path: &std::path::Path) -> std::io::Result<bool> {
+    let metadata_dir = metadata_dir_name(storage_root);
     let mut entries = tokio::fs::read_dir(bucket_path).await?;
     
     while let Some(entry) = entries.next_entry().await? {
@@ -17,7 +138,7 @@ async fn is_bucket_empty(bucket_path: &std::path::Path) -> std::io::Result<bool>
         let file_name_str = file_name.to_string_lossy();
         
         // Skip metadata directory
-        if file_name_str != ".fily-metadata" {
+        if metadata_dir.as_deref() != Some(&*file_name_str) {
             return Ok(false);
         }
     }
@@ -25,10 +146,64 @@ async fn is_bucket_empty(bucket_path: &std::path::Path) -> std::io::Result<bool>
     Ok(true)
 }
 
+/// Removes buckets left in the trash by deletes that were interrupted, e.g. by a restart
+pub(crate) async fn purge_trash(storage_root: &std::path::Path) -> std::io::Result<()> {
+    let mut trash_dirs = vec![storage_root.join(TRASH_DIR)];
+    if let MetadataLayout::External(metadata_root) = metadata_layout(storage_root) {
+        trash_dirs.push(metadata_root.join(TRASH_DIR));
+    }
+    for trash_dir in trash_dirs {
+        let mut entries = match tokio::fs::read_dir(&trash_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                purge(&path).await?;
+            } else if !path.with_extension("").is_dir() {
+                // Progress of a purge that finished, or of a bucket that never made it here
+                match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        match tokio::fs::remove_dir(&trash_dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Moves the state a bucket keeps outside its directory, when metadata is kept outside the
+/// buckets, to the trash of the metadata root
+async fn trash_external_metadata(storage_root: &std::path::Path, bucket: &str) -> std::io::Result<Option<std::path::PathBuf>> {
+    let MetadataLayout::External(metadata_root) = metadata_layout(storage_root) else {
+        return Ok(None);
+    };
+    let _ = tokio::fs::remove_dir_all(staging_dir(storage_root, bucket)).await;
+    match move_to_trash(&metadata_root.join(bucket), &metadata_root.join(TRASH_DIR), bucket).await {
+        Ok(trash_path) => Ok(Some(trash_path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 pub async fn handle(
     config: Extension<Arc<Config>>, 
-    Path(bucket): Path<String>
-) -> Result<impl IntoResponse, S3AppError> {
+    Extension(maintenance): Extension<Arc<MaintenanceMode>>,
+    principal: Principal,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, S3AppError> {
+    // `?prefix=` turns the bucket delete into a fily "folder" delete of every key under it
+    if let Some(prefix) = params.get("prefix") {
+        return delete_prefix(&config, &principal, &bucket, prefix).await;
+    }
+
     info!("Deleting bucket: {}", bucket);
 
     let bucket_path = format!("{}/{}", config.location, bucket);
@@ -40,7 +215,7 @@ pub async fn handle(
     }
     
     // Check if bucket is empty
-    match is_bucket_empty(path).await {
+    match is_bucket_empty(std::path::Path::new(&config.location), path).await {
         Ok(false) => {
             info!("Bucket {} is not empty, cannot delete", bucket);
             return Err(S3AppError::bucket_not_empty(&bucket));
@@ -56,17 +231,107 @@ pub async fn handle(
         }
     }
 
-    // Delete the bucket directory
-    match tokio::fs::remove_dir_all(&bucket_path).await {
-        Ok(_) => {
-            info!("Successfully deleted bucket: {}", bucket);
-            Ok(StatusCode::NO_CONTENT)
+    // Metadata kept outside the bucket goes first: were the bucket gone first, an interruption
+    // would leave it to be picked up by a new bucket of the same name
+    let external_trash = match trash_external_metadata(std::path::Path::new(&config.location), &bucket).await {
+        Ok(trash_path) => trash_path,
+        Err(e) => {
+            error!("Failed to delete metadata of bucket {}: {}", bucket, e);
+            return Err(S3AppError::internal_error(&format!(
+                "Failed to delete bucket: {}", e
+            )));
         }
+    };
+
+    // Move the bucket out of the way first so it disappears in one step, together with its
+    // policy, object metadata and staged uploads, instead of being visible half-deleted
+    let trash_dir = std::path::Path::new(&config.location).join(TRASH_DIR);
+    let trash_path = match move_to_trash(path, &trash_dir, &bucket).await {
+        Ok(trash_path) => trash_path,
         Err(e) => {
             error!("Failed to delete bucket {}: {}", bucket, e);
-            Err(S3AppError::internal_error(&format!(
+            return Err(S3AppError::internal_error(&format!(
                 "Failed to delete bucket: {}", e
-            )))
+            )));
         }
+    };
+
+    // A bucket created later under the same name starts without the old one's state
+    maintenance.forget_bucket(std::path::Path::new(&config.location), &bucket);
+    let scratch = sanitize_bucket_name(&bucket)
+        .ok()
+        .and_then(|safe_bucket| scratch_dir(std::path::Path::new(&config.location), &safe_bucket));
+    if let Some(scratch) = scratch {
+        // Staged writes and resumable uploads of the bucket
+        if let Err(e) = tokio::fs::remove_dir_all(&scratch).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove staged files of bucket {} from {}: {}", bucket, scratch.display(), e);
+            }
+        }
+    }
+
+    // The bucket was empty, so its mirror holds at most leftover staging and metadata directories
+    if let Some(mirror_root) = mirror::mirror_root(std::path::Path::new(&config.location), &bucket) {
+        if let Err(e) = tokio::fs::remove_dir_all(mirror_root.join(&bucket)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove bucket {} from mirror {}: {}", bucket, mirror_root.display(), e);
+            }
+        }
+    }
+
+    for trash_path in std::iter::once(trash_path).chain(external_trash) {
+        if let Err(e) = purge(&trash_path).await {
+            // The bucket is already gone; whatever is left is purged on the next start
+            warn!("Failed to remove deleted bucket {} from {}: {}", bucket, trash_path.display(), e);
+        }
+    }
+
+    info!("Successfully deleted bucket: {} for {}", bucket, principal);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_purge_removes_trash_and_progress() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let bucket = dir.path().join("photos");
+        tokio::fs::create_dir_all(bucket.join("2024/june")).await.unwrap();
+        for i in 0..3 {
+            tokio::fs::write(bucket.join(format!("2024/june/{}.jpg", i)), b"x").await.unwrap();
+        }
+        tokio::fs::write(bucket.join("a.jpg"), b"x").await.unwrap();
+
+        let trash_dir = dir.path().join(TRASH_DIR);
+        let trash_path = move_to_trash(&bucket, &trash_dir, "photos").await.unwrap();
+        assert!(!bucket.exists());
+        assert_eq!(load_progress(&trash_path).await.bucket.as_deref(), Some("photos"));
+
+        purge(&trash_path).await.unwrap();
+        assert!(!trash_path.exists());
+        assert!(!progress_path(&trash_path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_purge_trash_resumes_interrupted_purges() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let trash_dir = dir.path().join(TRASH_DIR);
+        // A purge stopped part-way, one that finished but left its progress, and a directory
+        // trashed without any, as migration rollbacks do
+        let interrupted = trash_dir.join("1f2e3d");
+        tokio::fs::create_dir_all(interrupted.join("deep/er")).await.unwrap();
+        tokio::fs::write(interrupted.join("deep/er/b.jpg"), b"x").await.unwrap();
+        let progress = PurgeProgress {
+            bucket: Some("photos".to_string()),
+            removed: 20_000,
+        };
+        save_progress(&interrupted, &progress).await.unwrap();
+        save_progress(&trash_dir.join("4c5b6a"), &progress).await.unwrap();
+        tokio::fs::create_dir_all(trash_dir.join("7d8e9f/c")).await.unwrap();
+
+        purge_trash(dir.path()).await.unwrap();
+        assert!(!trash_dir.exists());
     }
 }
@@ -5,13 +5,18 @@ use axum::response::IntoResponse;
 use axum::Extension;
 use hyper::StatusCode;
 
+use super::auth_middleware::Principal;
+use super::key_locks;
+use super::logging::AUDIT_LOG_TARGET;
 use super::metadata::delete_metadata;
+use super::mirror;
 use super::path_security::construct_safe_path;
 use super::s3_app_error::S3AppError;
 use super::Config;
 
 pub async fn handle(
     config: Extension<Arc<Config>>,
+    principal: Principal,
     Path((bucket, file)): Path<(String, String)>,
 ) -> Result<impl IntoResponse, S3AppError> {
     // Check if bucket exists first
@@ -32,6 +37,12 @@ pub async fn handle(
         }
     };
     
+    let _lock = key_locks::lock(storage_root, &bucket, &file).await;
+    // The mirror goes first, so a delete it cannot take leaves the object in place
+    if let Err(e) = mirror::remove(storage_root, &bucket, &file).await {
+        tracing::error!("Failed to delete {}/{} from its mirror: {}", bucket, file, e);
+        return Err(S3AppError::internal_error(&format!("Mirror delete failed: {}", e)));
+    }
     match tokio::fs::remove_file(path).await {
         Ok(_) => {
             // Also clean up metadata
@@ -40,6 +51,13 @@ pub async fn handle(
                 tracing::warn!("Failed to delete metadata for {}/{}: {}", bucket, file, e);
                 // Continue despite metadata cleanup failure
             }
+            tracing::info!(
+                target: AUDIT_LOG_TARGET,
+                principal = %principal,
+                bucket = %bucket,
+                key = %file,
+                "object deleted"
+            );
             Ok(StatusCode::NO_CONTENT)
         },
         Err(e) => {
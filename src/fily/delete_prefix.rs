@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use axum::body::Body;
+use axum::response::{IntoResponse, Response};
+use hyper::StatusCode;
+use quick_xml::se::to_string;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use super::auth_middleware::Principal;
+use super::key_locks;
+use super::logging::AUDIT_LOG_TARGET;
+use super::metadata::delete_metadata;
+use super::mirror;
+use super::path_security::construct_safe_path;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::search_bucket::ObjectWalker;
+use super::Config;
+
+/// Objects deleted between progress reports of a prefix delete
+const PROGRESS_INTERVAL: usize = 10_000;
+
+#[derive(Serialize, Debug)]
+struct DeleteError {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "Code")]
+    code: String,
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+/// Summary of a `DELETE /{bucket}?prefix=...` call; deleted keys are counted, not listed
+#[derive(Serialize, Debug)]
+struct DeletePrefixResult {
+    #[serde(rename = "Prefix")]
+    prefix: String,
+    #[serde(rename = "DeletedCount")]
+    deleted_count: usize,
+    #[serde(rename = "ErrorCount")]
+    error_count: usize,
+    #[serde(rename = "Error", default)]
+    errors: Vec<DeleteError>,
+}
+
+impl IntoResponse for DeletePrefixResult {
+    fn into_response(self) -> Response {
+        match to_string(&self) {
+            Ok(xml) => {
+                let mut resp = Response::new(Body::from(xml));
+                *resp.status_mut() = StatusCode::OK;
+                resp.headers_mut()
+                    .insert("content-type", "application/xml".parse().unwrap());
+                resp
+            }
+            Err(e) => S3AppError::internal_error(&e.to_string()).into_response(),
+        }
+    }
+}
+
+/// Removes directories left empty by a prefix delete, walking up towards the bucket root
+async fn remove_empty_parents(bucket_path: &Path, file_path: &Path) {
+    let mut dir = file_path.parent();
+    while let Some(current) = dir {
+        if current == bucket_path || !current.starts_with(bucket_path) {
+            break;
+        }
+        // remove_dir fails on non-empty directories, which ends the walk
+        if tokio::fs::remove_dir(current).await.is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// Deletes every object whose key starts with `prefix` in a single request
+///
+/// Keys are walked in order as they are deleted, one directory at a time, so memory stays
+/// bounded for any number of objects. Deleted keys are gone, so repeating a request that was
+/// interrupted carries on where it stopped.
+pub async fn delete_prefix(
+    config: &Config,
+    principal: &Principal,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Response, S3AppError> {
+    // An empty prefix would silently empty the whole bucket
+    if prefix.is_empty() {
+        return Err(S3AppError::with_message(
+            S3ErrorCode::InvalidArgument,
+            "Prefix delete requires a non-empty prefix".to_string(),
+        ));
+    }
+
+    let storage_root = Path::new(&config.location);
+    let bucket_path = storage_root.join(bucket);
+    if !bucket_path.is_dir() {
+        return Err(S3AppError::no_such_bucket(bucket));
+    }
+
+    info!("Deleting all objects under {}/{}", bucket, prefix);
+
+    let mut walker = ObjectWalker::new(&bucket_path, prefix, None);
+    let mut deleted_count = 0;
+    let mut errors = vec![];
+
+    loop {
+        let object = match walker.next().await {
+            Ok(Some(object)) => object,
+            Ok(None) => break,
+            Err(e) => {
+                error!("Failed to list bucket {} after {} deletes: {}", bucket, deleted_count, e);
+                return Err(S3AppError::internal_error(&format!("Failed to list bucket: {}", e)));
+            }
+        };
+        let path = match construct_safe_path(storage_root, bucket, &object.key) {
+            Ok(path) => path,
+            Err(e) => {
+                errors.push(DeleteError {
+                    key: object.key,
+                    code: S3ErrorCode::InvalidArgument.as_str().to_string(),
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let _lock = key_locks::lock(storage_root, bucket, &object.key).await;
+        if let Err(e) = mirror::remove(storage_root, bucket, &object.key).await {
+            error!("Failed to delete {}/{} from its mirror: {}", bucket, object.key, e);
+            errors.push(DeleteError {
+                key: object.key,
+                code: S3ErrorCode::InternalError.as_str().to_string(),
+                message: format!("Mirror delete failed: {}", e),
+            });
+            continue;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                if let Err(e) = delete_metadata(storage_root, bucket, &object.key).await {
+                    warn!("Failed to delete metadata for {}/{}: {}", bucket, object.key, e);
+                }
+                remove_empty_parents(&bucket_path, &path).await;
+                info!(
+                    target: AUDIT_LOG_TARGET,
+                    principal = %principal,
+                    bucket,
+                    key = %object.key,
+                    prefix,
+                    "object deleted"
+                );
+                deleted_count += 1;
+                if deleted_count % PROGRESS_INTERVAL == 0 {
+                    info!("Prefix delete of {}/{}: {} objects removed so far", bucket, prefix, deleted_count);
+                }
+            }
+            // Removed concurrently by another request, which is the outcome we want
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => deleted_count += 1,
+            Err(e) => {
+                error!("Failed to delete {}/{}: {}", bucket, object.key, e);
+                let code = match e.kind() {
+                    std::io::ErrorKind::PermissionDenied => S3ErrorCode::AccessDenied,
+                    _ => S3ErrorCode::InternalError,
+                };
+                errors.push(DeleteError {
+                    key: object.key,
+                    code: code.as_str().to_string(),
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+
+    info!(
+        "Prefix delete of {}/{} removed {} objects with {} errors",
+        bucket,
+        prefix,
+        deleted_count,
+        errors.len()
+    );
+
+    Ok(DeletePrefixResult {
+        prefix: prefix.to_string(),
+        deleted_count,
+        error_count: errors.len(),
+        errors,
+    }
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_remove_empty_parents_stops_at_bucket_and_non_empty_dirs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let bucket = dir.path().join("bucket");
+        tokio::fs::create_dir_all(bucket.join("photos/2023/june")).await.unwrap();
+        tokio::fs::write(bucket.join("photos/keep.jpg"), b"x").await.unwrap();
+
+        remove_empty_parents(&bucket, &bucket.join("photos/2023/june/a.jpg")).await;
+
+        assert!(!bucket.join("photos/2023").exists());
+        assert!(bucket.join("photos").exists());
+        assert!(bucket.exists());
+    }
+}
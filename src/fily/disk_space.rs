@@ -0,0 +1,274 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::Method;
+use tracing::{error, info, warn};
+
+use super::maintenance::service_unavailable;
+use super::notifications::{Event, EventKind, Notifier, Severity};
+use super::request_path::RequestTarget;
+use super::request_info::RequestInfo;
+
+/// Free space below which the storage volume counts as low, either absolute or relative
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watermark {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl Watermark {
+    fn min_free_bytes(&self, total: u64) -> u64 {
+        match *self {
+            Watermark::Bytes(bytes) => bytes,
+            Watermark::Percent(percent) => (total as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+impl FromStr for Watermark {
+    type Err = String;
+
+    /// Accepts a byte count (`10737418240`) or a percentage of the volume (`5%`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent = percent
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("{} is not a percentage", s))?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(format!("{} must be between 0% and 100%", s));
+            }
+            Ok(Watermark::Percent(percent))
+        } else {
+            s.parse::<u64>()
+                .map(Watermark::Bytes)
+                .map_err(|_| format!("{} is not a number of bytes or a percentage", s))
+        }
+    }
+}
+
+/// Low-disk thresholds for the storage volume; unset watermarks are not enforced
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskWatermarkConfig {
+    pub soft: Option<Watermark>,
+    pub hard: Option<Watermark>,
+    pub check_interval: Duration,
+}
+
+impl Default for DiskWatermarkConfig {
+    fn default() -> Self {
+        Self {
+            soft: None,
+            hard: None,
+            check_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl DiskWatermarkConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.soft.is_some() || self.hard.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum DiskLevel {
+    Ok = 0,
+    Soft = 1,
+    Hard = 2,
+}
+
+impl DiskLevel {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            2 => DiskLevel::Hard,
+            1 => DiskLevel::Soft,
+            _ => DiskLevel::Ok,
+        }
+    }
+}
+
+/// Periodically samples free space on the storage volume and tracks the watermark crossed
+pub struct DiskSpaceMonitor {
+    config: DiskWatermarkConfig,
+    storage_root: PathBuf,
+    level: AtomicU8,
+    notifier: Option<Arc<Notifier>>,
+}
+
+impl DiskSpaceMonitor {
+    pub fn new(config: DiskWatermarkConfig, storage_root: impl Into<PathBuf>) -> Self {
+        Self {
+            config,
+            storage_root: storage_root.into(),
+            level: AtomicU8::new(DiskLevel::Ok as u8),
+            notifier: None,
+        }
+    }
+
+    /// Reports every watermark crossing to the operators' webhook
+    pub fn with_notifier(mut self, notifier: Arc<Notifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn level(&self) -> DiskLevel {
+        DiskLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    fn level_for(&self, available: u64, total: u64) -> DiskLevel {
+        let below = |watermark: Option<Watermark>| {
+            watermark.is_some_and(|w| available < w.min_free_bytes(total))
+        };
+        if below(self.config.hard) {
+            DiskLevel::Hard
+        } else if below(self.config.soft) {
+            DiskLevel::Soft
+        } else {
+            DiskLevel::Ok
+        }
+    }
+
+    /// Records a free space sample, logging whenever a watermark is crossed
+    fn record(&self, available: u64, total: u64) {
+        let level = self.level_for(available, total);
+        let previous = DiskLevel::from_u8(self.level.swap(level as u8, Ordering::Relaxed));
+
+        metrics::gauge!("fily_disk_available_bytes").set(available as f64);
+        metrics::gauge!("fily_disk_total_bytes").set(total as f64);
+        metrics::gauge!("fily_disk_watermark_level").set(level as u8 as f64);
+
+        if level == previous {
+            return;
+        }
+        match level {
+            DiskLevel::Hard => error!(
+                "Free disk space {} bytes is below the hard watermark, rejecting uploads",
+                available
+            ),
+            DiskLevel::Soft => warn!(
+                "Free disk space {} bytes is below the soft watermark",
+                available
+            ),
+            DiskLevel::Ok => info!("Free disk space {} bytes is back above the watermarks", available),
+        }
+        if let Some(notifier) = &self.notifier {
+            let (name, severity, message) = match level {
+                DiskLevel::Hard => ("hard", Severity::Critical, "is below the hard watermark, uploads are rejected"),
+                DiskLevel::Soft => ("soft", Severity::Warning, "is below the soft watermark"),
+                DiskLevel::Ok => ("ok", Severity::Resolved, "is back above the watermarks"),
+            };
+            notifier.notify(Event::new(
+                EventKind::DiskWatermark,
+                severity,
+                format!("Free disk space of {} ({} bytes) {}", self.storage_root.display(), available, message),
+                serde_json::json!({
+                    "level": name,
+                    "available_bytes": available,
+                    "total_bytes": total,
+                }),
+            ));
+        }
+    }
+
+    pub async fn check(&self) -> std::io::Result<()> {
+        let root = self.storage_root.clone();
+        let stats = tokio::task::spawn_blocking(move || fs4::statvfs(root))
+            .await
+            .map_err(std::io::Error::other)??;
+        self.record(stats.available_space(), stats.total_space());
+        Ok(())
+    }
+
+    /// Re-checks free space every `check_interval` for the lifetime of the server
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.check_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.check().await {
+                warn!("Failed to check free disk space of {}: {}", self.storage_root.display(), e);
+            }
+        }
+    }
+}
+
+/// Middleware rejecting new uploads while free space is below the hard watermark
+pub async fn reject_uploads(req: Request, next: Next) -> Response {
+    if req.method() != Method::PUT && req.method() != Method::POST && req.method() != Method::PATCH {
+        return next.run(req).await;
+    }
+    let Some(monitor) = req.extensions().get::<Arc<DiskSpaceMonitor>>().cloned() else {
+        return next.run(req).await;
+    };
+    // Extension endpoints such as the admin API stay usable while the disk is full, except for
+    // those writing object data like a PUT
+    let is_extension = RequestInfo::of(&req).target == RequestTarget::Extension;
+    if is_extension || monitor.level() != DiskLevel::Hard {
+        return next.run(req).await;
+    }
+
+    // Free space is sampled once per check interval, so that is the earliest a retry can succeed
+    metrics::counter!("fily_disk_watermark_rejections_total").increment(1);
+    service_unavailable(
+        "Insufficient free disk space, please retry later.",
+        req.uri().path(),
+        monitor.config.check_interval.as_secs().max(1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_watermark() {
+        assert_eq!("1024".parse::<Watermark>(), Ok(Watermark::Bytes(1024)));
+        assert_eq!(" 5% ".parse::<Watermark>(), Ok(Watermark::Percent(5.0)));
+        assert!("150%".parse::<Watermark>().is_err());
+        assert!("10GB".parse::<Watermark>().is_err());
+    }
+
+    #[test]
+    fn test_watermark_levels() {
+        let monitor = DiskSpaceMonitor::new(
+            DiskWatermarkConfig {
+                soft: Some(Watermark::Percent(10.0)),
+                hard: Some(Watermark::Bytes(60)),
+                ..Default::default()
+            },
+            "/srv/fily",
+        );
+
+        monitor.record(500, 1000);
+        assert_eq!(monitor.level(), DiskLevel::Ok);
+        monitor.record(99, 1000);
+        assert_eq!(monitor.level(), DiskLevel::Soft);
+        monitor.record(50, 1000);
+        assert_eq!(monitor.level(), DiskLevel::Hard);
+        monitor.record(800, 1000);
+        assert_eq!(monitor.level(), DiskLevel::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_check_reads_volume_stats() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let monitor = DiskSpaceMonitor::new(
+            DiskWatermarkConfig {
+                hard: Some(Watermark::Percent(100.0)),
+                ..Default::default()
+            },
+            dir.path(),
+        );
+
+        monitor.check().await.unwrap();
+        assert_eq!(monitor.level(), DiskLevel::Hard);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cipher_cache;
 pub mod key_manager;
 pub mod traits;
 pub mod xchacha20poly1305;
@@ -6,6 +7,104 @@ pub use key_manager::KeyManager;
 pub use traits::{Encryptor, EncryptionError};
 pub use xchacha20poly1305::XChaCha20Poly1305Encryptor;
 
+/// Marks ciphertext keyed by a per-object encryption ID, which follows the marker
+const OBJECT_ID_MAGIC: &[u8; 4] = b"FEI1";
+const OBJECT_ID_LEN: usize = 32;
+
+/// Random ID an object's key is derived from, so its ciphertext survives renames and copies
+pub fn new_encryption_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+// Contains no '/', so it can never equal the `bucket/key` context of legacy objects
+fn object_id_context(encryption_id: &str) -> String {
+    format!("fily-object-id:{}", encryption_id)
+}
+
+/// Runs one cipher operation, recording its duration and the bytes it processed
+fn measured<F>(encryptor: &dyn Encryptor, operation: &'static str, len: usize, f: F) -> Result<Vec<u8>, EncryptionError>
+where
+    F: FnOnce() -> Result<Vec<u8>, EncryptionError>,
+{
+    let start = std::time::Instant::now();
+    let result = f();
+    let cipher = encryptor.cipher();
+    metrics::histogram!("fily_encryption_duration_seconds", "operation" => operation, "cipher" => cipher)
+        .record(start.elapsed().as_secs_f64());
+    metrics::counter!("fily_encryption_bytes_total", "operation" => operation, "cipher" => cipher)
+        .increment(len as u64);
+    result
+}
+
+fn encrypt(encryptor: &dyn Encryptor, plaintext: &[u8], context: &str) -> Result<Vec<u8>, EncryptionError> {
+    measured(encryptor, "encrypt", plaintext.len(), || encryptor.encrypt(plaintext, context.as_bytes()))
+}
+
+fn decrypt(encryptor: &dyn Encryptor, ciphertext: &[u8], context: &str) -> Result<Vec<u8>, EncryptionError> {
+    measured(encryptor, "decrypt", ciphertext.len(), || encryptor.decrypt(ciphertext, context.as_bytes()))
+}
+
+/// Encrypts an object under `encryption_id`, recording the ID in front of the ciphertext
+pub fn seal_object(
+    encryptor: &dyn Encryptor,
+    plaintext: &[u8],
+    encryption_id: &str,
+) -> Result<Vec<u8>, EncryptionError> {
+    if encryption_id.len() != OBJECT_ID_LEN || !encryption_id.is_ascii() {
+        return Err(EncryptionError::EncryptionFailed(format!(
+            "Invalid object encryption ID: {}",
+            encryption_id
+        )));
+    }
+    let ciphertext = encrypt(encryptor, plaintext, &object_id_context(encryption_id))?;
+
+    let mut sealed = Vec::with_capacity(OBJECT_ID_MAGIC.len() + OBJECT_ID_LEN + ciphertext.len());
+    sealed.extend_from_slice(OBJECT_ID_MAGIC);
+    sealed.extend_from_slice(encryption_id.as_bytes());
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Encryption ID and ciphertext of an object written by [`seal_object`]
+fn split_sealed(data: &[u8]) -> Option<(&str, &[u8])> {
+    data.strip_prefix(OBJECT_ID_MAGIC.as_slice())
+        .filter(|rest| rest.len() >= OBJECT_ID_LEN)
+        .map(|rest| rest.split_at(OBJECT_ID_LEN))
+        .and_then(|(id, ciphertext)| Some((std::str::from_utf8(id).ok()?, ciphertext)))
+}
+
+/// Encryption ID stored data was sealed under, `None` for plain and legacy objects
+pub fn sealed_id(data: &[u8]) -> Option<&str> {
+    split_sealed(data).map(|(id, _)| id)
+}
+
+/// Decrypts an object written by [`seal_object`], or a legacy object keyed by `legacy_context`
+/// (its `bucket/key`). An ID from the object's metadata must match the one in the ciphertext.
+pub fn open_object(
+    encryptor: &dyn Encryptor,
+    data: &[u8],
+    encryption_id: Option<&str>,
+    legacy_context: &str,
+) -> Result<Vec<u8>, EncryptionError> {
+    match (split_sealed(data), encryption_id) {
+        (Some((id, ciphertext)), Some(expected)) => {
+            if id != expected {
+                return Err(EncryptionError::DecryptionFailed(
+                    "Object encryption ID does not match its metadata".to_string(),
+                ));
+            }
+            decrypt(encryptor, ciphertext, &object_id_context(id))
+        }
+        // Without metadata a legacy nonce could happen to start with the marker
+        (Some((id, ciphertext)), None) => decrypt(encryptor, ciphertext, &object_id_context(id))
+            .or_else(|_| decrypt(encryptor, data, legacy_context)),
+        (None, Some(_)) => Err(EncryptionError::DecryptionFailed(
+            "Object is missing its encryption ID".to_string(),
+        )),
+        (None, None) => decrypt(encryptor, data, legacy_context),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -14,7 +113,7 @@ mod tests {
     #[test]
     fn test_xchacha20poly1305_encryption_roundtrip() {
         let key_bytes = [1u8; 32];
-        let key_b64 = general_purpose::STANDARD.encode(&key_bytes);
+        let key_b64 = general_purpose::STANDARD.encode(key_bytes);
         
         let key_manager = KeyManager::from_base64(&key_b64).unwrap();
         let encryptor = XChaCha20Poly1305Encryptor::new(key_manager);
@@ -29,6 +128,33 @@ mod tests {
         assert_eq!(decrypted, plaintext);
     }
 
+    #[test]
+    fn test_sealed_object_survives_renames() {
+        let encryptor = XChaCha20Poly1305Encryptor::new(KeyManager::new([3u8; 32]));
+        let id = new_encryption_id();
+
+        let sealed = seal_object(&encryptor, b"album cover", &id).unwrap();
+
+        // The key no longer depends on where the object is stored
+        assert_eq!(open_object(&encryptor, &sealed, Some(&id), "photos/a.jpg").unwrap(), b"album cover");
+        assert_eq!(open_object(&encryptor, &sealed, None, "archive/b.jpg").unwrap(), b"album cover");
+        assert!(open_object(&encryptor, &sealed, Some(&new_encryption_id()), "photos/a.jpg").is_err());
+
+        let empty = seal_object(&encryptor, b"", &id).unwrap();
+        assert_eq!(open_object(&encryptor, &empty, Some(&id), "photos/empty").unwrap(), b"");
+        assert_eq!(sealed_id(&empty), Some(id.as_str()));
+        assert_eq!(sealed_id(b"plain text"), None);
+    }
+
+    #[test]
+    fn test_legacy_objects_use_bucket_and_key() {
+        let encryptor = XChaCha20Poly1305Encryptor::new(KeyManager::new([3u8; 32]));
+        let legacy = encryptor.encrypt(b"old report", b"reports/q1.txt").unwrap();
+
+        assert_eq!(open_object(&encryptor, &legacy, None, "reports/q1.txt").unwrap(), b"old report");
+        assert!(open_object(&encryptor, &legacy, None, "reports/q2.txt").is_err());
+    }
+
     #[test]
     fn test_key_derivation() {
         let key_manager = KeyManager::new([2u8; 32]);
@@ -49,7 +175,7 @@ mod tests {
 
     #[test]
     fn test_wrong_key_size() {
-        let short_key = general_purpose::STANDARD.encode(&[1u8; 16]);
+        let short_key = general_purpose::STANDARD.encode([1u8; 16]);
         let result = KeyManager::from_base64(&short_key);
         assert!(result.is_err());
     }
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use zeroize::Zeroize;
+
+use super::key_manager::KeyManager;
+use super::traits::EncryptionError;
+
+/// Ciphers kept per encryptor; each object has its own key, so this bounds memory, not hit rate
+pub const CIPHER_CACHE_SIZE: usize = 1024;
+
+struct CachedCipher {
+    cipher: Arc<XChaCha20Poly1305>,
+    last_used: u64,
+}
+
+/// Ciphers keyed by their derivation context, so reading an object again skips the HKDF and key
+/// setup. A cipher is safe to share: every encryption draws a fresh random nonce. Ciphers wipe
+/// their key when dropped, which for an evicted one happens once no request still uses it.
+pub struct CipherCache {
+    capacity: usize,
+    entries: Mutex<(u64, HashMap<Vec<u8>, CachedCipher>)>,
+}
+
+impl CipherCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Cipher for the key `key_manager` derives from `context`
+    pub fn get(&self, key_manager: &KeyManager, context: &[u8]) -> Result<Arc<XChaCha20Poly1305>, EncryptionError> {
+        {
+            let (clock, entries) = &mut *self.entries.lock().unwrap();
+            *clock += 1;
+            if let Some(entry) = entries.get_mut(context) {
+                entry.last_used = *clock;
+                metrics::counter!("fily_encryption_key_cache_total", "result" => "hit").increment(1);
+                return Ok(entry.cipher.clone());
+            }
+        }
+        metrics::counter!("fily_encryption_key_cache_total", "result" => "miss").increment(1);
+
+        let mut derived_key = key_manager.derive_key(context)?;
+        let cipher = XChaCha20Poly1305::new_from_slice(&derived_key)
+            .map_err(|e| EncryptionError::InvalidKey(format!("Cipher creation failed: {}", e)));
+        derived_key.zeroize();
+        let cipher = Arc::new(cipher?);
+        if self.capacity == 0 {
+            return Ok(cipher);
+        }
+
+        let (clock, entries) = &mut *self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(context) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(context, _)| context.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            context.to_vec(),
+            CachedCipher {
+                cipher: cipher.clone(),
+                last_used: *clock,
+            },
+        );
+        Ok(cipher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn len(cache: &CipherCache) -> usize {
+        cache.entries.lock().unwrap().1.len()
+    }
+
+    #[test]
+    fn test_cipher_cache_bounded_lru() {
+        let key_manager = KeyManager::new([5u8; 32]);
+        let cache = CipherCache::new(2);
+
+        let a = cache.get(&key_manager, b"a").unwrap();
+        assert!(Arc::ptr_eq(&a, &cache.get(&key_manager, b"a").unwrap()));
+        cache.get(&key_manager, b"b").unwrap();
+        // "a" was used more recently than "b", so "b" makes way for "c"
+        cache.get(&key_manager, b"a").unwrap();
+        cache.get(&key_manager, b"c").unwrap();
+        assert_eq!(len(&cache), 2);
+        assert!(Arc::ptr_eq(&a, &cache.get(&key_manager, b"a").unwrap()));
+        assert_eq!(len(&cache), 2);
+    }
+}
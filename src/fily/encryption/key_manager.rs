@@ -2,11 +2,18 @@ use hkdf::Hkdf;
 use sha2::Sha256;
 use base64::{Engine as _, engine::general_purpose};
 use super::traits::EncryptionError;
+use crate::fily::logging::REDACTED;
 
 pub struct KeyManager {
     master_key: [u8; 32],
 }
 
+impl std::fmt::Debug for KeyManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyManager").field("master_key", &REDACTED).finish()
+    }
+}
+
 impl KeyManager {
     pub fn new(master_key: [u8; 32]) -> Self {
         Self { master_key }
@@ -13,6 +13,8 @@ pub enum EncryptionError {
 }
 
 pub trait Encryptor: Send + Sync {
+    /// Cipher name used to label encryption metrics
+    fn cipher(&self) -> &'static str;
     fn encrypt(&self, plaintext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, EncryptionError>;
     fn decrypt(&self, ciphertext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, EncryptionError>;
 }
\ No newline at end of file
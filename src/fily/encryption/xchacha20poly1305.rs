@@ -1,18 +1,23 @@
 use chacha20poly1305::{
-    aead::{Aead, KeyInit, OsRng},
-    XChaCha20Poly1305, XNonce
+    aead::{Aead, OsRng},
+    XNonce
 };
 use rand::RngCore;
+use super::cipher_cache::{CipherCache, CIPHER_CACHE_SIZE};
 use super::traits::{Encryptor, EncryptionError};
 use super::key_manager::KeyManager;
 
 pub struct XChaCha20Poly1305Encryptor {
     key_manager: KeyManager,
+    ciphers: CipherCache,
 }
 
 impl XChaCha20Poly1305Encryptor {
     pub fn new(key_manager: KeyManager) -> Self {
-        Self { key_manager }
+        Self {
+            key_manager,
+            ciphers: CipherCache::new(CIPHER_CACHE_SIZE),
+        }
     }
 
     fn generate_nonce() -> XNonce {
@@ -23,10 +28,12 @@ impl XChaCha20Poly1305Encryptor {
 }
 
 impl Encryptor for XChaCha20Poly1305Encryptor {
+    fn cipher(&self) -> &'static str {
+        "xchacha20poly1305"
+    }
+
     fn encrypt(&self, plaintext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
-        let derived_key = self.key_manager.derive_key(associated_data)?;
-        let cipher = XChaCha20Poly1305::new_from_slice(&derived_key)
-            .map_err(|e| EncryptionError::InvalidKey(format!("Cipher creation failed: {}", e)))?;
+        let cipher = self.ciphers.get(&self.key_manager, associated_data)?;
 
         let nonce = Self::generate_nonce();
         let ciphertext = cipher
@@ -49,9 +56,7 @@ impl Encryptor for XChaCha20Poly1305Encryptor {
         let (nonce_bytes, ciphertext) = encrypted_data.split_at(24);
         let nonce = *XNonce::from_slice(nonce_bytes);
 
-        let derived_key = self.key_manager.derive_key(associated_data)?;
-        let cipher = XChaCha20Poly1305::new_from_slice(&derived_key)
-            .map_err(|e| EncryptionError::InvalidKey(format!("Cipher creation failed: {}", e)))?;
+        let cipher = self.ciphers.get(&self.key_manager, associated_data)?;
 
         cipher
             .decrypt(&nonce, ciphertext)
@@ -1,5 +1,7 @@
 use md5::{Digest, Md5};
+use sha2::Sha256;
 
+/// ETag of a single-part object: the quoted MD5 of its plaintext, even when stored encrypted
 pub fn generate_etag(data: &[u8]) -> String {
     let mut hasher = Md5::new();
     hasher.update(data);
@@ -7,6 +9,40 @@ pub fn generate_etag(data: &[u8]) -> String {
     format!("\"{}\"", hex::encode(result))
 }
 
+/// ETag and hex SHA-256 of an object's plaintext, hashed together in one pass so each chunk is
+/// read from memory once for both
+pub fn content_digests(data: &[u8]) -> (String, String) {
+    let mut md5 = Md5::new();
+    let mut sha256 = Sha256::new();
+    for chunk in data.chunks(64 * 1024) {
+        md5.update(chunk);
+        sha256.update(chunk);
+    }
+    (format!("\"{}\"", hex::encode(md5.finalize())), hex::encode(sha256.finalize()))
+}
+
+/// ETag of a multipart object, `"<md5 of the concatenated binary part MD5s>-<part count>"`,
+/// built from the part ETags in part order. Returns `None` if a part ETag is not a quoted MD5.
+pub fn generate_multipart_etag<S: AsRef<str>>(part_etags: &[S]) -> Option<String> {
+    if part_etags.is_empty() {
+        return None;
+    }
+
+    let mut hasher = Md5::new();
+    for etag in part_etags {
+        let digest = hex::decode(etag.as_ref().trim_matches('"')).ok()?;
+        if digest.len() != 16 {
+            return None;
+        }
+        hasher.update(&digest);
+    }
+    Some(format!(
+        "\"{}-{}\"",
+        hex::encode(hasher.finalize()),
+        part_etags.len()
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -18,6 +54,31 @@ mod tests {
         assert_eq!(etag, "\"6cd3556deb0da54bca060b4c39479839\"");
     }
 
+    #[test]
+    fn test_content_digests() {
+        let data = vec![7u8; 200 * 1024];
+        assert_eq!(
+            content_digests(&data),
+            (generate_etag(&data), hex::encode(Sha256::digest(&data)))
+        );
+    }
+
+    #[test]
+    fn test_multipart_etag() {
+        let parts = [generate_etag(b"part one"), generate_etag(b"part two")];
+
+        let mut expected = Md5::new();
+        expected.update(Md5::digest(b"part one"));
+        expected.update(Md5::digest(b"part two"));
+        assert_eq!(
+            generate_multipart_etag(&parts),
+            Some(format!("\"{}-2\"", hex::encode(expected.finalize())))
+        );
+
+        assert_eq!(generate_multipart_etag::<String>(&[]), None);
+        assert_eq!(generate_multipart_etag(&["\"not-an-md5\""]), None);
+    }
+
     #[test]
     fn test_etag_empty_data() {
         let data = b"";
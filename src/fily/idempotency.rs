@@ -0,0 +1,300 @@
+//! Client idempotency tokens for PUT: a request carrying `x-fily-idempotency-token` is stored
+//! once, and a retry with the same token gets the original response back without the object
+//! being written again, so a client that timed out waiting for a response can retry safely.
+//!
+//! Tokens are scoped to the bucket and the caller's access key and remembered for
+//! [`IDEMPOTENCY_WINDOW`]. A token reused for a different key, or with a body hash that
+//! contradicts the stored object, is rejected rather than replayed.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use super::auth_middleware::Principal;
+use super::cpu_pool::CpuPool;
+use super::metadata::load_metadata;
+use super::path_security::{bucket_metadata_dir, sanitize_bucket_name};
+use super::put_object::store;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::Config;
+
+pub const IDEMPOTENCY_TOKEN_HEADER: &str = "x-fily-idempotency-token";
+
+/// Set on a response replayed from an earlier request with the same token
+pub const REPLAYED_HEADER: &str = "x-fily-idempotent-replay";
+
+/// How long a token is remembered; a retry after this is a new request
+pub const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_TOKEN_LEN: usize = 128;
+
+/// Directory in the bucket's metadata directory holding one record per token
+const RECORDS_DIR: &str = "idempotency";
+
+/// How often a bucket's expired records are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Tokens hashing to the same stripe share a lock. Separate from the key locks, which a write
+/// takes while holding its token's lock.
+const STRIPES: usize = 256;
+
+/// What a token's first request did, enough to answer its retries
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    etag: String,
+    content_sha256: Option<String>,
+    /// Headers of the original response
+    headers: Vec<(String, String)>,
+    /// Unix time of the original request
+    created: i64,
+}
+
+impl Record {
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        now.timestamp() - self.created >= IDEMPOTENCY_WINDOW.as_secs() as i64
+    }
+
+    /// Why the request cannot be a retry of the one this token was first used for, if it cannot
+    fn mismatch(&self, key: &str, headers: &HeaderMap) -> Option<&'static str> {
+        if self.key != key {
+            return Some("a different key");
+        }
+        let declared_sha256 = headers
+            .get("x-amz-content-sha256")
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| v.len() == 64 && v.bytes().all(|b| b.is_ascii_hexdigit()));
+        if let (Some(declared), Some(stored)) = (declared_sha256, &self.content_sha256) {
+            if !declared.eq_ignore_ascii_case(stored) {
+                return Some("a different body");
+            }
+        }
+        let declared_md5 = headers
+            .get("content-md5")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+            .map(hex::encode);
+        if declared_md5.is_some_and(|md5| md5 != self.etag.trim_matches('"')) {
+            return Some("a different body");
+        }
+        None
+    }
+
+    fn replay(&self) -> Response {
+        let mut response = (StatusCode::OK, "").into_response();
+        let headers = response.headers_mut();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (name.parse::<hyper::header::HeaderName>(), value.parse()) {
+                headers.insert(name, value);
+            }
+        }
+        headers.insert(REPLAYED_HEADER, "true".parse().unwrap());
+        response
+    }
+}
+
+/// The request's idempotency token, if it has one
+pub fn token(headers: &HeaderMap) -> Result<Option<&str>, S3AppError> {
+    let Some(value) = headers.get(IDEMPOTENCY_TOKEN_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(token) if !token.is_empty() && token.len() <= MAX_TOKEN_LEN && token.bytes().all(|b| b.is_ascii_graphic()) => {
+            Ok(Some(token))
+        }
+        _ => Err(S3AppError::with_message(
+            S3ErrorCode::InvalidArgument,
+            format!("{} must be 1 to {} printable characters", IDEMPOTENCY_TOKEN_HEADER, MAX_TOKEN_LEN),
+        )),
+    }
+}
+
+fn stripes() -> &'static [Mutex<()>] {
+    static LOCKS: OnceLock<Vec<Mutex<()>>> = OnceLock::new();
+    LOCKS.get_or_init(|| (0..STRIPES).map(|_| Mutex::new(())).collect())
+}
+
+/// Record file for a token; the name is a hash, so tokens need no escaping
+fn record_path(storage_root: &Path, bucket: &str, principal: &Principal, token: &str) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(principal.0.as_bytes());
+    hasher.update([0]);
+    hasher.update(token.as_bytes());
+    bucket_metadata_dir(storage_root, bucket)
+        .join(RECORDS_DIR)
+        .join(hex::encode(hasher.finalize()))
+}
+
+async fn load(path: &Path) -> Option<Record> {
+    let json = tokio::fs::read(path).await.ok()?;
+    // A record torn by a crash is as good as none: the retry writes the object again
+    serde_json::from_slice(&json).ok()
+}
+
+async fn save(path: &Path, record: &Record) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, serde_json::to_vec(record)?).await?;
+    Ok(())
+}
+
+/// Removes the bucket's expired records, at most once per [`PRUNE_INTERVAL`]
+fn prune(records_dir: PathBuf) {
+    static PRUNED: OnceLock<StdMutex<HashMap<PathBuf, Instant>>> = OnceLock::new();
+    {
+        let mut pruned = PRUNED.get_or_init(Default::default).lock().unwrap();
+        if pruned.get(&records_dir).is_some_and(|at| at.elapsed() < PRUNE_INTERVAL) {
+            return;
+        }
+        pruned.insert(records_dir.clone(), Instant::now());
+    }
+    tokio::spawn(async move {
+        let Ok(mut entries) = tokio::fs::read_dir(&records_dir).await else { return };
+        let mut removed = 0;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let written = entry.metadata().await.and_then(|m| m.modified());
+            let expired = written.is_ok_and(|at| {
+                SystemTime::now().duration_since(at).is_ok_and(|age| age >= IDEMPOTENCY_WINDOW)
+            });
+            if expired && tokio::fs::remove_file(entry.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+        debug!("Removed {} expired idempotency records from {}", removed, records_dir.display());
+    });
+}
+
+/// PUT with an optional idempotency token: the first request with a token stores the object and
+/// remembers the response, retries get that response back. Requests with one token run one at a
+/// time, so a retry arriving while the original is still writing waits for its result.
+#[allow(clippy::too_many_arguments)]
+pub async fn put(
+    config: Extension<Arc<Config>>,
+    cpu_pool: Arc<CpuPool>,
+    principal: Principal,
+    headers: HeaderMap,
+    bucket: String,
+    file: String,
+    bytes: Bytes,
+) -> Result<Response, S3AppError> {
+    let Some(token) = token(&headers)?.map(str::to_string) else {
+        return store(config, cpu_pool, principal, headers, bucket, file, bytes, |_| {}).await;
+    };
+    let safe_bucket = sanitize_bucket_name(&bucket).map_err(|_| S3AppError::invalid_bucket_name(&bucket))?;
+    let storage_root = PathBuf::from(&config.location);
+    let path = record_path(&storage_root, &safe_bucket, &principal, &token);
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let _guard = stripes()[(hasher.finish() % STRIPES as u64) as usize].lock().await;
+
+    if let Some(record) = load(&path).await.filter(|r| !r.expired(Utc::now())) {
+        if let Some(difference) = record.mismatch(&file, &headers) {
+            return Err(S3AppError::with_message(
+                S3ErrorCode::InvalidRequest,
+                format!("{} was already used for {}", IDEMPOTENCY_TOKEN_HEADER, difference),
+            ));
+        }
+        info!("Replaying PUT {}/{} for idempotency token of {}", bucket, file, principal);
+        metrics::counter!("fily_idempotent_replays_total").increment(1);
+        return Ok(record.replay());
+    }
+
+    let response = store(config, cpu_pool, principal, headers, safe_bucket.clone(), file.clone(), bytes, |_| {}).await?;
+    if response.status().is_success() {
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect::<Vec<_>>();
+        let metadata = load_metadata(&storage_root, &safe_bucket, &file).await.ok().flatten();
+        let etag = response
+            .headers()
+            .get("etag")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        // The object is stored either way; without a record a retry only writes it again
+        let content_sha256 = metadata.filter(|m| m.etag == etag).and_then(|m| m.content_sha256);
+        let record = Record {
+            key: file,
+            etag,
+            content_sha256,
+            headers,
+            created: Utc::now().timestamp(),
+        };
+        if let Err(e) = save(&path, &record).await {
+            warn!("Failed to record idempotency token for {}/{}: {}", safe_bucket, record.key, e);
+        }
+        if let Some(records_dir) = path.parent() {
+            prune(records_dir.to_path_buf());
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            key: "a.txt".to_string(),
+            etag: "\"5d41402abc4b2a76b9719d911017c592\"".to_string(),
+            content_sha256: Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string()),
+            headers: vec![("etag".to_string(), "\"5d41402abc4b2a76b9719d911017c592\"".to_string())],
+            created: Utc::now().timestamp(),
+        }
+    }
+
+    #[test]
+    fn test_token_validation() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(IDEMPOTENCY_TOKEN_HEADER, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(token(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(token(&headers("retry-1")).unwrap(), Some("retry-1"));
+        assert!(token(&headers("")).is_err());
+        assert!(token(&headers("has space")).is_err());
+        assert!(token(&headers(&"x".repeat(MAX_TOKEN_LEN + 1))).is_err());
+    }
+
+    #[test]
+    fn test_record_mismatch() {
+        let record = record();
+        assert_eq!(record.mismatch("a.txt", &HeaderMap::new()), None);
+        assert_eq!(record.mismatch("b.txt", &HeaderMap::new()), Some("a different key"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-content-sha256", "UNSIGNED-PAYLOAD".parse().unwrap());
+        headers.insert("content-md5", "XUFAKrxLKna5cZ2REBfFkg==".parse().unwrap());
+        assert_eq!(record.mismatch("a.txt", &headers), None);
+        headers.insert("content-md5", "AAAAAAAAAAAAAAAAAAAAAA==".parse().unwrap());
+        assert_eq!(record.mismatch("a.txt", &headers), Some("a different body"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-content-sha256", "0".repeat(64).parse().unwrap());
+        assert_eq!(record.mismatch("a.txt", &headers), Some("a different body"));
+
+        let expired = Record { created: Utc::now().timestamp() - 25 * 60 * 60, ..record };
+        assert!(expired.expired(Utc::now()));
+        assert_eq!(expired.replay().headers()[REPLAYED_HEADER], "true");
+    }
+}
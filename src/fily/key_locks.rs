@@ -0,0 +1,149 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Keys hashing to the same stripe share a lock, which costs concurrency but never correctness
+const STRIPES: usize = 1024;
+
+/// Process-wide, like the files they protect: servers embedded in one process may share a
+/// storage root
+static LOCKS: OnceLock<Vec<RwLock<()>>> = OnceLock::new();
+
+fn stripes() -> &'static [RwLock<()>] {
+    LOCKS.get_or_init(|| (0..STRIPES).map(|_| RwLock::new(())).collect())
+}
+
+fn stripe(storage_root: &Path, bucket: &str, key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (storage_root, bucket, key).hash(&mut hasher);
+    (hasher.finish() % STRIPES as u64) as usize
+}
+
+/// Held while an object's data and metadata are replaced, so concurrent writes to a key apply
+/// one after the other and the last writer's data always goes with its own metadata. Not
+/// reentrant: code holding it must write through helpers that do not lock again.
+pub struct KeyLock {
+    _guards: Vec<RwLockWriteGuard<'static, ()>>,
+}
+
+/// Held while an object's data and metadata are loaded, so a read never pairs one write's data
+/// with another's metadata. Shared with other reads of the key; like `KeyLock`, not reentrant.
+pub struct KeyReadLock {
+    _guard: RwLockReadGuard<'static, ()>,
+}
+
+/// Locks one object for writing
+pub async fn lock(storage_root: &Path, bucket: &str, key: &str) -> KeyLock {
+    lock_all(storage_root, &[(bucket, key)]).await
+}
+
+/// Locks several objects, e.g. both ends of a rename. Stripes are taken in ascending order so
+/// two requests locking overlapping keys cannot wait on each other.
+pub async fn lock_all(storage_root: &Path, objects: &[(&str, &str)]) -> KeyLock {
+    let mut indices: Vec<usize> = objects
+        .iter()
+        .map(|(bucket, key)| stripe(storage_root, bucket, key))
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut guards = Vec::with_capacity(indices.len());
+    for index in indices {
+        let lock = &stripes()[index];
+        let guard = match lock.try_write() {
+            Ok(guard) => guard,
+            Err(_) => {
+                metrics::counter!("fily_key_lock_contended_total").increment(1);
+                lock.write().await
+            }
+        };
+        guards.push(guard);
+    }
+    KeyLock { _guards: guards }
+}
+
+/// Locks one object for reading: waits out a write in progress but not other reads
+pub async fn read(storage_root: &Path, bucket: &str, key: &str) -> KeyReadLock {
+    let lock = &stripes()[stripe(storage_root, bucket, key)];
+    let guard = match lock.try_read() {
+        Ok(guard) => guard,
+        Err(_) => {
+            metrics::counter!("fily_key_lock_contended_total").increment(1);
+            lock.read().await
+        }
+    };
+    KeyReadLock { _guard: guard }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_lock_serializes_same_key() {
+        let root = Path::new("/data");
+        let held = lock(root, "photos", "a.jpg").await;
+
+        // Another key, almost always on another stripe, is not held up
+        let other = (0..100)
+            .map(|i| format!("b{}.jpg", i))
+            .find(|key| stripe(root, "photos", key) != stripe(root, "photos", "a.jpg"))
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), lock(root, "photos", &other))
+            .await
+            .unwrap();
+
+        let waiting = tokio::spawn(async move {
+            let _lock = lock(root, "photos", "a.jpg").await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reads_share_and_exclude_writes() {
+        let root = Path::new("/data");
+        let first = read(root, "photos", "d.jpg").await;
+        // Readers do not wait for each other
+        let second = tokio::time::timeout(Duration::from_secs(1), read(root, "photos", "d.jpg"))
+            .await
+            .unwrap();
+
+        let writer = tokio::spawn(async move {
+            let _lock = lock(root, "photos", "d.jpg").await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        drop(first);
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(1), writer).await.unwrap().unwrap();
+
+        // And a held write keeps readers out
+        let held = lock(root, "photos", "d.jpg").await;
+        let reader = tokio::spawn(async move {
+            let _lock = read(root, "photos", "d.jpg").await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!reader.is_finished());
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(1), reader).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lock_all_dedups_shared_stripes() {
+        let root = Path::new("/data");
+        // The same key twice maps to one stripe, which must only be taken once
+        let _lock = tokio::time::timeout(
+            Duration::from_secs(1),
+            lock_all(root, &[("photos", "c.jpg"), ("photos", "c.jpg")]),
+        )
+        .await
+        .unwrap();
+    }
+}
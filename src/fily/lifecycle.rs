@@ -0,0 +1,224 @@
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+use super::connections::ClientConnection;
+use super::timeouts::TimeoutConfig;
+
+/// Stops a running server from code, for applications that embed fily
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Starts a graceful shutdown; in-flight requests are allowed to finish
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once `shutdown` has been called, including before the wait started
+    pub async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in `self`, so the channel cannot close while we wait
+        let _ = receiver.wait_for(|stopped| *stopped).await;
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Name of the probe file written to check the storage location is usable
+const WRITE_CHECK_FILE: &str = ".fily-write-check";
+
+/// Creates the storage location, and the staging root when one is configured, if needed and
+/// checks that fily can write to them
+pub async fn validate_storage(location: &str, staging_root: Option<&str>) -> anyhow::Result<()> {
+    let root = Path::new(location);
+    tokio::fs::create_dir_all(root)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot create storage location {}: {}", location, e))?;
+
+    let probe = root.join(WRITE_CHECK_FILE);
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|e| anyhow::anyhow!("Storage location {} is not writable: {}", location, e))?;
+    tokio::fs::remove_file(&probe).await?;
+
+    if let Some(staging_root) = staging_root {
+        tokio::fs::create_dir_all(staging_root)
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot create staging root {}: {}", staging_root, e))?;
+        let probe = Path::new(staging_root).join(WRITE_CHECK_FILE);
+        tokio::fs::write(&probe, b"ok")
+            .await
+            .map_err(|e| anyhow::anyhow!("Staging root {} is not writable: {}", staging_root, e))?;
+        tokio::fs::remove_file(&probe).await?;
+    }
+    super::path_security::register_staging_root(root, staging_root.map(Into::into));
+
+    super::migrations::open_metadata_layout(root)
+        .map_err(|e| anyhow::anyhow!("Cannot read the metadata location of {}: {}", location, e))?;
+    super::delete_bucket::purge_trash(root)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot clean up deleted buckets in {}: {}", location, e))?;
+    super::commit::recover(root)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot recover interrupted writes in {}: {}", location, e))?;
+
+    let case_insensitive = is_case_insensitive(root).await?;
+    metrics::gauge!("fily_storage_case_insensitive").set(case_insensitive as u8 as f64);
+    if case_insensitive {
+        warn!(
+            "Storage location {} is on a case-insensitive filesystem: keys differing only in case \
+             (e.g. Key.txt and key.txt) are stored as the same file and overwrite each other. \
+             Use a case-sensitive volume to keep S3 key semantics.",
+            location
+        );
+    }
+
+    Ok(())
+}
+
+/// Name of the probe file used to detect case-insensitive storage
+const CASE_CHECK_FILE: &str = ".fily-case-check";
+
+/// Whether the filesystem at `root` treats file names that differ only in case as the same file
+pub async fn is_case_insensitive(root: &Path) -> std::io::Result<bool> {
+    let probe = root.join(CASE_CHECK_FILE);
+    tokio::fs::write(&probe, b"ok").await?;
+    let found = tokio::fs::try_exists(root.join(CASE_CHECK_FILE.to_uppercase())).await;
+    tokio::fs::remove_file(&probe).await?;
+    found
+}
+
+/// Accepts connections until `shutdown` resolves, then waits for in-flight requests to finish
+pub(crate) async fn serve_connections(
+    listener: TcpListener,
+    app: Router,
+    timeouts: &TimeoutConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually running out of file descriptors; back off instead of spinning
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let connection = ClientConnection::new(addr);
+        let service = app.clone().map_request(move |req: Request<Incoming>| {
+            let mut req = req.map(Body::new);
+            req.extensions_mut().insert(ConnectInfo(connection.clone()));
+            req
+        });
+        let service = TowerToHyperService::new(service);
+        let watcher = graceful.watcher();
+        let header_read_timeout = timeouts.header_read;
+
+        tokio::spawn(async move {
+            let mut builder = Builder::new(TokioExecutor::new());
+            // Without a timer hyper never enforces the header read timeout
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(header_read_timeout);
+            let connection = builder.serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(connection).await {
+                debug!("Connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_before_wait() {
+        let handle = ShutdownHandle::new();
+        assert!(!handle.is_shutdown());
+
+        handle.clone().shutdown();
+
+        assert!(handle.is_shutdown());
+        handle.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_validate_storage_creates_location() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let location = dir.path().join("data");
+
+        validate_storage(location.to_str().unwrap(), None).await.unwrap();
+
+        assert!(location.is_dir());
+        assert!(!location.join(WRITE_CHECK_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn test_case_sensitivity_probe() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let expected = {
+            std::fs::write(dir.path().join("Probe"), b"").unwrap();
+            dir.path().join("probe").exists()
+        };
+
+        assert_eq!(is_case_insensitive(dir.path()).await.unwrap(), expected);
+        assert!(!dir.path().join(CASE_CHECK_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn test_validate_storage_purges_deleted_buckets() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let leftover = dir.path().join(".fily-trash/0f3a/.fily-metadata");
+        std::fs::create_dir_all(&leftover).unwrap();
+        std::fs::write(leftover.join("bucket-policy"), b"{}").unwrap();
+
+        validate_storage(dir.path().to_str().unwrap(), None).await.unwrap();
+
+        assert!(!dir.path().join(".fily-trash").exists());
+    }
+}
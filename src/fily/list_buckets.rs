@@ -1,6 +1,10 @@
+use std::path::Path;
 use std::sync::Arc;
 
+use super::auth_middleware::AuthenticatedAccessKey;
 use super::s3_app_error::S3AppError;
+use super::tenancy::{account_for, explicitly_allowed, key_allows_bucket, load_bucket_policy, BucketAccess};
+use super::timestamp::iso8601;
 use super::Config;
 use anyhow::Context;
 use axum::body::Body;
@@ -43,38 +47,82 @@ impl IntoResponse for ListAllMyBucketsResult {
     }
 }
 
-async fn list_buckets(config: &Config) -> anyhow::Result<ListAllMyBucketsResult> {
+/// Names and creation times of the buckets `access_key` may read: those on its bucket allowlist,
+/// and of those the ones its account owns or was granted. Keys without either see every bucket,
+/// unless deny-by-default mode is on.
+pub(super) async fn visible_buckets(
+    config: &Config,
+    access_key: &str,
+) -> anyhow::Result<Vec<(String, DateTime<Utc>)>> {
     let location = &config.location;
+    let account = account_for(config, access_key);
+    let account = account.as_deref();
 
-    let mut buckets: Vec<Bucket> = vec![];
+    let mut buckets = vec![];
 
     let mut read_dir = tokio::fs::read_dir(location).await?;
     while let Some(entry) = read_dir.next_entry().await? {
         if let Ok(metadata) = entry.metadata().await {
-            if metadata.is_dir() {
+            // Dot directories hold fily internals such as tenant namespaces, never buckets
+            if metadata.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
                 let created_time: DateTime<Utc> = metadata.created()?.into();
+                let name = entry
+                    .file_name()
+                    .to_str()
+                    .context("failed turning os string to rust string")?
+                    .to_string();
 
-                buckets.push(Bucket {
-                    creation_date: created_time.format("%FT%T%:z").to_string(),
-                    name: entry
-                        .file_name()
-                        .to_str()
-                        .context("failed turning os string to rust string")
-                        .unwrap()
-                        .to_string(),
-                });
+                // Keys never learn the names of buckets they could not use
+                if !key_allows_bucket(config, access_key, &name) {
+                    continue;
+                }
+                // Accounts only see their own buckets and the ones shared with them
+                if account.is_some() || config.deny_by_default {
+                    let policy = load_bucket_policy(Path::new(location), &name)
+                        .await
+                        .ok()
+                        .flatten()
+                        .unwrap_or_default();
+                    if !policy.allows(account, BucketAccess::Read) {
+                        continue;
+                    }
+                    if config.deny_by_default
+                        && !explicitly_allowed(config, access_key, &name, &policy, BucketAccess::Read)
+                    {
+                        continue;
+                    }
+                }
+
+                buckets.push((name, created_time));
             }
         }
     }
 
+    Ok(buckets)
+}
+
+async fn list_buckets(config: &Config, access_key: &str) -> anyhow::Result<ListAllMyBucketsResult> {
+    let account = account_for(config, access_key);
+    let buckets = visible_buckets(config, access_key)
+        .await?
+        .into_iter()
+        .map(|(name, created_time)| Bucket {
+            creation_date: iso8601(created_time),
+            name,
+        })
+        .collect();
+
     Ok(ListAllMyBucketsResult {
         buckets: List { buckets },
-        owner: "".to_string(),
+        owner: account.unwrap_or_default().to_string(),
     })
 }
 
-pub async fn handle(config: Extension<Arc<Config>>) -> Result<Response, S3AppError> {
-    match list_buckets(&config).await {
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+) -> Result<Response, S3AppError> {
+    match list_buckets(&config, &access_key.0).await {
         Ok(list_buckets) => Ok(list_buckets.into_response()),
         Err(e) => Err(S3AppError::from(e)),
     }
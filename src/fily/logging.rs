@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tracing::info;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use super::request_log::{RequestLogSampler, SamplingConfig, SamplingFilter};
+
+/// Target of security and data-integrity events that must never be sampled away
+pub const AUDIT_LOG_TARGET: &str = "fily::audit";
+
+/// Stands in for secrets wherever configuration or credentials are printed
+pub const REDACTED: &str = "********";
+
+/// Filter applied while SIGUSR1 debugging is toggled on
+const SIGUSR1_FILTER: &str = "debug";
+
+/// Runtime controls for the installed tracing subscriber
+pub struct LoggingHandle {
+    pub sampler: Arc<RequestLogSampler>,
+    filter: reload::Handle<EnvFilter, Registry>,
+    base_filter: String,
+    current_filter: Mutex<String>,
+}
+
+impl LoggingHandle {
+    /// Directives of the active filter, e.g. `info,fily::fily::auth=debug`
+    pub fn filter(&self) -> String {
+        self.current_filter.lock().unwrap().clone()
+    }
+
+    /// Replaces the active filter with the given directives
+    pub fn set_filter(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.filter.reload(filter).map_err(|e| e.to_string())?;
+        *self.current_filter.lock().unwrap() = directives.to_string();
+        info!("Log filter changed to '{}'", directives);
+        Ok(())
+    }
+
+    /// Restores the filter the server was started with
+    pub fn reset_filter(&self) -> Result<(), String> {
+        self.set_filter(&self.base_filter.clone())
+    }
+
+    /// Switches between the startup filter and full debug logging
+    pub fn toggle_debug(&self) -> Result<(), String> {
+        if self.filter() == SIGUSR1_FILTER {
+            self.reset_filter()
+        } else {
+            self.set_filter(SIGUSR1_FILTER)
+        }
+    }
+}
+
+static LOGGING: OnceLock<LoggingHandle> = OnceLock::new();
+
+/// Installs the global tracing subscriber and keeps a handle for the admin API
+pub fn init(log_level: &str, sampling: SamplingConfig) -> anyhow::Result<()> {
+    let base_filter = log_level.to_lowercase();
+    let env_filter = EnvFilter::try_new(&base_filter)
+        .map_err(|e| anyhow::anyhow!("Invalid log level {}: {}", log_level, e))?;
+    let (env_filter, filter) = reload::Layer::new(env_filter);
+    let sampler = Arc::new(RequestLogSampler::new(sampling));
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_level(true)
+        .with_thread_names(true)
+        .with_target(true)
+        .with_filter(env_filter)
+        .with_filter(SamplingFilter::new(sampler.clone()));
+
+    tracing_subscriber::registry().with(fmt_layer).try_init()?;
+
+    LOGGING
+        .set(LoggingHandle {
+            sampler,
+            filter,
+            current_filter: Mutex::new(base_filter.clone()),
+            base_filter,
+        })
+        .map_err(|_| anyhow::anyhow!("Logging has already been initialised"))?;
+
+    Ok(())
+}
+
+/// Returns the logging handle when `init` has been called
+pub fn handle() -> Option<&'static LoggingHandle> {
+    LOGGING.get()
+}
+
+/// Toggles debug logging each time the process receives SIGUSR1
+#[cfg(unix)]
+pub async fn watch_sigusr1() {
+    let Some(handle) = handle() else {
+        return;
+    };
+
+    let mut signal =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                tracing::warn!("Failed to install SIGUSR1 handler: {}", e);
+                return;
+            }
+        };
+
+    while signal.recv().await.is_some() {
+        if let Err(e) = handle.toggle_debug() {
+            tracing::error!("Failed to toggle debug logging: {}", e);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hyper::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::s3_app_error::S3AppError;
+use super::request_path::RequestTarget;
+use super::request_info::RequestInfo;
+use super::tenancy::BucketAccess;
+use super::Config;
+
+/// Server-wide read-only switch and the Retry-After hint sent with rejected writes
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReadOnlyConfig {
+    pub enabled: bool,
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+fn default_retry_after() -> u64 {
+    60
+}
+
+impl Default for ReadOnlyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after: default_retry_after(),
+        }
+    }
+}
+
+/// Runtime read-only state for the whole server and for individual buckets
+pub struct MaintenanceMode {
+    server: RwLock<ReadOnlyConfig>,
+    // Bucket directories rather than names, so equally named buckets of different tenants stay apart
+    buckets: RwLock<HashSet<PathBuf>>,
+}
+
+impl MaintenanceMode {
+    pub fn new(config: ReadOnlyConfig) -> Self {
+        Self {
+            server: RwLock::new(config),
+            buckets: RwLock::new(HashSet::new()),
+        }
+    }
+
+    pub fn config(&self) -> ReadOnlyConfig {
+        self.server.read().unwrap().clone()
+    }
+
+    pub fn update(&self, config: ReadOnlyConfig) {
+        info!(
+            "Server read-only mode {} (retry after {}s)",
+            if config.enabled { "enabled" } else { "disabled" },
+            config.retry_after
+        );
+        *self.server.write().unwrap() = config;
+    }
+
+    /// Names of the read-only buckets under the given storage root
+    pub fn buckets(&self, storage_root: &Path) -> Vec<String> {
+        let mut buckets: Vec<String> = self
+            .buckets
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|path| path.parent() == Some(storage_root))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        buckets.sort();
+        buckets
+    }
+
+    pub fn set_bucket(&self, storage_root: &Path, bucket: &str, read_only: bool) {
+        let path = storage_root.join(bucket);
+        let mut buckets = self.buckets.write().unwrap();
+        if read_only {
+            buckets.insert(path);
+        } else {
+            buckets.remove(&path);
+        }
+        info!(
+            "Bucket {} read-only mode {}",
+            bucket,
+            if read_only { "enabled" } else { "disabled" }
+        );
+    }
+
+    /// Drops the bucket's read-only flag once the bucket itself is deleted
+    pub fn forget_bucket(&self, storage_root: &Path, bucket: &str) {
+        self.buckets.write().unwrap().remove(&storage_root.join(bucket));
+    }
+
+    /// Seconds to advertise in Retry-After when a write to the bucket must be rejected
+    pub(crate) fn rejects_writes(&self, storage_root: &Path, bucket: Option<&str>) -> Option<u64> {
+        let server = self.server.read().unwrap();
+        let bucket_read_only = bucket.is_some_and(|bucket| {
+            self.buckets
+                .read()
+                .unwrap()
+                .contains(&storage_root.join(bucket))
+        });
+        (server.enabled || bucket_read_only).then_some(server.retry_after)
+    }
+}
+
+/// Bucket targeted by an S3 request; `/_fily` extension endpoints stay writable
+fn bucket_of(target: &RequestTarget) -> Result<Option<String>, ()> {
+    match target {
+        RequestTarget::Extension => Err(()),
+        RequestTarget::Service => Ok(None),
+        RequestTarget::Bucket { bucket, .. } => Ok(Some(bucket.clone())),
+    }
+}
+
+/// 503 ServiceUnavailable with a Retry-After header, sent while writes are suspended
+pub fn service_unavailable(message: &str, resource: &str, retry_after: u64) -> Response {
+    let mut response = S3AppError::service_unavailable(message, resource).into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, retry_after.to_string().parse().unwrap());
+    response
+}
+
+/// Middleware rejecting writes while the server or the target bucket is read-only
+pub async fn reject_writes(req: Request, next: Next) -> Response {
+    if BucketAccess::for_method(req.method()) == BucketAccess::Read {
+        return next.run(req).await;
+    }
+    let (Some(config), Some(maintenance)) = (
+        req.extensions().get::<Arc<Config>>().cloned(),
+        req.extensions().get::<Arc<MaintenanceMode>>().cloned(),
+    ) else {
+        return next.run(req).await;
+    };
+    let Ok(bucket) = bucket_of(&RequestInfo::of(&req).target) else {
+        return next.run(req).await;
+    };
+
+    if let Some(retry_after) = maintenance.rejects_writes(Path::new(&config.location), bucket.as_deref()) {
+        debug!("Rejecting {} {} while read-only", req.method(), req.uri().path());
+        return service_unavailable(
+            "The resource is read-only for maintenance, please retry later.",
+            req.uri().path(),
+            retry_after,
+        );
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_and_bucket_read_only() {
+        let root = Path::new("/srv/fily");
+        let maintenance = MaintenanceMode::new(ReadOnlyConfig::default());
+        assert_eq!(maintenance.rejects_writes(root, Some("photos")), None);
+
+        maintenance.set_bucket(root, "photos", true);
+        assert_eq!(maintenance.rejects_writes(root, Some("photos")), Some(60));
+        assert_eq!(maintenance.rejects_writes(root, Some("backups")), None);
+        assert_eq!(maintenance.rejects_writes(Path::new("/srv/other"), Some("photos")), None);
+        assert_eq!(maintenance.buckets(root), vec!["photos".to_string()]);
+
+        maintenance.set_bucket(root, "photos", false);
+        maintenance.update(ReadOnlyConfig {
+            enabled: true,
+            retry_after: 300,
+        });
+        assert_eq!(maintenance.rejects_writes(root, None), Some(300));
+        assert_eq!(maintenance.rejects_writes(root, Some("backups")), Some(300));
+
+        maintenance.set_bucket(root, "archive", true);
+        maintenance.forget_bucket(root, "archive");
+        assert!(maintenance.buckets(root).is_empty());
+    }
+
+    #[test]
+    fn test_bucket_of() {
+        assert_eq!(bucket_of(&RequestTarget::from_path("/photos/a.jpg")), Ok(Some("photos".to_string())));
+        assert_eq!(bucket_of(&RequestTarget::from_path("/%70hotos/a.jpg")), Ok(Some("photos".to_string())));
+        assert_eq!(bucket_of(&RequestTarget::from_path("/")), Ok(None));
+        assert_eq!(bucket_of(&RequestTarget::from_path("/_fily/admin/read-only")), Err(()));
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use super::cpu_pool::CpuPool;
+use super::get_object::decrypt_object;
+use super::logging::REDACTED;
+use super::metadata::load_metadata;
+use super::path_security::{construct_safe_path, sanitize_bucket_name};
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::search_bucket::ObjectWalker;
+use super::Config;
+
+/// Format version written into every manifest
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Signature algorithm named in every manifest
+pub const SIGNATURE_ALGORITHM: &str = "HMAC-SHA256";
+
+/// Shortest accepted signing key
+pub const MIN_SIGNING_KEY_LEN: usize = 32;
+
+#[derive(Clone, Default)]
+pub struct ManifestConfig {
+    /// Secret the HMAC-SHA256 manifest signatures are keyed with (manifests are unavailable when unset)
+    pub signing_key: Option<String>,
+}
+
+impl fmt::Debug for ManifestConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ManifestConfig")
+            .field("signing_key", &self.signing_key.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl ManifestConfig {
+    fn signing_key(&self) -> Result<&[u8], S3AppError> {
+        self.signing_key.as_deref().map(str::as_bytes).ok_or_else(|| {
+            S3AppError::with_message(
+                S3ErrorCode::InvalidRequest,
+                "Manifests need a signing key, set FILY_MANIFEST_SIGNING_KEY".to_string(),
+            )
+        })
+    }
+}
+
+/// One object as it was stored when the manifest was taken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub key: String,
+    pub size: u64,
+    /// Hex SHA-256 of the object's content as clients read it, i.e. after decryption
+    pub sha256: String,
+}
+
+/// Every object of a bucket at a point in time, signed so it can be checked later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub bucket: String,
+    pub generated_at: String,
+    pub algorithm: String,
+    pub entries: Vec<ManifestEntry>,
+    /// Hex HMAC over the manifest serialized without this field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Manifest {
+    /// Bytes the signature covers: the compact JSON of the manifest without its signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        let unsigned = Manifest {
+            signature: None,
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned).expect("manifest serializes")
+    }
+
+    fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&self.signed_bytes());
+        mac
+    }
+
+    fn sign(&mut self, key: &[u8]) {
+        self.signature = Some(hex::encode(self.mac(key).finalize().into_bytes()));
+    }
+
+    /// Whether the manifest carries a valid signature made with `key`
+    pub fn verify_signature(&self, key: &[u8]) -> bool {
+        let Some(signature) = self.signature.as_deref().and_then(|s| hex::decode(s).ok()) else {
+            return false;
+        };
+        self.algorithm == SIGNATURE_ALGORITHM
+            && bool::from(self.mac(key).finalize().into_bytes().as_slice().ct_eq(&signature))
+    }
+}
+
+/// Outcome of checking a store against a manifest
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub bucket: String,
+    /// The signature matches and every listed object is stored unchanged with nothing added
+    pub valid: bool,
+    pub signature_valid: bool,
+    pub checked: usize,
+    /// Keys listed in the manifest that are no longer stored
+    pub missing: Vec<String>,
+    /// Keys whose size or content differs from the manifest
+    pub modified: Vec<String>,
+    /// Keys stored now that the manifest does not list
+    pub unexpected: Vec<String>,
+}
+
+fn bucket_path(config: &Config, bucket: &str) -> Result<std::path::PathBuf, S3AppError> {
+    let safe_bucket = sanitize_bucket_name(bucket)
+        .map_err(|e| S3AppError::with_message(S3ErrorCode::InvalidArgument, format!("Invalid bucket name: {}", e)))?;
+    let path = Path::new(&config.location).join(safe_bucket);
+    if !path.is_dir() {
+        return Err(S3AppError::no_such_bucket(bucket));
+    }
+    Ok(path)
+}
+
+/// Size and hex SHA-256 of an object's content as a GET would return it
+async fn hash_object(
+    config: &Arc<Config>,
+    cpu_pool: &CpuPool,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<(u64, String)>, S3AppError> {
+    let storage_root = Path::new(&config.location);
+    let path = construct_safe_path(storage_root, bucket, key)
+        .map_err(|e| S3AppError::internal_error(&format!("Invalid stored key {}: {}", key, e)))?;
+    let stored = match tokio::fs::read(&path).await {
+        Ok(stored) => stored,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(S3AppError::internal_error(&format!("Failed to read {}/{}: {}", bucket, key, e))),
+    };
+    let encryption_id = load_metadata(storage_root, bucket, key)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|m| m.encryption_id);
+    let plaintext = decrypt_object(config, cpu_pool, bucket, key, encryption_id, stored).await?;
+    let digest = cpu_pool
+        .run(move || (plaintext.len() as u64, hex::encode(Sha256::digest(&plaintext))))
+        .await?;
+    Ok(Some(digest))
+}
+
+/// Hashes every object of `bucket` and returns the signed manifest
+pub async fn build(config: &Arc<Config>, cpu_pool: &CpuPool, bucket: &str) -> Result<Manifest, S3AppError> {
+    let key = config.manifest.signing_key()?;
+    let bucket_path = bucket_path(config, bucket)?;
+    let generated_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+    let mut entries = vec![];
+    let mut walker = ObjectWalker::new(&bucket_path, "", None);
+    while let Some(object) = walker
+        .next()
+        .await
+        .map_err(|e| S3AppError::internal_error(&format!("Failed to list bucket {}: {}", bucket, e)))?
+    {
+        // Objects deleted while the manifest is taken are simply not listed
+        if let Some((size, sha256)) = hash_object(config, cpu_pool, bucket, &object.key).await? {
+            entries.push(ManifestEntry { key: object.key, size, sha256 });
+        }
+    }
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        bucket: bucket.to_string(),
+        generated_at,
+        algorithm: SIGNATURE_ALGORITHM.to_string(),
+        entries,
+        signature: None,
+    };
+    manifest.sign(key);
+    metrics::counter!("fily_manifest_total", "operation" => "export").increment(1);
+    Ok(manifest)
+}
+
+/// Checks the signature of `manifest` and re-hashes its bucket against it
+pub async fn verify(config: &Arc<Config>, cpu_pool: &CpuPool, manifest: &Manifest) -> Result<VerifyReport, S3AppError> {
+    let key = config.manifest.signing_key()?;
+    if manifest.version != MANIFEST_VERSION {
+        return Err(S3AppError::with_message(
+            S3ErrorCode::InvalidArgument,
+            format!("Unsupported manifest version {}", manifest.version),
+        ));
+    }
+    let bucket = manifest.bucket.as_str();
+    let bucket_path = bucket_path(config, bucket)?;
+    let signature_valid = manifest.verify_signature(key);
+
+    let mut expected: BTreeMap<&str, &ManifestEntry> =
+        manifest.entries.iter().map(|entry| (entry.key.as_str(), entry)).collect();
+    let mut report = VerifyReport {
+        bucket: bucket.to_string(),
+        valid: false,
+        signature_valid,
+        checked: 0,
+        missing: vec![],
+        modified: vec![],
+        unexpected: vec![],
+    };
+
+    let mut walker = ObjectWalker::new(&bucket_path, "", None);
+    while let Some(object) = walker
+        .next()
+        .await
+        .map_err(|e| S3AppError::internal_error(&format!("Failed to list bucket {}: {}", bucket, e)))?
+    {
+        let Some(entry) = expected.remove(object.key.as_str()) else {
+            report.unexpected.push(object.key);
+            continue;
+        };
+        report.checked += 1;
+        match hash_object(config, cpu_pool, bucket, &object.key).await? {
+            Some((size, sha256)) if size == entry.size && sha256 == entry.sha256 => {}
+            Some(_) => report.modified.push(object.key),
+            None => report.missing.push(object.key),
+        }
+    }
+    report.missing.extend(expected.into_keys().map(str::to_string));
+    report.missing.sort();
+
+    report.valid = report.signature_valid
+        && report.missing.is_empty()
+        && report.modified.is_empty()
+        && report.unexpected.is_empty();
+    metrics::counter!("fily_manifest_total", "operation" => "verify").increment(1);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn config(location: &Path) -> Arc<Config> {
+        Arc::new(Config {
+            location: location.to_string_lossy().into_owned(),
+            address: "127.0.0.1".to_string(),
+            manifest: ManifestConfig {
+                signing_key: Some(KEY.to_string()),
+            },
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_manifest_detects_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(dir.path());
+        let cpu_pool = CpuPool::new(&Default::default());
+        std::fs::create_dir_all(dir.path().join("records/2024")).unwrap();
+        std::fs::write(dir.path().join("records/a.txt"), b"alpha").unwrap();
+        std::fs::write(dir.path().join("records/2024/b.txt"), b"beta").unwrap();
+
+        let manifest = build(&config, &cpu_pool, "records").await.unwrap();
+        let keys: Vec<&str> = manifest.entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, ["2024/b.txt", "a.txt"]);
+        assert_eq!(manifest.entries[1].sha256, hex::encode(Sha256::digest(b"alpha")));
+        assert!(manifest.verify_signature(KEY.as_bytes()));
+        assert!(!manifest.verify_signature(b"another key of at least 32 bytes"));
+
+        // A manifest survives a round trip through its JSON form
+        let json = serde_json::to_string_pretty(&manifest).unwrap();
+        let parsed: Manifest = serde_json::from_str(&json).unwrap();
+        assert!(verify(&config, &cpu_pool, &parsed).await.unwrap().valid);
+
+        std::fs::write(dir.path().join("records/a.txt"), b"alphA").unwrap();
+        std::fs::remove_file(dir.path().join("records/2024/b.txt")).unwrap();
+        std::fs::write(dir.path().join("records/c.txt"), b"gamma").unwrap();
+        let report = verify(&config, &cpu_pool, &parsed).await.unwrap();
+        assert!(!report.valid);
+        assert!(report.signature_valid);
+        assert_eq!(report.modified, ["a.txt"]);
+        assert_eq!(report.missing, ["2024/b.txt"]);
+        assert_eq!(report.unexpected, ["c.txt"]);
+
+        // Editing the manifest to match the store breaks its signature
+        let mut forged = parsed.clone();
+        forged.entries[1].sha256 = hex::encode(Sha256::digest(b"alphA"));
+        assert!(!verify(&config, &cpu_pool, &forged).await.unwrap().signature_valid);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+use tracing::debug;
+
+/// Reading medium-sized objects that are transformed before being served (decrypted) through a
+/// memory map, so the ciphertext is read straight from the page cache instead of being copied into
+/// a buffer first
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedReadConfig {
+    pub enabled: bool,
+    /// Smallest object mapped; mapping costs more than it saves on small files
+    pub min_size: u64,
+    /// Largest object mapped, bounding the address space one request holds
+    pub max_size: u64,
+}
+
+impl Default for MappedReadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: 1024 * 1024,
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl MappedReadConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.min_size > self.max_size {
+            return Err(format!(
+                "minimum size {} is larger than maximum size {}",
+                self.min_size, self.max_size
+            ));
+        }
+        Ok(())
+    }
+
+    fn maps(&self, len: u64) -> bool {
+        self.enabled && (self.min_size..=self.max_size).contains(&len)
+    }
+}
+
+/// Contents of a stored object file, read into memory or mapped
+pub enum StoredData {
+    Buffered(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for StoredData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            StoredData::Buffered(data) => data,
+            StoredData::Mapped(map) => map,
+        }
+    }
+}
+
+impl From<Vec<u8>> for StoredData {
+    fn from(data: Vec<u8>) -> Self {
+        StoredData::Buffered(data)
+    }
+}
+
+impl StoredData {
+    /// The contents as a buffer, copying them out of a mapping
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            StoredData::Buffered(data) => data,
+            StoredData::Mapped(map) => map.to_vec(),
+        }
+    }
+}
+
+/// Reads the object file at `path`, mapping it when it is `transformed` before being served and
+/// its size is within the configured band. A file that cannot be mapped is read instead.
+pub fn read_blocking(path: &Path, config: &MappedReadConfig, transformed: bool) -> std::io::Result<StoredData> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if transformed && config.maps(len) {
+        // SAFETY: fily never writes to a stored object file in place; objects are replaced by
+        // renaming a new file over them and deleted by unlinking, neither of which changes the
+        // contents of a file that is already mapped
+        match unsafe { Mmap::map(&file) } {
+            Ok(map) => {
+                #[cfg(unix)]
+                let _ = map.advise(memmap2::Advice::Sequential);
+                metrics::counter!("fily_mapped_reads_total", "result" => "mapped").increment(1);
+                return Ok(StoredData::Mapped(map));
+            }
+            Err(e) => {
+                metrics::counter!("fily_mapped_reads_total", "result" => "fallback").increment(1);
+                debug!("Reading {} instead of mapping it: {}", path.display(), e);
+            }
+        }
+    }
+    let mut data = Vec::with_capacity(len as usize);
+    file.read_to_end(&mut data)?;
+    Ok(StoredData::Buffered(data))
+}
+
+/// [`read_blocking`] for async handlers; files that are not mapped are read as by `tokio::fs::read`
+pub async fn read(path: PathBuf, config: &MappedReadConfig, transformed: bool) -> std::io::Result<StoredData> {
+    if !transformed || !config.enabled {
+        return Ok(StoredData::Buffered(tokio::fs::read(path).await?));
+    }
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || read_blocking(&path, &config, true)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_maps_transformed_objects_in_band() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = MappedReadConfig {
+            enabled: true,
+            min_size: 4,
+            max_size: 8,
+        };
+        let write = |name: &str, len: usize| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, vec![7u8; len]).unwrap();
+            path
+        };
+        let (small, medium, large) = (write("small", 3), write("medium", 6), write("large", 9));
+
+        let mapped = read(medium.clone(), &config, true).await.unwrap();
+        assert!(matches!(mapped, StoredData::Mapped(_)));
+        assert_eq!(mapped.into_vec(), vec![7u8; 6]);
+
+        for (path, transformed) in [(small, true), (large, true), (medium, false)] {
+            let data = read(path, &config, transformed).await.unwrap();
+            assert!(matches!(data, StoredData::Buffered(_)));
+        }
+        assert!(read(dir.path().join("missing"), &config, true).await.is_err());
+        assert!(MappedReadConfig {
+            min_size: 9,
+            ..config
+        }
+        .validate()
+        .is_err());
+    }
+}
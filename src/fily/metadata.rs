@@ -1,9 +1,11 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use mime_guess::MimeGuess;
 
-use super::path_security::construct_safe_metadata_path;
+use super::commit::write_synced;
+use super::path_security::{construct_safe_metadata_path, construct_metadata_staging_path};
+use super::timestamp::http_date;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ObjectMetadata {
@@ -13,6 +15,22 @@ pub struct ObjectMetadata {
     pub last_modified: String,
     pub user_metadata: HashMap<String, String>,
     pub content_sha256: Option<String>, // SHA256 hash of original content for signature validation
+    #[serde(default)]
+    pub checksum_algorithm: Option<String>, // Algorithm of a client supplied x-amz-checksum-* value
+    #[serde(default)]
+    pub checksum_value: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>, // Object tags from x-amz-tagging
+    #[serde(default)]
+    pub part_sizes: Vec<u64>, // Plaintext size of each part of a multipart object, empty for single-part objects
+    #[serde(default)]
+    pub encryption_id: Option<String>, // ID the object's encryption key is derived from, None for legacy bucket/key objects
+    #[serde(default)]
+    pub created_by: Option<String>, // Access key that first wrote the key, None for objects written before provenance
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub modified_by: Option<String>, // Access key of the write that produced the current contents or metadata
 }
 
 impl ObjectMetadata {
@@ -26,9 +44,7 @@ impl ObjectMetadata {
             detect_content_type(file_path)
         });
 
-        let last_modified = chrono::Utc::now()
-            .format("%a, %d %b %Y %H:%M:%S GMT")
-            .to_string();
+        let last_modified = http_date(chrono::Utc::now());
 
         Self {
             content_type,
@@ -37,6 +53,14 @@ impl ObjectMetadata {
             last_modified,
             user_metadata: HashMap::new(),
             content_sha256: None,
+            checksum_algorithm: None,
+            checksum_value: None,
+            tags: HashMap::new(),
+            part_sizes: Vec::new(),
+            encryption_id: None,
+            created_by: None,
+            created_at: None,
+            modified_by: None,
         }
     }
 
@@ -63,6 +87,39 @@ impl ObjectMetadata {
     pub fn get_content_sha256(&self) -> Option<&String> {
         self.content_sha256.as_ref()
     }
+
+    /// Byte range `[start, end)` of the 1-based part and the number of parts; single-part
+    /// objects consist of one part spanning the whole object
+    pub fn part_range(&self, part_number: usize) -> Option<(u64, u64, usize)> {
+        if self.part_sizes.is_empty() {
+            return (part_number == 1).then_some((0, self.content_length, 1));
+        }
+
+        let size = *self.part_sizes.get(part_number.checked_sub(1)?)?;
+        let start: u64 = self.part_sizes[..part_number - 1].iter().sum();
+        Some((start, start + size, self.part_sizes.len()))
+    }
+
+    pub fn set_checksum(&mut self, algorithm: &str, value: String) {
+        self.checksum_algorithm = Some(algorithm.to_string());
+        self.checksum_value = Some(value);
+    }
+
+    /// Records a write by `access_key` at `last_modified`. Overwrites keep the creator of the
+    /// `previous` object at the key, so provenance survives until the key is deleted.
+    pub fn record_write(&mut self, access_key: &str, previous: Option<&ObjectMetadata>) {
+        match previous {
+            Some(previous) => {
+                self.created_by = previous.created_by.clone();
+                self.created_at = previous.created_at.clone();
+            }
+            None => {
+                self.created_by = Some(access_key.to_string());
+                self.created_at = Some(self.last_modified.clone());
+            }
+        }
+        self.modified_by = Some(access_key.to_string());
+    }
 }
 
 pub fn detect_content_type(file_path: &str) -> String {
@@ -89,6 +146,19 @@ pub fn extract_user_metadata(headers: &hyper::HeaderMap) -> HashMap<String, Stri
     user_metadata
 }
 
+/// Parses the URL-encoded `x-amz-tagging` header, e.g. `project=fily&env=prod`
+pub fn extract_tags(headers: &hyper::HeaderMap) -> HashMap<String, String> {
+    headers
+        .get("x-amz-tagging")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 pub async fn save_metadata(
     storage_path: &Path,
     bucket: &str,
@@ -99,11 +169,31 @@ pub async fn save_metadata(
     let metadata_file = construct_safe_metadata_path(storage_path, bucket, object)
         .map_err(|e| anyhow::anyhow!("Metadata path security violation: {}", e))?;
     
-    let metadata_json = serde_json::to_string_pretty(metadata)?;
-    tokio::fs::write(metadata_file, metadata_json).await?;
+    // Replaced with a rename, so a crash never leaves half-written JSON behind
+    let staged = stage_metadata(storage_path, bucket, metadata).await?;
+    if let Err(e) = tokio::fs::rename(&staged, metadata_file).await {
+        let _ = tokio::fs::remove_file(&staged).await;
+        return Err(e.into());
+    }
     Ok(())
 }
 
+/// Writes metadata to a durable staged file, to be renamed into place
+pub(crate) async fn stage_metadata(
+    storage_path: &Path,
+    bucket: &str,
+    metadata: &ObjectMetadata,
+) -> anyhow::Result<PathBuf> {
+    let staged = construct_metadata_staging_path(storage_path, bucket)
+        .map_err(|e| anyhow::anyhow!("Metadata path security violation: {}", e))?;
+    let metadata_json = serde_json::to_string_pretty(metadata)?;
+    if let Err(e) = write_synced(&staged, metadata_json.as_bytes()).await {
+        let _ = tokio::fs::remove_file(&staged).await;
+        return Err(e.into());
+    }
+    Ok(staged)
+}
+
 pub async fn load_metadata(
     storage_path: &Path,
     bucket: &str,
@@ -113,12 +203,10 @@ pub async fn load_metadata(
     let metadata_file = construct_safe_metadata_path(storage_path, bucket, object)
         .map_err(|e| anyhow::anyhow!("Metadata path security violation: {}", e))?;
     
-    if !metadata_file.exists() {
+    let Some(metadata_json) = super::replica::read_store_file(storage_path, &metadata_file).await? else {
         return Ok(None);
-    }
-    
-    let metadata_json = tokio::fs::read_to_string(metadata_file).await?;
-    let metadata: ObjectMetadata = serde_json::from_str(&metadata_json)?;
+    };
+    let metadata: ObjectMetadata = serde_json::from_slice(&metadata_json)?;
     Ok(Some(metadata))
 }
 
@@ -165,6 +253,57 @@ mod tests {
         assert_eq!(user_metadata.len(), 2);
     }
 
+    #[test]
+    fn test_extract_tags() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("x-amz-tagging", "project=fily&team=storage%20ops".parse().unwrap());
+
+        let tags = extract_tags(&headers);
+
+        assert_eq!(tags.get("project"), Some(&"fily".to_string()));
+        assert_eq!(tags.get("team"), Some(&"storage ops".to_string()));
+        assert!(extract_tags(&hyper::HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_part_range() {
+        let mut metadata = ObjectMetadata::new(None, 25, "\"abc123\"".to_string(), "a.bin");
+        assert_eq!(metadata.part_range(1), Some((0, 25, 1)));
+        assert_eq!(metadata.part_range(2), None);
+
+        metadata.part_sizes = vec![10, 10, 5];
+        assert_eq!(metadata.part_range(1), Some((0, 10, 3)));
+        assert_eq!(metadata.part_range(3), Some((20, 25, 3)));
+        assert_eq!(metadata.part_range(4), None);
+        assert_eq!(metadata.part_range(0), None);
+    }
+
+    #[test]
+    fn test_record_write() {
+        let mut created = ObjectMetadata::new(None, 1, "\"a\"".to_string(), "a.bin");
+        created.record_write("AKIACREATOR", None);
+        assert_eq!(created.created_by.as_deref(), Some("AKIACREATOR"));
+        assert_eq!(created.created_at.as_ref(), Some(&created.last_modified));
+        assert_eq!(created.modified_by.as_deref(), Some("AKIACREATOR"));
+
+        let mut overwritten = ObjectMetadata::new(None, 2, "\"b\"".to_string(), "a.bin");
+        overwritten.last_modified = "Tue, 02 Jan 2024 00:00:00 GMT".to_string();
+        overwritten.record_write("AKIAEDITOR", Some(&created));
+        assert_eq!(overwritten.created_by.as_deref(), Some("AKIACREATOR"));
+        assert_eq!(overwritten.created_at, created.created_at);
+        assert_eq!(overwritten.modified_by.as_deref(), Some("AKIAEDITOR"));
+
+        // Objects stored before provenance was recorded have no known creator
+        let legacy: ObjectMetadata = serde_json::from_str(
+            r#"{"content_type":"text/plain","content_length":1,"etag":"\"a\"","last_modified":"Mon, 01 Jan 2024 00:00:00 GMT","user_metadata":{},"content_sha256":null}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.created_by, None);
+        overwritten.record_write("AKIAEDITOR", Some(&legacy));
+        assert_eq!(overwritten.created_by, None);
+        assert_eq!(overwritten.modified_by.as_deref(), Some("AKIAEDITOR"));
+    }
+
     #[tokio::test]
     async fn test_save_and_load_metadata() {
         let temp_dir = TempDir::new().unwrap();
@@ -0,0 +1,281 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hmac::{Hmac, Mac};
+use hyper::Method;
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use super::bucket_subresource::has_subresource;
+use super::logging::REDACTED;
+use super::request_path::RequestTarget;
+use super::request_info::RequestInfo;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+
+/// Header carrying the authenticator code, as `serial code` like S3's MFA delete or just `code`
+pub const MFA_HEADER: &str = "x-amz-mfa";
+
+/// Shortest accepted TOTP secret, 80 bits as RFC 4226 requires
+pub const MIN_SECRET_LEN: usize = 10;
+
+/// Wrong codes in a row after which every protected delete is refused for `LOCKOUT`
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(300);
+
+/// TOTP time step; codes of the neighbouring steps are accepted for clock drift
+const STEP_SECS: u64 = 30;
+
+/// Requiring an authenticator code besides the SigV4 signature for deletes that wipe data, so
+/// one leaked access key cannot remove buckets
+#[derive(Clone, Default, PartialEq)]
+pub struct MfaDeleteConfig {
+    /// Base32 TOTP secret shared with the operators' authenticator app (protection is off when unset)
+    pub secret: Option<String>,
+    /// Also protect single-object deletes, not only bucket and prefix deletes
+    pub objects: bool,
+}
+
+impl fmt::Debug for MfaDeleteConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MfaDeleteConfig")
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("objects", &self.objects)
+            .finish()
+    }
+}
+
+impl MfaDeleteConfig {
+    /// Decoded TOTP secret, `None` when protection is off
+    pub fn key(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(secret) = &self.secret else {
+            return Ok(None);
+        };
+        let key = decode_base32(secret).ok_or_else(|| anyhow::anyhow!("FILY_MFA_DELETE_SECRET is not valid base32"))?;
+        if key.len() < MIN_SECRET_LEN {
+            return Err(anyhow::anyhow!(
+                "FILY_MFA_DELETE_SECRET must decode to at least {} bytes",
+                MIN_SECRET_LEN
+            ));
+        }
+        Ok(Some(key))
+    }
+}
+
+/// RFC 4648 base32 as authenticator apps show secrets: case-insensitive, spaces and padding ignored
+fn decode_base32(s: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u64;
+    let mut bit_count = 0;
+    let mut bytes = vec![];
+    for c in s.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Six-digit RFC 6238 code (HMAC-SHA1) of the time step `counter`
+fn totp(key: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:06}", code % 1_000_000)
+}
+
+/// Whether the code at the end of an `x-amz-mfa` value is valid at `now`
+fn verify(key: &[u8], header: &str, now: SystemTime) -> bool {
+    let Some(code) = header.split_whitespace().last() else {
+        return false;
+    };
+    let step = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP_SECS;
+    [step.saturating_sub(1), step, step + 1]
+        .iter()
+        .any(|&counter| bool::from(totp(key, counter).as_bytes().ct_eq(code.as_bytes())))
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Checks authenticator codes, refusing every protected delete for a while after repeated
+/// wrong codes so they cannot be guessed with a stolen access key
+pub struct MfaDelete {
+    key: Vec<u8>,
+    objects: bool,
+    failures: Mutex<Failures>,
+}
+
+impl MfaDelete {
+    /// `None` when protection is off
+    pub fn new(config: &MfaDeleteConfig) -> anyhow::Result<Option<Self>> {
+        Ok(config.key()?.map(|key| Self {
+            key,
+            objects: config.objects,
+            failures: Mutex::new(Failures {
+                count: 0,
+                locked_until: None,
+            }),
+        }))
+    }
+
+    /// Whether a DELETE needs a code: bucket deletes, including `?prefix=` deletes of everything
+    /// under a prefix, and object deletes when configured. Bucket subresources and `/_fily`
+    /// extensions other than WebDAV are settings or uploads, not stored data.
+    fn protects(&self, req: &Request) -> bool {
+        let path = req.uri().path();
+        if path.starts_with("/_fily/") && !path.starts_with("/_fily/dav/") {
+            return false;
+        }
+        match RequestInfo::of(req).target {
+            RequestTarget::Bucket { key: None, .. } => {
+                !has_subresource(req, "ownershipControls") && !has_subresource(req, "publicAccessBlock")
+            }
+            RequestTarget::Bucket { key: Some(_), .. } => self.objects,
+            _ => false,
+        }
+    }
+
+    fn check(&self, header: Option<&str>, now: SystemTime) -> Result<(), &'static str> {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.locked_until.is_some_and(|until| Instant::now() < until) {
+            return Err("Too many wrong MFA codes; protected deletes are refused for now");
+        }
+        let Some(header) = header else {
+            return Err("Mfa Authentication must be used for this request");
+        };
+        if verify(&self.key, header, now) {
+            failures.count = 0;
+            failures.locked_until = None;
+            return Ok(());
+        }
+        failures.count += 1;
+        if failures.count >= MAX_FAILURES {
+            warn!("{} wrong MFA codes in a row, refusing protected deletes for {}s", failures.count, LOCKOUT.as_secs());
+            failures.count = 0;
+            failures.locked_until = Some(Instant::now() + LOCKOUT);
+        }
+        Err("The MFA code is not valid")
+    }
+}
+
+/// Middleware refusing protected deletes without a valid `x-amz-mfa` code
+pub async fn require_mfa(req: Request, next: Next) -> Response {
+    if req.method() != Method::DELETE {
+        return next.run(req).await;
+    }
+    let Some(mfa) = req.extensions().get::<Arc<MfaDelete>>().cloned() else {
+        return next.run(req).await;
+    };
+    if !mfa.protects(&req) {
+        return next.run(req).await;
+    }
+    let path = req.uri().path().to_string();
+
+    let header = req.headers().get(MFA_HEADER).and_then(|value| value.to_str().ok());
+    match mfa.check(header, SystemTime::now()) {
+        Ok(()) => {
+            metrics::counter!("fily_mfa_delete_total", "result" => "allowed").increment(1);
+            next.run(req).await
+        }
+        Err(message) => {
+            metrics::counter!("fily_mfa_delete_total", "result" => "denied").increment(1);
+            warn!("Refusing DELETE {} without a valid MFA code", path);
+            S3AppError::with_message_and_resource(S3ErrorCode::AccessDenied, message.to_string(), path).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    // RFC 6238 test secret "12345678901234567890"
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_totp_matches_rfc_6238() {
+        let key = decode_base32(SECRET).unwrap();
+        assert_eq!(key, b"12345678901234567890");
+        assert_eq!(decode_base32("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(), key);
+        assert_eq!(decode_base32("not base32!"), None);
+
+        // The last six digits of the RFC's eight-digit SHA-1 codes
+        assert!(verify(&key, "287082", at(59)));
+        assert!(verify(&key, "arn:aws:iam::123456789012:mfa/user 081804", at(1111111109)));
+        // Neighbouring steps are accepted for clock drift, older ones are not
+        assert!(verify(&key, "081804", at(1111111109 + 30)));
+        assert!(!verify(&key, "081804", at(1111111109 + 90)));
+        assert!(!verify(&key, "", at(59)));
+    }
+
+    #[test]
+    fn test_protected_deletes() {
+        let config = |objects| MfaDeleteConfig {
+            secret: Some(SECRET.to_string()),
+            objects,
+        };
+        let protects = |mfa: &MfaDelete, uri: &str| {
+            mfa.protects(&Request::delete(uri).body(Body::empty()).unwrap())
+        };
+
+        let mfa = MfaDelete::new(&config(false)).unwrap().unwrap();
+        assert!(protects(&mfa, "/photos"));
+        assert!(protects(&mfa, "/photos?prefix=2024/"));
+        assert!(protects(&mfa, "/_fily/dav/photos"));
+        assert!(!protects(&mfa, "/photos?publicAccessBlock"));
+        assert!(!protects(&mfa, "/photos/a.jpg"));
+        assert!(!protects(&mfa, "/_fily/uploads/photos/a.jpg/upload-1"));
+        assert!(!protects(&mfa, "/_fily/presigned-urls/token"));
+
+        let mfa = MfaDelete::new(&config(true)).unwrap().unwrap();
+        assert!(protects(&mfa, "/photos/a.jpg"));
+        assert!(protects(&mfa, "/_fily/dav/photos/a.jpg"));
+
+        assert!(MfaDelete::new(&MfaDeleteConfig::default()).unwrap().is_none());
+        assert!(MfaDelete::new(&MfaDeleteConfig {
+            secret: Some("GEZDGNBV".to_string()),
+            objects: false,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_wrong_codes_lock_out() {
+        let mfa = MfaDelete::new(&MfaDeleteConfig {
+            secret: Some(SECRET.to_string()),
+            objects: false,
+        })
+        .unwrap()
+        .unwrap();
+
+        assert!(mfa.check(None, at(59)).is_err());
+        assert!(mfa.check(Some("287082"), at(59)).is_ok());
+        for _ in 0..MAX_FAILURES {
+            assert!(mfa.check(Some("000000"), at(59)).is_err());
+        }
+        // Even the right code is refused until the lockout ends
+        assert!(mfa.check(Some("287082"), at(59)).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::delete_bucket::TRASH_DIR;
+use super::path_security::{
+    bucket_metadata_dir, decode_key_segment, encode_key_segment, encode_object_name, external_staging_root,
+    metadata_dir_name, register_metadata_layout, staging_dir, MetadataLayout,
+};
+use super::tenancy::namespace_roots;
+
+/// File in the storage root recording the layout version of the store
+pub const LAYOUT_FILE: &str = ".fily-version";
+
+/// Layout written by this build
+pub const CURRENT_LAYOUT: u32 = 2;
+
+/// Directory in the storage root holding the layout a migration started from
+pub const BACKUP_DIR: &str = ".fily-backup";
+
+/// File in the storage root recording where the store keeps metadata, absent for the default
+pub const METADATA_LAYOUT_FILE: &str = ".fily-metadata-layout";
+
+/// Layout of stores written before the version file existed
+const UNVERSIONED_LAYOUT: u32 = 1;
+
+/// Where a store keeps metadata, and how far a move to another place got
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct LayoutRecord {
+    layout: MetadataLayout,
+    /// Layout metadata is being copied to; what is already there is only replaced while this is
+    /// recorded, so a directory of user keys is never mistaken for an interrupted copy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relocating_to: Option<MetadataLayout>,
+    /// Layout metadata was moved from, whose copy is left to remove
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<MetadataLayout>,
+}
+
+/// How the store is upgraded when it was written by an older fily
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MigrationConfig {
+    /// Migrate at startup; otherwise an outdated store stops fily until `fily migrate` runs
+    pub auto: bool,
+    /// Keep the layout a migration started from under `.fily-backup`, restored automatically if
+    /// the migration fails and by `fily migrate --rollback` afterwards
+    pub backup: bool,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            backup: false,
+        }
+    }
+}
+
+/// One step of the on-disk layout. Steps run synchronously on a blocking thread before requests
+/// are served, and must be safe to run again after an interruption. They rename or replace
+/// files rather than editing them in place, as backups share unchanged files through hard links.
+struct Migration {
+    /// Layout the store has once the step is done
+    to: u32,
+    description: &'static str,
+    run: fn(&Path) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    to: 2,
+    description: "store keys under their percent-encoded names",
+    run: encode_legacy_names,
+}];
+
+/// Buckets of the store, skipping fily's own dot directories
+fn bucket_dirs(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut buckets = Vec::new();
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            buckets.push(entry.path());
+        }
+    }
+    buckets.sort();
+    Ok(buckets)
+}
+
+/// Layout version of the store, `None` for a store without buckets or version file
+pub fn layout_version(root: &Path) -> Result<Option<u32>> {
+    match std::fs::read_to_string(root.join(LAYOUT_FILE)) {
+        Ok(version) => version
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("Invalid {} in {}: {:?}", LAYOUT_FILE, root.display(), version.trim())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let has_buckets = root.is_dir() && !bucket_dirs(root)?.is_empty();
+            Ok(has_buckets.then_some(UNVERSIONED_LAYOUT))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_layout_version(root: &Path, version: u32) -> std::io::Result<()> {
+    let staged = root.join(format!("{}.tmp", LAYOUT_FILE));
+    std::fs::write(&staged, format!("{}\n", version))?;
+    std::fs::rename(staged, root.join(LAYOUT_FILE))
+}
+
+fn backup_path(root: &Path, version: u32) -> PathBuf {
+    root.join(BACKUP_DIR).join(format!("layout-{}", version))
+}
+
+fn read_layout_record(root: &Path) -> Result<LayoutRecord> {
+    match std::fs::read(root.join(METADATA_LAYOUT_FILE)) {
+        Ok(json) => serde_json::from_slice(&json)
+            .with_context(|| format!("Invalid {} in {}", METADATA_LAYOUT_FILE, root.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(LayoutRecord::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_layout_record(root: &Path, record: &LayoutRecord) -> Result<()> {
+    let staged = root.join(format!("{}.tmp", METADATA_LAYOUT_FILE));
+    std::fs::write(&staged, serde_json::to_vec_pretty(record)?)?;
+    std::fs::rename(staged, root.join(METADATA_LAYOUT_FILE))?;
+    Ok(())
+}
+
+/// Makes the path helpers find metadata where the store at `root` recorded it. Run when the store
+/// is opened, before anything reads metadata.
+pub fn open_metadata_layout(root: &Path) -> Result<MetadataLayout> {
+    let layout = read_layout_record(root)?.layout;
+    register_metadata_layout(root, layout.clone());
+    Ok(layout)
+}
+
+/// `layout` as it applies to the tenant namespace at `namespace` of the store at `root`
+fn namespace_layout(layout: &MetadataLayout, root: &Path, namespace: &Path) -> MetadataLayout {
+    layout.nested(namespace.strip_prefix(root).unwrap_or(Path::new("")))
+}
+
+/// Brings the store and its tenant namespaces to `CURRENT_LAYOUT`, one step at a time, recording
+/// the version reached after each step so an interrupted upgrade resumes where it stopped, then
+/// moves metadata to where `metadata_layout` keeps it. Returns the layout the store had.
+pub async fn migrate(
+    root: &Path,
+    config: &MigrationConfig,
+    metadata_layout: &MetadataLayout,
+    startup: bool,
+) -> Result<Option<u32>> {
+    let root = root.to_path_buf();
+    let config = config.clone();
+    let metadata_layout = metadata_layout.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut from = None;
+        for namespace in namespace_roots(&root)? {
+            let version = migrate_blocking(&namespace, &config, startup)?;
+            if namespace == root {
+                from = version;
+            }
+        }
+        relocate_metadata(&root, &metadata_layout, &config, startup)?;
+        Ok(from)
+    })
+    .await?
+}
+
+fn migrate_blocking(root: &Path, config: &MigrationConfig, startup: bool) -> Result<Option<u32>> {
+    let from = match layout_version(root)? {
+        Some(version) => version,
+        None => {
+            // A new store starts out in the current layout
+            write_layout_version(root, CURRENT_LAYOUT)?;
+            return Ok(None);
+        }
+    };
+    if from > CURRENT_LAYOUT {
+        return Err(anyhow!(
+            "Storage layout {} in {} was written by a newer fily; this version supports up to layout {}",
+            from,
+            root.display(),
+            CURRENT_LAYOUT
+        ));
+    }
+    if from == CURRENT_LAYOUT {
+        return Ok(Some(from));
+    }
+    if startup && !config.auto {
+        return Err(anyhow!(
+            "Storage layout {} in {} needs migrating to layout {}; run `fily migrate` or set FILY_MIGRATE_ON_START=true",
+            from,
+            root.display(),
+            CURRENT_LAYOUT
+        ));
+    }
+
+    if config.backup {
+        let backup = backup_path(root, from);
+        info!("Backing up storage layout {} to {}", from, backup.display());
+        if backup.exists() {
+            std::fs::remove_dir_all(&backup)?;
+        }
+        for bucket in bucket_dirs(root)? {
+            let name = bucket.file_name().unwrap_or_default();
+            link_tree(&bucket, &backup.join(name), &staging_dir(root, &name.to_string_lossy()))?;
+        }
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.to > from) {
+        info!("Migrating storage to layout {}: {}", migration.to, migration.description);
+        let result = (migration.run)(root).and_then(|_| Ok(write_layout_version(root, migration.to)?));
+        if let Err(e) = result {
+            let e = e.context(format!("Migration to storage layout {} failed", migration.to));
+            if !config.backup {
+                return Err(e.context("Fix the cause and start again; completed steps are kept"));
+            }
+            return match restore(root, from) {
+                Ok(()) => Err(e.context(format!("Storage was rolled back to layout {}", from))),
+                Err(restore_error) => Err(e.context(format!(
+                    "Rolling back to layout {} failed as well ({}); the backup is in {}",
+                    from,
+                    restore_error,
+                    backup_path(root, from).display()
+                ))),
+            };
+        }
+        metrics::counter!("fily_storage_migrations_total").increment(1);
+    }
+    info!("Storage migrated from layout {} to {}", from, CURRENT_LAYOUT);
+    Ok(Some(from))
+}
+
+/// Moves the metadata of every bucket to where `layout` keeps it, when the store recorded another
+/// place. Copies are made first and the old ones removed once the new place is recorded, so an
+/// interrupted move resumes at the next start.
+fn relocate_metadata(root: &Path, layout: &MetadataLayout, config: &MigrationConfig, startup: bool) -> Result<()> {
+    let mut record = read_layout_record(root)?;
+    if let Some(previous) = record.previous.take() {
+        remove_metadata(root, &previous)?;
+        write_layout_record(root, &record)?;
+    }
+    if record.layout == *layout {
+        return Ok(());
+    }
+    let is_new = namespace_roots(root)?
+        .iter()
+        .try_fold(true, |empty, namespace| Ok::<_, std::io::Error>(empty && bucket_dirs(namespace)?.is_empty()))?;
+    if startup && !config.auto && !is_new {
+        return Err(anyhow!(
+            "Metadata in {} is kept as {:?}, not as configured ({:?}); run `fily migrate` or set FILY_MIGRATE_ON_START=true",
+            root.display(),
+            record.layout,
+            layout
+        ));
+    }
+
+    if record.relocating_to.as_ref() != Some(layout) {
+        for namespace in namespace_roots(root)? {
+            for bucket in bucket_dirs(&namespace)? {
+                let name = bucket.file_name().unwrap_or_default().to_string_lossy();
+                let target = namespace_layout(layout, root, &namespace).bucket_dir(&namespace, &name);
+                if target.exists() {
+                    return Err(anyhow!(
+                        "Cannot move metadata to {}: it already exists; move it out of the way first",
+                        target.display()
+                    ));
+                }
+            }
+        }
+        record.relocating_to = Some(layout.clone());
+        write_layout_record(root, &record)?;
+    }
+
+    info!("Moving metadata in {} from {:?} to {:?}", root.display(), record.layout, layout);
+    for namespace in namespace_roots(root)? {
+        for bucket in bucket_dirs(&namespace)? {
+            let name = bucket.file_name().unwrap_or_default().to_string_lossy();
+            let source = namespace_layout(&record.layout, root, &namespace).bucket_dir(&namespace, &name);
+            let target = namespace_layout(layout, root, &namespace).bucket_dir(&namespace, &name);
+            if target.exists() {
+                std::fs::remove_dir_all(&target)?;
+            }
+            if source.exists() {
+                // Staged files belong to writes recovery already finished or discarded
+                link_tree(&source, &target, &source.join("staging"))?;
+            }
+        }
+    }
+
+    let previous = std::mem::replace(&mut record.layout, layout.clone());
+    record.relocating_to = None;
+    record.previous = Some(previous.clone());
+    write_layout_record(root, &record)?;
+    register_metadata_layout(root, layout.clone());
+
+    remove_metadata(root, &previous)?;
+    record.previous = None;
+    write_layout_record(root, &record)?;
+    metrics::counter!("fily_storage_migrations_total").increment(1);
+    info!("Metadata in {} moved to {:?}", root.display(), layout);
+    Ok(())
+}
+
+/// Removes the metadata `layout` kept, once it was copied elsewhere
+fn remove_metadata(root: &Path, layout: &MetadataLayout) -> Result<()> {
+    for namespace in namespace_roots(root)? {
+        let namespace_layout = namespace_layout(layout, root, &namespace);
+        let mut dirs: Vec<PathBuf> = bucket_dirs(&namespace)?
+            .iter()
+            .map(|bucket| namespace_layout.bucket_dir(&namespace, &bucket.file_name().unwrap_or_default().to_string_lossy()))
+            .collect();
+        if let MetadataLayout::External(_) = layout {
+            dirs.push(external_staging_root(&namespace));
+        }
+        for dir in dirs {
+            match std::fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Restores the most recent backup, discarding everything written to its buckets since the
+/// migration. Buckets created after it are left alone. Returns the restored layout.
+pub async fn rollback(root: &Path) -> Result<u32> {
+    let root = root.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let version = std::fs::read_dir(root.join(BACKUP_DIR))
+            .with_context(|| format!("No backup in {}", root.join(BACKUP_DIR).display()))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_prefix("layout-")?.parse::<u32>().ok())
+            .max()
+            .ok_or_else(|| anyhow!("No backup in {}", root.join(BACKUP_DIR).display()))?;
+        restore(&root, version)?;
+        Ok(version)
+    })
+    .await?
+}
+
+/// Swaps the buckets of the backup of `version` back in; the migrated ones go to the trash,
+/// which is purged at the next start
+fn restore(root: &Path, version: u32) -> Result<()> {
+    let backup = backup_path(root, version);
+    let trash = root.join(TRASH_DIR);
+    std::fs::create_dir_all(&trash)?;
+    for saved in bucket_dirs(&backup)? {
+        let current = root.join(saved.file_name().unwrap_or_default());
+        if current.exists() {
+            std::fs::rename(&current, trash.join(uuid::Uuid::new_v4().simple().to_string()))?;
+        }
+        std::fs::rename(&saved, &current)?;
+    }
+    write_layout_version(root, version)?;
+    std::fs::remove_dir_all(&backup)?;
+    warn!("Storage rolled back to layout {} from {}", version, backup.display());
+    Ok(())
+}
+
+/// Mirrors `source` at `dest` with hard links, or copies where linking fails, skipping `skip`
+fn link_tree(source: &Path, dest: &Path, skip: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dest)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let path = entry.path();
+        let target = dest.join(entry.file_name());
+        if path == skip {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            link_tree(&path, &target, skip)?;
+        } else if std::fs::hard_link(&path, &target).is_err() {
+            std::fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a stored file name is what the key segment it decodes to is stored under now
+fn is_encoded(name: &str) -> bool {
+    encode_key_segment(&decode_key_segment(name)) == name
+}
+
+/// Object files of a bucket as `/`-separated paths relative to it
+fn object_files(bucket: &Path, dir: &Path, metadata_dir: Option<&str>, files: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if dir == bucket && metadata_dir.is_some_and(|name| entry.file_name() == name) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            object_files(bucket, &path, metadata_dir, files)?;
+        } else if let Some(relative) = path.strip_prefix(bucket).ok().and_then(|p| p.to_str()) {
+            files.push(relative.replace(std::path::MAIN_SEPARATOR, "/"));
+        }
+    }
+    Ok(())
+}
+
+/// Layout 2: keys used to be stored under their literal names, which Windows cannot hold and
+/// which were only found through a fallback lookup. Objects with a segment no encoded name can
+/// have are moved, with their metadata, to where `construct_safe_path` puts them now. Names that
+/// read the same either way are left, as the fallback still finds them.
+fn encode_legacy_names(root: &Path) -> Result<()> {
+    let metadata_dir_name = metadata_dir_name(root);
+    for bucket in bucket_dirs(root)? {
+        let mut files = Vec::new();
+        object_files(&bucket, &bucket, metadata_dir_name.as_deref(), &mut files)?;
+        let metadata_dir = bucket_metadata_dir(root, &bucket.file_name().unwrap_or_default().to_string_lossy());
+        let metadata_name = |key: &str| format!("{}.json", key.replace('/', "_"));
+
+        for key in files.into_iter().filter(|key| !key.split('/').all(is_encoded)) {
+            let encoded = encode_object_name(&key);
+            let dest = bucket.join(&encoded);
+            if dest.exists() {
+                warn!("Leaving {}/{} in place: {} already exists", bucket.display(), key, encoded);
+                continue;
+            }
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let source = bucket.join(&key);
+            std::fs::rename(&source, &dest)?;
+
+            let legacy_metadata = metadata_dir.join(metadata_name(&key));
+            let metadata = metadata_dir.join(metadata_name(&encoded));
+            if legacy_metadata.exists() && !metadata.exists() {
+                std::fs::rename(&legacy_metadata, &metadata)?;
+            }
+
+            // Directories the object was the last entry of
+            let mut dir = source.parent();
+            while let Some(current) = dir.filter(|d| *d != bucket) {
+                if std::fs::remove_dir(current).is_err() {
+                    break;
+                }
+                dir = current.parent();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn legacy_store() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let bucket = dir.path().join("logs");
+        std::fs::create_dir_all(bucket.join("2024:01")).unwrap();
+        std::fs::create_dir_all(bucket.join(".fily-metadata")).unwrap();
+        std::fs::write(bucket.join("2024:01/app.log"), b"legacy").unwrap();
+        std::fs::write(bucket.join(".fily-metadata/2024:01_app.log.json"), b"{}").unwrap();
+        std::fs::write(bucket.join("plain.txt"), b"plain").unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_new_store_starts_at_current_layout() {
+        let dir = TempDir::new().unwrap();
+        let from = migrate(dir.path(), &MigrationConfig::default(), &MetadataLayout::default(), true).await;
+        assert_eq!(from.unwrap(), None);
+        assert_eq!(layout_version(dir.path()).unwrap(), Some(CURRENT_LAYOUT));
+    }
+
+    #[tokio::test]
+    async fn test_migrates_legacy_names() {
+        let dir = legacy_store();
+        assert_eq!(layout_version(dir.path()).unwrap(), Some(1));
+
+        let from = migrate(dir.path(), &MigrationConfig::default(), &MetadataLayout::default(), true).await;
+        assert_eq!(from.unwrap(), Some(1));
+
+        let bucket = dir.path().join("logs");
+        assert_eq!(std::fs::read(bucket.join("2024%3A01/app.log")).unwrap(), b"legacy");
+        assert!(bucket.join(".fily-metadata/2024%3A01_app.log.json").exists());
+        assert!(!bucket.join("2024:01").exists());
+        assert!(bucket.join("plain.txt").exists());
+        assert_eq!(layout_version(dir.path()).unwrap(), Some(CURRENT_LAYOUT));
+        assert!(!dir.path().join(BACKUP_DIR).exists());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_newer_or_unapproved_layouts() {
+        let dir = legacy_store();
+        let manual = MigrationConfig {
+            auto: false,
+            backup: false,
+        };
+        assert!(migrate(dir.path(), &manual, &MetadataLayout::default(), true).await.is_err());
+        assert_eq!(layout_version(dir.path()).unwrap(), Some(1));
+        // `fily migrate` runs what startup refused
+        migrate(dir.path(), &manual, &MetadataLayout::default(), false).await.unwrap();
+
+        std::fs::write(dir.path().join(LAYOUT_FILE), format!("{}\n", CURRENT_LAYOUT + 1)).unwrap();
+        assert!(migrate(dir.path(), &MigrationConfig::default(), &MetadataLayout::default(), true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_rollback() {
+        let dir = legacy_store();
+        let config = MigrationConfig {
+            auto: true,
+            backup: true,
+        };
+        migrate(dir.path(), &config, &MetadataLayout::default(), true).await.unwrap();
+        let backup = backup_path(dir.path(), 1).join("logs");
+        assert_eq!(std::fs::read(backup.join("2024:01/app.log")).unwrap(), b"legacy");
+
+        assert_eq!(rollback(dir.path()).await.unwrap(), 1);
+
+        let bucket = dir.path().join("logs");
+        assert_eq!(std::fs::read(bucket.join("2024:01/app.log")).unwrap(), b"legacy");
+        assert!(bucket.join(".fily-metadata/2024:01_app.log.json").exists());
+        assert!(!bucket.join("2024%3A01").exists());
+        assert_eq!(layout_version(dir.path()).unwrap(), Some(1));
+        assert!(rollback(dir.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relocates_metadata() {
+        let dir = legacy_store();
+        let tenant = dir.path().join(".fily-tenants/acme/docs/.fily-metadata");
+        std::fs::create_dir_all(&tenant).unwrap();
+        std::fs::write(tenant.join("a.txt.json"), b"{}").unwrap();
+        std::fs::write(dir.path().join("logs/.fily-metadata/policy.json"), b"{}").unwrap();
+        let config = MigrationConfig::default();
+
+        let external = MetadataLayout::External(dir.path().join(".meta"));
+        migrate(dir.path(), &config, &external, true).await.unwrap();
+        assert!(dir.path().join(".meta/logs/policy.json").exists());
+        assert!(dir.path().join(".meta/.fily-tenants/acme/docs/a.txt.json").exists());
+        assert!(!dir.path().join("logs/.fily-metadata").exists());
+        assert!(!tenant.exists());
+        assert_eq!(read_layout_record(dir.path()).unwrap().layout, external);
+
+        let renamed = MetadataLayout::InBucket(".meta-data".to_string());
+        migrate(dir.path(), &config, &renamed, true).await.unwrap();
+        assert!(dir.path().join("logs/.meta-data/policy.json").exists());
+        assert!(dir.path().join(".fily-tenants/acme/docs/.meta-data/a.txt.json").exists());
+        assert!(!dir.path().join(".meta/logs").exists());
+        assert_eq!(open_metadata_layout(dir.path()).unwrap(), renamed);
+    }
+
+    #[tokio::test]
+    async fn test_relocation_keeps_existing_directories() {
+        let dir = legacy_store();
+        // Keys under the new name would be taken for metadata
+        std::fs::create_dir_all(dir.path().join("logs/meta")).unwrap();
+        let manual = MigrationConfig {
+            auto: false,
+            backup: false,
+        };
+        let layout = MetadataLayout::InBucket("meta".to_string());
+        migrate(dir.path(), &MigrationConfig::default(), &MetadataLayout::default(), true)
+            .await
+            .unwrap();
+        assert!(migrate(dir.path(), &manual, &layout, true).await.is_err());
+        assert!(migrate(dir.path(), &manual, &layout, false).await.is_err());
+        assert!(dir.path().join("logs/.fily-metadata").exists());
+        assert_eq!(read_layout_record(dir.path()).unwrap(), LayoutRecord::default());
+    }
+}
@@ -0,0 +1,192 @@
+//! Synchronous mirroring of object writes to a second storage root, for deployments without RAID
+//! that still need to survive losing a disk.
+//!
+//! Every commit to a mirrored bucket is first committed to the mirror, with the same intent
+//! records and renames as the primary, and only then to the primary; a write the mirror cannot
+//! take fails before the primary changes. The mirror is a complete fily store with the default
+//! metadata layout, so after losing the primary volume fily can serve it as `FILY_LOCATION`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+
+use tokio::io::AsyncWriteExt;
+
+use super::metadata::{delete_metadata, save_metadata as save_object_metadata, ObjectMetadata};
+use super::path_security::{construct_safe_path, construct_staging_path, lookup};
+
+/// Name of the probe file written to check the mirror is usable
+const WRITE_CHECK_FILE: &str = ".fily-write-check";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MirrorConfig {
+    /// Second storage root, on another device; mirroring is off without one
+    pub root: Option<String>,
+    /// Buckets to mirror, all of them when empty
+    pub buckets: Vec<String>,
+}
+
+impl MirrorConfig {
+    pub fn enabled(&self) -> bool {
+        self.root.is_some()
+    }
+
+    /// The mirror must be a separate tree: nested in the storage location it would be listed as a
+    /// bucket, and containing it, it would mirror itself
+    pub fn validate(&self, location: &Path) -> Result<(), String> {
+        let Some(root) = &self.root else {
+            if !self.buckets.is_empty() {
+                return Err("mirrored buckets need a mirror root".to_string());
+            }
+            return Ok(());
+        };
+        let root = Path::new(root);
+        if root.starts_with(location) || location.starts_with(root) {
+            return Err("the mirror root must be outside the storage location".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mirror {
+    root: PathBuf,
+    buckets: Vec<String>,
+}
+
+/// Mirrors of the stores opened by this process, looked up like their staging roots so tenant
+/// namespaces nested in a store are mirrored below its mirror root
+fn mirrors() -> &'static RwLock<HashMap<PathBuf, Mirror>> {
+    static MIRRORS: OnceLock<RwLock<HashMap<PathBuf, Mirror>>> = OnceLock::new();
+    MIRRORS.get_or_init(Default::default)
+}
+
+/// Checks the mirror is writable, finishes commits a crash interrupted on it and starts
+/// mirroring writes to the store at `storage_root`
+pub async fn open(storage_root: &Path, config: &MirrorConfig) -> anyhow::Result<()> {
+    let Some(root) = &config.root else {
+        mirrors().write().unwrap().remove(storage_root);
+        return Ok(());
+    };
+    tokio::fs::create_dir_all(root)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot create mirror root {}: {}", root, e))?;
+    let probe = Path::new(root).join(WRITE_CHECK_FILE);
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|e| anyhow::anyhow!("Mirror root {} is not writable: {}", root, e))?;
+    tokio::fs::remove_file(&probe).await?;
+    super::commit::recover(Path::new(root))
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot recover interrupted writes in mirror {}: {}", root, e))?;
+
+    mirrors().write().unwrap().insert(
+        storage_root.to_path_buf(),
+        Mirror {
+            root: PathBuf::from(root),
+            buckets: config.buckets.clone(),
+        },
+    );
+    Ok(())
+}
+
+/// Storage root mirroring `bucket` of the store at `storage_root`, if it is mirrored
+pub(crate) fn mirror_root(storage_root: &Path, bucket: &str) -> Option<PathBuf> {
+    let (mirror, relative) = lookup(mirrors(), storage_root)?;
+    (mirror.buckets.is_empty() || mirror.buckets.iter().any(|b| b == bucket)).then(|| mirror.root.join(relative))
+}
+
+/// Copies object data into the mirror's staging directory for the bucket, synced, ready for a
+/// commit there; the bucket and the key's parent directories are created as for a PUT
+pub(crate) async fn stage_copy(mirror_root: &Path, bucket: &str, key: &str, source: &Path) -> anyhow::Result<PathBuf> {
+    let security = |e| anyhow::anyhow!("Path security violation: {}", e);
+    tokio::fs::create_dir_all(mirror_root.join(bucket)).await?;
+    let dest = construct_safe_path(mirror_root, bucket, key).map_err(security)?;
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let staged = construct_staging_path(mirror_root, bucket).map_err(security)?;
+    let copied = async {
+        let mut reader = tokio::fs::File::open(source).await?;
+        let mut writer = tokio::fs::File::create(&staged).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.flush().await?;
+        writer.sync_all().await
+    }
+    .await;
+    if let Err(e) = copied {
+        let _ = tokio::fs::remove_file(&staged).await;
+        return Err(e.into());
+    }
+    Ok(staged)
+}
+
+/// Removes a deleted or renamed key from the bucket's mirror, if it has one. The caller holds
+/// the key's lock.
+pub(crate) async fn remove(storage_root: &Path, bucket: &str, key: &str) -> anyhow::Result<()> {
+    let Some(mirror_root) = mirror_root(storage_root, bucket) else {
+        return Ok(());
+    };
+    let path = construct_safe_path(&mirror_root, bucket, key)
+        .map_err(|e| anyhow::anyhow!("Path security violation: {}", e))?;
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    delete_metadata(&mirror_root, bucket, key).await
+}
+
+/// Saves a metadata-only change, which has no commit of its own, to the bucket's mirror first.
+/// Keys the mirror does not hold yet are left alone, as it gets their metadata with their data.
+pub(crate) async fn save_metadata(storage_root: &Path, bucket: &str, key: &str, metadata: &ObjectMetadata) -> anyhow::Result<()> {
+    let Some(mirror_root) = mirror_root(storage_root, bucket) else {
+        return Ok(());
+    };
+    let path = construct_safe_path(&mirror_root, bucket, key)
+        .map_err(|e| anyhow::anyhow!("Path security violation: {}", e))?;
+    if !tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+        return Ok(());
+    }
+    save_object_metadata(&mirror_root, bucket, key, metadata).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirror_config_validation() {
+        let location = Path::new("/data/fily");
+        let config = |root: Option<&str>, buckets: &[&str]| MirrorConfig {
+            root: root.map(str::to_string),
+            buckets: buckets.iter().map(|b| b.to_string()).collect(),
+        };
+        assert!(config(None, &[]).validate(location).is_ok());
+        assert!(config(Some("/mirror/fily"), &["photos"]).validate(location).is_ok());
+        assert!(config(None, &["photos"]).validate(location).is_err());
+        assert!(config(Some("/data/fily/mirror"), &[]).validate(location).is_err());
+        assert!(config(Some("/data"), &[]).validate(location).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_listed_buckets_mirrored() {
+        let primary = tempfile::tempdir().unwrap();
+        let secondary = tempfile::tempdir().unwrap();
+        let config = MirrorConfig {
+            root: Some(secondary.path().to_string_lossy().into_owned()),
+            buckets: vec!["photos".to_string()],
+        };
+        open(primary.path(), &config).await.unwrap();
+
+        assert_eq!(mirror_root(primary.path(), "photos"), Some(secondary.path().to_path_buf()));
+        assert_eq!(mirror_root(primary.path(), "logs"), None);
+        // Tenant namespaces nested in the store are mirrored below the mirror root
+        assert_eq!(
+            mirror_root(&primary.path().join(".tenants/acme"), "photos"),
+            Some(secondary.path().join(".tenants/acme"))
+        );
+        open(primary.path(), &MirrorConfig::default()).await.unwrap();
+        assert_eq!(mirror_root(primary.path(), "photos"), None);
+    }
+}
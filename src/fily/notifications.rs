@@ -0,0 +1,318 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::Full;
+use hyper::Request;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::logging::REDACTED;
+use super::request_info::current;
+
+/// Events waiting for delivery; further events are dropped while the queue is full
+const QUEUE_SIZE: usize = 256;
+
+/// Attempts per event before it is given up
+const MAX_ATTEMPTS: u32 = 3;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Operational events an operator can be notified of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A source IP or access key was locked out after repeated failed signature checks
+    AuthLockout,
+    /// Free disk space crossed a watermark, in either direction
+    DiskWatermark,
+    /// A bucket sync started failing, or recovered
+    SyncFailure,
+    /// A background scrub found an object whose content no longer matches its checksum
+    ScrubFailure,
+    /// Most clients agree the server clock is off, or agree with it again
+    ClockSkew,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::AuthLockout => "auth_lockout",
+            EventKind::DiskWatermark => "disk_watermark",
+            EventKind::SyncFailure => "sync_failure",
+            EventKind::ScrubFailure => "scrub_failure",
+            EventKind::ClockSkew => "clock_skew",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "auth_lockout" => Ok(EventKind::AuthLockout),
+            "disk_watermark" => Ok(EventKind::DiskWatermark),
+            "sync_failure" => Ok(EventKind::SyncFailure),
+            "scrub_failure" => Ok(EventKind::ScrubFailure),
+            "clock_skew" => Ok(EventKind::ClockSkew),
+            other => Err(format!(
+                "unknown event {}, expected auth_lockout, disk_watermark, sync_failure, scrub_failure or clock_skew",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// A previously reported problem is resolved
+    Resolved,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Resolved => "resolved",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        })
+    }
+}
+
+/// Webhook receiving operational events
+#[derive(Clone, Default)]
+pub struct NotificationConfig {
+    /// `http://` URL events are POSTed to as JSON (notifications are disabled when unset)
+    pub webhook_url: Option<String>,
+    /// Events to deliver, every event when empty
+    pub events: Vec<EventKind>,
+}
+
+impl fmt::Debug for NotificationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Webhook URLs such as Slack's carry their credential in the path
+        f.debug_struct("NotificationConfig")
+            .field("webhook_url", &self.webhook_url.as_ref().map(|_| REDACTED))
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl NotificationConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(webhook_url) = &self.webhook_url else {
+            return Ok(());
+        };
+        let url = url::Url::parse(webhook_url).map_err(|e| format!("invalid webhook URL: {}", e))?;
+        if url.scheme() != "http" || url.host_str().is_none() {
+            return Err("the webhook URL must be an http:// URL; relay https endpoints such as Slack through a local proxy".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// JSON body of a webhook call; `text` makes it readable by Slack-compatible incoming webhooks
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub text: String,
+    pub event: EventKind,
+    pub severity: Severity,
+    pub timestamp: String,
+    /// Request that raised the event, for events raised while serving one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub details: serde_json::Value,
+}
+
+impl Event {
+    pub fn new(event: EventKind, severity: Severity, message: String, details: serde_json::Value) -> Self {
+        Self {
+            text: format!("[fily {}] {}", severity, message),
+            event,
+            severity,
+            timestamp: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            request_id: current().map(|info| info.request_id.clone()),
+            details,
+        }
+    }
+}
+
+/// Queues operational events and POSTs them to the configured webhook in the background, so
+/// the code raising an event never waits on the network
+pub struct Notifier {
+    config: NotificationConfig,
+    sender: mpsc::Sender<Event>,
+    receiver: Mutex<Option<mpsc::Receiver<Event>>>,
+}
+
+impl Notifier {
+    pub fn new(config: NotificationConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        Self {
+            config,
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    /// Queues `event` unless it is filtered out or the queue is full
+    pub fn notify(&self, event: Event) {
+        if !self.config.events.is_empty() && !self.config.events.contains(&event.event) {
+            return;
+        }
+        let kind = event.event.as_str();
+        if self.sender.try_send(event).is_err() {
+            warn!("Dropping {} notification, the delivery queue is full", kind);
+            metrics::counter!("fily_notifications_total", "event" => kind, "result" => "dropped").increment(1);
+        }
+    }
+
+    /// Delivers queued events for the lifetime of the server
+    pub async fn deliver(self: std::sync::Arc<Self>) {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            return;
+        };
+        let Some(webhook_url) = self.config.webhook_url.clone() else {
+            return;
+        };
+        let client: Client<HttpConnector, Full<Bytes>> = Client::builder(TokioExecutor::new()).build_http();
+
+        while let Some(event) = receiver.recv().await {
+            let kind = event.event.as_str();
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => Bytes::from(body),
+                Err(e) => {
+                    warn!("Failed to serialize {} notification: {}", kind, e);
+                    continue;
+                }
+            };
+
+            let mut delivered = false;
+            for attempt in 1..=MAX_ATTEMPTS {
+                let request = Request::post(&webhook_url)
+                    .header("content-type", "application/json")
+                    .body(Full::new(body.clone()));
+                let result = match request {
+                    Ok(request) => tokio::time::timeout(DELIVERY_TIMEOUT, client.request(request))
+                        .await
+                        .map_err(|_| "timed out".to_string())
+                        .and_then(|response| response.map_err(|e| e.to_string()))
+                        .and_then(|response| match response.status().is_success() {
+                            true => Ok(()),
+                            false => Err(format!("webhook returned {}", response.status())),
+                        }),
+                    Err(e) => Err(e.to_string()),
+                };
+                match result {
+                    Ok(()) => {
+                        delivered = true;
+                        break;
+                    }
+                    Err(e) if attempt < MAX_ATTEMPTS => {
+                        debug!("Delivering {} notification failed (attempt {}): {}", kind, attempt, e);
+                        tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+                    }
+                    Err(e) => warn!("Giving up on {} notification after {} attempts: {}", kind, attempt, e),
+                }
+            }
+            let result = if delivered { "sent" } else { "failed" };
+            metrics::counter!("fily_notifications_total", "event" => kind, "result" => result).increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_config_validation() {
+        let config = |url: &str| NotificationConfig {
+            webhook_url: Some(url.to_string()),
+            events: vec![],
+        };
+        assert!(config("http://127.0.0.1:9000/hooks/fily").validate().is_ok());
+        assert!(config("https://hooks.slack.com/services/T000/B000/XXXX").validate().is_err());
+        assert!(config("not a url").validate().is_err());
+        assert!(NotificationConfig::default().validate().is_ok());
+        assert!(!format!("{:?}", config("http://relay/T000/B000/XXXX")).contains("XXXX"));
+
+        assert_eq!("sync_failure".parse::<EventKind>().unwrap(), EventKind::SyncFailure);
+        assert_eq!("scrub_failure".parse::<EventKind>().unwrap(), EventKind::ScrubFailure);
+        assert_eq!("clock_skew".parse::<EventKind>().unwrap(), EventKind::ClockSkew);
+        assert!("quota".parse::<EventKind>().is_err());
+    }
+
+    /// Accepts one connection and returns the body of the request it carried
+    async fn receive(listener: &tokio::net::TcpListener) -> serde_json::Value {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(str::to_string))
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                    return serde_json::from_str(body).unwrap();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivers_selected_events() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let notifier = Arc::new(Notifier::new(NotificationConfig {
+            webhook_url: Some(format!("http://{}/hooks/fily", listener.local_addr().unwrap())),
+            events: vec![EventKind::DiskWatermark],
+        }));
+        let delivery = tokio::spawn(notifier.clone().deliver());
+
+        notifier.notify(Event::new(
+            EventKind::AuthLockout,
+            Severity::Warning,
+            "filtered out".to_string(),
+            serde_json::Value::Null,
+        ));
+        notifier.notify(Event::new(
+            EventKind::DiskWatermark,
+            Severity::Critical,
+            "Free disk space is below the hard watermark".to_string(),
+            serde_json::json!({ "available_bytes": 1024 }),
+        ));
+
+        let body = receive(&listener).await;
+        assert_eq!(body["event"], "disk_watermark");
+        assert_eq!(body["severity"], "critical");
+        assert_eq!(body["text"], "[fily critical] Free disk space is below the hard watermark");
+        assert_eq!(body["details"]["available_bytes"], 1024);
+        delivery.abort();
+    }
+}
@@ -0,0 +1,280 @@
+//! GET /_fily/search/{bucket}: finds objects by key, size, modification time, content type and
+//! tags without the client listing the bucket and fetching every object's metadata.
+//!
+//! The key prefix narrows the walk to the directories beneath it, as it does for a listing; every
+//! other filter is checked against the object's stored metadata. Each page examines at most
+//! [`SEARCH_SCAN_LIMIT`] objects, so a selective query over a large bucket returns short (even
+//! empty) pages with a continuation token rather than walking the whole bucket in one request.
+
+use std::collections::HashMap;
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use tracing::{debug, error};
+
+use super::metadata::{load_metadata, ObjectMetadata};
+use super::path_security::sanitize_bucket_name;
+use super::s3_app_error::S3AppError;
+use super::search_bucket::{
+    continuation_token_key, invalid_argument, ContinuationToken, Listed, ObjectWalker, StoredObject, TagFilter,
+    DEFAULT_MAX_KEYS,
+};
+use super::timestamp::iso8601;
+use super::Config;
+
+/// Objects examined per page, matching or not
+pub const SEARCH_SCAN_LIMIT: usize = 10_000;
+
+/// Compiled size a `key-regex` may reach
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// What an object must match; absent filters match everything
+#[derive(Debug, Default)]
+struct SearchQuery {
+    prefix: String,
+    suffix: Option<String>,
+    key_regex: Option<Regex>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<DateTime<Utc>>,
+    modified_before: Option<DateTime<Utc>>,
+    /// `image/png`, or `image/*` for any image type
+    content_type: Option<String>,
+    tags: Vec<TagFilter>,
+    max_keys: usize,
+    continuation_token: Option<String>,
+}
+
+impl SearchQuery {
+    fn parse(params: &[(String, String)]) -> Result<Self, S3AppError> {
+        let size = |name: &str, raw: &str| {
+            raw.parse::<u64>().map_err(|_| invalid_argument(format!("Invalid {}: {}", name, raw)))
+        };
+        let date = |name: &str, raw: &str| {
+            DateTime::parse_from_rfc3339(raw)
+                .map(|date| date.with_timezone(&Utc))
+                .map_err(|_| invalid_argument(format!("Invalid {}, expected an RFC 3339 date: {}", name, raw)))
+        };
+
+        let mut query = SearchQuery {
+            max_keys: DEFAULT_MAX_KEYS,
+            ..Default::default()
+        };
+        for (name, value) in params {
+            match name.as_str() {
+                "prefix" => query.prefix = value.clone(),
+                "suffix" => query.suffix = Some(value.clone()),
+                "key-regex" => {
+                    let regex = RegexBuilder::new(value)
+                        .size_limit(REGEX_SIZE_LIMIT)
+                        .build()
+                        .map_err(|e| invalid_argument(format!("Invalid key-regex: {}", e)))?;
+                    query.key_regex = Some(regex);
+                }
+                "min-size" => query.min_size = Some(size(name, value)?),
+                "max-size" => query.max_size = Some(size(name, value)?),
+                "modified-after" => query.modified_after = Some(date(name, value)?),
+                "modified-before" => query.modified_before = Some(date(name, value)?),
+                "content-type" => query.content_type = Some(value.to_ascii_lowercase()),
+                "tag" => query.tags.push(TagFilter::parse(value)?),
+                "max-keys" => {
+                    query.max_keys = value
+                        .parse::<usize>()
+                        .map_err(|_| invalid_argument(format!("Invalid max-keys: {}", value)))?
+                        .min(DEFAULT_MAX_KEYS)
+                }
+                "continuation-token" => query.continuation_token = Some(value.clone()),
+                // Pre-signed requests carry their signature in the query
+                _ => {}
+            }
+        }
+        Ok(query)
+    }
+
+    /// Whether the object's key and modification time match, which the walk alone tells
+    fn matches_listing(&self, object: &StoredObject) -> bool {
+        self.suffix.as_ref().is_none_or(|suffix| object.key.ends_with(suffix.as_str()))
+            && self.key_regex.as_ref().is_none_or(|regex| regex.is_match(&object.key))
+            && self.modified_after.is_none_or(|after| object.last_modified > after)
+            && self.modified_before.is_none_or(|before| object.last_modified < before)
+    }
+
+    /// Whether the filters that need the object's metadata match; `size` is the uploaded size
+    fn matches_metadata(&self, size: u64, metadata: Option<&ObjectMetadata>) -> bool {
+        if self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+        if let Some(wanted) = &self.content_type {
+            let Some(metadata) = metadata else { return false };
+            // Parameters such as `; charset=utf-8` do not take part in the comparison
+            let actual = metadata.content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+            let matched = match wanted.strip_suffix("/*") {
+                Some(top_level) => actual.split('/').next() == Some(top_level),
+                None => actual == *wanted,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        self.tags.is_empty() || metadata.is_some_and(|m| self.tags.iter().all(|tag| tag.matches(&m.tags)))
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct FoundObject {
+    key: String,
+    size: u64,
+    last_modified: String,
+    etag: Option<String>,
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+struct SearchResult {
+    bucket: String,
+    objects: Vec<FoundObject>,
+    /// Objects examined for this page
+    scanned: usize,
+    is_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_continuation_token: Option<String>,
+}
+
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    Path(bucket): Path<String>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Response, S3AppError> {
+    let bucket = sanitize_bucket_name(&bucket).map_err(|_| S3AppError::invalid_bucket_name(&bucket))?;
+    let storage_root = FsPath::new(&config.location);
+    let bucket_path = storage_root.join(&bucket);
+    if !bucket_path.is_dir() {
+        return Err(S3AppError::no_such_bucket(&bucket));
+    }
+    let query = SearchQuery::parse(&params)?;
+
+    // Tokens are listing cursors; the filters are the caller's to repeat on every page
+    let token_key = continuation_token_key(&config);
+    let now = Utc::now();
+    let (start_after, generation) = match &query.continuation_token {
+        Some(token) => {
+            let token = ContinuationToken::decode(token_key, token, &bucket, &query.prefix, None, now)?;
+            (Some(token.after), token.generation)
+        }
+        None => (None, now.timestamp_millis()),
+    };
+
+    let mut walker = ObjectWalker::new(&bucket_path, &query.prefix, start_after);
+    let mut objects = vec![];
+    let mut scanned = 0;
+    let mut last_key = None;
+    let mut is_truncated = false;
+    while let Some(listed) = walker.next_listed().await.map_err(|e| {
+        error!("Failed to search bucket {}: {}", bucket, e);
+        S3AppError::internal_error(&format!("Failed to search bucket: {}", e))
+    })? {
+        let Listed::Object(object) = listed else { continue };
+        if objects.len() >= query.max_keys || scanned >= SEARCH_SCAN_LIMIT {
+            is_truncated = true;
+            break;
+        }
+        scanned += 1;
+        last_key = Some(object.key.clone());
+        if !query.matches_listing(&object) {
+            continue;
+        }
+        let metadata = load_metadata(storage_root, &bucket, &object.key).await.ok().flatten();
+        // Encrypted objects are larger on disk, so compare the plaintext size recorded at upload
+        let size = metadata.as_ref().map_or(object.size, |m| m.content_length);
+        if !query.matches_metadata(size, metadata.as_ref()) {
+            continue;
+        }
+        objects.push(FoundObject {
+            key: object.key,
+            size,
+            last_modified: iso8601(object.last_modified),
+            etag: metadata.as_ref().map(|m| m.etag.clone()),
+            content_type: metadata.as_ref().map(|m| m.content_type.clone()),
+            tags: metadata.map(|m| m.tags).unwrap_or_default(),
+        });
+    }
+    debug!("Search of {} matched {} of {} objects examined", bucket, objects.len(), scanned);
+
+    let next_continuation_token = last_key.filter(|_| is_truncated).map(|after| {
+        ContinuationToken {
+            bucket: bucket.clone(),
+            prefix: query.prefix.clone(),
+            delimiter: None,
+            after,
+            generation,
+        }
+        .encode(token_key)
+    });
+    let result = SearchResult {
+        bucket,
+        objects,
+        scanned,
+        is_truncated,
+        next_continuation_token,
+    };
+    let body = serde_json::to_string(&result).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    Ok((StatusCode::OK, [("content-type", "application/json")], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: &[(&str, &str)]) -> SearchQuery {
+        let params: Vec<_> = params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        SearchQuery::parse(&params).unwrap()
+    }
+
+    fn object(key: &str) -> StoredObject {
+        StoredObject {
+            key: key.to_string(),
+            size: 0,
+            last_modified: DateTime::parse_from_rfc3339("2024-10-15T10:00:00Z").unwrap().with_timezone(&Utc),
+        }
+    }
+
+    #[test]
+    fn test_search_filters() {
+        let q = query(&[("suffix", ".jpg"), ("key-regex", "^2024/"), ("modified-after", "2024-10-01T00:00:00Z")]);
+        assert!(q.matches_listing(&object("2024/a.jpg")));
+        assert!(!q.matches_listing(&object("2024/a.png")));
+        assert!(!q.matches_listing(&object("2023/a.jpg")));
+        assert!(!query(&[("modified-before", "2024-10-15T10:00:00Z")]).matches_listing(&object("a")));
+
+        let metadata = ObjectMetadata {
+            content_type: "image/jpeg; quality=high".to_string(),
+            tags: [("env".to_string(), "prod".to_string())].into(),
+            ..ObjectMetadata::new(None, 0, String::new(), "a.jpg")
+        };
+        let q = query(&[("min-size", "10"), ("max-size", "20"), ("content-type", "image/*"), ("tag", "env=prod")]);
+        assert!(q.matches_metadata(15, Some(&metadata)));
+        assert!(!q.matches_metadata(21, Some(&metadata)));
+        assert!(!q.matches_metadata(15, None));
+        assert!(query(&[("content-type", "IMAGE/JPEG")]).matches_metadata(0, Some(&metadata)));
+        assert!(!query(&[("tag", "env"), ("tag", "team")]).matches_metadata(0, Some(&metadata)));
+        assert!(query(&[]).matches_metadata(0, None));
+    }
+
+    #[test]
+    fn test_search_query_rejects_bad_parameters() {
+        let parse = |name: &str, value: &str| SearchQuery::parse(&[(name.to_string(), value.to_string())]);
+        assert!(parse("key-regex", "(unclosed").is_err());
+        assert!(parse("min-size", "-1").is_err());
+        assert!(parse("modified-after", "yesterday").is_err());
+        assert_eq!(parse("max-keys", "5000").unwrap().max_keys, DEFAULT_MAX_KEYS);
+    }
+}
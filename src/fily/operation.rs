@@ -0,0 +1,211 @@
+//! S3 operation names, tagged onto handlers where they are routed: `handler.layer(tag("PutObject"))`.
+//! The tag opens a tracing span named after the operation and is recorded in the request's
+//! [`RequestInfo`], so the request log, metrics and anything else wrapping the router name a
+//! request the same way instead of each guessing from its path.
+//!
+//! Since it is the one place that knows which operation a request is, the tag also refuses
+//! operations the configuration disables, globally or for some buckets, so a deployment can run
+//! without e.g. DeleteBucket whatever its credentials allow. Entry points that run a handler
+//! without routing to it (WebDAV, batches, resumable upload commits) check the operation they
+//! amount to with [`DisabledOperations::check`] themselves.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, Result};
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use tower::{Layer, Service};
+use tracing::{warn, Instrument};
+
+use super::create_bucket::is_valid_bucket_name;
+use super::request_info::RequestInfo;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::Config;
+
+/// Every operation handlers are tagged with, the names `FILY_DISABLED_OPERATIONS` accepts
+pub const OPERATIONS: &[&str] = &[
+    "BatchGetObjects",
+    "BatchPutObjects",
+    "CloneBucket",
+    "ComposeObject",
+    "CopyObject",
+    "CreateBucket",
+    "DeleteBucket",
+    "DeleteBucketOwnershipControls",
+    "DeleteObject",
+    "DeletePrefix",
+    "DeletePublicAccessBlock",
+    "GetBucketLogging",
+    "GetBucketOwnershipControls",
+    "GetBucketPolicyStatus",
+    "GetObject",
+    "GetPublicAccessBlock",
+    "HeadBucket",
+    "HeadObject",
+    "ListBuckets",
+    "ListObjects",
+    "ListObjectsV2",
+    "PatchObjectMetadata",
+    "PutBucketAcl",
+    "PutBucketLogging",
+    "PutBucketOwnershipControls",
+    "PutObject",
+    "PutObjectAcl",
+    "PutPublicAccessBlock",
+    "RenameObject",
+    "SearchObjects",
+];
+
+/// Operations refused with `MethodNotAllowed`, for a locked-down deployment
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DisabledOperations {
+    /// Refused for every bucket
+    pub global: Vec<String>,
+    /// Refused only for the bucket they are listed under
+    pub buckets: BTreeMap<String, Vec<String>>,
+}
+
+impl DisabledOperations {
+    pub fn validate(&self) -> Result<()> {
+        let lists = std::iter::once(&self.global).chain(self.buckets.values());
+        if let Some(unknown) = lists.flatten().find(|op| !OPERATIONS.contains(&op.as_str())) {
+            return Err(anyhow!("Unknown operation: {}", unknown));
+        }
+        if let Some(bucket) = self.buckets.keys().find(|b| !is_valid_bucket_name(b)) {
+            return Err(anyhow!("Invalid bucket name: {}", bucket));
+        }
+        Ok(())
+    }
+
+    /// Whether `operation` is disabled, for `bucket` if the request has one
+    pub fn refuses(&self, operation: &str, bucket: Option<&str>) -> bool {
+        self.global.iter().any(|op| op == operation)
+            || bucket
+                .and_then(|bucket| self.buckets.get(bucket))
+                .is_some_and(|ops| ops.iter().any(|op| op == operation))
+    }
+
+    /// Fails with `MethodNotAllowed` when `operation` is disabled, for `bucket` if there is one
+    pub fn check(&self, operation: &str, bucket: Option<&str>) -> Result<(), S3AppError> {
+        if !self.refuses(operation, bucket) {
+            return Ok(());
+        }
+        warn!("Refusing disabled operation {} on {}", operation, bucket.unwrap_or("/"));
+        Err(S3AppError::with_message(
+            S3ErrorCode::MethodNotAllowed,
+            format!("{} is disabled on this server", operation),
+        ))
+    }
+}
+
+/// Layer tagging a handler with the S3 operation it implements
+pub fn tag(operation: &'static str) -> OperationLayer {
+    OperationLayer { operation }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OperationLayer {
+    operation: &'static str,
+}
+
+impl<S> Layer<S> for OperationLayer {
+    type Service = OperationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        OperationService {
+            inner,
+            operation: self.operation,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OperationService<S> {
+    inner: S,
+    operation: &'static str,
+}
+
+impl<S> Service<Request> for OperationService<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let operation = self.operation;
+        let info = req.extensions().get::<Arc<RequestInfo>>().cloned();
+        if let Some(info) = &info {
+            info.set_operation(operation);
+        }
+        let bucket = info.as_ref().and_then(|info| info.target.bucket());
+        if let Some(config) = req.extensions().get::<Arc<Config>>() {
+            if let Err(error) = config.disabled_operations.check(operation, bucket) {
+                return Box::pin(async move { Ok(error.into_response()) });
+            }
+        }
+        let span = tracing::info_span!("s3_operation", operation);
+        Box::pin(self.inner.call(req).instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::handler::Handler;
+
+    async fn echo(req: Request) -> String {
+        RequestInfo::of(&req).operation().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_tag_recorded_in_request_info() {
+        let info = Arc::new(RequestInfo::new("/photos"));
+        let mut req = Request::get("/photos?list-type=2").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(info.clone());
+        let response = echo.layer(tag("ListObjectsV2")).call(req, ()).await;
+        assert_eq!(info.operation(), Some("ListObjectsV2"));
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, "ListObjectsV2");
+    }
+
+    #[test]
+    fn test_disabled_operations() {
+        let disabled = DisabledOperations {
+            global: vec!["DeleteBucket".to_string()],
+            buckets: [("prod".to_string(), vec!["DeleteObject".to_string()])].into(),
+        };
+        assert!(disabled.validate().is_ok());
+        assert!(disabled.refuses("DeleteBucket", Some("dev")));
+        assert!(disabled.refuses("DeleteBucket", None));
+        assert!(disabled.refuses("DeleteObject", Some("prod")));
+        assert!(!disabled.refuses("DeleteObject", Some("dev")));
+        assert!(!disabled.refuses("GetObject", Some("prod")));
+        let refused = disabled.check("DeleteObject", Some("prod"));
+        assert!(refused.is_err_and(|e| matches!(e.code, S3ErrorCode::MethodNotAllowed)));
+        assert!(disabled.check("DeleteObject", Some("dev")).is_ok());
+
+        let typo = DisabledOperations {
+            global: vec!["DeleteBuckets".to_string()],
+            ..Default::default()
+        };
+        assert!(typo.validate().is_err());
+        let bad_bucket = DisabledOperations {
+            buckets: [("Prod_Data".to_string(), vec![])].into(),
+            ..Default::default()
+        };
+        assert!(bad_bucket.validate().is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::path::Path as FsPath;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::auth_middleware::AuthenticatedAccessKey;
+use super::bucket_policy::manageable_policy;
+use super::path_security::{bucket_metadata_dir, sanitize_bucket_name};
+use super::public_access_block::reject_public_acl;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::Config;
+
+/// Stored next to the bucket policy, under a name no object key maps to
+const OWNERSHIP_CONTROLS_FILE: &str = "ownership-controls";
+const S3_XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Header setting the object ownership of a bucket as it is created
+pub const OBJECT_OWNERSHIP_HEADER: &str = "x-amz-object-ownership";
+
+/// Who owns objects written to a bucket; fily has no ACLs, so only `BucketOwnerEnforced` changes
+/// behaviour, by rejecting requests that try to set one
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ObjectOwnership {
+    BucketOwnerPreferred,
+    ObjectWriter,
+    BucketOwnerEnforced,
+}
+
+impl FromStr for ObjectOwnership {
+    type Err = S3AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BucketOwnerPreferred" => Ok(Self::BucketOwnerPreferred),
+            "ObjectWriter" => Ok(Self::ObjectWriter),
+            "BucketOwnerEnforced" => Ok(Self::BucketOwnerEnforced),
+            _ => Err(S3AppError::with_message(
+                S3ErrorCode::InvalidArgument,
+                format!("Invalid object ownership {}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OwnershipRule {
+    #[serde(rename = "ObjectOwnership")]
+    object_ownership: ObjectOwnership,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OwnershipControls {
+    #[serde(rename = "@xmlns", default, skip_deserializing)]
+    xmlns: String,
+    #[serde(rename = "Rule")]
+    rules: Vec<OwnershipRule>,
+}
+
+fn ownership_controls_path(storage_root: &FsPath, bucket: &str) -> anyhow::Result<std::path::PathBuf> {
+    let bucket = sanitize_bucket_name(bucket)
+        .map_err(|e| anyhow::anyhow!("Ownership controls path security violation: {}", e))?;
+    Ok(bucket_metadata_dir(storage_root, &bucket).join(OWNERSHIP_CONTROLS_FILE))
+}
+
+pub async fn load_object_ownership(storage_root: &FsPath, bucket: &str) -> anyhow::Result<Option<ObjectOwnership>> {
+    let path = ownership_controls_path(storage_root, bucket)?;
+    match super::replica::read_store_file(storage_root, &path).await? {
+        Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_object_ownership(storage_root: &FsPath, bucket: &str, ownership: ObjectOwnership) -> anyhow::Result<()> {
+    let path = ownership_controls_path(storage_root, bucket)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&path, serde_json::to_string(&ownership)?).await?;
+    Ok(())
+}
+
+/// Whether the request tries to set an ACL, either via `?acl` or the canned/grant headers;
+/// granting the bucket owner full control is what BucketOwnerEnforced implies anyway
+pub(crate) fn sets_acl(headers: &HeaderMap, acl_subresource: bool) -> bool {
+    let canned = headers
+        .get("x-amz-acl")
+        .is_some_and(|acl| acl.as_bytes() != b"bucket-owner-full-control");
+    let grants = headers.keys().any(|name| name.as_str().starts_with("x-amz-grant-"));
+    acl_subresource || canned || grants
+}
+
+/// Rejects ACL writes to buckets whose ownership controls disable ACLs, and public ACLs on
+/// buckets whose public access block forbids them
+pub async fn reject_acl_write(
+    storage_root: &FsPath,
+    bucket: &str,
+    headers: &HeaderMap,
+    acl_subresource: bool,
+) -> Result<(), S3AppError> {
+    if !sets_acl(headers, acl_subresource) {
+        return Ok(());
+    }
+    let ownership = load_object_ownership(storage_root, bucket).await.ok().flatten();
+    if ownership == Some(ObjectOwnership::BucketOwnerEnforced) {
+        return Err(S3AppError::with_resource(
+            S3ErrorCode::AccessControlListNotSupported,
+            format!("/{}", bucket),
+        ));
+    }
+    reject_public_acl(storage_root, bucket, headers).await
+}
+
+/// PUT /{bucket}?acl and PUT /{bucket}/{file}?acl: fily keeps no ACLs, so there is nothing to set
+pub async fn put_acl(
+    config: Extension<Arc<Config>>,
+    Path(params): Path<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Result<Response, S3AppError> {
+    let bucket = params.first().map(|(_, bucket)| bucket.as_str()).unwrap_or_default();
+    reject_acl_write(FsPath::new(&config.location), bucket, &headers, true).await?;
+    let operation = if params.len() > 1 { "PutObjectAcl" } else { "PutBucketAcl" };
+    Err(S3AppError::with_message_and_resource(
+        S3ErrorCode::NotImplemented,
+        format!("{} is not implemented by fily", operation),
+        params.iter().fold(String::new(), |path, (_, segment)| format!("{}/{}", path, segment)),
+    ))
+}
+
+fn xml_response(ownership: ObjectOwnership) -> Result<Response, S3AppError> {
+    let controls = OwnershipControls {
+        xmlns: S3_XMLNS.to_string(),
+        rules: vec![OwnershipRule {
+            object_ownership: ownership,
+        }],
+    };
+    let xml = quick_xml::se::to_string(&controls).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    let mut resp = Response::new(Body::from(xml));
+    resp.headers_mut()
+        .insert("content-type", "application/xml".parse().unwrap());
+    Ok(resp)
+}
+
+/// GET /{bucket}?ownershipControls
+pub async fn get(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let ownership = load_object_ownership(FsPath::new(&config.location), &bucket)
+        .await
+        .map_err(|e| {
+            error!("Failed to load ownership controls for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to load ownership controls: {}", e))
+        })?
+        .ok_or_else(|| {
+            S3AppError::with_resource(S3ErrorCode::OwnershipControlsNotFoundError, format!("/{}", bucket))
+        })?;
+    xml_response(ownership)
+}
+
+/// PUT /{bucket}?ownershipControls
+pub async fn put(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+    body: Bytes,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let controls: OwnershipControls = std::str::from_utf8(&body)
+        .ok()
+        .and_then(|xml| quick_xml::de::from_str(xml).ok())
+        .ok_or_else(|| S3AppError::new(S3ErrorCode::MalformedXML))?;
+    let [rule] = controls.rules.as_slice() else {
+        return Err(S3AppError::with_message(
+            S3ErrorCode::MalformedXML,
+            "Ownership controls must contain exactly one rule".to_string(),
+        ));
+    };
+
+    save_object_ownership(FsPath::new(&config.location), &bucket, rule.object_ownership)
+        .await
+        .map_err(|e| {
+            error!("Failed to save ownership controls for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to save ownership controls: {}", e))
+        })?;
+
+    info!(
+        "Object ownership of bucket {} set to {:?} by {}",
+        bucket, rule.object_ownership, access_key.0
+    );
+    Ok(StatusCode::OK.into_response())
+}
+
+/// DELETE /{bucket}?ownershipControls
+pub async fn delete(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let path = ownership_controls_path(FsPath::new(&config.location), &bucket)
+        .map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            error!("Failed to delete ownership controls for bucket {}: {}", bucket, e);
+            return Err(S3AppError::internal_error(&format!(
+                "Failed to delete ownership controls: {}",
+                e
+            )));
+        }
+        _ => {}
+    }
+
+    info!("Ownership controls of bucket {} deleted by {}", bucket, access_key.0);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ownership_controls_xml() {
+        let xml = r#"<OwnershipControls xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Rule><ObjectOwnership>BucketOwnerEnforced</ObjectOwnership></Rule></OwnershipControls>"#;
+        let controls: OwnershipControls = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(controls.rules.len(), 1);
+        assert_eq!(controls.rules[0].object_ownership, ObjectOwnership::BucketOwnerEnforced);
+
+        let response = xml_response(ObjectOwnership::BucketOwnerEnforced).unwrap();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, xml.as_bytes());
+        assert!(quick_xml::de::from_str::<OwnershipControls>(
+            "<OwnershipControls><Rule><ObjectOwnership>Everyone</ObjectOwnership></Rule></OwnershipControls>"
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_acl_write() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        let mut public = HeaderMap::new();
+        public.insert("x-amz-acl", "public-read".parse().unwrap());
+        let mut owner = HeaderMap::new();
+        owner.insert("x-amz-acl", "bucket-owner-full-control".parse().unwrap());
+
+        // ACLs are ignored rather than rejected until the bucket disables them
+        assert!(reject_acl_write(root, "photos", &public, false).await.is_ok());
+
+        save_object_ownership(root, "photos", ObjectOwnership::BucketOwnerEnforced).await.unwrap();
+        assert!(matches!(
+            reject_acl_write(root, "photos", &public, false).await.unwrap_err().code,
+            S3ErrorCode::AccessControlListNotSupported
+        ));
+        assert!(reject_acl_write(root, "photos", &HeaderMap::new(), true).await.is_err());
+        assert!(reject_acl_write(root, "photos", &owner, false).await.is_ok());
+        assert!(reject_acl_write(root, "photos", &HeaderMap::new(), false).await.is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+//! PATCH /_fily/metadata/{bucket}/{file}: changes an object's tags and user metadata in place,
+//! without the copy-in-place S3 needs to change either.
+//!
+//! The body is a JSON merge patch of the two maps, `{"tags": {"env": "prod", "stale": null},
+//! "metadata": {"owner": "ops"}}`: a string sets an entry and `null` removes it, entries not
+//! named are kept. The object's data, ETag and last-modified date are left as they are.
+
+use std::collections::HashMap;
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::auth_middleware::Principal;
+use super::key_locks;
+use super::mirror;
+use super::metadata::{load_metadata, save_metadata};
+use super::path_security::{construct_safe_path, sanitize_bucket_name};
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::Config;
+
+/// Largest accepted patch document
+const MAX_PATCH_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetadataPatch {
+    #[serde(default)]
+    tags: HashMap<String, Option<String>>,
+    #[serde(default)]
+    metadata: HashMap<String, Option<String>>,
+}
+
+#[derive(Serialize, Debug)]
+struct PatchResult {
+    key: String,
+    etag: String,
+    tags: HashMap<String, String>,
+    metadata: HashMap<String, String>,
+}
+
+fn invalid_argument(message: String) -> S3AppError {
+    S3AppError::with_message(S3ErrorCode::InvalidArgument, message)
+}
+
+impl MetadataPatch {
+    fn parse(body: &[u8]) -> Result<Self, S3AppError> {
+        if body.len() > MAX_PATCH_SIZE {
+            return Err(invalid_argument(format!("The patch is larger than {} bytes", MAX_PATCH_SIZE)));
+        }
+        let patch: MetadataPatch =
+            serde_json::from_slice(body).map_err(|e| invalid_argument(format!("Invalid metadata patch: {}", e)))?;
+        if patch.tags.keys().any(|key| key.is_empty()) {
+            return Err(invalid_argument("Tag keys must not be empty".to_string()));
+        }
+        // User metadata is returned as x-amz-meta-* headers, so it must make valid ones
+        for (key, value) in &patch.metadata {
+            let valid_name = !key.is_empty() && HeaderName::from_bytes(format!("x-amz-meta-{}", key).as_bytes()).is_ok();
+            let valid_value = value.as_deref().is_none_or(|value| HeaderValue::from_str(value).is_ok());
+            if !valid_name || !valid_value {
+                return Err(invalid_argument(format!("Invalid user metadata entry: {}", key)));
+            }
+        }
+        Ok(patch)
+    }
+
+    fn apply(self, tags: &mut HashMap<String, String>, metadata: &mut HashMap<String, String>) {
+        for (map, changes) in [(tags, self.tags), (metadata, self.metadata)] {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => map.insert(key, value),
+                    None => map.remove(&key),
+                };
+            }
+        }
+    }
+}
+
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    principal: Principal,
+    Path((bucket, file)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<Response, S3AppError> {
+    let bucket = sanitize_bucket_name(&bucket).map_err(|_| S3AppError::invalid_bucket_name(&bucket))?;
+    let storage_root = FsPath::new(&config.location);
+    if !storage_root.join(&bucket).is_dir() {
+        return Err(S3AppError::no_such_bucket(&bucket));
+    }
+    let patch = MetadataPatch::parse(&body)?;
+    // Metadata keys are case-insensitive header names, stored lowercase like a PUT stores them
+    let patch = MetadataPatch {
+        metadata: patch.metadata.into_iter().map(|(k, v)| (k.to_ascii_lowercase(), v)).collect(),
+        ..patch
+    };
+    let path = construct_safe_path(storage_root, &bucket, &file)
+        .map_err(|e| invalid_argument(format!("Invalid bucket or object name: {}", e)))?;
+
+    let _lock = key_locks::lock(storage_root, &bucket, &file).await;
+    if !tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+        return Err(S3AppError::no_such_key(&bucket, &file));
+    }
+    let Some(mut metadata) = load_metadata(storage_root, &bucket, &file).await.ok().flatten() else {
+        return Err(S3AppError::with_message(
+            S3ErrorCode::InvalidRequest,
+            "The object has no stored metadata to patch; copy it onto itself with x-amz-metadata-directive: REPLACE first".to_string(),
+        ));
+    };
+    let previous = metadata.clone();
+    patch.apply(&mut metadata.tags, &mut metadata.user_metadata);
+    metadata.record_write(&principal.0, Some(&previous));
+    let saved = match mirror::save_metadata(storage_root, &bucket, &file, &metadata).await {
+        Ok(()) => save_metadata(storage_root, &bucket, &file, &metadata).await,
+        Err(e) => Err(e),
+    };
+    saved.map_err(|e| {
+        error!("Failed to save metadata of {}/{}: {}", bucket, file, e);
+        S3AppError::internal_error(&format!("Failed to save metadata: {}", e))
+    })?;
+
+    info!("Patched metadata of {}/{} for {}", bucket, file, principal);
+    let result = PatchResult {
+        key: file,
+        etag: metadata.etag,
+        tags: metadata.tags,
+        metadata: metadata.user_metadata,
+    };
+    let body = serde_json::to_string(&result).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    Ok((StatusCode::OK, [("content-type", "application/json")], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_sets_and_removes_entries() {
+        let patch = MetadataPatch::parse(br#"{"tags": {"env": "prod", "stale": null}, "metadata": {"owner": "ops"}}"#).unwrap();
+        let mut tags = HashMap::from([("stale".to_string(), "yes".to_string()), ("team".to_string(), "dam".to_string())]);
+        let mut metadata = HashMap::new();
+        patch.apply(&mut tags, &mut metadata);
+        assert_eq!(
+            tags,
+            HashMap::from([("env".to_string(), "prod".to_string()), ("team".to_string(), "dam".to_string())])
+        );
+        assert_eq!(metadata, HashMap::from([("owner".to_string(), "ops".to_string())]));
+    }
+
+    #[test]
+    fn test_patch_rejects_invalid_documents() {
+        assert!(MetadataPatch::parse(b"not json").is_err());
+        assert!(MetadataPatch::parse(br#"{"labels": {}}"#).is_err());
+        assert!(MetadataPatch::parse(br#"{"tags": {"": "x"}}"#).is_err());
+        assert!(MetadataPatch::parse(br#"{"metadata": {"has space": "x"}}"#).is_err());
+        assert!(MetadataPatch::parse(br#"{"metadata": {"owner": "line\nbreak"}}"#).is_err());
+        assert!(MetadataPatch::parse(b"{}").is_ok());
+    }
+}
@@ -1,7 +1,163 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::debug;
 
+/// Default directory inside each bucket holding its object metadata and bucket settings
+pub const METADATA_DIR: &str = ".fily-metadata";
+
+/// Directory in a storage root staging object data when metadata is kept outside the buckets
+const STAGING_DIR: &str = ".fily-staging";
+
+/// Where a store keeps object metadata and bucket settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataLayout {
+    /// In a directory of this name inside each bucket, hidden from listings; keys under it
+    /// cannot be stored
+    InBucket(String),
+    /// In `{root}/{bucket}`, outside the buckets and possibly on another volume
+    External(PathBuf),
+}
+
+impl Default for MetadataLayout {
+    fn default() -> Self {
+        MetadataLayout::InBucket(METADATA_DIR.to_string())
+    }
+}
+
+impl MetadataLayout {
+    pub fn validate(&self, storage_root: &Path) -> Result<(), String> {
+        match self {
+            MetadataLayout::InBucket(name) => {
+                // Keys are matched against it by their first segment, so it must be stored as is
+                if name.is_empty()
+                    || name == "."
+                    || name == ".."
+                    || name.contains(['/', '\\'])
+                    || encode_key_segment(name) != *name
+                {
+                    return Err(format!("Invalid metadata directory name: {:?}", name));
+                }
+            }
+            MetadataLayout::External(root) => {
+                if !is_outside_buckets(root, storage_root) {
+                    return Err(format!(
+                        "Metadata root {} must be outside the storage location or in a directory starting with '.'",
+                        root.display()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Same layout for a storage root nested `relative` below the one this layout belongs to,
+    /// such as a tenant namespace
+    pub(crate) fn nested(&self, relative: &Path) -> Self {
+        match self {
+            _ if relative.as_os_str().is_empty() => self.clone(),
+            MetadataLayout::InBucket(name) => MetadataLayout::InBucket(name.clone()),
+            MetadataLayout::External(root) => MetadataLayout::External(root.join(relative)),
+        }
+    }
+
+    /// Directory holding a bucket's metadata; `bucket` must already be sanitized
+    pub(crate) fn bucket_dir(&self, storage_root: &Path, bucket: &str) -> PathBuf {
+        match self {
+            MetadataLayout::InBucket(name) => storage_root.join(bucket).join(name),
+            MetadataLayout::External(root) => root.join(bucket),
+        }
+    }
+}
+
+/// Whether `path` cannot be taken for a bucket: outside the storage location, or in one of its
+/// hidden directories
+pub fn is_outside_buckets(path: &Path, storage_root: &Path) -> bool {
+    match path.strip_prefix(storage_root) {
+        Ok(inside) => inside
+            .components()
+            .next()
+            .is_some_and(|c| c.as_os_str().to_string_lossy().starts_with('.')),
+        Err(_) => true,
+    }
+}
+
+/// Layouts of the stores opened by this process. Path helpers only get a storage root, so the
+/// layout recorded in each store is registered here when it is opened.
+fn layouts() -> &'static RwLock<HashMap<PathBuf, MetadataLayout>> {
+    static LAYOUTS: OnceLock<RwLock<HashMap<PathBuf, MetadataLayout>>> = OnceLock::new();
+    LAYOUTS.get_or_init(Default::default)
+}
+
+/// Scratch directories configured for the stores opened by this process
+fn staging_roots() -> &'static RwLock<HashMap<PathBuf, PathBuf>> {
+    static STAGING_ROOTS: OnceLock<RwLock<HashMap<PathBuf, PathBuf>>> = OnceLock::new();
+    STAGING_ROOTS.get_or_init(Default::default)
+}
+
+/// Value registered for `storage_root`, or for the closest store it is nested in together with
+/// the path of `storage_root` below that store
+pub(crate) fn lookup<T: Clone>(registry: &RwLock<HashMap<PathBuf, T>>, storage_root: &Path) -> Option<(T, PathBuf)> {
+    registry
+        .read()
+        .unwrap()
+        .iter()
+        .filter_map(|(root, value)| Some((root, value, storage_root.strip_prefix(root).ok()?)))
+        .max_by_key(|(root, _, _)| root.components().count())
+        .map(|(_, value, relative)| (value.clone(), relative.to_path_buf()))
+}
+
+pub(crate) fn register_metadata_layout(storage_root: &Path, layout: MetadataLayout) {
+    layouts().write().unwrap().insert(storage_root.to_path_buf(), layout);
+}
+
+pub(crate) fn register_staging_root(storage_root: &Path, staging_root: Option<PathBuf>) {
+    let mut staging_roots = staging_roots().write().unwrap();
+    match staging_root {
+        Some(staging_root) => staging_roots.insert(storage_root.to_path_buf(), staging_root),
+        None => staging_roots.remove(storage_root),
+    };
+}
+
+/// Layout of the store at `storage_root`, or of the store it is nested in; the default layout
+/// for stores that were never opened, such as in tests
+pub fn metadata_layout(storage_root: &Path) -> MetadataLayout {
+    lookup(layouts(), storage_root)
+        .map(|(layout, relative)| layout.nested(&relative))
+        .unwrap_or_default()
+}
+
+/// Directory on the scratch volume holding a bucket's staged object data and resumable uploads,
+/// when one is configured; `bucket` must already be sanitized
+pub fn scratch_dir(storage_root: &Path, bucket: &str) -> Option<PathBuf> {
+    lookup(staging_roots(), storage_root).map(|(staging_root, relative)| staging_root.join(relative).join(bucket))
+}
+
+/// Name of the metadata directory inside each bucket, which listings skip
+pub fn metadata_dir_name(storage_root: &Path) -> Option<String> {
+    match metadata_layout(storage_root) {
+        MetadataLayout::InBucket(name) => Some(name),
+        MetadataLayout::External(_) => None,
+    }
+}
+
+/// Directory holding a bucket's metadata; `bucket` must already be sanitized
+pub fn bucket_metadata_dir(storage_root: &Path, bucket: &str) -> PathBuf {
+    metadata_layout(storage_root).bucket_dir(storage_root, bucket)
+}
+
+/// Directory every bucket's metadata directory is under
+fn metadata_base(storage_root: &Path) -> PathBuf {
+    match metadata_layout(storage_root) {
+        MetadataLayout::InBucket(_) => storage_root.to_path_buf(),
+        MetadataLayout::External(root) => root,
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PathSecurityError {
     #[error("Invalid bucket name: {0}")]
@@ -164,20 +320,97 @@ pub fn sanitize_object_name(object: &str) -> Result<String, PathSecurityError> {
     Ok(components.join("/"))
 }
 
+/// Characters Windows does not allow in file names
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves regardless of case or extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// ASCII byte a `%XX` escape at the start of `s` stands for; other sequences are literal text
+fn escaped_byte(s: &str) -> Option<u8> {
+    let hex = s.strip_prefix('%')?.get(..2)?;
+    u8::from_str_radix(hex, 16).ok().filter(|b| b.is_ascii() && hex.bytes().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// File name a key segment is stored under, valid on Windows as well as Unix filesystems
+///
+/// Reserved characters, control characters, a trailing dot or space, the first letter of a
+/// device name, and any `%` that would otherwise read as an escape are percent-encoded. Every
+/// other segment is stored unchanged, so existing buckets keep their layout.
+pub fn encode_key_segment(segment: &str) -> String {
+    let stem = segment.split('.').next().unwrap_or_default();
+    let is_device_name = RESERVED_NAMES.iter().any(|name| stem.trim_end().eq_ignore_ascii_case(name));
+
+    let mut encoded = String::with_capacity(segment.len());
+    for (i, ch) in segment.char_indices() {
+        let escape = RESERVED_CHARS.contains(&ch)
+            || ch.is_ascii_control()
+            || (ch == '%' && escaped_byte(&segment[i..]).is_some())
+            || ((ch == '.' || ch == ' ') && i + 1 == segment.len())
+            || (i == 0 && is_device_name);
+        if escape {
+            encoded.push_str(&format!("%{:02X}", ch as u32));
+        } else {
+            encoded.push(ch);
+        }
+    }
+    encoded
+}
+
+/// Key segment stored under the file name `name`; the inverse of [`encode_key_segment`]
+pub fn decode_key_segment(name: &str) -> String {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(ch) = rest.chars().next() {
+        match escaped_byte(rest) {
+            Some(byte) => {
+                decoded.push(byte as char);
+                rest = &rest[3..];
+            }
+            None => {
+                decoded.push(ch);
+                rest = &rest[ch.len_utf8()..];
+            }
+        }
+    }
+    decoded
+}
+
+/// Stored path of a sanitized object name, relative to its bucket
+pub(crate) fn encode_object_name(object: &str) -> String {
+    object.split('/').map(encode_key_segment).collect::<Vec<_>>().join("/")
+}
+
+/// `encoded`, unless only the path used before key encoding existed holds the object
+fn with_legacy_fallback(encoded: PathBuf, legacy: PathBuf) -> PathBuf {
+    // On Windows the unencoded name could address an alternate data stream instead
+    if cfg!(not(windows)) && encoded != legacy && !encoded.exists() && legacy.exists() {
+        return legacy;
+    }
+    encoded
+}
+
+/// Stored path of an object, sanitized but without creating or canonicalizing anything
+pub fn resolve_object_path(storage_root: &Path, bucket: &str, object: &str) -> Result<PathBuf, PathSecurityError> {
+    let safe_bucket = sanitize_bucket_name(bucket)?;
+    let safe_object = sanitize_object_name(object)?;
+    let bucket_path = storage_root.join(&safe_bucket);
+    Ok(with_legacy_fallback(
+        bucket_path.join(encode_object_name(&safe_object)),
+        bucket_path.join(&safe_object),
+    ))
+}
+
 /// Constructs a safe file path within the storage directory
 pub fn construct_safe_path(
     storage_root: &Path,
     bucket: &str,
     object: &str,
 ) -> Result<PathBuf, PathSecurityError> {
-    // Sanitize inputs
-    let safe_bucket = sanitize_bucket_name(bucket)?;
-    let safe_object = sanitize_object_name(object)?;
-
-    // Construct the path
-    let mut path = storage_root.to_path_buf();
-    path.push(&safe_bucket);
-    path.push(&safe_object);
+    let path = resolve_object_path(storage_root, bucket, object)?;
 
     // Final security check: ensure the constructed path is within storage_root
     let canonical_storage = storage_root.canonicalize().map_err(|_| {
@@ -219,18 +452,14 @@ pub fn construct_safe_metadata_path(
     let safe_bucket = sanitize_bucket_name(bucket)?;
     let safe_object = sanitize_object_name(object)?;
 
-    let mut path = storage_root.to_path_buf();
-    path.push(&safe_bucket);
-    path.push(".fily-metadata");
+    let metadata_dir = bucket_metadata_dir(storage_root, &safe_bucket);
 
     // Create safe filename for metadata (replace path separators with underscores)
-    let metadata_filename = format!("{}.json", safe_object.replace('/', "_"));
-    path.push(metadata_filename);
-
-    // Security check similar to construct_safe_path
-    let canonical_storage = storage_root.canonicalize().map_err(|_| {
-        PathSecurityError::InvalidCharacter("Cannot canonicalize storage root".to_string())
-    })?;
+    let metadata_filename = |object: &str| format!("{}.json", object.replace('/', "_"));
+    let path = with_legacy_fallback(
+        metadata_dir.join(metadata_filename(&encode_object_name(&safe_object))),
+        metadata_dir.join(metadata_filename(&safe_object)),
+    );
 
     if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent).map_err(|_| {
@@ -240,6 +469,11 @@ pub fn construct_safe_metadata_path(
         })?;
     }
 
+    // Security check similar to construct_safe_path
+    let canonical_storage = metadata_base(storage_root).canonicalize().map_err(|_| {
+        PathSecurityError::InvalidCharacter("Cannot canonicalize storage root".to_string())
+    })?;
+
     // For metadata files, we don't need to check canonical path since the file might not exist yet
     // But we do validate that the parent directory is within our storage
     if let Some(parent) = path.parent() {
@@ -256,6 +490,58 @@ pub fn construct_safe_metadata_path(
     Ok(path)
 }
 
+/// Directory in a storage root holding the staging directories of its buckets when metadata is
+/// kept outside them
+pub(crate) fn external_staging_root(storage_root: &Path) -> PathBuf {
+    storage_root.join(STAGING_DIR)
+}
+
+/// Directory a bucket stages object data and commit records in, on the objects' filesystem
+pub(crate) fn staging_dir(storage_root: &Path, bucket: &str) -> PathBuf {
+    match metadata_layout(storage_root) {
+        // Metadata file names always end in .json, so this directory cannot collide with them
+        MetadataLayout::InBucket(name) => storage_root.join(bucket).join(name).join("staging"),
+        MetadataLayout::External(_) => external_staging_root(storage_root).join(bucket),
+    }
+}
+
+/// Directory a bucket stages metadata in, on the metadata's filesystem
+pub(crate) fn metadata_staging_dir(storage_root: &Path, bucket: &str) -> PathBuf {
+    bucket_metadata_dir(storage_root, bucket).join("staging")
+}
+
+fn fresh_path(dir: PathBuf) -> Result<PathBuf, PathSecurityError> {
+    std::fs::create_dir_all(&dir).map_err(|_| {
+        PathSecurityError::InvalidCharacter("Cannot create staging directory".to_string())
+    })?;
+    Ok(dir.join(uuid::Uuid::new_v4().simple().to_string()))
+}
+
+/// Fresh path to stage metadata in before renaming it into place
+pub fn construct_metadata_staging_path(storage_root: &Path, bucket: &str) -> Result<PathBuf, PathSecurityError> {
+    let safe_bucket = sanitize_bucket_name(bucket)?;
+    fresh_path(metadata_staging_dir(storage_root, &safe_bucket))
+}
+
+/// Fresh path to stage object data in before renaming it into place; staying inside the bucket,
+/// or next to the buckets, keeps it on the objects' filesystem
+pub fn construct_staging_path(storage_root: &Path, bucket: &str) -> Result<PathBuf, PathSecurityError> {
+    let safe_bucket = sanitize_bucket_name(bucket)?;
+
+    fresh_path(staging_dir(storage_root, &safe_bucket))
+}
+
+/// Fresh path to write new object data to, on the scratch volume when one is configured and
+/// otherwise where `construct_staging_path` puts it
+pub fn construct_scratch_path(storage_root: &Path, bucket: &str) -> Result<PathBuf, PathSecurityError> {
+    let safe_bucket = sanitize_bucket_name(bucket)?;
+
+    match scratch_dir(storage_root, &safe_bucket) {
+        Some(scratch) => fresh_path(scratch.join("staging")),
+        None => fresh_path(staging_dir(storage_root, &safe_bucket)),
+    }
+}
+
 /// Checks if a string matches an IP address pattern
 fn is_ip_address_pattern(s: &str) -> bool {
     // Simple check for IPv4 pattern (x.x.x.x where x is 1-3 digits)
@@ -338,6 +624,72 @@ mod tests {
         assert!(path.to_string_lossy().contains("file.txt"));
     }
 
+    #[test]
+    fn test_key_segment_encoding() {
+        for (segment, stored) in [
+            ("report.pdf", "report.pdf"),
+            ("12:30:00", "12%3A30%3A00"),
+            ("what?*", "what%3F%2A"),
+            ("name.", "name%2E"),
+            ("trailing ", "trailing%20"),
+            ("con.txt", "%63on.txt"),
+            ("console", "console"),
+            ("100%", "100%"),
+            ("50%off", "50%off"),
+            ("%3A", "%253A"),
+            ("%C3%A9", "%C3%A9"),
+            ("tab\there", "tab%09here"),
+        ] {
+            assert_eq!(encode_key_segment(segment), stored);
+            assert_eq!(decode_key_segment(stored), segment);
+        }
+    }
+
+    #[test]
+    fn test_construct_safe_path_encodes_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_root = temp_dir.path();
+
+        let path = construct_safe_path(storage_root, "logs", "2024:01/app?.log.").unwrap();
+        assert_eq!(path, storage_root.join("logs/2024%3A01/app%3F.log%2E"));
+        let metadata = construct_safe_metadata_path(storage_root, "logs", "2024:01/app?.log.").unwrap();
+        assert_eq!(metadata, storage_root.join("logs/.fily-metadata/2024%3A01_app%3F.log%2E.json"));
+
+        // Objects written before keys were encoded are still found under their original name
+        std::fs::write(storage_root.join("logs/a:b"), b"old").unwrap();
+        assert_eq!(construct_safe_path(storage_root, "logs", "a:b").unwrap(), storage_root.join("logs/a:b"));
+    }
+
+    #[test]
+    fn test_external_metadata_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_root = temp_dir.path().join("data");
+        let metadata_root = temp_dir.path().join("metadata");
+        std::fs::create_dir_all(storage_root.join("logs")).unwrap();
+        register_metadata_layout(&storage_root, MetadataLayout::External(metadata_root.clone()));
+
+        let metadata = construct_safe_metadata_path(&storage_root, "logs", "a/b.txt").unwrap();
+        assert_eq!(metadata, metadata_root.join("logs/a_b.txt.json"));
+        assert_eq!(staging_dir(&storage_root, "logs"), storage_root.join(".fily-staging/logs"));
+        assert_eq!(metadata_dir_name(&storage_root), None);
+        // Tenant namespaces nest under the metadata root like under the storage root
+        let tenant = storage_root.join(".fily-tenants/acme");
+        assert_eq!(bucket_metadata_dir(&tenant, "logs"), metadata_root.join(".fily-tenants/acme/logs"));
+    }
+
+    #[test]
+    fn test_validate_metadata_layout() {
+        let root = Path::new("/srv/fily");
+        assert!(MetadataLayout::default().validate(root).is_ok());
+        assert!(MetadataLayout::InBucket("_meta".to_string()).validate(root).is_ok());
+        assert!(MetadataLayout::InBucket("a/b".to_string()).validate(root).is_err());
+        assert!(MetadataLayout::InBucket("meta:data".to_string()).validate(root).is_err());
+        assert!(MetadataLayout::InBucket("..".to_string()).validate(root).is_err());
+        assert!(MetadataLayout::External("/fast/fily".into()).validate(root).is_ok());
+        assert!(MetadataLayout::External("/srv/fily/.meta".into()).validate(root).is_ok());
+        assert!(MetadataLayout::External("/srv/fily/meta".into()).validate(root).is_err());
+    }
+
     #[test]
     fn test_construct_safe_path_traversal_attempt() {
         let temp_dir = TempDir::new().unwrap();
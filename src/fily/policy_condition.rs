@@ -0,0 +1,319 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use axum::extract::{ConnectInfo, Request};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::connections::ClientConnection;
+use super::request_info::RequestInfo;
+
+/// IPv4 or IPv6 network in CIDR notation; a bare address is a single-host network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+        let network = IpAddr::from_str(address)
+            .map_err(|_| format!("{} is not an IP address or CIDR block", s))?
+            .to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("{} has an invalid prefix length", s))?,
+            None => max_len,
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl Serialize for IpCidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpCidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Conditions a request must meet for a policy entry to apply, named after the AWS condition keys
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PolicyCondition {
+    /// Client address must fall in one of these networks
+    #[serde(rename = "aws:SourceIp", default, skip_serializing_if = "Vec::is_empty")]
+    pub source_ip: Vec<IpCidr>,
+    /// Whether the request must (or must not) have arrived over TLS
+    #[serde(rename = "aws:SecureTransport", default, skip_serializing_if = "Option::is_none")]
+    pub secure_transport: Option<bool>,
+    /// Listings must use a prefix starting with one of these; other requests are unaffected
+    #[serde(rename = "s3:prefix", default, skip_serializing_if = "Vec::is_empty")]
+    pub prefix: Vec<String>,
+    /// Tags the target object must carry, a `null` value for a tag it must not carry; requests
+    /// not naming an object are unaffected
+    #[serde(rename = "s3:ExistingObjectTag", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub existing_object_tag: BTreeMap<String, Option<String>>,
+}
+
+impl PolicyCondition {
+    pub fn is_empty(&self) -> bool {
+        self.source_ip.is_empty()
+            && self.secure_transport.is_none()
+            && self.prefix.is_empty()
+            && self.existing_object_tag.is_empty()
+    }
+
+    pub fn matches(&self, context: &RequestContext) -> bool {
+        // An unknown client address never satisfies an address restriction
+        let source_ip_ok = self.source_ip.is_empty()
+            || context
+                .source_ip
+                .is_some_and(|ip| self.source_ip.iter().any(|cidr| cidr.contains(ip)));
+        let secure_transport_ok = self
+            .secure_transport
+            .is_none_or(|required| required == context.secure_transport);
+        let prefix_ok = match &context.list_prefix {
+            Some(list_prefix) if !self.prefix.is_empty() => {
+                self.prefix.iter().any(|allowed| list_prefix.starts_with(allowed.as_str()))
+            }
+            _ => true,
+        };
+        // An object whose tags were not loaded, e.g. because it does not exist, carries none
+        let tags_ok = context.object_key.is_none()
+            || self.existing_object_tag.iter().all(|(name, value)| {
+                context.object_tags.as_ref().and_then(|tags| tags.get(name)) == value.as_ref()
+            });
+        source_ip_ok && secure_transport_ok && prefix_ok && tags_ok
+    }
+}
+
+/// Request attributes policy conditions are evaluated against
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestContext {
+    pub source_ip: Option<IpAddr>,
+    pub secure_transport: bool,
+    /// Prefix of a ListObjects request, `None` for every other operation
+    pub list_prefix: Option<String>,
+    /// Key of the object the request targets, `None` for bucket and service requests
+    pub object_key: Option<String>,
+    /// Tags of that object, only loaded when a policy has `s3:ExistingObjectTag` conditions
+    pub object_tags: Option<HashMap<String, String>>,
+}
+
+impl RequestContext {
+    /// Context of `req`; forwarding headers are only believed when the peer is a trusted proxy
+    pub fn from_request(req: &Request, trusted_proxies: &[IpCidr]) -> Self {
+        let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(*ip));
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<ClientConnection>>()
+            .map(|ConnectInfo(connection)| connection.addr.ip().to_canonical());
+
+        let (source_ip, secure_transport) = match peer {
+            Some(peer) if is_trusted(&peer) => {
+                let header = |name: &str| {
+                    req.headers()
+                        .get_all(name)
+                        .iter()
+                        .filter_map(|v| v.to_str().ok())
+                        .flat_map(|v| v.split(','))
+                        .map(str::trim)
+                        .collect::<Vec<_>>()
+                };
+                // The client is the nearest address not added by one of our own proxies
+                let forwarded: Vec<IpAddr> = header("x-forwarded-for")
+                    .into_iter()
+                    .filter_map(|ip| ip.parse::<IpAddr>().ok())
+                    .map(|ip| ip.to_canonical())
+                    .collect();
+                let client = forwarded
+                    .iter()
+                    .rev()
+                    .find(|ip| !is_trusted(ip))
+                    .or(forwarded.first())
+                    .copied()
+                    .unwrap_or(peer);
+                let secure = header("x-forwarded-proto")
+                    .first()
+                    .is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+                (Some(client), secure)
+            }
+            // fily itself only speaks plain HTTP
+            peer => (peer, false),
+        };
+
+        Self {
+            source_ip,
+            secure_transport,
+            list_prefix: list_prefix(req),
+            object_key: RequestInfo::of(req).target.key().map(str::to_string),
+            object_tags: None,
+        }
+    }
+}
+
+/// Prefix of a ListObjects request (`GET /{bucket}`), empty when the listing is unfiltered
+fn list_prefix(req: &Request) -> Option<String> {
+    let path = req.uri().path().trim_matches('/');
+    if req.method() != hyper::Method::GET || path.is_empty() || path.contains('/') {
+        return None;
+    }
+    let prefix = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(name, _)| name == "prefix")
+        .map(|(_, value)| value.into_owned());
+    Some(prefix.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use std::net::SocketAddr;
+
+    fn request(uri: &str, peer: &str, headers: &[(&str, &str)]) -> Request {
+        let mut builder = Request::get(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        let addr: SocketAddr = peer.parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(ClientConnection::new(addr)));
+        req
+    }
+
+    #[test]
+    fn test_cidr_parse_and_contains() {
+        let cidr: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.200.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.0.9".parse().unwrap()));
+
+        let host: IpCidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.to_string(), "2001:db8::1/128");
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpCidr>().unwrap().contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("office".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn test_condition_matches() {
+        let condition: PolicyCondition = serde_json::from_str(
+            r#"{"aws:SourceIp": ["192.168.0.0/24"], "aws:SecureTransport": true, "s3:prefix": ["shared/"]}"#,
+        )
+        .unwrap();
+        let context = RequestContext {
+            source_ip: Some("192.168.0.7".parse().unwrap()),
+            secure_transport: true,
+            list_prefix: Some("shared/reports/".to_string()),
+            ..Default::default()
+        };
+        assert!(condition.matches(&context));
+        assert!(condition.matches(&RequestContext {
+            list_prefix: None,
+            ..context.clone()
+        }));
+
+        assert!(!condition.matches(&RequestContext {
+            source_ip: Some("192.168.1.7".parse().unwrap()),
+            ..context.clone()
+        }));
+        assert!(!condition.matches(&RequestContext {
+            source_ip: None,
+            ..context.clone()
+        }));
+        assert!(!condition.matches(&RequestContext {
+            secure_transport: false,
+            ..context.clone()
+        }));
+        assert!(!condition.matches(&RequestContext {
+            list_prefix: Some(String::new()),
+            ..context
+        }));
+        assert!(PolicyCondition::default().matches(&RequestContext::default()));
+    }
+
+    #[test]
+    fn test_existing_object_tag_condition() {
+        let condition: PolicyCondition =
+            serde_json::from_str(r#"{"s3:ExistingObjectTag": {"team": "web", "pii": null}}"#).unwrap();
+        assert!(!condition.is_empty());
+        let tagged = |tags: &[(&str, &str)]| RequestContext {
+            object_key: Some("report.csv".to_string()),
+            object_tags: Some(tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+            ..Default::default()
+        };
+
+        assert!(condition.matches(&tagged(&[("team", "web"), ("tmp", "true")])));
+        assert!(!condition.matches(&tagged(&[("team", "web"), ("pii", "true")])));
+        assert!(!condition.matches(&tagged(&[("team", "data")])));
+        assert!(!condition.matches(&RequestContext {
+            object_tags: None,
+            ..tagged(&[])
+        }));
+        // Listings and bucket requests name no object
+        assert!(condition.matches(&RequestContext::default()));
+    }
+
+    #[test]
+    fn test_context_from_request() {
+        let trusted: Vec<IpCidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let forwarded = [("x-forwarded-for", "203.0.113.9, 10.0.0.2"), ("x-forwarded-proto", "https")];
+
+        let direct = RequestContext::from_request(&request("/photos?prefix=2023/", "198.51.100.1:5000", &forwarded), &trusted);
+        assert_eq!(direct.source_ip, Some("198.51.100.1".parse().unwrap()));
+        assert!(!direct.secure_transport);
+        assert_eq!(direct.list_prefix.as_deref(), Some("2023/"));
+        assert_eq!(direct.object_key, None);
+
+        let proxied = RequestContext::from_request(&request("/photos/a.jpg", "10.0.0.1:5000", &forwarded), &trusted);
+        assert_eq!(proxied.source_ip, Some("203.0.113.9".parse().unwrap()));
+        assert!(proxied.secure_transport);
+        assert_eq!(proxied.list_prefix, None);
+        assert_eq!(proxied.object_key.as_deref(), Some("a.jpg"));
+
+        let unfiltered = RequestContext::from_request(&request("/photos", "10.0.0.1:5000", &[]), &trusted);
+        assert_eq!(unfiltered.source_ip, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(unfiltered.list_prefix.as_deref(), Some(""));
+    }
+}
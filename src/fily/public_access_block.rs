@@ -0,0 +1,281 @@
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use bytes::Bytes;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::auth_middleware::AuthenticatedAccessKey;
+use super::bucket_policy::manageable_policy;
+use super::path_security::{bucket_metadata_dir, sanitize_bucket_name};
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::Config;
+
+/// Stored next to the bucket policy, under a name no object key maps to
+const PUBLIC_ACCESS_BLOCK_FILE: &str = "public-access-block";
+const S3_XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Canned ACLs that open objects or buckets beyond the owner
+const PUBLIC_CANNED_ACLS: &[&str] = &["public-read", "public-read-write", "authenticated-read"];
+/// Grantee groups that make a grant public
+const PUBLIC_GROUPS: &[&str] = &[
+    "http://acs.amazonaws.com/groups/global/AllUsers",
+    "http://acs.amazonaws.com/groups/global/AuthenticatedUsers",
+];
+
+/// S3 PublicAccessBlockConfiguration; absent settings are off
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PublicAccessBlock {
+    #[serde(rename = "BlockPublicAcls", default)]
+    pub block_public_acls: bool,
+    #[serde(rename = "IgnorePublicAcls", default)]
+    pub ignore_public_acls: bool,
+    #[serde(rename = "BlockPublicPolicy", default)]
+    pub block_public_policy: bool,
+    #[serde(rename = "RestrictPublicBuckets", default)]
+    pub restrict_public_buckets: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct PublicAccessBlockConfiguration {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "BlockPublicAcls")]
+    block_public_acls: bool,
+    #[serde(rename = "IgnorePublicAcls")]
+    ignore_public_acls: bool,
+    #[serde(rename = "BlockPublicPolicy")]
+    block_public_policy: bool,
+    #[serde(rename = "RestrictPublicBuckets")]
+    restrict_public_buckets: bool,
+}
+
+impl From<PublicAccessBlock> for PublicAccessBlockConfiguration {
+    fn from(settings: PublicAccessBlock) -> Self {
+        Self {
+            xmlns: S3_XMLNS.to_string(),
+            block_public_acls: settings.block_public_acls,
+            ignore_public_acls: settings.ignore_public_acls,
+            block_public_policy: settings.block_public_policy,
+            restrict_public_buckets: settings.restrict_public_buckets,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PolicyStatus {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "IsPublic")]
+    is_public: bool,
+}
+
+fn public_access_block_path(storage_root: &FsPath, bucket: &str) -> anyhow::Result<std::path::PathBuf> {
+    let bucket = sanitize_bucket_name(bucket)
+        .map_err(|e| anyhow::anyhow!("Public access block path security violation: {}", e))?;
+    Ok(bucket_metadata_dir(storage_root, &bucket).join(PUBLIC_ACCESS_BLOCK_FILE))
+}
+
+pub async fn load_public_access_block(storage_root: &FsPath, bucket: &str) -> anyhow::Result<Option<PublicAccessBlock>> {
+    let path = public_access_block_path(storage_root, bucket)?;
+    match super::replica::read_store_file(storage_root, &path).await? {
+        Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_public_access_block(storage_root: &FsPath, bucket: &str, block: &PublicAccessBlock) -> anyhow::Result<()> {
+    let path = public_access_block_path(storage_root, bucket)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&path, serde_json::to_string(block)?).await?;
+    Ok(())
+}
+
+/// Removes the bucket's public access block; a bucket without one is left as it is
+pub async fn delete_public_access_block(storage_root: &FsPath, bucket: &str) -> anyhow::Result<()> {
+    let path = public_access_block_path(storage_root, bucket)?;
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the request's ACL headers grant access to everyone or every AWS user
+fn sets_public_acl(headers: &HeaderMap) -> bool {
+    let canned = headers
+        .get("x-amz-acl")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|acl| PUBLIC_CANNED_ACLS.contains(&acl));
+    let grants = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-amz-grant-"))
+        .filter_map(|(_, value)| value.to_str().ok())
+        .any(|grantees| PUBLIC_GROUPS.iter().any(|group| grantees.contains(group)));
+    canned || grants
+}
+
+/// Rejects public ACLs on buckets that block them
+pub async fn reject_public_acl(storage_root: &FsPath, bucket: &str, headers: &HeaderMap) -> Result<(), S3AppError> {
+    if !sets_public_acl(headers) {
+        return Ok(());
+    }
+    let block = load_public_access_block(storage_root, bucket).await.ok().flatten();
+    if block.is_some_and(|block| block.block_public_acls) {
+        warn!("Public ACL rejected by the public access block of bucket {}", bucket);
+        return Err(S3AppError::with_message_and_resource(
+            S3ErrorCode::AccessDenied,
+            "Public ACLs are blocked by the bucket's public access block".to_string(),
+            format!("/{}", bucket),
+        ));
+    }
+    Ok(())
+}
+
+fn xml_response<T: Serialize>(value: &T) -> Result<Response, S3AppError> {
+    let xml = quick_xml::se::to_string(value).map_err(|e| S3AppError::internal_error(&e.to_string()))?;
+    let mut resp = Response::new(Body::from(xml));
+    resp.headers_mut()
+        .insert("content-type", "application/xml".parse().unwrap());
+    Ok(resp)
+}
+
+/// GET /{bucket}?policyStatus; fily policies only grant access to named accounts, so a bucket
+/// is never public
+pub async fn get_policy_status(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+    xml_response(&PolicyStatus {
+        xmlns: S3_XMLNS.to_string(),
+        is_public: false,
+    })
+}
+
+/// GET /{bucket}?publicAccessBlock
+pub async fn get(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let settings = load_public_access_block(FsPath::new(&config.location), &bucket)
+        .await
+        .map_err(|e| {
+            error!("Failed to load public access block for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to load public access block: {}", e))
+        })?
+        .ok_or_else(|| {
+            S3AppError::with_resource(S3ErrorCode::NoSuchPublicAccessBlockConfiguration, format!("/{}", bucket))
+        })?;
+    xml_response(&PublicAccessBlockConfiguration::from(settings))
+}
+
+/// PUT /{bucket}?publicAccessBlock
+pub async fn put(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+    body: Bytes,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    let settings: PublicAccessBlock = std::str::from_utf8(&body)
+        .ok()
+        .and_then(|xml| quick_xml::de::from_str(xml).ok())
+        .ok_or_else(|| S3AppError::new(S3ErrorCode::MalformedXML))?;
+
+    save_public_access_block(FsPath::new(&config.location), &bucket, &settings)
+        .await
+        .map_err(|e| {
+            error!("Failed to save public access block for bucket {}: {}", bucket, e);
+            S3AppError::internal_error(&format!("Failed to save public access block: {}", e))
+        })?;
+
+    info!("Public access block of bucket {} set to {:?} by {}", bucket, settings, access_key.0);
+    Ok(StatusCode::OK.into_response())
+}
+
+/// DELETE /{bucket}?publicAccessBlock
+pub async fn delete(
+    config: Extension<Arc<Config>>,
+    Extension(access_key): Extension<AuthenticatedAccessKey>,
+    Path(bucket): Path<String>,
+) -> Result<Response, S3AppError> {
+    manageable_policy(&config, &access_key, &bucket).await?;
+
+    if let Err(e) = delete_public_access_block(FsPath::new(&config.location), &bucket).await {
+        error!("Failed to delete public access block for bucket {}: {}", bucket, e);
+        return Err(S3AppError::internal_error(&format!(
+            "Failed to delete public access block: {}",
+            e
+        )));
+    }
+
+    info!("Public access block of bucket {} deleted by {}", bucket, access_key.0);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_public_access_block_xml() {
+        let xml = r#"<PublicAccessBlockConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><BlockPublicAcls>true</BlockPublicAcls><RestrictPublicBuckets>true</RestrictPublicBuckets></PublicAccessBlockConfiguration>"#;
+        let settings: PublicAccessBlock = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(
+            settings,
+            PublicAccessBlock {
+                block_public_acls: true,
+                restrict_public_buckets: true,
+                ..Default::default()
+            }
+        );
+
+        let response = xml_response(&PublicAccessBlockConfiguration::from(settings)).unwrap();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            r#"<PublicAccessBlockConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><BlockPublicAcls>true</BlockPublicAcls><IgnorePublicAcls>false</IgnorePublicAcls><BlockPublicPolicy>false</BlockPublicPolicy><RestrictPublicBuckets>true</RestrictPublicBuckets></PublicAccessBlockConfiguration>"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_public_acl() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        let mut public = HeaderMap::new();
+        public.insert("x-amz-acl", "public-read".parse().unwrap());
+        let mut group = HeaderMap::new();
+        group.insert(
+            "x-amz-grant-read",
+            "uri=\"http://acs.amazonaws.com/groups/global/AllUsers\"".parse().unwrap(),
+        );
+        let mut private = HeaderMap::new();
+        private.insert("x-amz-acl", "private".parse().unwrap());
+
+        assert!(reject_public_acl(root, "photos", &public).await.is_ok());
+
+        let block = PublicAccessBlock {
+            block_public_acls: true,
+            ..Default::default()
+        };
+        save_public_access_block(root, "photos", &block).await.unwrap();
+        assert!(matches!(
+            reject_public_acl(root, "photos", &public).await.unwrap_err().code,
+            S3ErrorCode::AccessDenied
+        ));
+        assert!(reject_public_acl(root, "photos", &group).await.is_err());
+        assert!(reject_public_acl(root, "photos", &private).await.is_ok());
+    }
+}
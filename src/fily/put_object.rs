@@ -6,18 +6,41 @@ use axum::Extension;
 use bytes::Bytes;
 use hyper::{HeaderMap, StatusCode};
 use tracing::{debug, info, error, instrument};
-use sha2::{Sha256, Digest};
 
-use super::encryption::{Encryptor, KeyManager, XChaCha20Poly1305Encryptor};
-use super::etag::generate_etag;
-use super::metadata::{ObjectMetadata, extract_user_metadata, save_metadata};
-use super::path_security::construct_safe_path;
+use super::auth_middleware::Principal;
+use super::aws_chunked::{
+    decode_aws_chunked, is_aws_chunked_request, verify_trailing_checksum, AwsChunkedError,
+};
+use super::cpu_pool::CpuPool;
+use super::encryption::{new_encryption_id, seal_object, KeyManager, XChaCha20Poly1305Encryptor};
+use super::commit::{commit_object, write_synced, ObjectData};
+use super::etag::content_digests;
+use super::idempotency;
+use super::key_locks;
+use super::metadata::{ObjectMetadata, extract_tags, extract_user_metadata, load_metadata};
+use super::ownership_controls::reject_acl_write;
+use super::path_security::{construct_safe_path, construct_scratch_path};
 use super::s3_app_error::S3AppError;
 use super::Config;
 
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    Extension(cpu_pool): Extension<Arc<CpuPool>>,
+    principal: Principal,
+    headers: HeaderMap,
+    Path((bucket, file)): Path<(String, String)>,
+    bytes: Bytes,
+) -> anyhow::Result<Response, S3AppError> {
+    idempotency::put(config, cpu_pool, principal, headers, bucket, file, bytes).await
+}
+
+/// Stores an object like a PUT. `adjust` can amend the metadata before it is saved, for writers
+/// such as compose that describe the object differently; data and metadata are written under the
+/// key's lock, so a concurrent write cannot pair its data with this write's metadata.
+#[allow(clippy::too_many_arguments)]
 #[instrument(
     name = "put_object",
-    skip(config, headers, bytes),
+    skip(config, cpu_pool, headers, bytes, adjust),
     fields(
         bucket = %bucket,
         object = %file,
@@ -26,16 +49,41 @@ use super::Config;
         has_encryption = config.encryption.as_ref().map(|e| e.enabled).unwrap_or(false)
     )
 )]
-pub async fn handle(
+pub(crate) async fn store(
     config: Extension<Arc<Config>>,
+    cpu_pool: Arc<CpuPool>,
+    principal: Principal,
     headers: HeaderMap,
-    Path((bucket, file)): Path<(String, String)>,
+    bucket: String,
+    file: String,
     bytes: Bytes,
+    adjust: impl FnOnce(&mut ObjectMetadata),
 ) -> anyhow::Result<Response, S3AppError> {
-    info!("Starting PUT object operation for {}/{}", bucket, file);
+    info!("Starting PUT object operation for {}/{} by {}", bucket, file, principal);
     debug!("Request headers: {:?}", headers);
     debug!("Content length: {} bytes", bytes.len());
 
+    reject_acl_write(std::path::Path::new(&config.location), &bucket, &headers, false).await?;
+
+    // Decode aws-chunked bodies and verify the trailing checksum before touching disk
+    let mut verified_checksum = None;
+    let bytes = if is_aws_chunked_request(&headers) {
+        let trailer_headers = headers.clone();
+        let (decoded, checksum) = cpu_pool
+            .run(move || {
+                let decoded = decode_aws_chunked(&bytes)?;
+                let checksum = verify_trailing_checksum(&trailer_headers, &decoded)?;
+                Ok::<_, AwsChunkedError>((decoded, checksum))
+            })
+            .await?
+            .map_err(chunked_error_to_s3)?;
+        verified_checksum = checksum;
+        debug!("Decoded aws-chunked body to {} bytes", decoded.data.len());
+        decoded.data
+    } else {
+        bytes
+    };
+
     // Use secure path construction to prevent path traversal attacks
     let storage_root = std::path::Path::new(&config.location);
     let path = match construct_safe_path(storage_root, &bucket, &file) {
@@ -61,7 +109,7 @@ pub async fn handle(
                     anyhow::anyhow!("Directory creation failed: {}", e)
                 })?;
 
-            let data_to_write = if let Some(encryption_config) = &config.encryption {
+            let encryptor = if let Some(encryption_config) = &config.encryption {
                 if encryption_config.enabled {
                     info!("Encryption is enabled, encrypting object data");
                     
@@ -72,21 +120,7 @@ pub async fn handle(
                                 error!("Failed to initialize encryption key manager: {}", e);
                                 anyhow::anyhow!("Encryption key error: {}", e)
                             })?;
-                        let encryptor = XChaCha20Poly1305Encryptor::new(key_manager);
-
-                        let associated_data = format!("{}/{}", bucket, file);
-                        debug!("Using associated data for encryption: {}", associated_data);
-                        
-                        let encrypted_data = encryptor
-                            .encrypt(bytes.as_ref(), associated_data.as_bytes())
-                            .map_err(|e| {
-                                error!("Encryption failed for {}/{}: {}", bucket, file, e);
-                                anyhow::anyhow!("Encryption failed: {}", e)
-                            })?;
-                        
-                        info!("Successfully encrypted object data (original: {} bytes, encrypted: {} bytes)", 
-                              bytes.len(), encrypted_data.len());
-                        encrypted_data
+                        Some(XChaCha20Poly1305Encryptor::new(key_manager))
                     } else {
                         error!("Encryption is enabled but no master key provided in configuration");
                         return Err(S3AppError::internal_error(
@@ -95,27 +129,52 @@ pub async fn handle(
                     }
                 } else {
                     debug!("Encryption is disabled, storing object data unencrypted");
-                    bytes.to_vec()
+                    None
                 }
             } else {
                 debug!("No encryption configuration found, storing object data unencrypted");
-                bytes.to_vec()
+                None
+            };
+
+            // Encryption and hashing of large bodies would stall the async runtime threads
+            let encryption_id = encryptor.as_ref().map(|_| new_encryption_id());
+            let object_encryption_id = encryption_id.clone();
+            let body = bytes.clone();
+            let (encrypted, etag, content_sha256) = cpu_pool
+                .run(move || {
+                    let encrypted = encryptor
+                        .zip(object_encryption_id)
+                        .map(|(encryptor, id)| seal_object(&encryptor, body.as_ref(), &id))
+                        .transpose();
+
+                    // ETag and the SHA-256 cached for signature validation, both of the
+                    // original content (before encryption)
+                    let (etag, content_sha256) = content_digests(body.as_ref());
+
+                    (encrypted, etag, content_sha256)
+                })
+                .await?;
+
+            let data_to_write = match encrypted {
+                Ok(Some(encrypted_data)) => {
+                    info!("Successfully encrypted object data (original: {} bytes, encrypted: {} bytes)", 
+                          bytes.len(), encrypted_data.len());
+                    encrypted_data
+                }
+                Ok(None) => bytes.to_vec(),
+                Err(e) => {
+                    error!("Encryption failed for {}/{}: {}", bucket, file, e);
+                    return Err(anyhow::anyhow!("Encryption failed: {}", e).into());
+                }
             };
 
-            debug!("Writing {} bytes to disk at {}", data_to_write.len(), path.display());
-            tokio::fs::write(&path, &data_to_write).await
+            debug!("Staging {} bytes for {}", data_to_write.len(), path.display());
+            let staged = stage_object(storage_root, &bucket, &data_to_write).await
                 .map_err(|e| {
                     error!("Failed to write object {}/{} to disk: {}", bucket, file, e);
                     anyhow::anyhow!("File write failed: {}", e)
                 })?;
-            
-            // Generate e-tag for the original content (before encryption)
-            let etag = generate_etag(bytes.as_ref());
-            
-            // Compute SHA256 hash of original content for signature validation caching
-            let mut hasher = Sha256::new();
-            hasher.update(bytes.as_ref());
-            let content_sha256 = hex::encode(hasher.finalize());
+
             debug!("Computed content SHA256 hash for caching: {}", content_sha256);
             
             // Extract content-type from headers
@@ -133,21 +192,39 @@ pub async fn handle(
                 content_sha256,
             );
             
+            if let Some(checksum) = &verified_checksum {
+                metadata.set_checksum(checksum.algorithm.as_str(), checksum.value.clone());
+            }
+            metadata.encryption_id = encryption_id;
+
             // Add user metadata from x-amz-meta-* headers
             let user_metadata = extract_user_metadata(&headers);
             for (key, value) in user_metadata {
                 metadata.add_user_metadata(key, value);
             }
-            
-            // Save metadata to disk
-            let storage_path = std::path::Path::new(&config.location);
-            if let Err(e) = save_metadata(storage_path, &bucket, &file, &metadata).await {
-                error!("Failed to save metadata for {}/{}: {}", bucket, file, e);
-                // Continue despite metadata save failure
-            }
+            metadata.tags = extract_tags(&headers);
+
+            // Data and metadata are committed together, keeping the creator of the object this
+            // write replaces
+            let _lock = key_locks::lock(storage_root, &bucket, &file).await;
+            let previous = load_metadata(storage_root, &bucket, &file).await.ok().flatten();
+            metadata.record_write(&principal.0, previous.as_ref());
+            adjust(&mut metadata);
+            commit_object(storage_root, &bucket, &file, ObjectData::Staged(&staged), &metadata)
+                .await
+                .map_err(|e| {
+                    error!("Failed to commit object {}/{}: {}", bucket, file, e);
+                    anyhow::anyhow!("File write failed: {}", e)
+                })?;
             
             let mut response_headers = HeaderMap::new();
-            response_headers.insert("etag", etag.parse().unwrap());
+            response_headers.insert("etag", metadata.etag.parse().unwrap());
+
+            if let Some(checksum) = &verified_checksum {
+                if let Ok(value) = checksum.value.parse() {
+                    response_headers.insert(checksum.algorithm.header_name(), value);
+                }
+            }
             
             // Include content-type in response if provided
             if let Some(ct) = content_type {
@@ -156,7 +233,10 @@ pub async fn handle(
                 }
             }
                 
-            info!("Successfully stored object {}/{} ({} bytes)", bucket, file, data_to_write.len());
+            info!(
+                "Successfully stored object {}/{} ({} bytes) for {}",
+                bucket, file, data_to_write.len(), principal
+            );
             Ok((StatusCode::OK, response_headers, "").into_response())
         }
         None => {
@@ -167,3 +247,34 @@ pub async fn handle(
         }
     }
 }
+
+/// Writes object data to a durable staged file for `commit_object` to move into place, so
+/// readers never see a partial object and hard-linked copies of the previous data are left
+/// untouched. The file is on the scratch volume when one is configured.
+pub(crate) async fn stage_object(
+    storage_root: &std::path::Path,
+    bucket: &str,
+    data: &[u8],
+) -> anyhow::Result<std::path::PathBuf> {
+    let staging = construct_scratch_path(storage_root, bucket)
+        .map_err(|e| anyhow::anyhow!("Path security violation: {}", e))?;
+    if let Err(e) = write_synced(&staging, data).await {
+        let _ = tokio::fs::remove_file(&staging).await;
+        return Err(e.into());
+    }
+    Ok(staging)
+}
+
+fn chunked_error_to_s3(err: AwsChunkedError) -> S3AppError {
+    error!("Rejecting aws-chunked upload: {}", err);
+    match err {
+        AwsChunkedError::ChecksumMismatch(header) => S3AppError::with_message(
+            super::s3_app_error::S3ErrorCode::BadDigest,
+            format!("The {} you specified did not match the calculated checksum.", header),
+        ),
+        other => S3AppError::with_message(
+            super::s3_app_error::S3ErrorCode::InvalidRequest,
+            other.to_string(),
+        ),
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::debug;
+
+use super::migrations::{self, CURRENT_LAYOUT};
+use super::path_security::lookup;
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::tenancy::BucketAccess;
+
+/// Cached files per store; the cache starts over once it grows beyond this
+const MAX_CACHED_FILES: usize = 100_000;
+
+/// `/_fily` endpoints a replica serves for every method: they only change in-process state or
+/// read objects
+const ALLOWED_EXTENSIONS: [&str; 3] = ["/_fily/admin/", "/_fily/presigned-urls", "/_fily/batch/get/"];
+
+/// Serving a store another fily instance writes to, e.g. over NFS or CephFS, for read scaling
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaConfig {
+    pub enabled: bool,
+    /// Longest a cached metadata file is used without checking its modification time
+    pub max_staleness: Duration,
+}
+
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_staleness: Duration::from_secs(5),
+        }
+    }
+}
+
+struct CachedFile {
+    /// Modification time and length the contents were read at
+    version: Option<(SystemTime, u64)>,
+    /// `None` for a file that did not exist
+    contents: Option<Arc<Vec<u8>>>,
+    checked: Instant,
+}
+
+/// Small files (object metadata, bucket policies) read from a shared store. An entry is used
+/// as is for up to `max_staleness`, then kept only while the file's mtime and length are
+/// unchanged, so a replica never serves a file more than `max_staleness` older than the store.
+pub struct FileCache {
+    max_staleness: Duration,
+    files: RwLock<HashMap<PathBuf, CachedFile>>,
+}
+
+impl FileCache {
+    pub fn new(max_staleness: Duration) -> Self {
+        Self {
+            max_staleness,
+            files: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Contents of the file at `path`, `None` when it does not exist
+    pub async fn read(&self, path: &Path) -> std::io::Result<Option<Arc<Vec<u8>>>> {
+        let cached = self.files.read().unwrap().get(path).map(|file| {
+            (file.version, file.contents.clone(), file.checked.elapsed() < self.max_staleness)
+        });
+        if let Some((_, contents, true)) = &cached {
+            metrics::counter!("fily_replica_cache_total", "result" => "hit").increment(1);
+            return Ok(contents.clone());
+        }
+
+        let version = match tokio::fs::metadata(path).await {
+            Ok(metadata) => Some((metadata.modified()?, metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let contents = match cached {
+            Some((cached_version, contents, _)) if cached_version == version => {
+                metrics::counter!("fily_replica_cache_total", "result" => "revalidated").increment(1);
+                contents
+            }
+            _ => {
+                metrics::counter!("fily_replica_cache_total", "result" => "miss").increment(1);
+                match version {
+                    Some(_) => match tokio::fs::read(path).await {
+                        Ok(contents) => Some(Arc::new(contents)),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                        Err(e) => return Err(e),
+                    },
+                    None => None,
+                }
+            }
+        };
+
+        let mut files = self.files.write().unwrap();
+        if files.len() >= MAX_CACHED_FILES {
+            files.clear();
+        }
+        files.insert(
+            path.to_path_buf(),
+            CachedFile {
+                version,
+                contents: contents.clone(),
+                checked: Instant::now(),
+            },
+        );
+        Ok(contents)
+    }
+}
+
+/// Caches of the stores this process serves as a replica
+fn caches() -> &'static RwLock<HashMap<PathBuf, Arc<FileCache>>> {
+    static CACHES: OnceLock<RwLock<HashMap<PathBuf, Arc<FileCache>>>> = OnceLock::new();
+    CACHES.get_or_init(Default::default)
+}
+
+/// File cache of the replicated store `storage_root` is, or is nested in
+fn cache(storage_root: &Path) -> Option<Arc<FileCache>> {
+    lookup(caches(), storage_root).map(|(cache, _)| cache)
+}
+
+/// Contents of a small file of the store at `storage_root`, through its cache when the store is
+/// replicated; `None` when the file does not exist
+pub(crate) async fn read_store_file(storage_root: &Path, path: &Path) -> std::io::Result<Option<Arc<Vec<u8>>>> {
+    if let Some(cache) = cache(storage_root) {
+        return cache.read(path).await;
+    }
+    match tokio::fs::read(path).await {
+        Ok(contents) => Ok(Some(Arc::new(contents))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens the store at `location` for serving without writing to it. The instance writing to the
+/// store owns migrations, recovery and clean-up, so the store must already be at the current
+/// layout.
+pub fn open(location: &str, config: &ReplicaConfig) -> anyhow::Result<()> {
+    let root = Path::new(location);
+    if !root.is_dir() {
+        return Err(anyhow::anyhow!("Replicated storage location {} is not a directory", location));
+    }
+    match migrations::layout_version(root)? {
+        None => {}
+        Some(CURRENT_LAYOUT) => {}
+        Some(version) => {
+            return Err(anyhow::anyhow!(
+                "Replicated storage location {} has layout version {}, not {}; start the primary instance to migrate it first",
+                location,
+                version,
+                CURRENT_LAYOUT
+            ))
+        }
+    }
+    migrations::open_metadata_layout(root)
+        .map_err(|e| anyhow::anyhow!("Cannot read the metadata location of {}: {}", location, e))?;
+    caches()
+        .write()
+        .unwrap()
+        .insert(root.to_path_buf(), Arc::new(FileCache::new(config.max_staleness)));
+    Ok(())
+}
+
+/// Whether a replica serves `method` on `path`: reads, and extensions that do not write to the
+/// store
+fn is_allowed(method: &hyper::Method, path: &str) -> bool {
+    BucketAccess::for_method(method) == BucketAccess::Read
+        || ALLOWED_EXTENSIONS.iter().any(|prefix| path.starts_with(prefix))
+}
+
+/// Middleware rejecting every write sent to a read-only replica
+pub async fn reject_writes(req: Request, next: Next) -> Response {
+    if is_allowed(req.method(), req.uri().path()) {
+        return next.run(req).await;
+    }
+    debug!("Rejecting {} {} on a read-only replica", req.method(), req.uri().path());
+    metrics::counter!("fily_replica_rejected_writes_total").increment(1);
+    S3AppError::with_message_and_resource(
+        S3ErrorCode::AccessDenied,
+        "This fily instance is a read-only replica; send writes to the primary.".to_string(),
+        req.uri().path().to_string(),
+    )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+
+    #[tokio::test]
+    async fn test_cache_revalidates_after_max_staleness() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("photos.json");
+        let cache = FileCache::new(Duration::from_millis(50));
+        assert_eq!(cache.read(&path).await.unwrap(), None);
+
+        std::fs::write(&path, b"one").unwrap();
+        // A file missing a moment ago is still missing within the staleness bound
+        assert_eq!(cache.read(&path).await.unwrap(), None);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.read(&path).await.unwrap().as_deref(), Some(&b"one".to_vec()));
+
+        std::fs::write(&path, b"three").unwrap();
+        assert_eq!(cache.read(&path).await.unwrap().as_deref(), Some(&b"one".to_vec()));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.read(&path).await.unwrap().as_deref(), Some(&b"three".to_vec()));
+
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.read(&path).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_replica_allows_reads_only() {
+        assert!(is_allowed(&Method::GET, "/photos/a.jpg"));
+        assert!(is_allowed(&Method::HEAD, "/photos/a.jpg"));
+        assert!(is_allowed(&Method::from_bytes(b"PROPFIND").unwrap(), "/_fily/dav/photos"));
+        assert!(is_allowed(&Method::PUT, "/_fily/admin/logging/level"));
+        assert!(is_allowed(&Method::POST, "/_fily/batch/get/photos"));
+        assert!(!is_allowed(&Method::PUT, "/photos/a.jpg"));
+        assert!(!is_allowed(&Method::DELETE, "/photos"));
+        assert!(!is_allowed(&Method::POST, "/_fily/batch/put/photos"));
+        assert!(!is_allowed(&Method::POST, "/_fily/uploads/photos/a.jpg"));
+    }
+}
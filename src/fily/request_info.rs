@@ -0,0 +1,139 @@
+//! Per-request context, created once by the outermost middleware and shared by everything inside
+//! it: the layers and handlers read the request ID, the decoded bucket and key, and what auth and
+//! routing learned about the request from here instead of each re-parsing the URI.
+//!
+//! The context is in the request extensions as `Arc<RequestInfo>`, and while the request is
+//! served it is also the task's current request, so code without the request at hand (error
+//! responses, notifications) can still name it.
+
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+
+use super::request_path::RequestTarget;
+
+pub const REQUEST_ID_HEADER: &str = "x-amz-request-id";
+
+tokio::task_local! {
+    static CURRENT: Arc<RequestInfo>;
+}
+
+/// What is known about a request, filled in as it passes auth and routing
+#[derive(Debug)]
+pub struct RequestInfo {
+    /// Returned as `x-amz-request-id` and in error bodies
+    pub request_id: String,
+    pub received: DateTime<Utc>,
+    pub started: Instant,
+    /// Bucket and key, decoded as the handlers see them
+    pub target: RequestTarget,
+    principal: OnceLock<String>,
+    operation: OnceLock<&'static str>,
+    error_code: OnceLock<String>,
+}
+
+impl RequestInfo {
+    pub fn new(path: &str) -> Self {
+        Self {
+            request_id: uuid::Uuid::new_v4().to_string(),
+            received: Utc::now(),
+            started: Instant::now(),
+            target: RequestTarget::from_path(path),
+            principal: OnceLock::new(),
+            operation: OnceLock::new(),
+            error_code: OnceLock::new(),
+        }
+    }
+
+    /// Context of `req`, or a fresh one for requests that did not pass the middleware (tests
+    /// that drive a single layer)
+    pub fn of(req: &Request) -> Arc<RequestInfo> {
+        req.extensions()
+            .get::<Arc<RequestInfo>>()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(RequestInfo::new(req.uri().path())))
+    }
+
+    /// Access key the request authenticated as
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.get().map(String::as_str)
+    }
+
+    pub fn set_principal(&self, access_key: &str) {
+        let _ = self.principal.set(access_key.to_string());
+    }
+
+    /// S3 operation of the handler the request was routed to
+    pub fn operation(&self) -> Option<&'static str> {
+        self.operation.get().copied()
+    }
+
+    pub fn set_operation(&self, operation: &'static str) {
+        let _ = self.operation.set(operation);
+    }
+
+    /// Code of the error the request was answered with
+    pub fn error_code(&self) -> Option<&str> {
+        self.error_code.get().map(String::as_str)
+    }
+
+    pub fn set_error_code(&self, code: &str) {
+        let _ = self.error_code.set(code.to_string());
+    }
+}
+
+/// The request the current task is serving, if any
+pub fn current() -> Option<Arc<RequestInfo>> {
+    CURRENT.try_with(Arc::clone).ok()
+}
+
+/// Middleware creating the request's context and answering with its ID
+pub async fn attach(mut req: Request, next: Next) -> Response {
+    let info = Arc::new(RequestInfo::new(req.uri().path()));
+    req.extensions_mut().insert(info.clone());
+    let mut response = CURRENT.scope(info.clone(), next.run(req)).await;
+    if let Ok(request_id) = info.request_id.parse() {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use crate::fily::s3_app_error::{S3AppError, S3ErrorCode};
+
+    #[tokio::test]
+    async fn test_context_shared_with_inner_layers() {
+        async fn handler(req: Request) -> Response {
+            let info = RequestInfo::of(&req);
+            assert_eq!(info.target.bucket(), Some("photos"));
+            assert_eq!(info.target.key(), Some("a b.jpg"));
+            assert_eq!(current().map(|c| c.request_id.clone()), Some(info.request_id.clone()));
+            S3AppError::new(S3ErrorCode::NoSuchKey).into_response()
+        }
+        let app = Router::new()
+            .route("/{bucket}/{key}", get(handler))
+            .layer(axum::middleware::from_fn(attach));
+
+        let response = app
+            .oneshot(Request::get("/photos/a%20b.jpg").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        // The error body and the header carry the same ID
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains(&request_id));
+        assert!(current().is_none());
+    }
+}
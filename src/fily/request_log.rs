@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use axum::extract::{ConnectInfo, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::{error, info, warn, Event, Metadata};
+use tracing_subscriber::layer::{Context, Filter};
+
+use super::connections::ClientConnection;
+use super::request_info::RequestInfo;
+use super::Config;
+
+/// Target of the one-line-per-request log events subject to sampling
+pub const REQUEST_LOG_TARGET: &str = "fily::request_log";
+
+/// Target of requests slower than the configured threshold; never sampled
+pub const SLOW_REQUEST_LOG_TARGET: &str = "fily::slow_request";
+
+/// Fraction of successful requests to log, overall and per HTTP method
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SamplingConfig {
+    pub default_rate: f64,
+    #[serde(default)]
+    pub method_rates: HashMap<String, f64>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            default_rate: 1.0,
+            method_rates: HashMap::new(),
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let rates = std::iter::once(("default", self.default_rate))
+            .chain(self.method_rates.iter().map(|(m, r)| (m.as_str(), *r)));
+        for (name, rate) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!(
+                    "Sample rate for {} must be between 0.0 and 1.0, got {}",
+                    name, rate
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn rate_for(&self, method: &str) -> f64 {
+        self.method_rates
+            .iter()
+            .find(|(m, _)| m.eq_ignore_ascii_case(method))
+            .map(|(_, rate)| *rate)
+            .unwrap_or(self.default_rate)
+    }
+}
+
+/// Decides which request log lines are emitted; errors are never sampled away
+pub struct RequestLogSampler {
+    config: RwLock<SamplingConfig>,
+}
+
+impl RequestLogSampler {
+    pub fn new(config: SamplingConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+        }
+    }
+
+    pub fn config(&self) -> SamplingConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn update(&self, config: SamplingConfig) -> Result<(), String> {
+        config.validate()?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn should_log(&self, method: &str, status: u64) -> bool {
+        if status >= 400 {
+            return true;
+        }
+        let rate = self.config.read().unwrap().rate_for(method);
+        rate >= 1.0 || (rate > 0.0 && rand::random::<f64>() < rate)
+    }
+}
+
+/// Per-layer filter that samples successful request log events
+pub struct SamplingFilter {
+    sampler: Arc<RequestLogSampler>,
+}
+
+impl SamplingFilter {
+    pub fn new(sampler: Arc<RequestLogSampler>) -> Self {
+        Self { sampler }
+    }
+}
+
+impl<S> Filter<S> for SamplingFilter {
+    fn enabled(&self, _meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        true
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, _cx: &Context<'_, S>) -> bool {
+        if event.metadata().target() != REQUEST_LOG_TARGET {
+            return true;
+        }
+
+        let mut visitor = RequestFieldVisitor::default();
+        event.record(&mut visitor);
+        self.sampler
+            .should_log(visitor.method.as_deref().unwrap_or_default(), visitor.status)
+    }
+}
+
+#[derive(Default)]
+struct RequestFieldVisitor {
+    method: Option<String>,
+    status: u64,
+}
+
+impl Visit for RequestFieldVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "status" {
+            self.status = value;
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "method" {
+            self.method = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "method" {
+            self.method = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// S3 operation name of a request guessed from its path, for requests that never reached a
+/// handler tagged with its operation; a low-cardinality metrics label either way
+fn operation(method: &str, path: &str, query: Option<&str>) -> &'static str {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let bucket = segments.next().filter(|b| !b.is_empty());
+    let key = segments.next().filter(|k| !k.is_empty());
+    let has_param = |name: &str| {
+        query.is_some_and(|q| q.split('&').any(|p| p.split('=').next() == Some(name)))
+    };
+
+    match (method, bucket, key) {
+        (_, Some("_fily"), _) => "FilyExtension",
+        ("GET", None, _) => "ListBuckets",
+        ("GET", Some(_), None) => "ListObjects",
+        ("PUT", Some(_), None) => "CreateBucket",
+        ("DELETE", Some(_), None) => "DeleteBucket",
+        ("GET", Some(_), Some(_)) if has_param("partNumber") => "GetObjectPart",
+        ("GET", Some(_), Some(_)) => "GetObject",
+        ("HEAD", Some(_), Some(_)) => "HeadObject",
+        ("PUT", Some(_), Some(_)) => "PutObject",
+        ("DELETE", Some(_), Some(_)) => "DeleteObject",
+        _ => "Other",
+    }
+}
+
+fn content_length(headers: &hyper::HeaderMap) -> u64 {
+    headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Middleware emitting a single summary line per request under `REQUEST_LOG_TARGET`,
+/// recording latency histograms and logging requests over the slow request threshold
+pub async fn log_request(req: Request, next: Next) -> Response {
+    let info = RequestInfo::of(&req);
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let operation = operation(&method, &path, req.uri().query());
+    let request_bytes = content_length(req.headers());
+    let user_agent = req
+        .headers()
+        .get(hyper::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let connection = req
+        .extensions()
+        .get::<ConnectInfo<ClientConnection>>()
+        .map(|ConnectInfo(connection)| connection.clone());
+    let slow_threshold = req
+        .extensions()
+        .get::<Arc<Config>>()
+        .and_then(|config| config.slow_request_threshold);
+    if let Some(connection) = &connection {
+        connection.stats.record_request();
+    }
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    // Requests turned away before reaching a handler keep the name guessed from their path
+    let operation = info.operation().unwrap_or(operation);
+    let elapsed = start.elapsed();
+    let status = response.status().as_u16() as u64;
+    let latency_ms = elapsed.as_millis() as u64;
+    let method = method.as_str();
+
+    let response_bytes = content_length(response.headers());
+    let status_class = format!("{}xx", status / 100);
+    metrics::histogram!(
+        "fily_request_duration_seconds",
+        "method" => method.to_string(),
+        "operation" => operation,
+        "status" => status_class
+    )
+    .record(elapsed.as_secs_f64());
+    if request_bytes > 0 {
+        metrics::histogram!("fily_request_body_bytes", "operation" => operation)
+            .record(request_bytes as f64);
+    }
+    if response_bytes > 0 {
+        metrics::histogram!("fily_response_body_bytes", "operation" => operation)
+            .record(response_bytes as f64);
+    }
+
+    // Unauthenticated requests, including those rejected by the auth layer, log an empty principal
+    let principal = info.principal().unwrap_or_default();
+    let request_id = info.request_id.as_str();
+    if status >= 500 {
+        error!(target: REQUEST_LOG_TARGET, method, operation, path = %path, status, latency_ms, principal, request_id, "request failed");
+    } else if status >= 400 {
+        warn!(target: REQUEST_LOG_TARGET, method, operation, path = %path, status, latency_ms, principal, request_id, "request rejected");
+    } else {
+        info!(target: REQUEST_LOG_TARGET, method, operation, path = %path, status, latency_ms, principal, request_id, "request completed");
+    }
+
+    if slow_threshold.is_some_and(|threshold| elapsed >= threshold) {
+        metrics::counter!("fily_slow_requests_total", "operation" => operation).increment(1);
+        let bucket = info.target.bucket().unwrap_or_default();
+        let key = info.target.key().unwrap_or_default();
+        warn!(
+            target: SLOW_REQUEST_LOG_TARGET,
+            method,
+            operation,
+            bucket,
+            key,
+            status,
+            latency_ms,
+            request_bytes,
+            response_bytes,
+            client = connection.as_ref().map(|c| c.addr.to_string()).unwrap_or_default(),
+            connection_requests = connection.as_ref().map_or(0, |c| c.stats.requests()),
+            access_key = principal,
+            request_id,
+            user_agent = user_agent.as_deref().unwrap_or_default(),
+            "slow request"
+        );
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_are_always_logged() {
+        let sampler = RequestLogSampler::new(SamplingConfig {
+            default_rate: 0.0,
+            method_rates: HashMap::new(),
+        });
+        assert!(!sampler.should_log("GET", 200));
+        assert!(sampler.should_log("GET", 404));
+        assert!(sampler.should_log("PUT", 500));
+    }
+
+    #[test]
+    fn test_method_rates_override_default() {
+        let sampler = RequestLogSampler::new(SamplingConfig {
+            default_rate: 1.0,
+            method_rates: HashMap::from([("GET".to_string(), 0.0)]),
+        });
+        assert!(!sampler.should_log("GET", 200));
+        assert!(sampler.should_log("PUT", 200));
+    }
+
+    #[test]
+    fn test_operation_names() {
+        assert_eq!(operation("GET", "/", None), "ListBuckets");
+        assert_eq!(operation("GET", "/photos", Some("prefix=2024/")), "ListObjects");
+        assert_eq!(operation("PUT", "/photos/2024/a.jpg", None), "PutObject");
+        assert_eq!(operation("GET", "/photos/a.jpg", Some("partNumber=2")), "GetObjectPart");
+        assert_eq!(operation("PUT", "/_fily/admin/read-only", None), "FilyExtension");
+        assert_eq!(operation("PATCH", "/photos/a.jpg", None), "Other");
+    }
+
+    #[test]
+    fn test_invalid_rates_are_rejected() {
+        let sampler = RequestLogSampler::new(SamplingConfig::default());
+        let invalid = SamplingConfig {
+            default_rate: 1.5,
+            method_rates: HashMap::new(),
+        };
+        assert!(sampler.update(invalid).is_err());
+        assert_eq!(sampler.config(), SamplingConfig::default());
+    }
+}
@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+
+use percent_encoding::percent_decode_str;
+
+/// `/_fily` extensions whose next path segment is the bucket they read or write
+const BUCKET_SCOPED_EXTENSIONS: &[&str] = &[
+    "/_fily/uploads/",
+    "/_fily/compose/",
+    "/_fily/batch/put/",
+    "/_fily/dav/",
+    "/_fily/metadata/",
+    "/_fily/search/",
+];
+
+/// What a request path addresses, as seen by middleware that runs before routing. Routes match
+/// the raw path, but axum's `Path` extractor hands handlers percent-decoded segments, so bucket
+/// names and keys are decoded here the same way; otherwise `/%70hotos/a.jpg` would be checked
+/// against the policy of a bucket called `%70hotos` and served from `photos`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestTarget {
+    /// `/`, listing or creating buckets
+    Service,
+    /// A bucket, an object in it, or a bucket-scoped `/_fily` extension
+    Bucket { bucket: String, key: Option<String> },
+    /// The rest of the `/_fily` extension namespace
+    Extension,
+}
+
+/// One path segment decoded like a route parameter; invalid UTF-8, which the extractor rejects
+/// before the handler runs, is replaced rather than failing here
+pub fn decode_segment(segment: &str) -> Cow<'_, str> {
+    percent_decode_str(segment).decode_utf8_lossy()
+}
+
+/// Key made of the decoded segments; empty segments are dropped, as for the bucket
+fn decode_key<'a>(segments: impl Iterator<Item = &'a str>) -> Option<String> {
+    let key = segments
+        .filter(|s| !s.is_empty())
+        .map(decode_segment)
+        .collect::<Vec<_>>()
+        .join("/");
+    (!key.is_empty()).then_some(key)
+}
+
+impl RequestTarget {
+    pub fn from_path(path: &str) -> Self {
+        if let Some(rest) = BUCKET_SCOPED_EXTENSIONS.iter().find_map(|prefix| path.strip_prefix(prefix)) {
+            let mut segments = rest.split('/');
+            return match segments.next().filter(|bucket| !bucket.is_empty()) {
+                Some(bucket) => RequestTarget::Bucket {
+                    bucket: decode_segment(bucket).into_owned(),
+                    key: decode_key(segments),
+                },
+                None => RequestTarget::Extension,
+            };
+        }
+
+        let mut segments = path.trim_start_matches('/').split('/');
+        match segments.next() {
+            Some("_fily") => RequestTarget::Extension,
+            Some("") | None => RequestTarget::Service,
+            Some(bucket) => RequestTarget::Bucket {
+                bucket: decode_segment(bucket).into_owned(),
+                key: decode_key(segments),
+            },
+        }
+    }
+
+    /// Bucket the request reads or writes, if any
+    pub fn bucket(&self) -> Option<&str> {
+        match self {
+            RequestTarget::Bucket { bucket, .. } => Some(bucket),
+            _ => None,
+        }
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            RequestTarget::Bucket { key, .. } => key.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(bucket: &str, key: Option<&str>) -> RequestTarget {
+        RequestTarget::Bucket {
+            bucket: bucket.to_string(),
+            key: key.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(RequestTarget::from_path("/"), RequestTarget::Service);
+        assert_eq!(RequestTarget::from_path("/photos"), bucket("photos", None));
+        assert_eq!(RequestTarget::from_path("/photos/"), bucket("photos", None));
+        assert_eq!(RequestTarget::from_path("/photos/2023/a.jpg"), bucket("photos", Some("2023/a.jpg")));
+        assert_eq!(RequestTarget::from_path("/_fily/admin/logging/level"), RequestTarget::Extension);
+        assert_eq!(
+            RequestTarget::from_path("/_fily/uploads/photos/a.jpg/0123"),
+            bucket("photos", Some("a.jpg/0123"))
+        );
+        assert_eq!(RequestTarget::from_path("/_fily/compose/logs/day.log"), bucket("logs", Some("day.log")));
+        assert_eq!(RequestTarget::from_path("/_fily/compose/"), RequestTarget::Extension);
+    }
+
+    #[test]
+    fn test_decodes_like_the_path_extractor() {
+        assert_eq!(RequestTarget::from_path("/%70hotos/a.jpg"), bucket("photos", Some("a.jpg")));
+        assert_eq!(
+            RequestTarget::from_path("/photos/beach%20day%2F1.jpg"),
+            bucket("photos", Some("beach day/1.jpg"))
+        );
+        assert_eq!(RequestTarget::from_path("/_fily/dav/%70hotos/a%20b"), bucket("photos", Some("a b")));
+        assert_eq!(RequestTarget::from_path("/photos/%FF").key(), Some("\u{FFFD}"));
+    }
+}
@@ -4,6 +4,8 @@ use quick_xml::se::to_string;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::request_info::current;
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct S3Error {
     #[serde(rename = "Code")]
@@ -24,6 +26,10 @@ pub enum S3ErrorCode {
     BucketNotEmpty,
     NoSuchBucket,
     InvalidBucketName,
+    OwnershipControlsNotFoundError,
+    AccessControlListNotSupported,
+    NoSuchPublicAccessBlockConfiguration,
+    InvalidTargetBucketForLogging,
     
     // Object errors
     NoSuchKey,
@@ -44,6 +50,11 @@ pub enum S3ErrorCode {
     MalformedXML,
     InvalidDigest,
     BadDigest,
+    XAmzContentSHA256Mismatch,
+    InvalidRange,
+    RequestTimeout,
+    OperationAborted,
+    MethodNotAllowed,
     
     // Server errors
     InternalError,
@@ -55,6 +66,7 @@ pub enum S3ErrorCode {
     NoSuchUpload,
     InvalidPart,
     InvalidPartOrder,
+    InvalidPartNumber,
     
     // Generic fallback
     AccountProblem,
@@ -68,6 +80,10 @@ impl S3ErrorCode {
             S3ErrorCode::BucketNotEmpty => "BucketNotEmpty",
             S3ErrorCode::NoSuchBucket => "NoSuchBucket",
             S3ErrorCode::InvalidBucketName => "InvalidBucketName",
+            S3ErrorCode::OwnershipControlsNotFoundError => "OwnershipControlsNotFoundError",
+            S3ErrorCode::AccessControlListNotSupported => "AccessControlListNotSupported",
+            S3ErrorCode::NoSuchPublicAccessBlockConfiguration => "NoSuchPublicAccessBlockConfiguration",
+            S3ErrorCode::InvalidTargetBucketForLogging => "InvalidTargetBucketForLogging",
             S3ErrorCode::NoSuchKey => "NoSuchKey",
             S3ErrorCode::InvalidObjectName => "InvalidObjectName",
             S3ErrorCode::EntityTooLarge => "EntityTooLarge",
@@ -82,6 +98,11 @@ impl S3ErrorCode {
             S3ErrorCode::MalformedXML => "MalformedXML",
             S3ErrorCode::InvalidDigest => "InvalidDigest",
             S3ErrorCode::BadDigest => "BadDigest",
+            S3ErrorCode::XAmzContentSHA256Mismatch => "XAmzContentSHA256Mismatch",
+            S3ErrorCode::InvalidRange => "InvalidRange",
+            S3ErrorCode::RequestTimeout => "RequestTimeout",
+            S3ErrorCode::OperationAborted => "OperationAborted",
+            S3ErrorCode::MethodNotAllowed => "MethodNotAllowed",
             S3ErrorCode::InternalError => "InternalError",
             S3ErrorCode::NotImplemented => "NotImplemented",
             S3ErrorCode::ServiceUnavailable => "ServiceUnavailable",
@@ -89,6 +110,7 @@ impl S3ErrorCode {
             S3ErrorCode::NoSuchUpload => "NoSuchUpload",
             S3ErrorCode::InvalidPart => "InvalidPart",
             S3ErrorCode::InvalidPartOrder => "InvalidPartOrder",
+            S3ErrorCode::InvalidPartNumber => "InvalidPartNumber",
             S3ErrorCode::AccountProblem => "AccountProblem",
         }
     }
@@ -100,6 +122,10 @@ impl S3ErrorCode {
             S3ErrorCode::BucketNotEmpty => StatusCode::CONFLICT,
             S3ErrorCode::NoSuchBucket => StatusCode::NOT_FOUND,
             S3ErrorCode::InvalidBucketName => StatusCode::BAD_REQUEST,
+            S3ErrorCode::OwnershipControlsNotFoundError => StatusCode::NOT_FOUND,
+            S3ErrorCode::AccessControlListNotSupported => StatusCode::BAD_REQUEST,
+            S3ErrorCode::NoSuchPublicAccessBlockConfiguration => StatusCode::NOT_FOUND,
+            S3ErrorCode::InvalidTargetBucketForLogging => StatusCode::BAD_REQUEST,
             S3ErrorCode::NoSuchKey => StatusCode::NOT_FOUND,
             S3ErrorCode::InvalidObjectName => StatusCode::BAD_REQUEST,
             S3ErrorCode::EntityTooLarge => StatusCode::BAD_REQUEST,
@@ -114,6 +140,11 @@ impl S3ErrorCode {
             S3ErrorCode::MalformedXML => StatusCode::BAD_REQUEST,
             S3ErrorCode::InvalidDigest => StatusCode::BAD_REQUEST,
             S3ErrorCode::BadDigest => StatusCode::BAD_REQUEST,
+            S3ErrorCode::XAmzContentSHA256Mismatch => StatusCode::BAD_REQUEST,
+            S3ErrorCode::InvalidRange => StatusCode::RANGE_NOT_SATISFIABLE,
+            S3ErrorCode::RequestTimeout => StatusCode::BAD_REQUEST,
+            S3ErrorCode::OperationAborted => StatusCode::CONFLICT,
+            S3ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
             S3ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
             S3ErrorCode::NotImplemented => StatusCode::NOT_IMPLEMENTED,
             S3ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
@@ -121,6 +152,7 @@ impl S3ErrorCode {
             S3ErrorCode::NoSuchUpload => StatusCode::NOT_FOUND,
             S3ErrorCode::InvalidPart => StatusCode::BAD_REQUEST,
             S3ErrorCode::InvalidPartOrder => StatusCode::BAD_REQUEST,
+            S3ErrorCode::InvalidPartNumber => StatusCode::RANGE_NOT_SATISFIABLE,
             S3ErrorCode::AccountProblem => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -132,6 +164,10 @@ impl S3ErrorCode {
             S3ErrorCode::BucketNotEmpty => "The bucket you tried to delete is not empty.",
             S3ErrorCode::NoSuchBucket => "The specified bucket does not exist.",
             S3ErrorCode::InvalidBucketName => "The specified bucket is not valid.",
+            S3ErrorCode::OwnershipControlsNotFoundError => "The bucket ownership controls were not found.",
+            S3ErrorCode::AccessControlListNotSupported => "The bucket does not allow ACLs.",
+            S3ErrorCode::NoSuchPublicAccessBlockConfiguration => "The public access block configuration was not found.",
+            S3ErrorCode::InvalidTargetBucketForLogging => "The target bucket for logging does not exist or is not owned by you.",
             S3ErrorCode::NoSuchKey => "The specified key does not exist.",
             S3ErrorCode::InvalidObjectName => "The specified object name is not valid.",
             S3ErrorCode::EntityTooLarge => "Your proposed upload size exceeds the maximum allowed object size.",
@@ -146,6 +182,11 @@ impl S3ErrorCode {
             S3ErrorCode::MalformedXML => "The XML you provided was not well-formed or did not validate against our published schema.",
             S3ErrorCode::InvalidDigest => "The Content-MD5 you specified is not valid.",
             S3ErrorCode::BadDigest => "The Content-MD5 you specified did not match what we received.",
+            S3ErrorCode::XAmzContentSHA256Mismatch => "The provided 'x-amz-content-sha256' header does not match what was computed.",
+            S3ErrorCode::InvalidRange => "The requested range is not satisfiable",
+            S3ErrorCode::RequestTimeout => "Your socket connection to the server was not read from or written to within the timeout period.",
+            S3ErrorCode::OperationAborted => "A conflicting conditional operation is currently in progress against this resource. Try again.",
+            S3ErrorCode::MethodNotAllowed => "The specified method is not allowed against this resource.",
             S3ErrorCode::InternalError => "We encountered an internal error. Please try again.",
             S3ErrorCode::NotImplemented => "A header you provided implies functionality that is not implemented.",
             S3ErrorCode::ServiceUnavailable => "Reduce your request rate.",
@@ -153,12 +194,14 @@ impl S3ErrorCode {
             S3ErrorCode::NoSuchUpload => "The specified multipart upload does not exist.",
             S3ErrorCode::InvalidPart => "One or more of the specified parts could not be found.",
             S3ErrorCode::InvalidPartOrder => "The list of parts was not in ascending order.",
+            S3ErrorCode::InvalidPartNumber => "The requested partnumber is not satisfiable",
             S3ErrorCode::AccountProblem => "There is a problem with your AWS account that prevents the operation from completing successfully.",
         }
     }
 }
 
 // Enhanced S3AppError that supports specific error codes
+#[derive(Debug)]
 pub struct S3AppError {
     pub code: S3ErrorCode,
     pub message: Option<String>,
@@ -227,6 +270,18 @@ impl S3AppError {
         Self::with_resource(S3ErrorCode::AccessDenied, resource.to_string())
     }
     
+    pub fn service_unavailable(message: &str, resource: &str) -> Self {
+        Self::with_message_and_resource(
+            S3ErrorCode::ServiceUnavailable,
+            message.to_string(),
+            resource.to_string(),
+        )
+    }
+    
+    pub fn request_timeout(resource: &str) -> Self {
+        Self::with_resource(S3ErrorCode::RequestTimeout, resource.to_string())
+    }
+    
     pub fn internal_error(message: &str) -> Self {
         Self::with_message(S3ErrorCode::InternalError, message.to_string())
     }
@@ -242,7 +297,15 @@ impl S3AppError {
 // Tell axum how to convert `S3AppError` into a response.
 impl IntoResponse for S3AppError {
     fn into_response(self) -> Response {
-        let request_id = Uuid::new_v4().to_string();
+        // Errors name the request they answer, as the x-amz-request-id header does
+        let current = current();
+        let request_id = match &current {
+            Some(info) => {
+                info.set_error_code(self.code.as_str());
+                info.request_id.clone()
+            }
+            None => Uuid::new_v4().to_string(),
+        };
         
         let err = S3Error {
             code: self.code.as_str().to_string(),
@@ -0,0 +1,509 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::cpu_pool::CpuPool;
+use super::get_object::decrypt_object;
+use super::key_locks;
+use super::logging::AUDIT_LOG_TARGET;
+use super::etag::content_digests;
+use super::metadata::{load_metadata, save_metadata};
+use super::notifications::{Event, EventKind, Notifier, Severity};
+use super::path_security::{bucket_metadata_dir, construct_safe_path};
+use super::search_bucket::ObjectWalker;
+use super::tenancy::namespace_roots;
+use super::Config;
+
+/// Bucket setting holding the progress of its current or last scrub
+const PROGRESS_FILE: &str = "scrub-progress";
+
+/// Failures kept in the progress of a pass; later ones are only logged and counted
+const MAX_RECORDED_FAILURES: usize = 100;
+
+/// How often a running scrub records its cursor, bounding the work repeated after a restart
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Pause between looking for buckets due for a scrub
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Background re-verification of the SHA-256 recorded for every stored object
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrubConfig {
+    pub enabled: bool,
+    /// Object bytes read per second, keeping scrubs from competing with client traffic
+    pub rate: u64,
+    /// Time from the start of one pass over a bucket to the start of the next
+    pub interval: Duration,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate: 16 * 1024 * 1024,
+            interval: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Object that failed verification during a pass
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrubFailure {
+    pub key: String,
+    pub reason: String,
+}
+
+/// State of the current or last pass over a bucket, kept with its settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrubProgress {
+    /// RFC 3339 time the pass started
+    pub started: String,
+    /// RFC 3339 time the pass finished, unset while it is running
+    pub completed: Option<String>,
+    /// Last key verified by an unfinished pass, which resumes after it
+    pub cursor: Option<String>,
+    pub objects: u64,
+    pub bytes: u64,
+    /// Objects stored without metadata to verify them against
+    pub unverified: u64,
+    /// Objects stored without a SHA-256, e.g. before checksums were recorded, whose hash the
+    /// pass computed and recorded
+    #[serde(default)]
+    pub backfilled: u64,
+    pub failures: Vec<ScrubFailure>,
+}
+
+impl ScrubProgress {
+    fn start(now: DateTime<Utc>) -> Self {
+        Self {
+            started: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            completed: None,
+            cursor: None,
+            objects: 0,
+            bytes: 0,
+            unverified: 0,
+            backfilled: 0,
+            failures: vec![],
+        }
+    }
+
+    /// Whether a bucket with this progress needs scrubbing at `now`
+    fn is_due(&self, interval: Duration, now: DateTime<Utc>) -> bool {
+        if self.completed.is_none() {
+            return true;
+        }
+        let Ok(started) = DateTime::parse_from_rfc3339(&self.started) else {
+            return true;
+        };
+        chrono::Duration::from_std(interval).is_ok_and(|interval| started + interval <= now)
+    }
+}
+
+fn progress_path(storage_root: &Path, bucket: &str) -> PathBuf {
+    bucket_metadata_dir(storage_root, bucket).join(PROGRESS_FILE)
+}
+
+/// Reads the scrub progress of a bucket, `None` when it was never scrubbed
+pub async fn load_progress(storage_root: &Path, bucket: &str) -> std::io::Result<Option<ScrubProgress>> {
+    match tokio::fs::read(progress_path(storage_root, bucket)).await {
+        Ok(json) => serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(std::io::Error::other),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn save_progress(storage_root: &Path, bucket: &str, progress: &ScrubProgress) -> std::io::Result<()> {
+    let path = progress_path(storage_root, bucket);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let json = serde_json::to_vec_pretty(progress).map_err(std::io::Error::other)?;
+    let staged = path.with_extension("tmp");
+    tokio::fs::write(&staged, json).await?;
+    tokio::fs::rename(&staged, &path).await
+}
+
+/// Buckets of one namespace, skipping fily's own directories
+async fn buckets(storage_root: &Path) -> std::io::Result<Vec<String>> {
+    let mut buckets = vec![];
+    let mut entries = tokio::fs::read_dir(storage_root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_dir() && !name.starts_with('.') {
+            buckets.push(name);
+        }
+    }
+    buckets.sort();
+    Ok(buckets)
+}
+
+/// Scrub progress of every bucket in the namespace at `storage_root`
+pub async fn bucket_progress(storage_root: &Path) -> std::io::Result<BTreeMap<String, Option<ScrubProgress>>> {
+    let mut progress = BTreeMap::new();
+    for bucket in buckets(storage_root).await? {
+        let state = load_progress(storage_root, &bucket).await?;
+        progress.insert(bucket, state);
+    }
+    Ok(progress)
+}
+
+/// Outcome of verifying one object
+enum Check {
+    Ok,
+    Unverified,
+    /// Had no SHA-256, which is now recorded
+    Backfilled,
+    /// Deleted after it was listed
+    Missing,
+    Failed(String),
+}
+
+impl Check {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Check::Ok => "ok",
+            Check::Unverified => "unverified",
+            Check::Backfilled => "backfilled",
+            Check::Missing => "missing",
+            Check::Failed(_) => "failed",
+        }
+    }
+}
+
+/// Keeps reads at the configured rate by sleeping whenever a pass gets ahead of it
+struct Throttle {
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    async fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            tokio::time::sleep(ahead).await;
+        }
+    }
+}
+
+/// Low-priority background pass re-hashing every stored object and comparing it with the
+/// SHA-256 recorded when it was written, like a ZFS scrub
+pub struct Scrubber {
+    config: Arc<Config>,
+    cpu_pool: Arc<CpuPool>,
+    notifier: Option<Arc<Notifier>>,
+}
+
+impl Scrubber {
+    pub fn new(config: Arc<Config>, cpu_pool: Arc<CpuPool>) -> Self {
+        Self {
+            config,
+            cpu_pool,
+            notifier: None,
+        }
+    }
+
+    /// Reports every object failing verification to the operators' webhook
+    pub fn with_notifier(mut self, notifier: Arc<Notifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    async fn check(&self, storage_root: &Path, bucket: &str, key: &str) -> Check {
+        let path = match construct_safe_path(storage_root, bucket, key) {
+            Ok(path) => path,
+            Err(e) => return Check::Failed(format!("invalid stored key: {}", e)),
+        };
+        // Data and metadata are read together so a concurrent overwrite is never half seen
+        let (stored, metadata) = {
+            let _lock = key_locks::read(storage_root, bucket, key).await;
+            let stored = match tokio::fs::read(&path).await {
+                Ok(stored) => stored,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Check::Missing,
+                Err(e) => return Check::Failed(format!("read failed: {}", e)),
+            };
+            match load_metadata(storage_root, bucket, key).await {
+                Ok(metadata) => (stored, metadata),
+                Err(e) => return Check::Failed(format!("unreadable metadata: {}", e)),
+            }
+        };
+        let Some(metadata) = metadata else {
+            return Check::Unverified;
+        };
+        let encryption_id = metadata.encryption_id.clone();
+        let plaintext = match decrypt_object(&self.config, &self.cpu_pool, bucket, key, encryption_id, stored).await {
+            Ok(plaintext) => plaintext,
+            Err(e) => return Check::Failed(format!("decryption failed: {}", e.message.unwrap_or_default())),
+        };
+        let (etag, actual) = match self.cpu_pool.run(move || content_digests(&plaintext)).await {
+            Ok(digests) => digests,
+            Err(e) => return Check::Failed(format!("hashing failed: {}", e.message.unwrap_or_default())),
+        };
+        match &metadata.content_sha256 {
+            Some(expected) if actual.eq_ignore_ascii_case(expected) => Check::Ok,
+            Some(expected) => Check::Failed(format!("expected SHA-256 {}, found {}", expected, actual)),
+            None => self.backfill(storage_root, bucket, key, &metadata.etag, &etag, actual).await,
+        }
+    }
+
+    /// Records the SHA-256 of an object written before hashes were, so signature validation can
+    /// use it. Single-part ETags are the MD5 of the data, which the data must still match.
+    async fn backfill(&self, storage_root: &Path, bucket: &str, key: &str, recorded_etag: &str, etag: &str, sha256: String) -> Check {
+        if !recorded_etag.contains('-') && recorded_etag != etag {
+            return Check::Failed(format!("expected ETag {}, found {}", recorded_etag, etag));
+        }
+        let _lock = key_locks::lock(storage_root, bucket, key).await;
+        // Only the object that was hashed gets the hash, not one written since
+        let mut metadata = match load_metadata(storage_root, bucket, key).await {
+            Ok(Some(metadata)) if metadata.etag == recorded_etag && metadata.content_sha256.is_none() => metadata,
+            _ => return Check::Missing,
+        };
+        metadata.set_content_sha256(sha256);
+        match save_metadata(storage_root, bucket, key, &metadata).await {
+            Ok(()) => Check::Backfilled,
+            Err(e) => Check::Failed(format!("recording SHA-256 failed: {}", e)),
+        }
+    }
+
+    fn report(&self, bucket: &str, key: &str, reason: &str) {
+        error!(
+            target: AUDIT_LOG_TARGET,
+            bucket,
+            key,
+            reason,
+            "object failed scrub verification"
+        );
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(Event::new(
+                EventKind::ScrubFailure,
+                Severity::Critical,
+                format!("Object /{}/{} failed scrub verification: {}", bucket, key, reason),
+                serde_json::json!({
+                    "bucket": bucket,
+                    "key": key,
+                    "reason": reason,
+                }),
+            ));
+        }
+    }
+
+    /// Continues or starts a pass over `bucket` when one is due, returning its progress
+    pub async fn scrub_bucket(&self, storage_root: &Path, bucket: &str) -> std::io::Result<ScrubProgress> {
+        let now = Utc::now();
+        let mut progress = match load_progress(storage_root, bucket).await? {
+            Some(progress) if !progress.is_due(self.config.scrub.interval, now) => return Ok(progress),
+            Some(progress) if progress.completed.is_none() => progress,
+            _ => ScrubProgress::start(now),
+        };
+        if progress.cursor.is_none() {
+            info!("Scrubbing bucket {} of {}", bucket, storage_root.display());
+        }
+
+        let mut throttle = Throttle::new(self.config.scrub.rate);
+        let mut checkpoint = Instant::now();
+        let mut walker = ObjectWalker::new(&storage_root.join(bucket), "", progress.cursor.clone());
+        while let Some(object) = walker.next().await? {
+            let check = self.check(storage_root, bucket, &object.key).await;
+            metrics::counter!("fily_scrub_objects_total", "result" => check.as_str()).increment(1);
+            match check {
+                Check::Ok => {}
+                Check::Unverified => progress.unverified += 1,
+                Check::Backfilled => progress.backfilled += 1,
+                Check::Missing => {}
+                Check::Failed(reason) => {
+                    self.report(bucket, &object.key, &reason);
+                    if progress.failures.len() < MAX_RECORDED_FAILURES {
+                        progress.failures.push(ScrubFailure { key: object.key.clone(), reason });
+                    }
+                }
+            }
+            progress.objects += 1;
+            progress.bytes += object.size;
+            metrics::counter!("fily_scrub_bytes_total").increment(object.size);
+            progress.cursor = Some(object.key);
+
+            if checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                save_progress(storage_root, bucket, &progress).await?;
+                checkpoint = Instant::now();
+            }
+            throttle.consume(object.size).await;
+        }
+
+        progress.cursor = None;
+        progress.completed = Some(Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+        save_progress(storage_root, bucket, &progress).await?;
+        metrics::counter!("fily_scrub_passes_total").increment(1);
+        info!(
+            "Scrubbed bucket {} of {}: {} objects, {} bytes, {} failures",
+            bucket,
+            storage_root.display(),
+            progress.objects,
+            progress.bytes,
+            progress.failures.len()
+        );
+        Ok(progress)
+    }
+
+    /// Scrubs every bucket due for it, in every namespace
+    async fn sweep(&self) -> std::io::Result<()> {
+        for root in namespace_roots(Path::new(&self.config.location))? {
+            for bucket in buckets(&root).await? {
+                if let Err(e) = self.scrub_bucket(&root, &bucket).await {
+                    warn!("Failed to scrub bucket {} of {}: {}", bucket, root.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps scrubbing for the lifetime of the server
+    pub async fn watch(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.sweep().await {
+                warn!("Failed to look for buckets to scrub: {}", e);
+            }
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fily::etag::generate_etag;
+    use crate::fily::metadata::ObjectMetadata;
+    use sha2::{Digest, Sha256};
+
+    fn config(location: &Path) -> Arc<Config> {
+        Arc::new(Config {
+            location: location.to_string_lossy().into_owned(),
+            address: "127.0.0.1".to_string(),
+            scrub: ScrubConfig {
+                enabled: true,
+                rate: u64::MAX,
+                interval: Duration::from_secs(3600),
+            },
+            ..Default::default()
+        })
+    }
+
+    async fn store(root: &Path, bucket: &str, key: &str, contents: &[u8]) {
+        let path = root.join(bucket).join(key);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        let metadata = ObjectMetadata::with_content_sha256(
+            None,
+            contents.len() as u64,
+            "\"etag\"".to_string(),
+            key,
+            hex::encode(Sha256::digest(contents)),
+        );
+        save_metadata(root, bucket, key, &metadata).await.unwrap();
+    }
+
+    fn scrubber(root: &Path) -> Scrubber {
+        Scrubber::new(config(root), Arc::new(CpuPool::new(&Default::default())))
+    }
+
+    #[tokio::test]
+    async fn test_scrub_detects_corruption() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        store(root, "photos", "a.jpg", b"alpha").await;
+        store(root, "photos", "2024/b.jpg", b"beta").await;
+        std::fs::write(root.join("photos/c.jpg"), b"no checksum").unwrap();
+        std::fs::write(root.join("photos/a.jpg"), b"alphA").unwrap();
+
+        let progress = scrubber(root).scrub_bucket(root, "photos").await.unwrap();
+        assert!(progress.completed.is_some());
+        assert_eq!(progress.cursor, None);
+        assert_eq!(progress.objects, 3);
+        assert_eq!(progress.bytes, 20);
+        assert_eq!(progress.unverified, 1);
+        assert_eq!(progress.failures.len(), 1);
+        assert_eq!(progress.failures[0].key, "a.jpg");
+        assert_eq!(load_progress(root, "photos").await.unwrap(), Some(progress.clone()));
+
+        // A finished pass is not repeated before the interval is up
+        std::fs::write(root.join("photos/2024/b.jpg"), b"betA").unwrap();
+        let again = scrubber(root).scrub_bucket(root, "photos").await.unwrap();
+        assert_eq!(again, progress);
+    }
+
+    #[tokio::test]
+    async fn test_scrub_backfills_missing_hashes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        for (key, contents, etag) in [
+            ("a.txt", b"alpha", generate_etag(b"alpha")),
+            ("b.txt", b"bravo", generate_etag(b"other")),
+            ("c.txt", b"gamma", "\"abc-2\"".to_string()),
+        ] {
+            std::fs::create_dir_all(root.join("docs")).unwrap();
+            std::fs::write(root.join("docs").join(key), contents).unwrap();
+            let metadata = ObjectMetadata::new(None, 5, etag, key);
+            save_metadata(root, "docs", key, &metadata).await.unwrap();
+        }
+
+        let progress = scrubber(root).scrub_bucket(root, "docs").await.unwrap();
+        assert_eq!(progress.backfilled, 2);
+        let sha256 = |key| async move { load_metadata(root, "docs", key).await.unwrap().unwrap().content_sha256 };
+        assert_eq!(sha256("a.txt").await, Some(hex::encode(Sha256::digest(b"alpha"))));
+        assert_eq!(sha256("c.txt").await, Some(hex::encode(Sha256::digest(b"gamma"))));
+        // Data no longer matching its single-part ETag is reported instead of trusted
+        assert_eq!(sha256("b.txt").await, None);
+        assert_eq!(progress.failures.len(), 1);
+        assert_eq!(progress.failures[0].key, "b.txt");
+    }
+
+    #[tokio::test]
+    async fn test_scrub_resumes_after_cursor() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        store(root, "docs", "a.txt", b"alpha").await;
+        store(root, "docs", "b.txt", b"beta").await;
+        store(root, "docs", "c.txt", b"gamma").await;
+        // Corruption before the cursor belongs to the part of the pass already done
+        std::fs::write(root.join("docs/a.txt"), b"alphA").unwrap();
+
+        let mut interrupted = ScrubProgress::start(Utc::now());
+        interrupted.cursor = Some("b.txt".to_string());
+        interrupted.objects = 2;
+        interrupted.bytes = 9;
+        save_progress(root, "docs", &interrupted).await.unwrap();
+
+        let progress = scrubber(root).scrub_bucket(root, "docs").await.unwrap();
+        assert_eq!(progress.started, interrupted.started);
+        assert_eq!(progress.objects, 3);
+        assert_eq!(progress.bytes, 14);
+        assert!(progress.failures.is_empty());
+    }
+
+    #[test]
+    fn test_progress_is_due() {
+        let now = Utc::now();
+        let interval = Duration::from_secs(3600);
+        let mut progress = ScrubProgress::start(now - chrono::Duration::minutes(30));
+        assert!(progress.is_due(interval, now));
+
+        progress.completed = Some(now.to_rfc3339());
+        assert!(!progress.is_due(interval, now));
+        assert!(progress.is_due(interval, now + chrono::Duration::minutes(30)));
+    }
+}
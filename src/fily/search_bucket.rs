@@ -1,12 +1,923 @@
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
 
-use axum::response::IntoResponse;
+use axum::body::Body;
+use axum::extract::{Path, Query};
+use axum::response::{IntoResponse, Response};
 use axum::Extension;
-use hyper::StatusCode;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use bytes::Bytes;
+use hyper::body::{Body as HttpBody, Frame};
+use hyper::{HeaderMap, StatusCode};
+use quick_xml::se::{to_string, to_string_with_root};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, error, info};
 
-use super::s3_app_error::S3AppError;
+use super::metadata::load_metadata;
+use super::path_security::{decode_key_segment, metadata_dir_name, sanitize_bucket_name};
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::timestamp::iso8601;
 use super::Config;
 
-pub async fn handle(_: Extension<Arc<Config>>) -> Result<impl IntoResponse, S3AppError> {
-    Ok(StatusCode::OK)
+pub(crate) const DEFAULT_MAX_KEYS: usize = 1000;
+const S3_XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Continuation tokens older than this are refused, like pre-signed URLs past their longest expiry
+const CONTINUATION_TOKEN_MAX_AGE: chrono::Duration = chrono::Duration::days(7);
+
+/// fily extension restricting listings to objects carrying a tag, as `key` or `key=value`
+pub const TAG_FILTER_PARAM: &str = "x-fily-tag-filter";
+
+/// An object file found on disk, keyed by its path relative to the bucket
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Directory entry waiting to be visited; directory keys end with `/`
+struct WalkEntry {
+    key: String,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+/// What an [`ObjectWalker`] yields: an object, or a directory rolled up into a common prefix
+#[derive(Debug)]
+pub enum Listed {
+    Object(StoredObject),
+    CommonPrefix(String),
+}
+
+/// Lazily walks a bucket in key order, holding only the directories on the current path in memory
+///
+/// Sorting each directory by key, with a `/` appended to directory names, yields keys in the same
+/// order as sorting the whole bucket. Subtrees outside the prefix or before the cursor are never read.
+pub struct ObjectWalker {
+    root: Option<PathBuf>,
+    stack: Vec<std::vec::IntoIter<WalkEntry>>,
+    prefix: String,
+    after: Option<String>,
+    /// Metadata directory at the top of the bucket, when metadata is kept inside it
+    metadata_dir: Option<String>,
+    /// Whether directories below the prefix are yielded as common prefixes instead of walked
+    roll_up: bool,
+}
+
+impl ObjectWalker {
+    pub fn new(bucket_path: &std::path::Path, prefix: &str, start_after: Option<String>) -> Self {
+        Self {
+            root: Some(bucket_path.to_path_buf()),
+            stack: vec![],
+            prefix: prefix.to_string(),
+            after: start_after,
+            metadata_dir: metadata_dir_name(bucket_path.parent().unwrap_or(bucket_path)),
+            roll_up: false,
+        }
+    }
+
+    /// Yields each non-empty directory below the prefix as a common prefix, as a `/` delimiter
+    /// would roll it up, without reading the keys beneath it
+    ///
+    /// The directory tree is the store's prefix index: a directory one segment below the prefix
+    /// is exactly the common prefix its keys share, so a listing costs one directory read per
+    /// level instead of one per key.
+    pub fn roll_up_directories(mut self) -> Self {
+        self.roll_up = true;
+        self
+    }
+
+    /// Skips every key up to and including `after`, e.g. the rest of a rolled-up common prefix
+    pub fn seek(&mut self, after: String) {
+        if self.after.as_ref().is_none_or(|current| after > *current) {
+            self.after = Some(after);
+        }
+    }
+
+    fn in_range(&self, entry: &WalkEntry) -> bool {
+        let key = entry.key.as_str();
+        if entry.is_dir {
+            (key.starts_with(&self.prefix) || self.prefix.starts_with(key))
+                && self
+                    .after
+                    .as_ref()
+                    .is_none_or(|after| key > after.as_str() || after.starts_with(key))
+        } else {
+            key.starts_with(&self.prefix) && self.after.as_ref().is_none_or(|after| key > after.as_str())
+        }
+    }
+
+    async fn read_dir(&self, dir: &std::path::Path, key_prefix: &str) -> std::io::Result<Vec<WalkEntry>> {
+        let mut entries = vec![];
+        let mut dir_entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if key_prefix.is_empty() && self.metadata_dir.as_deref() == Some(name.as_str()) {
+                continue;
+            }
+            let is_dir = entry.file_type().await?.is_dir();
+            let segment = decode_key_segment(&name);
+            let key = if is_dir {
+                format!("{}{}/", key_prefix, segment)
+            } else {
+                format!("{}{}", key_prefix, segment)
+            };
+            entries.push(WalkEntry {
+                key,
+                path: entry.path(),
+                is_dir,
+            });
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(entries)
+    }
+
+    /// Whether a directory is yielded as a common prefix rather than walked. A cursor inside the
+    /// directory means part of it was already listed, so it is walked to find the keys after it.
+    fn rolls_up(&self, entry: &WalkEntry) -> bool {
+        let Some(rest) = entry.key.strip_prefix(self.prefix.as_str()) else {
+            return false;
+        };
+        self.roll_up
+            && rest.find('/').is_some_and(|idx| idx + 1 == rest.len())
+            && self.after.as_ref().is_none_or(|after| !after.starts_with(&entry.key))
+    }
+
+    /// Next object in key order, or `None` once the bucket is exhausted
+    pub async fn next(&mut self) -> std::io::Result<Option<StoredObject>> {
+        while let Some(listed) = self.next_listed().await? {
+            if let Listed::Object(object) = listed {
+                return Ok(Some(object));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Next object or rolled-up common prefix in key order, or `None` once the bucket is exhausted
+    pub async fn next_listed(&mut self) -> std::io::Result<Option<Listed>> {
+        if let Some(root) = self.root.take() {
+            let entries = self.read_dir(&root, "").await?;
+            self.stack.push(entries.into_iter());
+        }
+
+        while let Some(frame) = self.stack.last_mut() {
+            let Some(entry) = frame.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if !self.in_range(&entry) {
+                continue;
+            }
+
+            if entry.is_dir && self.rolls_up(&entry) {
+                // Directories left behind by deletes hold no keys and have no common prefix
+                if contains_object(&entry.path).await? {
+                    return Ok(Some(Listed::CommonPrefix(entry.key)));
+                }
+                continue;
+            }
+            if entry.is_dir {
+                let entries = self.read_dir(&entry.path, &entry.key).await?;
+                self.stack.push(entries.into_iter());
+                continue;
+            }
+            // Objects deleted since their directory was read are simply not listed
+            let metadata = match tokio::fs::symlink_metadata(&entry.path).await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            return Ok(Some(Listed::Object(StoredObject {
+                key: entry.key,
+                size: metadata.len(),
+                last_modified: metadata.modified()?.into(),
+            })));
+        }
+        Ok(None)
+    }
+}
+
+/// Whether any object is stored beneath `dir`, stopping at the first one found
+async fn contains_object(dir: &std::path::Path) -> std::io::Result<bool> {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            } else {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Walks a bucket directory and returns every object sorted by key, skipping fily metadata
+#[cfg(test)]
+pub async fn list_stored_objects(bucket_path: &std::path::Path) -> std::io::Result<Vec<StoredObject>> {
+    let mut walker = ObjectWalker::new(bucket_path, "", None);
+    let mut objects = vec![];
+    while let Some(object) = walker.next().await? {
+        objects.push(object);
+    }
+    Ok(objects)
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct TagFilter {
+    key: String,
+    value: Option<String>,
+}
+
+impl TagFilter {
+    pub(crate) fn parse(raw: &str) -> Result<Self, S3AppError> {
+        let (key, value) = match raw.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (raw, None),
+        };
+        if key.is_empty() {
+            return Err(S3AppError::with_message(
+                S3ErrorCode::InvalidArgument,
+                format!("Invalid {}: tag key must not be empty", TAG_FILTER_PARAM),
+            ));
+        }
+        Ok(Self {
+            key: key.to_string(),
+            value,
+        })
+    }
+
+    pub(crate) fn matches(&self, tags: &HashMap<String, String>) -> bool {
+        match (tags.get(&self.key), &self.value) {
+            (Some(actual), Some(expected)) => actual == expected,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Contents {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "LastModified")]
+    last_modified: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+    #[serde(rename = "Size")]
+    size: u64,
+    #[serde(rename = "StorageClass")]
+    storage_class: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CommonPrefix {
+    #[serde(rename = "Prefix")]
+    prefix: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ListBucketResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Prefix")]
+    prefix: String,
+    #[serde(rename = "Marker", skip_serializing_if = "Option::is_none")]
+    marker: Option<String>,
+    #[serde(rename = "NextMarker", skip_serializing_if = "Option::is_none")]
+    next_marker: Option<String>,
+    #[serde(rename = "StartAfter", skip_serializing_if = "Option::is_none")]
+    start_after: Option<String>,
+    #[serde(rename = "ContinuationToken", skip_serializing_if = "Option::is_none")]
+    continuation_token: Option<String>,
+    #[serde(rename = "NextContinuationToken", skip_serializing_if = "Option::is_none")]
+    next_continuation_token: Option<String>,
+    #[serde(rename = "KeyCount", skip_serializing_if = "Option::is_none")]
+    key_count: Option<usize>,
+    #[serde(rename = "Delimiter", skip_serializing_if = "Option::is_none")]
+    delimiter: Option<String>,
+    #[serde(rename = "MaxKeys")]
+    max_keys: usize,
+    #[serde(rename = "IsTruncated")]
+    is_truncated: bool,
+    #[serde(rename = "Contents", default)]
+    contents: Vec<Contents>,
+    #[serde(rename = "CommonPrefixes", default)]
+    common_prefixes: Vec<CommonPrefix>,
+}
+
+impl IntoResponse for ListBucketResult {
+    fn into_response(mut self) -> Response {
+        let contents = std::mem::take(&mut self.contents);
+        let common_prefixes = std::mem::take(&mut self.common_prefixes);
+        // Everything but the entries is serialized up front, so a malformed result is still a 500
+        let head = match to_string(&self) {
+            Ok(xml) => match xml.strip_suffix(LIST_BUCKET_RESULT_END) {
+                Some(head) => head.to_string(),
+                None => return S3AppError::internal_error("Unexpected ListBucketResult XML").into_response(),
+            },
+            Err(e) => return S3AppError::internal_error(&e.to_string()).into_response(),
+        };
+
+        let body = ListingBody {
+            head: Some(head),
+            contents: contents.into_iter(),
+            common_prefixes: common_prefixes.into_iter(),
+            finished: false,
+        };
+        let mut resp = Response::new(Body::new(body));
+        *resp.status_mut() = StatusCode::OK;
+        resp.headers_mut()
+            .insert("content-type", "application/xml".parse().unwrap());
+        resp
+    }
+}
+
+/// Closing tag of a listing, written after its last entry
+const LIST_BUCKET_RESULT_END: &str = "</ListBucketResult>";
+
+/// Entries serialized into each chunk of a listing body
+const ENTRIES_PER_CHUNK: usize = 64;
+
+/// Listing XML serialized a chunk of entries at a time as the client reads it, so a page of long
+/// keys is never held as one string alongside the entries it was built from
+struct ListingBody {
+    head: Option<String>,
+    contents: std::vec::IntoIter<Contents>,
+    common_prefixes: std::vec::IntoIter<CommonPrefix>,
+    finished: bool,
+}
+
+impl ListingBody {
+    fn next_chunk(&mut self) -> Result<Option<String>, quick_xml::SeError> {
+        if let Some(head) = self.head.take() {
+            return Ok(Some(head));
+        }
+        if self.finished {
+            return Ok(None);
+        }
+
+        let mut chunk = String::new();
+        for _ in 0..ENTRIES_PER_CHUNK {
+            if let Some(entry) = self.contents.next() {
+                chunk.push_str(&to_string_with_root("Contents", &entry)?);
+            } else if let Some(entry) = self.common_prefixes.next() {
+                chunk.push_str(&to_string_with_root("CommonPrefixes", &entry)?);
+            } else {
+                chunk.push_str(LIST_BUCKET_RESULT_END);
+                self.finished = true;
+                break;
+            }
+        }
+        Ok(Some(chunk))
+    }
+}
+
+impl HttpBody for ListingBody {
+    type Data = Bytes;
+    type Error = axum::BoxError;
+
+    fn poll_frame(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        Poll::Ready(match self.get_mut().next_chunk() {
+            Ok(chunk) => chunk.map(|chunk| Ok(Frame::data(Bytes::from(chunk)))),
+            Err(e) => {
+                error!("Failed to serialize listing: {}", e);
+                Some(Err(e.into()))
+            }
+        })
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.head.is_none() && self.finished
+    }
+}
+
+pub(crate) fn invalid_argument(message: String) -> S3AppError {
+    S3AppError::with_message(S3ErrorCode::InvalidArgument, message)
+}
+
+/// Signs continuation tokens with `FILY_LIST_TOKEN_KEY`, or with a key random to this process, in
+/// which case tokens do not survive a restart or move between instances
+pub(crate) fn continuation_token_key(config: &Config) -> &[u8] {
+    static PROCESS_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+    match &config.list_token_key {
+        Some(key) => key.as_bytes(),
+        None => PROCESS_KEY.get_or_init(|| {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        }),
+    }
+}
+
+/// Cursor of a V2 listing: the last key returned, the listing it belongs to, and the generation
+/// (start time) of that listing, which every page carries forward. Signed, so clients can neither
+/// forge a cursor nor reuse one for another bucket, prefix or delimiter.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ContinuationToken {
+    #[serde(rename = "b")]
+    pub(crate) bucket: String,
+    #[serde(rename = "p")]
+    pub(crate) prefix: String,
+    #[serde(rename = "d", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) delimiter: Option<String>,
+    #[serde(rename = "k")]
+    pub(crate) after: String,
+    /// Milliseconds since the epoch at which the first page was listed
+    #[serde(rename = "g")]
+    pub(crate) generation: i64,
+}
+
+impl ContinuationToken {
+    fn mac(key: &[u8], payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// `<payload>.<signature>`, both base64url
+    pub(crate) fn encode(&self, key: &[u8]) -> String {
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let payload = engine.encode(serde_json::to_vec(self).expect("token serializes"));
+        let signature = engine.encode(Self::mac(key, &payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Verifies and decodes a token issued for this bucket, prefix and delimiter
+    pub(crate) fn decode(
+        key: &[u8],
+        token: &str,
+        bucket: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, S3AppError> {
+        let incorrect = || invalid_argument("The continuation token provided is incorrect".to_string());
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let (payload, signature) = token.split_once('.').ok_or_else(incorrect)?;
+        let signature = engine.decode(signature).map_err(|_| incorrect())?;
+        Self::mac(key, payload).verify_slice(&signature).map_err(|_| incorrect())?;
+        let token: Self = engine
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(incorrect)?;
+
+        if token.bucket != bucket || token.prefix != prefix || token.delimiter.as_deref() != delimiter {
+            return Err(invalid_argument(
+                "The continuation token was issued for a different bucket, prefix or delimiter".to_string(),
+            ));
+        }
+        let started = DateTime::from_timestamp_millis(token.generation).ok_or_else(incorrect)?;
+        if now - started > CONTINUATION_TOKEN_MAX_AGE {
+            return Err(invalid_argument("The continuation token has expired".to_string()));
+        }
+        Ok(token)
+    }
+}
+
+pub async fn handle(
+    config: Extension<Arc<Config>>,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, S3AppError> {
+    let bucket = sanitize_bucket_name(&bucket).map_err(|_| S3AppError::invalid_bucket_name(&bucket))?;
+    let bucket_path = std::path::Path::new(&config.location).join(&bucket);
+    if !bucket_path.is_dir() {
+        return Err(S3AppError::no_such_bucket(&bucket));
+    }
+
+    let is_v2 = params.get("list-type").map(String::as_str) == Some("2");
+    let prefix = params.get("prefix").cloned().unwrap_or_default();
+    let delimiter = params.get("delimiter").filter(|d| !d.is_empty()).cloned();
+    let max_keys = match params.get("max-keys") {
+        Some(raw) => raw
+            .parse::<usize>()
+            .map_err(|_| invalid_argument(format!("Invalid max-keys: {}", raw)))?
+            .min(DEFAULT_MAX_KEYS),
+        None => DEFAULT_MAX_KEYS,
+    };
+
+    // Header wins over the query parameter so SDK users can set it without touching the URL
+    let tag_filter = headers
+        .get(TAG_FILTER_PARAM)
+        .and_then(|v| v.to_str().ok())
+        .or(params.get(TAG_FILTER_PARAM).map(String::as_str))
+        .map(TagFilter::parse)
+        .transpose()?;
+
+    let continuation_token = params.get("continuation-token").cloned();
+    let token_key = continuation_token_key(&config);
+    let now = Utc::now();
+    let mut generation = now.timestamp_millis();
+    let start_after = if is_v2 {
+        match &continuation_token {
+            Some(token) => {
+                let token = ContinuationToken::decode(token_key, token, &bucket, &prefix, delimiter.as_deref(), now)?;
+                generation = token.generation;
+                Some(token.after)
+            }
+            None => params.get("start-after").cloned(),
+        }
+    } else {
+        params.get("marker").cloned()
+    };
+
+    info!("Listing bucket {} with prefix '{}'", bucket, prefix);
+
+    // Objects are read incrementally, so only the page being returned is held in memory
+    let mut walker = ObjectWalker::new(&bucket_path, &prefix, start_after);
+    // Without a tag filter every key under a directory counts, so a `/` delimiter rolls up
+    // directories as they are found instead of reading the keys beneath them
+    if delimiter.as_deref() == Some("/") && tag_filter.is_none() {
+        walker = walker.roll_up_directories();
+    }
+    let storage_root = std::path::Path::new(&config.location);
+    let mut contents = vec![];
+    let mut common_prefixes = BTreeSet::new();
+    let mut last_key = None;
+    let mut is_truncated = false;
+
+    while let Some(listed) = walker.next_listed().await.map_err(|e| {
+        error!("Failed to list bucket {}: {}", bucket, e);
+        S3AppError::internal_error(&format!("Failed to list bucket: {}", e))
+    })? {
+        let object = match listed {
+            Listed::Object(object) => object,
+            Listed::CommonPrefix(common_prefix) => {
+                if contents.len() + common_prefixes.len() >= max_keys {
+                    is_truncated = true;
+                    break;
+                }
+                last_key = Some(common_prefix.clone());
+                common_prefixes.insert(common_prefix);
+                continue;
+            }
+        };
+        let metadata = load_metadata(storage_root, &bucket, &object.key).await.ok().flatten();
+        if let Some(filter) = &tag_filter {
+            if !metadata.as_ref().is_some_and(|m| filter.matches(&m.tags)) {
+                continue;
+            }
+        }
+
+        // Keys sharing a delimited segment after the prefix roll up into one common prefix
+        let rolled_up = delimiter.as_ref().and_then(|d| {
+            object.key[prefix.len()..]
+                .find(d.as_str())
+                .map(|idx| object.key[..prefix.len() + idx + d.len()].to_string())
+        });
+        if let Some(common_prefix) = &rolled_up {
+            if common_prefixes.contains(common_prefix) {
+                continue;
+            }
+        }
+
+        if contents.len() + common_prefixes.len() >= max_keys {
+            is_truncated = true;
+            break;
+        }
+
+        match rolled_up {
+            Some(common_prefix) => {
+                // Nothing else under a rolled-up prefix can appear in this page
+                walker.seek(format!("{}\u{10FFFF}", common_prefix));
+                last_key = Some(common_prefix.clone());
+                common_prefixes.insert(common_prefix);
+            }
+            None => {
+                last_key = Some(object.key.clone());
+                // Encrypted objects are larger on disk, so report the plaintext size recorded at upload
+                let size = metadata.as_ref().map_or(object.size, |m| m.content_length);
+                contents.push(Contents {
+                    etag: metadata
+                        .map(|m| m.etag)
+                        .unwrap_or_else(|| "\"\"".to_string()),
+                    key: object.key,
+                    last_modified: iso8601(object.last_modified),
+                    size,
+                    storage_class: "STANDARD".to_string(),
+                });
+            }
+        }
+    }
+
+    debug!(
+        "Listed {} objects and {} common prefixes in {}",
+        contents.len(),
+        common_prefixes.len(),
+        bucket
+    );
+
+    // A common prefix cursor must skip every key beneath it, so resume after its last possible key
+    let next_marker = last_key.filter(|_| is_truncated).map(|key| {
+        if delimiter.as_ref().is_some_and(|d| key.ends_with(d.as_str())) {
+            format!("{}\u{10FFFF}", key)
+        } else {
+            key
+        }
+    });
+
+    let next_continuation_token = next_marker.clone().filter(|_| is_v2).map(|after| {
+        ContinuationToken {
+            bucket: bucket.clone(),
+            prefix: prefix.clone(),
+            delimiter: delimiter.clone(),
+            after,
+            generation,
+        }
+        .encode(token_key)
+    });
+
+    let key_count = contents.len() + common_prefixes.len();
+    let result = ListBucketResult {
+        xmlns: S3_XMLNS.to_string(),
+        name: bucket,
+        prefix,
+        marker: (!is_v2).then(|| params.get("marker").cloned().unwrap_or_default()),
+        next_marker: if is_v2 { None } else { next_marker.clone() },
+        start_after: if is_v2 { params.get("start-after").cloned() } else { None },
+        continuation_token: if is_v2 { continuation_token } else { None },
+        next_continuation_token,
+        key_count: is_v2.then_some(key_count),
+        delimiter,
+        max_keys,
+        is_truncated,
+        contents,
+        common_prefixes: common_prefixes
+            .into_iter()
+            .map(|prefix| CommonPrefix { prefix })
+            .collect(),
+    };
+
+    Ok(result.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_filter_parse_and_match() {
+        let tags = HashMap::from([("env".to_string(), "prod".to_string())]);
+
+        assert!(TagFilter::parse("env").unwrap().matches(&tags));
+        assert!(TagFilter::parse("env=prod").unwrap().matches(&tags));
+        assert!(!TagFilter::parse("env=dev").unwrap().matches(&tags));
+        assert!(!TagFilter::parse("team").unwrap().matches(&tags));
+        assert!(TagFilter::parse("=prod").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_stored_objects_skips_metadata() {
+        let dir = tempfile::TempDir::new().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("photos/2023")).await.unwrap();
+        tokio::fs::create_dir_all(dir.path().join(".fily-metadata")).await.unwrap();
+        tokio::fs::write(dir.path().join("photos/2023/a.jpg"), b"a").await.unwrap();
+        tokio::fs::write(dir.path().join("readme.txt"), b"hello").await.unwrap();
+        tokio::fs::write(dir.path().join(".fily-metadata/readme.txt.json"), b"{}").await.unwrap();
+
+        let keys: Vec<String> = list_stored_objects(dir.path())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.key)
+            .collect();
+
+        assert_eq!(keys, vec!["photos/2023/a.jpg", "readme.txt"]);
+    }
+
+    #[test]
+    fn test_continuation_token() {
+        let key = b"0123456789abcdef0123456789abcdef";
+        let now = Utc::now();
+        let token = ContinuationToken {
+            bucket: "photos".to_string(),
+            prefix: "2024/".to_string(),
+            delimiter: None,
+            after: "2024/b.jpg".to_string(),
+            generation: now.timestamp_millis(),
+        };
+        let encoded = token.encode(key);
+        assert_eq!(ContinuationToken::decode(key, &encoded, "photos", "2024/", None, now).unwrap(), token);
+
+        // Forged, re-signed with another key, or replayed against another listing
+        let forged = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(br#"{"b":"photos","p":"","k":"","g":0}"#);
+        let (_, signature) = encoded.split_once('.').unwrap();
+        assert!(ContinuationToken::decode(key, &format!("{}.{}", forged, signature), "photos", "", None, now).is_err());
+        assert!(ContinuationToken::decode(b"another key", &encoded, "photos", "2024/", None, now).is_err());
+        assert!(ContinuationToken::decode(key, &encoded, "photos", "", None, now).is_err());
+        assert!(ContinuationToken::decode(key, &encoded, "photos", "2024/", Some("/"), now).is_err());
+        assert!(ContinuationToken::decode(key, &encoded, "videos", "2024/", None, now).is_err());
+        // The old, unsigned format
+        let legacy = base64::engine::general_purpose::STANDARD.encode("2024/b.jpg");
+        assert!(ContinuationToken::decode(key, &legacy, "photos", "2024/", None, now).is_err());
+
+        let later = now + CONTINUATION_TOKEN_MAX_AGE + chrono::Duration::seconds(1);
+        let error = ContinuationToken::decode(key, &encoded, "photos", "2024/", None, later).unwrap_err();
+        assert!(error.message.unwrap().contains("expired"));
+    }
+
+    #[tokio::test]
+    async fn test_pages_are_stable_across_writes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("photos")).await.unwrap();
+        for key in ["b", "d", "f", "h"] {
+            tokio::fs::write(dir.path().join("photos").join(key), b"x").await.unwrap();
+        }
+        let config = Arc::new(Config {
+            location: dir.path().to_string_lossy().into_owned(),
+            address: "127.0.0.1".to_string(),
+            ..Default::default()
+        });
+        let list = |token: Option<String>| {
+            let mut params = HashMap::from([
+                ("list-type".to_string(), "2".to_string()),
+                ("max-keys".to_string(), "2".to_string()),
+            ]);
+            if let Some(token) = token {
+                params.insert("continuation-token".to_string(), token);
+            }
+            let config = config.clone();
+            async move {
+                let response = handle(Extension(config), Path("photos".to_string()), Query(params), HeaderMap::new())
+                    .await
+                    .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                let keys: Vec<String> = body
+                    .split("<Key>")
+                    .skip(1)
+                    .map(|rest| rest[..rest.find("</Key>").unwrap()].to_string())
+                    .collect();
+                let token = body
+                    .split_once("<NextContinuationToken>")
+                    .map(|(_, rest)| rest[..rest.find('<').unwrap()].to_string());
+                (keys, token)
+            }
+        };
+
+        let (keys, token) = list(None).await;
+        assert_eq!(keys, vec!["b", "d"]);
+        // Writes on both sides of the cursor neither repeat nor skip the keys still to come
+        tokio::fs::write(dir.path().join("photos/a"), b"x").await.unwrap();
+        tokio::fs::write(dir.path().join("photos/e"), b"x").await.unwrap();
+        tokio::fs::remove_file(dir.path().join("photos/d")).await.unwrap();
+        let (keys, token) = list(token).await;
+        assert_eq!(keys, vec!["e", "f"]);
+        let (keys, token) = list(token).await;
+        assert_eq!(keys, vec!["h"]);
+        assert!(token.is_none());
+    }
+
+    async fn walk(walker: &mut ObjectWalker) -> Vec<String> {
+        let mut keys = vec![];
+        while let Some(object) = walker.next().await.unwrap() {
+            keys.push(object.key);
+        }
+        keys
+    }
+
+    #[tokio::test]
+    async fn test_listing_body_matches_whole_document() {
+        let result = || ListBucketResult {
+            xmlns: S3_XMLNS.to_string(),
+            name: "photos".to_string(),
+            prefix: String::new(),
+            marker: None,
+            next_marker: None,
+            start_after: None,
+            continuation_token: None,
+            next_continuation_token: Some("token".to_string()),
+            key_count: Some(ENTRIES_PER_CHUNK * 2 + 1),
+            delimiter: Some("/".to_string()),
+            max_keys: 1000,
+            is_truncated: true,
+            contents: (0..ENTRIES_PER_CHUNK * 2)
+                .map(|i| Contents {
+                    key: format!("{:04}<&>.jpg", i),
+                    last_modified: "2024-01-01T00:00:00.000Z".to_string(),
+                    etag: "\"abc\"".to_string(),
+                    size: i as u64,
+                    storage_class: "STANDARD".to_string(),
+                })
+                .collect(),
+            common_prefixes: vec![CommonPrefix {
+                prefix: "albums/".to_string(),
+            }],
+        };
+
+        let mut body = result().into_response().into_body();
+        let mut chunks = 0;
+        let mut streamed = vec![];
+        while let Some(frame) = http_body_util::BodyExt::frame(&mut body).await {
+            streamed.extend_from_slice(&frame.unwrap().into_data().unwrap());
+            chunks += 1;
+        }
+
+        assert!(chunks > 2);
+        assert_eq!(String::from_utf8(streamed).unwrap(), to_string(&result()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_walker_orders_keys_and_prunes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("a/b")).await.unwrap();
+        tokio::fs::create_dir_all(dir.path().join("z")).await.unwrap();
+        for key in ["a-c", "a/b/d", "a/e", "ab", "z/y"] {
+            tokio::fs::write(dir.path().join(key), b"x").await.unwrap();
+        }
+
+        // Directory entries sort as if their name ended in '/', matching S3 key order
+        assert_eq!(
+            walk(&mut ObjectWalker::new(dir.path(), "", None)).await,
+            vec!["a-c", "a/b/d", "a/e", "ab", "z/y"]
+        );
+        assert_eq!(walk(&mut ObjectWalker::new(dir.path(), "a/", None)).await, vec!["a/b/d", "a/e"]);
+        assert_eq!(
+            walk(&mut ObjectWalker::new(dir.path(), "", Some("a/b/d".to_string()))).await,
+            vec!["a/e", "ab", "z/y"]
+        );
+
+        let mut walker = ObjectWalker::new(dir.path(), "", None);
+        assert_eq!(walker.next().await.unwrap().unwrap().key, "a-c");
+        assert_eq!(walker.next().await.unwrap().unwrap().key, "a/b/d");
+        walker.seek("a/\u{10FFFF}".to_string());
+        assert_eq!(walk(&mut walker).await, vec!["ab", "z/y"]);
+    }
+
+    #[tokio::test]
+    async fn test_walker_rolls_up_directories() {
+        let dir = tempfile::TempDir::new().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("a/b/c")).await.unwrap();
+        tokio::fs::create_dir_all(dir.path().join("empty/left/behind")).await.unwrap();
+        tokio::fs::create_dir_all(dir.path().join("z")).await.unwrap();
+        for key in ["a-c", "a/b/c/d", "a/e", "ab", "z/y"] {
+            tokio::fs::write(dir.path().join(key), b"x").await.unwrap();
+        }
+
+        async fn listed(mut walker: ObjectWalker) -> Vec<String> {
+            let mut listed = vec![];
+            while let Some(entry) = walker.next_listed().await.unwrap() {
+                listed.push(match entry {
+                    Listed::Object(object) => object.key,
+                    Listed::CommonPrefix(prefix) => format!("[{}]", prefix),
+                });
+            }
+            listed
+        }
+
+        assert_eq!(
+            listed(ObjectWalker::new(dir.path(), "", None).roll_up_directories()).await,
+            vec!["a-c", "[a/]", "ab", "[z/]"]
+        );
+        assert_eq!(
+            listed(ObjectWalker::new(dir.path(), "a/", None).roll_up_directories()).await,
+            vec!["[a/b/]", "a/e"]
+        );
+        // A prefix ending mid-segment still rolls up whole directories
+        assert_eq!(
+            listed(ObjectWalker::new(dir.path(), "a/b", None).roll_up_directories()).await,
+            vec!["[a/b/]"]
+        );
+        // A cursor inside a directory resumes within it rather than repeating its prefix
+        assert_eq!(
+            listed(ObjectWalker::new(dir.path(), "", Some("a/b/c/d".to_string())).roll_up_directories()).await,
+            vec!["a/e", "ab", "[z/]"]
+        );
+        assert_eq!(
+            listed(ObjectWalker::new(dir.path(), "", Some("a/\u{10FFFF}".to_string())).roll_up_directories()).await,
+            vec!["ab", "[z/]"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_walker_decodes_stored_names() {
+        let dir = tempfile::TempDir::new().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("logs%3F")).await.unwrap();
+        tokio::fs::write(dir.path().join("logs%3F/12%3A00%2E"), b"x").await.unwrap();
+        tokio::fs::write(dir.path().join("100%.txt"), b"x").await.unwrap();
+
+        assert_eq!(
+            walk(&mut ObjectWalker::new(dir.path(), "", None)).await,
+            vec!["100%.txt", "logs?/12:00."]
+        );
+    }
 }
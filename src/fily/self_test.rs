@@ -0,0 +1,384 @@
+//! Checks run at startup before serving: a canary object is written, read back and deleted, the
+//! master key must round-trip and still open data it sealed on earlier starts, and the clock is
+//! compared against an NTP server. Misconfigured keys and clocks otherwise only show up once
+//! requests start failing.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+
+use super::encryption::{self, KeyManager, XChaCha20Poly1305Encryptor};
+use super::Config;
+
+/// Hidden directory next to the buckets holding the canaries; it is never served or listed
+pub const SYSTEM_DIR: &str = ".fily-system";
+
+/// Sealed with the master key on the first start with encryption, so later starts can tell
+/// whether the key changed
+const KEY_CANARY_FILE: &str = "encryption-canary";
+const KEY_CANARY_PLAINTEXT: &[u8] = b"fily encryption key canary";
+
+const CANARY_SIZE: usize = 4096;
+
+/// How long to wait for the NTP server
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+
+/// Clocks before this date are wrong regardless of what NTP says (2024-01-01)
+const EARLIEST_PLAUSIBLE_TIME: u64 = 1_704_067_200;
+
+/// What to do when a check fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfTestFailureMode {
+    /// Do not start
+    #[default]
+    Refuse,
+    /// Log the failure and serve anyway
+    Warn,
+}
+
+/// Self-test run at startup
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestConfig {
+    pub enabled: bool,
+    pub on_failure: SelfTestFailureMode,
+    /// `host:port` of the NTP server the clock is compared against (clock check off when unset)
+    pub ntp_server: Option<String>,
+    /// Largest accepted difference between the local clock and the NTP server
+    pub max_clock_skew: Duration,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            on_failure: SelfTestFailureMode::Refuse,
+            ntp_server: Some("pool.ntp.org:123".to_string()),
+            max_clock_skew: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Skipped,
+    Error,
+}
+
+/// Result of one self-test step
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String, String>) -> Self {
+        let (status, detail) = match result {
+            Ok(detail) => (CheckStatus::Ok, detail),
+            Err(detail) => (CheckStatus::Error, detail),
+        };
+        Self { name, status, detail }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Skipped, detail: detail.into() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    /// The failed checks as one line, for the startup error
+    pub fn failures(&self) -> String {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Error)
+            .map(|check| format!("{}: {}", check.name, check.detail))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Runs every check, logging each result and recording it in `fily_self_test_failed{check}`.
+/// Replicas only read, so they skip the storage canary and do not create the key canary.
+pub async fn run(config: &Config) -> SelfTestReport {
+    let root = Path::new(&config.location);
+    let checks = vec![
+        if config.replica.enabled {
+            Check::skipped("storage", "read-only replica")
+        } else {
+            Check::new("storage", check_storage(root).await)
+        },
+        check_encryption(config, root).await,
+        match &config.self_test.ntp_server {
+            Some(server) => check_clock(server, config.self_test.max_clock_skew).await,
+            None => Check::skipped("clock", "no NTP server configured"),
+        },
+    ];
+
+    for check in &checks {
+        metrics::gauge!("fily_self_test_failed", "check" => check.name)
+            .set((check.status == CheckStatus::Error) as u8 as f64);
+        match check.status {
+            CheckStatus::Ok => info!(check = check.name, "Self-test passed: {}", check.detail),
+            CheckStatus::Skipped => warn!(check = check.name, "Self-test skipped: {}", check.detail),
+            CheckStatus::Error => error!(check = check.name, "Self-test failed: {}", check.detail),
+        }
+    }
+    let passed = checks.iter().all(|check| check.status != CheckStatus::Error);
+    SelfTestReport { passed, checks }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Writes a canary to the system directory, syncs it, reads it back and deletes it
+async fn check_storage(root: &Path) -> Result<String, String> {
+    let dir = root.join(SYSTEM_DIR);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+
+    let canary = dir.join(format!("self-test-{}", uuid::Uuid::new_v4().simple()));
+    let contents = random_bytes(CANARY_SIZE);
+    let result = async {
+        let mut file = tokio::fs::File::create(&canary).await.map_err(|e| format!("cannot write canary: {}", e))?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &contents)
+            .await
+            .map_err(|e| format!("cannot write canary: {}", e))?;
+        file.sync_all().await.map_err(|e| format!("cannot sync canary: {}", e))?;
+        let read = tokio::fs::read(&canary).await.map_err(|e| format!("cannot read canary: {}", e))?;
+        if read != contents {
+            return Err("canary read back differs from what was written".to_string());
+        }
+        Ok(())
+    }
+    .await;
+    let removed = tokio::fs::remove_file(&canary).await;
+    result?;
+    removed.map_err(|e| format!("cannot delete canary: {}", e))?;
+    Ok(format!("wrote, read and deleted a {} byte canary", CANARY_SIZE))
+}
+
+/// Round-trips an object through the configured master key, then opens the key canary sealed on
+/// an earlier start (creating it on the first)
+async fn check_encryption(config: &Config, root: &Path) -> Check {
+    let Some(encryption) = config.encryption.as_ref().filter(|e| e.enabled) else {
+        return Check::skipped("encryption", "encryption is disabled");
+    };
+    let result = async {
+        let master_key = encryption
+            .master_key
+            .as_deref()
+            .ok_or("encryption is enabled but no master key is set")?;
+        let key_manager = KeyManager::from_base64(master_key).map_err(|e| e.to_string())?;
+        let encryptor = XChaCha20Poly1305Encryptor::new(key_manager);
+
+        let plaintext = random_bytes(CANARY_SIZE);
+        let id = encryption::new_encryption_id();
+        let sealed = encryption::seal_object(&encryptor, &plaintext, &id).map_err(|e| e.to_string())?;
+        let opened = encryption::open_object(&encryptor, &sealed, Some(&id), "")
+            .map_err(|e| format!("round trip failed: {}", e))?;
+        if opened != plaintext {
+            return Err("round trip returned different plaintext".to_string());
+        }
+
+        let path = root.join(SYSTEM_DIR).join(KEY_CANARY_FILE);
+        match tokio::fs::read(&path).await {
+            Ok(sealed) => {
+                let opened = encryption::open_object(&encryptor, &sealed, None, "").map_err(|_| {
+                    format!(
+                        "the master key cannot open {}, which an earlier start sealed: the key has changed \
+                         and objects written before cannot be read",
+                        path.display()
+                    )
+                })?;
+                if opened != KEY_CANARY_PLAINTEXT {
+                    return Err(format!("{} holds unexpected contents", path.display()));
+                }
+                Ok("round trip passed and the master key opens the key canary".to_string())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !config.replica.enabled => {
+                let id = encryption::new_encryption_id();
+                let sealed = encryption::seal_object(&encryptor, KEY_CANARY_PLAINTEXT, &id).map_err(|e| e.to_string())?;
+                let staged = path.with_extension("tmp");
+                async {
+                    tokio::fs::create_dir_all(root.join(SYSTEM_DIR)).await?;
+                    tokio::fs::write(&staged, &sealed).await?;
+                    tokio::fs::rename(&staged, &path).await
+                }
+                .await
+                .map_err(|e| format!("cannot write the key canary: {}", e))?;
+                Ok("round trip passed; created the key canary".to_string())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok("round trip passed; the store has no key canary yet".to_string())
+            }
+            Err(e) => Err(format!("cannot read the key canary: {}", e)),
+        }
+    }
+    .await;
+    Check::new("encryption", result)
+}
+
+/// Compares the clock against `server`. An unreachable server skips the check rather than
+/// failing it, as the clock cannot be judged wrong without an answer; a clock before 2024 fails
+/// either way.
+async fn check_clock(server: &str, max_skew: Duration) -> Check {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    if now.as_secs() < EARLIEST_PLAUSIBLE_TIME {
+        return Check::new("clock", Err(format!("system clock reads {}s since 1970, before 2024", now.as_secs())));
+    }
+    match tokio::time::timeout(NTP_TIMEOUT, ntp_offset(server)).await {
+        Ok(Ok(offset)) if offset.abs() <= max_skew.as_secs_f64() => {
+            Check::new("clock", Ok(format!("{:+.3}s from {}", offset, server)))
+        }
+        Ok(Ok(offset)) => Check::new(
+            "clock",
+            Err(format!(
+                "system clock is {:+.3}s off {}, more than the {}s allowed",
+                offset,
+                server,
+                max_skew.as_secs()
+            )),
+        ),
+        Ok(Err(e)) => Check::skipped("clock", format!("cannot query {}: {}", server, e)),
+        Err(_) => Check::skipped("clock", format!("no answer from {} within {}s", server, NTP_TIMEOUT.as_secs())),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+fn to_ntp(secs: f64) -> [u8; 8] {
+    let ntp = secs + NTP_UNIX_OFFSET;
+    let whole = ntp.trunc() as u32;
+    let fraction = (ntp.fract() * 4_294_967_296.0) as u32;
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&whole.to_be_bytes());
+    bytes[4..].copy_from_slice(&fraction.to_be_bytes());
+    bytes
+}
+
+fn from_ntp(bytes: &[u8]) -> f64 {
+    let whole = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64;
+    let fraction = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as f64;
+    whole - NTP_UNIX_OFFSET + fraction / 4_294_967_296.0
+}
+
+/// SNTP client request (RFC 4330): version 4, client mode, the send time as transmit timestamp
+fn ntp_request(sent: f64) -> [u8; 48] {
+    let mut packet = [0u8; 48];
+    packet[0] = 0b00_100_011;
+    packet[40..48].copy_from_slice(&to_ntp(sent));
+    packet
+}
+
+/// Seconds the local clock is behind the server (negative when ahead), from a server response
+/// to [`ntp_request`] sent at `sent` and received at `received`
+fn ntp_offset_from(response: &[u8], request: &[u8; 48], sent: f64, received: f64) -> Result<f64, String> {
+    if response.len() < 48 {
+        return Err("short NTP response".to_string());
+    }
+    if response[0] & 0b111 != 4 {
+        return Err("NTP response is not from a server".to_string());
+    }
+    if response[1] == 0 {
+        return Err("NTP server is unsynchronised or refused the request".to_string());
+    }
+    // The server echoes our transmit timestamp, so stray or spoofed packets are ignored
+    if response[24..32] != request[40..48] {
+        return Err("NTP response does not answer our request".to_string());
+    }
+    let server_received = from_ntp(&response[32..40]);
+    let server_sent = from_ntp(&response[40..48]);
+    Ok(((server_received - sent) + (server_sent - received)) / 2.0)
+}
+
+async fn ntp_offset(server: &str) -> Result<f64, String> {
+    let addr: SocketAddr = tokio::net::lookup_host(server)
+        .await
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or("no address")?;
+    let bind: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { (std::net::Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket = UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+    socket.connect(addr).await.map_err(|e| e.to_string())?;
+
+    let sent = unix_secs(SystemTime::now());
+    let request = ntp_request(sent);
+    socket.send(&request).await.map_err(|e| e.to_string())?;
+    let mut response = [0u8; 68];
+    let len = socket.recv(&mut response).await.map_err(|e| e.to_string())?;
+    let received = unix_secs(SystemTime::now());
+    ntp_offset_from(&response[..len], &request, sent, received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers one SNTP request with a clock `offset` seconds ahead of ours
+    async fn fake_ntp_server(offset: f64) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut request = [0u8; 48];
+            let (_, peer) = socket.recv_from(&mut request).await.unwrap();
+            let now = to_ntp(unix_secs(SystemTime::now()) + offset);
+            let mut response = [0u8; 48];
+            response[0] = 0b00_100_100;
+            response[1] = 2;
+            response[24..32].copy_from_slice(&request[40..48]);
+            response[32..40].copy_from_slice(&now);
+            response[40..48].copy_from_slice(&now);
+            socket.send_to(&response, peer).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_clock_check() {
+        let server = fake_ntp_server(0.0).await;
+        let check = check_clock(&server, Duration::from_secs(60)).await;
+        assert_eq!(check.status, CheckStatus::Ok, "{}", check.detail);
+
+        let server = fake_ntp_server(3600.0).await;
+        let check = check_clock(&server, Duration::from_secs(60)).await;
+        assert_eq!(check.status, CheckStatus::Error);
+        assert!(check.detail.contains("+3600"), "{}", check.detail);
+
+        // Responses that do not echo our request are not trusted
+        let request = ntp_request(1_800_000_000.0);
+        let mut response = [0u8; 48];
+        response[0] = 0b00_100_100;
+        response[1] = 2;
+        assert!(ntp_offset_from(&response, &request, 1_800_000_000.0, 1_800_000_000.1).is_err());
+    }
+
+    #[test]
+    fn test_ntp_timestamps() {
+        let secs = 1_800_000_000.25;
+        assert!((from_ntp(&to_ntp(secs)) - secs).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::OnceLock;
+
+use axum::response::{IntoResponse, Response};
+use hyper::StatusCode;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use super::s3_app_error::S3AppError;
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+const SIZE_BUCKETS: &[f64] = &[
+    1024.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+    268435456.0, 1073741824.0,
+];
+// A cipher pass over a small object takes microseconds
+const ENCRYPTION_BUCKETS: &[f64] = &[
+    0.00001, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+    0.5, 1.0, 2.5, 5.0,
+];
+// Client clocks are off by seconds when healthy, by the 15 minute SigV4 limit when not
+const CLOCK_SKEW_BUCKETS: &[f64] = &[0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 86400.0];
+const CONNECTION_REQUEST_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0];
+
+/// Installs the global metrics recorder backing the `/_fily/metrics` endpoint
+pub fn init() -> anyhow::Result<()> {
+    // Histograms rather than summaries, so latency percentiles can be aggregated across instances
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)
+        .and_then(|b| b.set_buckets_for_metric(Matcher::Suffix("_bytes".to_string()), SIZE_BUCKETS))
+        .and_then(|b| {
+            b.set_buckets_for_metric(
+                Matcher::Full("fily_encryption_duration_seconds".to_string()),
+                ENCRYPTION_BUCKETS,
+            )
+        })
+        .and_then(|b| {
+            b.set_buckets_for_metric(
+                Matcher::Full("fily_client_clock_skew_seconds".to_string()),
+                CLOCK_SKEW_BUCKETS,
+            )
+        })
+        .and_then(|b| {
+            b.set_buckets_for_metric(
+                Matcher::Full("fily_connection_requests".to_string()),
+                CONNECTION_REQUEST_BUCKETS,
+            )
+        })
+        .map_err(|e| anyhow::anyhow!("Invalid metrics histogram buckets: {}", e))?
+        .install_recorder()
+        .map_err(|e| anyhow::anyhow!("Failed to install metrics recorder: {}", e))?;
+
+    PROMETHEUS
+        .set(handle)
+        .map_err(|_| anyhow::anyhow!("Metrics have already been initialised"))?;
+
+    Ok(())
+}
+
+/// Metrics in the Prometheus text exposition format
+pub async fn handle() -> Result<Response, S3AppError> {
+    let handle = PROMETHEUS
+        .get()
+        .ok_or_else(|| S3AppError::not_implemented("metrics"))?;
+    handle.run_upkeep();
+
+    Ok((
+        StatusCode::OK,
+        [("content-type", "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+        .into_response())
+}
@@ -0,0 +1,207 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use tokio::time::{Instant, Sleep};
+use tracing::warn;
+
+use super::s3_app_error::S3AppError;
+use super::Config;
+
+/// Limits protecting the server from slow or stalled clients; `None` disables a limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeoutConfig {
+    /// Time from the request headers arriving until the response is ready
+    pub request: Option<Duration>,
+    /// Time a client may take to send the request headers (HTTP/1)
+    pub header_read: Option<Duration>,
+    /// Time the request body may go without receiving any data
+    pub body_idle: Option<Duration>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            request: None,
+            header_read: Some(Duration::from_secs(30)),
+            body_idle: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+/// Error produced by [`IdleTimeoutBody`] when the client stops sending the body
+#[derive(Debug, thiserror::Error)]
+#[error("request body idle for longer than {0:?}")]
+pub struct BodyIdleTimeout(pub Duration);
+
+/// Whether `err` or one of its sources is a [`BodyIdleTimeout`]
+pub fn is_body_idle_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if err.is::<BodyIdleTimeout>() {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Request body that fails once no data has arrived for `idle`
+pub struct IdleTimeoutBody {
+    inner: Body,
+    idle: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl IdleTimeoutBody {
+    pub fn new(inner: Body, idle: Duration) -> Self {
+        Self {
+            inner,
+            idle,
+            deadline: Box::pin(tokio::time::sleep(idle)),
+        }
+    }
+}
+
+impl HttpBody for IdleTimeoutBody {
+    type Data = Bytes;
+    type Error = axum::BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                let idle = this.idle;
+                this.deadline.as_mut().reset(Instant::now() + idle);
+                Poll::Ready(frame.map(|frame| frame.map_err(Into::into)))
+            }
+            Poll::Pending => match this.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Some(Err(BodyIdleTimeout(this.idle).into()))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware applying the per-request and idle-body timeouts, answering with RequestTimeout
+pub async fn enforce(req: Request, next: Next) -> Response {
+    let Some(config) = req.extensions().get::<Arc<Config>>().cloned() else {
+        return next.run(req).await;
+    };
+    let timeouts = &config.timeouts;
+
+    let req = match timeouts.body_idle {
+        Some(idle) => req.map(|body| Body::new(IdleTimeoutBody::new(body, idle))),
+        None => req,
+    };
+    let Some(limit) = timeouts.request else {
+        return next.run(req).await;
+    };
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            metrics::counter!("fily_request_timeouts_total", "kind" => "request").increment(1);
+            warn!("{} {} did not complete within {:?}", method, path, limit);
+            S3AppError::request_timeout(&path).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    /// Body that never yields, like a client that stopped sending mid-upload
+    struct Stalled;
+
+    impl HttpBody for Stalled {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Pending
+        }
+    }
+
+    fn config(timeouts: TimeoutConfig) -> Arc<Config> {
+        Arc::new(Config {
+            location: "./test_data".to_string(),
+            address: "127.0.0.1".to_string(),
+            timeouts,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_times_out() {
+        let body = IdleTimeoutBody::new(Body::new(Stalled), Duration::from_millis(20));
+
+        let err = body.collect().await.unwrap_err();
+
+        assert!(is_body_idle_timeout(err.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn test_complete_body_is_passed_through() {
+        let body = IdleTimeoutBody::new(Body::from("hello"), Duration::from_millis(20));
+
+        let bytes = body.collect().await.unwrap().to_bytes();
+
+        assert_eq!(bytes, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_returns_request_timeout() {
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "done"
+                }),
+            )
+            .layer(axum::middleware::from_fn(enforce))
+            .layer(Extension(config(TimeoutConfig {
+                request: Some(Duration::from_millis(20)),
+                ..Default::default()
+            })));
+
+        let response = app
+            .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), hyper::StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("<Code>RequestTimeout</Code>"));
+    }
+}
@@ -0,0 +1,94 @@
+//! Timestamps in the formats S3 and HTTP use: RFC 1123 (IMF-fixdate) for headers such as
+//! `Last-Modified`, ISO 8601 with milliseconds for XML bodies, and the three RFC 7231 date
+//! formats accepted in conditional request headers.
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+const ISO8601_MILLIS: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+
+/// `Tue, 15 Oct 2024 10:00:00 GMT`, for `Last-Modified` and other HTTP date headers
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format(IMF_FIXDATE).to_string()
+}
+
+/// `2024-10-15T10:00:00.000Z`, for `LastModified` and `CreationDate` in XML responses
+pub fn iso8601(time: DateTime<Utc>) -> String {
+    time.format(ISO8601_MILLIS).to_string()
+}
+
+/// Parses an HTTP date in any of the RFC 7231 formats: IMF-fixdate
+/// (`Sun, 06 Nov 1994 08:49:37 GMT`), the obsolete RFC 850 form
+/// (`Sunday, 06-Nov-94 08:49:37 GMT`) and ANSI C's asctime (`Sun Nov  6 08:49:37 1994`)
+pub fn parse_http_date(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(time) = NaiveDateTime::parse_from_str(s, IMF_FIXDATE) {
+        return Some(time.and_utc());
+    }
+    if let Some(time) = parse_rfc850(s) {
+        return Some(time);
+    }
+    NaiveDateTime::parse_from_str(s, "%a %b %e %H:%M:%S %Y")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// RFC 850 dates have two-digit years, which RFC 7231 reads as the most recent year with those
+/// digits that is not more than 50 years in the future
+fn parse_rfc850(s: &str) -> Option<DateTime<Utc>> {
+    let (_, rest) = s.split_once(", ")?;
+    let (date, time) = rest.strip_suffix(" GMT")?.split_once(' ')?;
+    let mut parts = date.split('-');
+    let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || year.len() != 2 {
+        return None;
+    }
+    let year: i32 = year.parse().ok()?;
+    let this_year = Utc::now().year();
+    let mut full_year = this_year - this_year.rem_euclid(100) + year;
+    if full_year > this_year + 50 {
+        full_year -= 100;
+    }
+    NaiveDateTime::parse_from_str(&format!("{} {} {} {}", day, month, full_year, time), "%d %b %Y %H:%M:%S")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_formats_match_s3() {
+        let time = at("1994-11-06T08:49:37.5Z");
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(iso8601(time), "1994-11-06T08:49:37.500Z");
+        assert_eq!(iso8601(at("2024-01-01T00:00:00Z")), "2024-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_parse_rfc_7231_formats() {
+        let expected = Some(at("1994-11-06T08:49:37Z"));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(parse_http_date(" Tue, 15 Oct 2024 10:00:00 GMT "), Some(at("2024-10-15T10:00:00Z")));
+        // Two-digit years more than 50 years ahead belong to the previous century
+        assert_eq!(parse_http_date("Tuesday, 15-Oct-24 10:00:00 GMT"), Some(at("2024-10-15T10:00:00Z")));
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 +0000"), None);
+        assert_eq!(parse_http_date("1994-11-06T08:49:37Z"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let time = at("2024-10-15T10:00:00Z");
+        assert_eq!(parse_http_date(&http_date(time)), Some(time));
+    }
+}
@@ -0,0 +1,136 @@
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hyper::Method;
+
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+
+/// Bucket subresources fily does not implement, with the noun of their S3 operations
+const BUCKET_SUBRESOURCES: &[(&str, &str)] = &[
+    ("accelerate", "BucketAccelerateConfiguration"),
+    ("acl", "BucketAcl"),
+    ("analytics", "BucketAnalyticsConfiguration"),
+    ("cors", "BucketCors"),
+    ("encryption", "BucketEncryption"),
+    ("intelligent-tiering", "BucketIntelligentTieringConfiguration"),
+    ("inventory", "BucketInventoryConfiguration"),
+    ("lifecycle", "BucketLifecycleConfiguration"),
+    ("metrics", "BucketMetricsConfiguration"),
+    ("notification", "BucketNotificationConfiguration"),
+    ("object-lock", "ObjectLockConfiguration"),
+    ("policy", "BucketPolicy"),
+    ("replication", "BucketReplication"),
+    ("requestPayment", "BucketRequestPayment"),
+    ("tagging", "BucketTagging"),
+    ("versioning", "BucketVersioning"),
+    ("website", "BucketWebsite"),
+];
+
+/// Object subresources fily does not implement, with the noun of their S3 operations
+const OBJECT_SUBRESOURCES: &[(&str, &str)] = &[
+    ("acl", "ObjectAcl"),
+    ("attributes", "ObjectAttributes"),
+    ("legal-hold", "ObjectLegalHold"),
+    ("retention", "ObjectRetention"),
+    ("tagging", "ObjectTagging"),
+    ("torrent", "ObjectTorrent"),
+];
+
+/// Name of the unimplemented S3 operation a request addresses, if any. Without this, such
+/// requests would list the bucket, or read or even overwrite the object named in the path.
+fn unimplemented_operation(method: &Method, path: &str, query: Option<&str>, copy: bool) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    segments.next().filter(|bucket| !bucket.is_empty() && *bucket != "_fily")?;
+    let is_object = segments.next().is_some_and(|key| !key.is_empty());
+    let params: Vec<String> = url::form_urlencoded::parse(query?.as_bytes())
+        .map(|(name, _)| name.into_owned())
+        .collect();
+    let has = |name: &str| params.iter().any(|p| p == name);
+
+    let special = match (method.as_str(), is_object) {
+        ("PUT", true) if has("uploadId") && copy => Some("UploadPartCopy"),
+        ("PUT", true) if has("uploadId") => Some("UploadPart"),
+        ("GET", true) if has("uploadId") => Some("ListParts"),
+        ("POST", true) if has("uploadId") => Some("CompleteMultipartUpload"),
+        ("DELETE", true) if has("uploadId") => Some("AbortMultipartUpload"),
+        ("POST", true) if has("uploads") => Some("CreateMultipartUpload"),
+        ("POST", true) if has("restore") => Some("RestoreObject"),
+        ("POST", true) if has("select") => Some("SelectObjectContent"),
+        ("GET", false) if has("uploads") => Some("ListMultipartUploads"),
+        ("GET", false) if has("versions") => Some("ListObjectVersions"),
+        ("POST", false) if has("delete") => Some("DeleteObjects"),
+        // S3 has no such operation, but without this the request would delete the bucket
+        ("DELETE", false) if has("logging") => Some("DeleteBucketLogging"),
+        _ => None,
+    };
+    if let Some(operation) = special {
+        return Some(operation.to_string());
+    }
+
+    let verb = match *method {
+        Method::GET | Method::HEAD => "Get",
+        // Handled by the ACL handler, which must still report ACLs disabled by ownership controls
+        Method::PUT if has("acl") => return None,
+        Method::PUT => "Put",
+        Method::DELETE => "Delete",
+        _ => return None,
+    };
+    let subresources = if is_object { OBJECT_SUBRESOURCES } else { BUCKET_SUBRESOURCES };
+    subresources
+        .iter()
+        .find(|(name, _)| has(name))
+        .map(|(_, noun)| format!("{}{}", verb, noun))
+}
+
+/// Middleware answering known but unimplemented S3 operations with a NotImplemented error
+/// that names the operation
+pub async fn reject_unimplemented(req: Request, next: Next) -> Response {
+    let copy = req.headers().contains_key("x-amz-copy-source");
+    if let Some(operation) = unimplemented_operation(req.method(), req.uri().path(), req.uri().query(), copy) {
+        return S3AppError::with_message_and_resource(
+            S3ErrorCode::NotImplemented,
+            format!("{} is not implemented by fily", operation),
+            req.uri().path().to_string(),
+        )
+        .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(method: Method, path: &str, query: &str) -> Option<String> {
+        unimplemented_operation(&method, path, Some(query), false)
+    }
+
+    #[test]
+    fn test_unimplemented_operation() {
+        assert_eq!(operation(Method::GET, "/photos", "replication").as_deref(), Some("GetBucketReplication"));
+        assert_eq!(operation(Method::PUT, "/photos", "analytics&id=a").as_deref(), Some("PutBucketAnalyticsConfiguration"));
+        assert_eq!(operation(Method::DELETE, "/photos", "cors").as_deref(), Some("DeleteBucketCors"));
+        assert_eq!(operation(Method::GET, "/photos/a.jpg", "tagging").as_deref(), Some("GetObjectTagging"));
+        assert_eq!(operation(Method::PUT, "/photos/a.jpg", "tagging").as_deref(), Some("PutObjectTagging"));
+        assert_eq!(
+            operation(Method::PUT, "/photos/a.jpg", "partNumber=1&uploadId=abc").as_deref(),
+            Some("UploadPart")
+        );
+        assert_eq!(
+            unimplemented_operation(&Method::PUT, "/photos/a.jpg", Some("partNumber=1&uploadId=abc"), true).as_deref(),
+            Some("UploadPartCopy")
+        );
+        assert_eq!(operation(Method::POST, "/photos", "delete").as_deref(), Some("DeleteObjects"));
+        assert_eq!(operation(Method::GET, "/photos", "versions").as_deref(), Some("ListObjectVersions"));
+
+        // Implemented operations and ordinary parameters pass through
+        assert_eq!(operation(Method::GET, "/photos", "prefix=tagging&list-type=2"), None);
+        assert_eq!(operation(Method::GET, "/photos", "ownershipControls"), None);
+        assert_eq!(operation(Method::PUT, "/photos", "logging"), None);
+        assert_eq!(operation(Method::DELETE, "/photos", "logging").as_deref(), Some("DeleteBucketLogging"));
+        assert_eq!(operation(Method::GET, "/photos/a.jpg", "partNumber=2"), None);
+        assert_eq!(operation(Method::PUT, "/photos", "acl"), None);
+        assert_eq!(operation(Method::GET, "/_fily/admin/read-only", "policy"), None);
+        assert_eq!(unimplemented_operation(&Method::GET, "/photos", None, false), None);
+    }
+}
@@ -0,0 +1,268 @@
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use hyper::HeaderMap;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use super::aws_chunked::{
+    AwsChunkedError, ChunkSignatureVerifier, STREAMING_AWS4_HMAC_SHA256_PAYLOAD, STREAMING_AWS4_HMAC_SHA256_PAYLOAD_TRAILER,
+    STREAMING_UNSIGNED_PAYLOAD_TRAILER,
+};
+use super::s3_app_error::{S3AppError, S3ErrorCode};
+use super::timeouts::is_body_idle_timeout;
+
+const X_AMZ_CONTENT_SHA256_HEADER: &str = "x-amz-content-sha256";
+
+/// Values `x-amz-content-sha256` may hold in place of the SHA-256 of the body
+const PAYLOAD_PLACEHOLDERS: [&str; 4] = [
+    "UNSIGNED-PAYLOAD",
+    STREAMING_UNSIGNED_PAYLOAD_TRAILER,
+    STREAMING_AWS4_HMAC_SHA256_PAYLOAD,
+    STREAMING_AWS4_HMAC_SHA256_PAYLOAD_TRAILER,
+];
+
+/// Streaming payload types signed with SigV4a, which fily does not verify
+const UNSUPPORTED_PAYLOAD_TYPES: [&str; 2] = [
+    "STREAMING-AWS4-ECDSA-P256-SHA256-PAYLOAD",
+    "STREAMING-AWS4-ECDSA-P256-SHA256-PAYLOAD-TRAILER",
+];
+
+/// Error produced by [`VerifiedBody`] when the body does not hash to the declared value
+#[derive(Debug, thiserror::Error)]
+#[error("request body does not match x-amz-content-sha256")]
+pub struct PayloadHashMismatch;
+
+/// Why a streamed request body failed, recorded for the middleware that handed it out since
+/// handlers only see their extractor's rejection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFailure {
+    HashMismatch,
+    IdleTimeout,
+    ChunkSignatureMismatch,
+    MalformedChunks,
+}
+
+impl BodyFailure {
+    pub fn into_response(self, resource: &str) -> Response {
+        match self {
+            BodyFailure::HashMismatch => {
+                S3AppError::with_resource(S3ErrorCode::XAmzContentSHA256Mismatch, resource.to_string())
+                    .into_response()
+            }
+            BodyFailure::IdleTimeout => S3AppError::request_timeout(resource).into_response(),
+            BodyFailure::ChunkSignatureMismatch => {
+                S3AppError::with_resource(S3ErrorCode::SignatureDoesNotMatch, resource.to_string())
+                    .into_response()
+            }
+            BodyFailure::MalformedChunks => S3AppError::with_message_and_resource(
+                S3ErrorCode::InvalidRequest,
+                "The aws-chunked request body is malformed.".to_string(),
+                resource.to_string(),
+            )
+            .into_response(),
+        }
+    }
+}
+
+/// The SHA-256 a request declares for its body in `x-amz-content-sha256`, `None` for the
+/// unsigned and streaming placeholders
+pub fn declared_sha256(headers: &HeaderMap) -> Option<[u8; 32]> {
+    let value = headers.get(X_AMZ_CONTENT_SHA256_HEADER)?.to_str().ok()?;
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(value, &mut hash).ok()?;
+    Some(hash)
+}
+
+/// Whether `x-amz-content-sha256` holds something other than a SHA-256 or one of the unsigned
+/// and streaming placeholders, which would leave the body unverified although it was signed
+pub fn malformed_declared_sha256(headers: &HeaderMap) -> bool {
+    let Some(value) = headers.get(X_AMZ_CONTENT_SHA256_HEADER) else {
+        return false;
+    };
+    let Ok(value) = value.to_str() else {
+        return true;
+    };
+    !PAYLOAD_PLACEHOLDERS.contains(&value)
+        && !UNSUPPORTED_PAYLOAD_TYPES.contains(&value)
+        && declared_sha256(headers).is_none()
+}
+
+/// Whether `x-amz-content-sha256` names a streaming payload type fily cannot verify
+pub fn unsupported_payload_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(X_AMZ_CONTENT_SHA256_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| UNSUPPORTED_PAYLOAD_TYPES.contains(&v))
+}
+
+/// Request body streamed to handlers while it is hashed, failing at its end when it does not
+/// match the SHA-256 the client signed, instead of being buffered to check up front. Signed
+/// aws-chunked bodies have their chunk signatures checked as they pass instead.
+pub struct VerifiedBody {
+    inner: Body,
+    expected: Option<[u8; 32]>,
+    hasher: Sha256,
+    chunks: Option<ChunkSignatureVerifier>,
+    done: bool,
+    failure: Arc<OnceLock<BodyFailure>>,
+}
+
+impl VerifiedBody {
+    pub fn new(inner: Body, expected: Option<[u8; 32]>) -> Self {
+        Self {
+            inner,
+            expected,
+            hasher: Sha256::new(),
+            chunks: None,
+            done: false,
+            failure: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Checks the body's chunk signatures, once the request signature they chain from is known
+    pub fn verify_chunk_signatures(&mut self, chunks: ChunkSignatureVerifier) {
+        self.chunks = Some(chunks);
+    }
+
+    /// Where the body records how it failed, if it does
+    pub fn failure(&self) -> Arc<OnceLock<BodyFailure>> {
+        self.failure.clone()
+    }
+
+    fn fail(&mut self, failure: BodyFailure) {
+        self.done = true;
+        let _ = self.failure.set(failure);
+    }
+
+    fn fail_chunks(&mut self, e: AwsChunkedError) -> axum::BoxError {
+        warn!("Rejecting aws-chunked request body: {}", e);
+        self.fail(match e {
+            AwsChunkedError::SignatureMismatch => BodyFailure::ChunkSignatureMismatch,
+            _ => BodyFailure::MalformedChunks,
+        });
+        e.into()
+    }
+}
+
+impl HttpBody for VerifiedBody {
+    type Data = Bytes;
+    type Error = axum::BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(_)) = (frame.data_ref(), this.expected) {
+                    this.hasher.update(data);
+                }
+                if let (Some(data), Some(chunks)) = (frame.data_ref(), this.chunks.as_mut()) {
+                    if let Err(e) = chunks.update(data) {
+                        return Poll::Ready(Some(Err(this.fail_chunks(e))));
+                    }
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                let e: axum::BoxError = e.into();
+                if is_body_idle_timeout(e.as_ref()) {
+                    this.fail(BodyFailure::IdleTimeout);
+                }
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                this.done = true;
+                if let Some(Err(e)) = this.chunks.as_ref().map(ChunkSignatureVerifier::finish) {
+                    return Poll::Ready(Some(Err(this.fail_chunks(e))));
+                }
+                let Some(expected) = this.expected else {
+                    return Poll::Ready(None);
+                };
+                if this.hasher.finalize_reset().as_slice() == expected {
+                    return Poll::Ready(None);
+                }
+                warn!("Request body does not match its x-amz-content-sha256");
+                this.fail(BodyFailure::HashMismatch);
+                Poll::Ready(Some(Err(PayloadHashMismatch.into())))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // The end is only reported once the hash has been checked
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn headers(sha256: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_AMZ_CONTENT_SHA256_HEADER, sha256.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_body_checked_against_declared_hash() {
+        let declared = declared_sha256(&headers(&hex::encode(Sha256::digest(b"hello world"))));
+        assert!(declared.is_some());
+
+        let body = VerifiedBody::new(Body::from("hello world"), declared);
+        let failure = body.failure();
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello world");
+        assert_eq!(failure.get(), None);
+
+        let body = VerifiedBody::new(Body::from("hello there"), declared);
+        let failure = body.failure();
+        let err = body.collect().await.unwrap_err();
+        assert!(err.is::<PayloadHashMismatch>());
+        assert_eq!(failure.get(), Some(&BodyFailure::HashMismatch));
+
+        // Placeholders declare no hash, so the body is passed through unchecked
+        assert_eq!(declared_sha256(&headers("UNSIGNED-PAYLOAD")), None);
+        assert_eq!(declared_sha256(&headers("STREAMING-UNSIGNED-PAYLOAD-TRAILER")), None);
+        let body = VerifiedBody::new(Body::from("anything"), None);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "anything");
+    }
+
+    #[test]
+    fn test_malformed_declared_hash() {
+        assert!(!malformed_declared_sha256(&HeaderMap::new()));
+        assert!(!malformed_declared_sha256(&headers("UNSIGNED-PAYLOAD")));
+        assert!(!malformed_declared_sha256(&headers("STREAMING-UNSIGNED-PAYLOAD-TRAILER")));
+        assert!(!malformed_declared_sha256(&headers("STREAMING-AWS4-HMAC-SHA256-PAYLOAD")));
+        assert!(!malformed_declared_sha256(&headers("STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER")));
+        assert!(!malformed_declared_sha256(&headers(&hex::encode(Sha256::digest(b"")))));
+        assert!(malformed_declared_sha256(&headers("anything")));
+        assert!(malformed_declared_sha256(&headers("unsigned-payload")));
+        assert!(malformed_declared_sha256(&headers("STREAMING-")));
+        assert!(malformed_declared_sha256(&headers("STREAMING-ANYTHING")));
+        assert!(malformed_declared_sha256(&headers("STREAMING-AWS4-HMAC-SHA256-PAYLOAD-EXTRA")));
+        assert!(malformed_declared_sha256(&headers(&hex::encode(Sha256::digest(b""))[..63])));
+
+        // SigV4a payloads are known, just not supported
+        let ecdsa = headers("STREAMING-AWS4-ECDSA-P256-SHA256-PAYLOAD");
+        assert!(!malformed_declared_sha256(&ecdsa));
+        assert!(unsupported_payload_type(&ecdsa));
+        assert!(!unsupported_payload_type(&headers("STREAMING-AWS4-HMAC-SHA256-PAYLOAD")));
+    }
+}
@@ -1,2 +1,5 @@
+// The effective config served by /_fily/admin/config is one large `json!` literal
+#![recursion_limit = "256"]
+
 pub mod fily;
 pub use fily::*;
\ No newline at end of file
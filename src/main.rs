@@ -1,18 +1,62 @@
-mod fily;
+mod bench;
 mod config;
+mod manifest;
+mod migrate;
+mod validate_config;
 
-use std::str::FromStr;
 use std::env;
 
+use clap::Parser;
 use dotenv::dotenv;
-use tracing::Level;
+use bench::BenchArgs;
 use config::ConfigLoader;
+use manifest::ManifestArgs;
+use migrate::MigrateArgs;
+use validate_config::ValidateArgs;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load .env file if present (for development)
     dotenv().ok();
 
+    // `fily bench [options]` load-tests a running instance instead of serving
+    if env::args().nth(1).as_deref() == Some("bench") {
+        let args = BenchArgs::parse_from(env::args().skip(1));
+        let config = ConfigLoader::load()?;
+        bench::run(args, &config.aws_credentials).await?;
+        return Ok(());
+    }
+
+    // `fily validate-config [--format json]` checks the configuration without serving
+    if env::args().nth(1).as_deref() == Some("validate-config") {
+        let args = ValidateArgs::parse_from(env::args().skip(1));
+        return validate_config::run(args).await;
+    }
+
+    // `fily sync` runs every configured bucket sync once and exits
+    if env::args().nth(1).as_deref() == Some("sync") {
+        let config = ConfigLoader::load()?;
+        ConfigLoader::validate(&config)?;
+        fily::logging::init(&config.log_level, config.request_log_sampling.clone())?;
+        let reports = fily::sync::run(config).await?;
+        if reports.iter().any(|report| report.failed > 0) {
+            anyhow::bail!("Some objects could not be pulled");
+        }
+        return Ok(());
+    }
+
+    // `fily manifest export <bucket>` / `fily manifest verify <file>` for compliance exports
+    if env::args().nth(1).as_deref() == Some("manifest") {
+        let args = ManifestArgs::parse_from(env::args().skip(1));
+        return manifest::run(args).await;
+    }
+
+    // `fily migrate [--backup | --rollback]` upgrades or restores the storage layout and exits
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        let args = MigrateArgs::parse_from(env::args().skip(1));
+        return migrate::run(args).await;
+    }
+
     // Check for help flag
     if env::args().any(|arg| arg == "--help" || arg == "-h" || arg == "help") {
         ConfigLoader::print_help();
@@ -25,13 +69,11 @@ async fn main() -> anyhow::Result<()> {
     // Validate configuration
     ConfigLoader::validate(&config)?;
 
-    // Initialize tracing with configured log level
-    tracing_subscriber::fmt()
-        .with_max_level(Level::from_str(&config.log_level).unwrap())
-        .with_level(true)
-        .with_thread_names(true)
-        .with_target(true)
-        .init();
+    // Initialize tracing with configured log level and request log sampling
+    fily::logging::init(&config.log_level, config.request_log_sampling.clone())?;
+
+    // Install the metrics recorder behind /_fily/metrics
+    fily::telemetry::init()?;
 
     // Run the server
     fily::run(config).await
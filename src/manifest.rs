@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+
+use fily::cpu_pool::CpuPool;
+use fily::manifest::{self, Manifest};
+use fily::s3_app_error::S3AppError;
+
+use crate::config::ConfigLoader;
+
+/// Exports signed integrity manifests of buckets and checks the store against them, reading the
+/// storage location directly
+#[derive(Debug, Parser)]
+#[command(name = "fily manifest")]
+pub struct ManifestArgs {
+    #[command(subcommand)]
+    pub command: ManifestCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ManifestCommand {
+    /// Writes the signed manifest of every object in a bucket
+    Export {
+        bucket: String,
+        /// File to write the manifest to instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Checks a manifest's signature and compares its bucket with it, failing on any difference
+    Verify { manifest: PathBuf },
+}
+
+fn s3_error(error: S3AppError) -> anyhow::Error {
+    let message = error.message.as_deref().unwrap_or(error.code.default_message());
+    anyhow::anyhow!("{}: {}", error.code.as_str(), message)
+}
+
+/// `fily manifest export|verify`
+pub async fn run(args: ManifestArgs) -> anyhow::Result<()> {
+    let config = ConfigLoader::load()?;
+    ConfigLoader::validate(&config)?;
+    let cpu_pool = CpuPool::new(&config.cpu_pool);
+    let config = Arc::new(config);
+
+    match args.command {
+        ManifestCommand::Export { bucket, output } => {
+            let manifest = manifest::build(&config, &cpu_pool, &bucket).await.map_err(s3_error)?;
+            let json = serde_json::to_string_pretty(&manifest)?;
+            match output {
+                Some(path) => {
+                    tokio::fs::write(&path, json).await?;
+                    eprintln!("Wrote manifest of {} objects in {} to {}", manifest.entries.len(), bucket, path.display());
+                }
+                None => println!("{}", json),
+            }
+        }
+        ManifestCommand::Verify { manifest: path } => {
+            let manifest: Manifest = serde_json::from_slice(&tokio::fs::read(&path).await?)
+                .map_err(|e| anyhow::anyhow!("Invalid manifest {}: {}", path.display(), e))?;
+            let report = manifest::verify(&config, &cpu_pool, &manifest).await.map_err(s3_error)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.valid {
+                anyhow::bail!("Bucket {} does not match the manifest", report.bucket);
+            }
+        }
+    }
+    Ok(())
+}
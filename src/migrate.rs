@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use clap::Parser;
+
+use fily::migrations::{self, CURRENT_LAYOUT};
+
+use crate::config::ConfigLoader;
+
+/// Upgrades the store to the layout of this version, or restores the layout kept by the last
+/// migration, reading the storage location directly. Run it while fily is stopped.
+#[derive(Debug, Parser)]
+#[command(name = "fily migrate")]
+pub struct MigrateArgs {
+    /// Keep the current layout in .fily-backup, restoring it if the migration fails
+    #[arg(long)]
+    pub backup: bool,
+    /// Restore the most recent backup instead, discarding writes made since it was taken
+    #[arg(long, conflicts_with = "backup")]
+    pub rollback: bool,
+}
+
+/// `fily migrate [--backup | --rollback]`
+pub async fn run(args: MigrateArgs) -> anyhow::Result<()> {
+    let config = ConfigLoader::load()?;
+    ConfigLoader::validate(&config)?;
+    fily::logging::init(&config.log_level, config.request_log_sampling.clone())?;
+    let root = Path::new(&config.location);
+
+    if args.rollback {
+        let version = migrations::rollback(root).await?;
+        eprintln!("Restored storage layout {} in {}", version, root.display());
+        return Ok(());
+    }
+
+    // Interrupted writes are resolved before anything moves, as a server start would
+    fily::lifecycle::validate_storage(&config.location, config.staging_root.as_deref()).await?;
+    let mut migration = config.migrations.clone();
+    migration.backup |= args.backup;
+    match migrations::migrate(root, &migration, &config.metadata_layout, false).await? {
+        Some(from) if from < CURRENT_LAYOUT => {
+            eprintln!("Migrated {} from storage layout {} to {}", root.display(), from, CURRENT_LAYOUT)
+        }
+        _ => eprintln!("{} is at storage layout {}", root.display(), CURRENT_LAYOUT),
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use axum::http::Method;
+use base64::{engine::general_purpose, Engine as _};
+use fily::fily::batch::BatchConfig;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        batch: BatchConfig {
+            enabled: true,
+            max_objects: 10,
+            max_object_size: 16,
+        },
+        ..test_config(location)
+    }
+}
+
+/// Lines of an NDJSON response body
+fn ndjson(response: &str) -> Vec<serde_json::Value> {
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    body.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
+#[tokio::test]
+async fn test_batch_put_and_get() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/sensors", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let encode = |data: &[u8]| general_purpose::STANDARD.encode(data);
+    let request = format!(
+        "{}\n{}\n\n{}\n",
+        serde_json::json!({"key": "t-001.json", "data": encode(b"{\"c\":21.5}"), "content_type": "application/json"}),
+        serde_json::json!({"key": "t-002.bin", "data": encode(&[0, 1, 2, 255])}),
+        serde_json::json!({"key": "../escape", "data": encode(b"x")}),
+    );
+    let response = send(addr, Method::POST, "/_fily/batch/put/sensors", &[], request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let results = ndjson(&response);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0]["key"], "t-001.json");
+    assert_eq!(results[0]["size"], 10);
+    assert!(results[0]["etag"].as_str().unwrap().starts_with('"'));
+    assert!(results[1]["error"].is_null());
+    assert_eq!(results[2]["error"]["code"], "InvalidArgument");
+
+    // Batched objects are regular objects
+    let response = send(addr, Method::GET, "/sensors/t-001.json", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.to_ascii_lowercase().contains("content-type: application/json"), "{}", response);
+    assert!(response.ends_with("{\"c\":21.5}"), "{}", response);
+    send(addr, Method::PUT, "/sensors/t-003.txt", &[("content-type", "text/plain")], b"from PUT").await;
+    send(addr, Method::PUT, "/sensors/large.txt", &[], b"larger than sixteen bytes").await;
+
+    let request = "{\"key\": \"t-002.bin\"}\n{\"key\": \"t-003.txt\"}\n{\"key\": \"missing\"}\n{\"key\": \"large.txt\"}";
+    let response = send(addr, Method::POST, "/_fily/batch/get/sensors", &[], request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let results = ndjson(&response);
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0]["data"], encode(&[0, 1, 2, 255]));
+    assert_eq!(results[1]["data"], encode(b"from PUT"));
+    assert_eq!(results[1]["content_type"], "text/plain");
+    assert_eq!(results[1]["etag"], "\"96aea17d816ffa2cb8abc9cd8539d344\"");
+    assert_eq!(results[2]["error"]["code"], "NoSuchKey");
+    assert_eq!(results[3]["error"]["code"], "EntityTooLarge");
+
+    // Invalid batches are rejected before anything is stored
+    let request = format!(
+        "{}\n{}",
+        serde_json::json!({"key": "ok.txt", "data": encode(b"fine")}),
+        serde_json::json!({"key": "big.txt", "data": encode(&[7; 17])}),
+    );
+    let response = send(addr, Method::POST, "/_fily/batch/put/sensors", &[], request.as_bytes()).await;
+    assert!(response.contains("<Code>EntityTooLarge</Code>"), "{}", response);
+    assert!(!temp_dir.path().join("sensors/ok.txt").exists());
+    let response = send(addr, Method::POST, "/_fily/batch/put/sensors", &[], b"{\"key\": \"a\"}").await;
+    assert!(response.contains("<Code>InvalidArgument</Code>"), "{}", response);
+    let response = send(addr, Method::POST, "/_fily/batch/get/nothing", &[], b"{\"key\": \"a\"}").await;
+    assert!(response.contains("<Code>NoSuchBucket</Code>"), "{}", response);
+
+    stop.await;
+}
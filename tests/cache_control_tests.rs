@@ -0,0 +1,72 @@
+use axum::http::Method;
+use fily::fily::cache_control::CacheRule;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, header, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        cache_rules: vec![CacheRule {
+            bucket: "assets".to_string(),
+            prefix: "img-".to_string(),
+            max_age: Some(300),
+            s_maxage: Some(86400),
+            stale_while_revalidate: Some(60),
+            stale_if_error: None,
+            public: true,
+            surrogate_keys: true,
+        }],
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_cache_rules_and_revalidation() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    for path in ["/assets", "/assets/img-logo.svg", "/assets/index.html"] {
+        let response = send(addr, Method::PUT, path, &[], b"<svg/>").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", path, response);
+    }
+
+    let response = send(addr, Method::GET, "/assets/img-logo.svg", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(
+        header(&response, "cache-control"),
+        Some("public, max-age=300, s-maxage=86400, stale-while-revalidate=60")
+    );
+    assert_eq!(header(&response, "surrogate-key"), Some("assets assets/img-logo.svg"));
+    let etag = header(&response, "etag").unwrap().to_string();
+    let last_modified = header(&response, "last-modified").unwrap().to_string();
+    assert!(response.ends_with("<svg/>"), "{}", response);
+
+    // Objects outside the rule's prefix are left alone
+    let response = send(addr, Method::GET, "/assets/index.html", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(header(&response, "cache-control"), None);
+
+    // A CDN revalidating its stale copy gets a 304 that refreshes the cache headers
+    let response = send(addr, Method::GET, "/assets/img-logo.svg", &[("if-none-match", &etag)], b"").await;
+    assert!(response.starts_with("HTTP/1.1 304"), "{}", response);
+    assert_eq!(header(&response, "etag"), Some(etag.as_str()));
+    assert!(header(&response, "cache-control").is_some(), "{}", response);
+    assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    let response = send(addr, Method::GET, "/assets/img-logo.svg", &[("if-modified-since", &last_modified)], b"").await;
+    assert!(response.starts_with("HTTP/1.1 304"), "{}", response);
+    let response = send(addr, Method::GET, "/assets/img-logo.svg", &[("if-none-match", "\"stale\"")], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("<svg/>"), "{}", response);
+
+    // Errors are not made cacheable
+    let response = send(addr, Method::GET, "/assets/img-missing.svg", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    assert_eq!(header(&response, "cache-control"), None);
+
+    stop.await;
+}
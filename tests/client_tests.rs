@@ -0,0 +1,52 @@
+#![cfg(feature = "client")]
+
+use bytes::Bytes;
+use fily::fily::client::{Client, Credentials};
+use hyper::{Method, StatusCode};
+use tempfile::TempDir;
+
+mod common;
+
+use common::{ACCESS_KEY_ID, SECRET_ACCESS_KEY, serve, test_config};
+
+fn credentials(secret_access_key: &str) -> Credentials {
+    Credentials {
+        access_key_id: ACCESS_KEY_ID.to_string(),
+        secret_access_key: secret_access_key.to_string(),
+        region: "us-east-1".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_client_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(test_config(dir.path().to_str().unwrap())).await;
+    let endpoint = format!("http://{}", addr);
+
+    let client = Client::new(&endpoint, credentials(SECRET_ACCESS_KEY)).unwrap();
+    let response = client.send(Method::PUT, "/photos", &[], Bytes::new()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let key = "2024/summer holiday+beach.jpg";
+    let put = client.put_object("photos", key, Bytes::from_static(b"sand")).await.unwrap();
+    assert!(put.headers().contains_key("etag"));
+    let get = client.get_object("photos", key).await.unwrap();
+    assert_eq!(get.body().as_ref(), b"sand");
+
+    let list = client
+        .send(Method::GET, "/photos", &[("list-type", "2"), ("prefix", "2024/")], Bytes::new())
+        .await
+        .unwrap();
+    assert_eq!(list.status(), StatusCode::OK);
+    assert!(String::from_utf8_lossy(list.body()).contains("<Key>2024/summer holiday+beach.jpg</Key>"));
+
+    client.delete_object("photos", key).await.unwrap();
+    assert!(client.get_object("photos", key).await.is_err());
+
+    // A wrong secret is refused rather than silently ignored
+    let forged = Client::new(&endpoint, credentials("not-the-secret")).unwrap();
+    let error = forged.get_object("photos", "anything").await.unwrap_err();
+    assert!(error.to_string().contains("403"), "{}", error);
+
+    stop.await;
+}
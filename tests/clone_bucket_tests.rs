@@ -0,0 +1,62 @@
+use axum::http::Method;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, header, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_clone_bucket_copies_objects_and_metadata() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/prod", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let headers = [("content-type", "text/plain"), ("x-amz-tagging", "env=prod")];
+    send(addr, Method::PUT, "/prod/a.txt", &headers, b"alpha").await;
+    send(addr, Method::PUT, "/prod/nested%2Fb.txt", &[], b"bravo").await;
+    let original = send(addr, Method::GET, "/prod/a.txt", &[], b"").await;
+
+    let clone = [("x-fily-clone-source", "prod")];
+    let response = send(addr, Method::PUT, "/staging", &clone, b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let result: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(result["objects"], 2);
+    assert_eq!(result["bytes"], 10);
+
+    let response = send(addr, Method::GET, "/staging/a.txt", &[], b"").await;
+    assert!(response.ends_with("\r\n\r\nalpha"), "{}", response);
+    assert_eq!(header(&response, "content-type"), Some("text/plain"));
+    assert_eq!(header(&response, "etag"), header(&original, "etag"));
+    let response = send(addr, Method::GET, "/staging/nested%2Fb.txt", &[], b"").await;
+    assert!(response.ends_with("\r\n\r\nbravo"), "{}", response);
+    let response = send(addr, Method::GET, "/_fily/search/staging", &[], b"").await;
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let found: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(found["objects"][0]["key"], "a.txt");
+    assert_eq!(found["objects"][0]["tags"]["env"], "prod");
+
+    // The buckets share data only until one of them writes
+    send(addr, Method::PUT, "/staging/a.txt", &[], b"changed").await;
+    let response = send(addr, Method::GET, "/prod/a.txt", &[], b"").await;
+    assert!(response.ends_with("\r\n\r\nalpha"), "{}", response);
+
+    let response = send(addr, Method::PUT, "/staging", &clone, b"").await;
+    assert!(response.contains("BucketAlreadyExists"), "{}", response);
+    let response = send(addr, Method::PUT, "/other", &[("x-fily-clone-source", "missing")], b"").await;
+    assert!(response.contains("NoSuchBucket"), "{}", response);
+    let response = send(addr, Method::GET, "/other", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    stop.await;
+}
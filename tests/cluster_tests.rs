@@ -0,0 +1,145 @@
+use axum::http::Method;
+use fily::fily::cluster::{ClusterConfig, HashRing, Peer};
+use fily::fily::Config;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{body, send, serve_on, test_config};
+
+const CLUSTER_SECRET: &str = "shared by the nodes";
+
+/// Serves the store on `listener` until the returned future is awaited
+async fn start(
+    location: &std::path::Path,
+    cluster: ClusterConfig,
+    listener: tokio::net::TcpListener,
+) -> impl std::future::Future<Output = ()> {
+    let config = Config {
+        cluster,
+        ..test_config(location.to_str().unwrap())
+    };
+    serve_on(config, listener).await
+}
+
+#[tokio::test]
+async fn test_nodes_forward_requests_to_the_bucket_owner() {
+    let temp_dir = TempDir::new().unwrap();
+    let listeners = [
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+    let peers = vec![
+        Peer { id: "a".to_string(), endpoint: format!("http://{}", addrs[0]) },
+        Peer { id: "b".to_string(), endpoint: format!("http://{}", addrs[1]) },
+    ];
+    let ring = HashRing::new(&peers, 128);
+    let bucket_of = |id: &str| {
+        (0..)
+            .map(|i| format!("bucket-{}", i))
+            .find(|bucket| ring.owner(bucket).id == id)
+            .unwrap()
+    };
+    let (on_a, on_b) = (bucket_of("a"), bucket_of("b"));
+
+    let mut stops = vec![];
+    for (listener, id) in listeners.into_iter().zip(["a", "b"]) {
+        let cluster = ClusterConfig {
+            node_id: Some(id.to_string()),
+            peers: peers.clone(),
+            secret: Some(CLUSTER_SECRET.to_string()),
+            ..Default::default()
+        };
+        stops.push(start(&temp_dir.path().join(id), cluster, listener).await);
+    }
+
+    // Every request sent to node a lands on the node owning its bucket
+    for bucket in [&on_a, &on_b] {
+        let response = send(addrs[0], Method::PUT, &format!("/{}", bucket), &[], b"").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let response = send(addrs[0], Method::PUT, &format!("/{}/note.txt", bucket), &[], bucket.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+    assert!(temp_dir.path().join("a").join(&on_a).join("note.txt").exists());
+    assert!(!temp_dir.path().join("a").join(&on_b).exists());
+    assert!(temp_dir.path().join("b").join(&on_b).join("note.txt").exists());
+
+    // Either node serves either bucket
+    for addr in &addrs {
+        for bucket in [&on_a, &on_b] {
+            let response = send(*addr, Method::GET, &format!("/{}/note.txt", bucket), &[], b"").await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert_eq!(body(&response), bucket.as_str());
+        }
+    }
+
+    for stop in stops {
+        stop.await;
+    }
+}
+
+#[tokio::test]
+async fn test_reads_fail_over_to_the_replica_node() {
+    let temp_dir = TempDir::new().unwrap();
+    let listeners = [
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+    let peers = vec![
+        Peer { id: "a".to_string(), endpoint: format!("http://{}", addrs[0]) },
+        Peer { id: "b".to_string(), endpoint: format!("http://{}", addrs[1]) },
+    ];
+    let ring = HashRing::new(&peers, 128);
+    let bucket = (0..)
+        .map(|i| format!("bucket-{}", i))
+        .find(|bucket| ring.owner(bucket).id == "b")
+        .unwrap();
+    // Node a holds a copy of the bucket, e.g. kept up to date with FILY_SYNC
+    for id in ["a", "b"] {
+        let dir = temp_dir.path().join(id).join(&bucket);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("note.txt"), id).unwrap();
+    }
+
+    let mut stops = vec![];
+    for (listener, id) in listeners.into_iter().zip(["a", "b"]) {
+        let cluster = ClusterConfig {
+            node_id: Some(id.to_string()),
+            peers: peers.clone(),
+            secret: Some(CLUSTER_SECRET.to_string()),
+            health_interval: Duration::from_millis(50),
+            failure_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        stops.push(start(&temp_dir.path().join(id), cluster, listener).await);
+    }
+    let path = format!("/{}/note.txt", bucket);
+    let response = send(addrs[0], Method::GET, &path, &[], b"").await;
+    assert_eq!(body(&response), "b");
+
+    // With the owner gone, node a serves reads from its own copy and refuses writes
+    let stop_b = stops.pop().unwrap();
+    stop_b.await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let response = send(addrs[0], Method::GET, &path, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(body(&response), "a");
+    let response = send(addrs[0], Method::PUT, &path, &[], b"new").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    // Claiming to have been forwarded by the owner does not get a write past the routing
+    let response = send(addrs[0], Method::PUT, &path, &[("x-fily-forwarded-by", "b")], b"new").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+    let response = send(addrs[0], Method::GET, "/_fily/admin/cluster", &[], b"").await;
+    let state: serde_json::Value = serde_json::from_str(body(&response)).unwrap();
+    assert_eq!(state["node_id"], "a");
+    assert_eq!(state["nodes"][0]["up"], true);
+    assert_eq!(state["nodes"][1]["up"], false);
+
+    for stop in stops {
+        stop.await;
+    }
+}
@@ -0,0 +1,78 @@
+use axum::http::Method;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, header, send, send_with_query, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_compose_concatenates_objects_and_ranges() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    for bucket in ["/logs", "/archive"] {
+        let response = send(addr, Method::PUT, bucket, &[], b"").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+    let text = [("content-type", "text/plain")];
+    send(addr, Method::PUT, "/logs/00.log", &text, b"first line\n").await;
+    send(addr, Method::PUT, "/logs/01.log", &text, b"second line\n").await;
+    send(addr, Method::PUT, "/archive/02.log", &[], b"[header] third line\n").await;
+
+    let request = br#"{"sources": [
+        {"key": "00.log"},
+        {"key": "01.log"},
+        {"bucket": "archive", "key": "02.log", "range": "bytes=9-"}
+    ]}"#;
+    let response = send(addr, Method::POST, "/_fily/compose/logs/day.log", &[], request).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let result: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(result["size"], 34);
+    assert_eq!(result["parts"], 3);
+    assert!(result["etag"].as_str().unwrap().ends_with("-3\""), "{}", result);
+
+    let response = send(addr, Method::GET, "/logs/day.log", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(header(&response, "content-type"), Some("text/plain"));
+    assert_eq!(header(&response, "etag"), result["etag"].as_str());
+    assert_eq!(header(&response, "x-fily-part-layout"), Some("0-10,11-22,23-33"));
+    assert!(response.ends_with("\r\n\r\nfirst line\nsecond line\nthird line\n"), "{}", response);
+
+    // Every source is a part of the composed object
+    let response = send_with_query(
+        addr,
+        Method::GET,
+        "/logs/day.log",
+        vec![("partNumber".to_string(), "3".to_string())],
+        &[],
+        b"",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nthird line\n"), "{}", response);
+
+    let missing = br#"{"sources": [{"key": "00.log"}, {"key": "missing.log"}]}"#;
+    let response = send(addr, Method::POST, "/_fily/compose/logs/broken.log", &[], missing).await;
+    assert!(response.contains("NoSuchKey"), "{}", response);
+    let response = send(addr, Method::GET, "/logs/broken.log", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    let bad_range = br#"{"sources": [{"key": "00.log", "range": "bytes=100-"}]}"#;
+    let response = send(addr, Method::POST, "/_fily/compose/logs/broken.log", &[], bad_range).await;
+    assert!(response.contains("InvalidArgument"), "{}", response);
+
+    let response = send(addr, Method::POST, "/_fily/compose/logs/broken.log", &[], br#"{"sources": []}"#).await;
+    assert!(response.contains("InvalidArgument"), "{}", response);
+
+    stop.await;
+}
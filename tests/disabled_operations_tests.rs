@@ -0,0 +1,157 @@
+use axum::http::Method;
+use base64::{engine::general_purpose, Engine as _};
+use fily::fily::batch::BatchConfig;
+use fily::fily::operation::DisabledOperations;
+use fily::fily::resumable_upload::ResumableUploadConfig;
+use fily::fily::webdav::{WebDavConfig, WebDavUser};
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{ACCESS_KEY_ID, header, send, send_raw, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_disabled_operations_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = create_test_config(temp_dir.path().to_str().unwrap());
+    config.disabled_operations = DisabledOperations {
+        global: vec!["DeleteBucket".to_string()],
+        buckets: [("archive".to_string(), vec!["PutObject".to_string(), "DeleteObject".to_string()])].into(),
+    };
+    let (addr, stop) = serve(config).await;
+
+    for bucket in ["/logs", "/archive"] {
+        let response = send(addr, Method::PUT, bucket, &[], b"").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+    let response = send(addr, Method::DELETE, "/logs", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    assert!(response.contains("<Code>MethodNotAllowed</Code>"), "{}", response);
+    assert!(response.contains("DeleteBucket is disabled"), "{}", response);
+
+    // Operations disabled for one bucket still work on the others
+    let response = send(addr, Method::PUT, "/archive/a.log", &[], b"archived").await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    let response = send(addr, Method::PUT, "/logs/a.log", &[], b"logged").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = send(addr, Method::DELETE, "/logs/a.log", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    let response = send(addr, Method::GET, "/archive", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    stop.await;
+}
+
+/// Writes to `archive` refused, everything else allowed
+fn archive_read_only() -> DisabledOperations {
+    DisabledOperations {
+        global: vec!["CreateBucket".to_string(), "DeleteBucket".to_string()],
+        buckets: [(
+            "archive".to_string(),
+            vec!["PutObject".to_string(), "GetObject".to_string(), "DeleteObject".to_string()],
+        )]
+        .into(),
+    }
+}
+
+#[tokio::test]
+async fn test_disabled_operations_refused_through_webdav() {
+    let temp_dir = TempDir::new().unwrap();
+    for bucket in ["logs", "archive"] {
+        std::fs::create_dir(temp_dir.path().join(bucket)).unwrap();
+    }
+    std::fs::write(temp_dir.path().join("archive/a.log"), b"archived").unwrap();
+    let mut config = create_test_config(temp_dir.path().to_str().unwrap());
+    config.disabled_operations = archive_read_only();
+    config.webdav = WebDavConfig {
+        enabled: true,
+        users: vec![WebDavUser {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+            access_key_id: ACCESS_KEY_ID.to_string(),
+        }],
+    };
+    let (addr, stop) = serve(config).await;
+    let authorization = format!("Basic {}", general_purpose::STANDARD.encode("alice:correct horse"));
+    let headers = [("authorization", authorization.as_str())];
+
+    // Each WebDAV method is refused as the S3 operation it performs
+    for (method, path, operation) in [
+        ("MKCOL", "/_fily/dav/photos/", "CreateBucket"),
+        ("DELETE", "/_fily/dav/logs/", "DeleteBucket"),
+        ("PUT", "/_fily/dav/archive/b.log", "PutObject"),
+        ("GET", "/_fily/dav/archive/a.log", "GetObject"),
+        ("DELETE", "/_fily/dav/archive/a.log", "DeleteObject"),
+    ] {
+        let body: &[u8] = if method == "PUT" { b"data" } else { b"" };
+        let response = send_raw(addr, method, path, &headers, body).await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{} {}: {}", method, path, response);
+        assert!(response.contains(&format!("{} is disabled", operation)), "{}", response);
+    }
+    assert!(temp_dir.path().join("logs").is_dir());
+    assert!(temp_dir.path().join("archive/a.log").is_file());
+
+    let response = send_raw(addr, "PUT", "/_fily/dav/logs/b.log", &headers, b"logged").await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+
+    stop.await;
+}
+
+#[tokio::test]
+async fn test_disabled_put_refuses_batches_and_resumable_uploads() {
+    let temp_dir = TempDir::new().unwrap();
+    let location = temp_dir.path().to_str().unwrap();
+    let config = || Config {
+        resumable_uploads: ResumableUploadConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        batch: BatchConfig {
+            enabled: true,
+            max_objects: 10,
+            max_object_size: 16,
+        },
+        ..create_test_config(location)
+    };
+
+    // An upload started before PutObject was disabled for the bucket
+    let (addr, stop) = serve(config()).await;
+    let response = send(addr, Method::PUT, "/archive", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = send(addr, Method::POST, "/_fily/uploads/archive/a.log", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    let upload = format!("/_fily/uploads/archive/a.log/{}", header(&response, "x-fily-upload-id").unwrap());
+    let response = send(addr, Method::PATCH, &upload, &[("x-fily-upload-offset", "0")], b"archived").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    stop.await;
+
+    let (addr, stop) = serve(Config {
+        disabled_operations: archive_read_only(),
+        ..config()
+    })
+    .await;
+    let response = send(addr, Method::POST, &upload, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    assert!(response.contains("PutObject is disabled"), "{}", response);
+    assert!(!temp_dir.path().join("archive/a.log").exists());
+    let response = send(addr, Method::POST, "/_fily/uploads/archive/b.log", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+
+    let request = serde_json::json!({"key": "c.log", "data": general_purpose::STANDARD.encode(b"batched")});
+    let response = send(addr, Method::POST, "/_fily/batch/put/archive", &[], request.to_string().as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    assert!(!temp_dir.path().join("archive/c.log").exists());
+    let response = send(addr, Method::POST, "/_fily/batch/get/archive", &[], b"{\"key\": \"a.log\"}").await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    assert!(response.contains("GetObject is disabled"), "{}", response);
+
+    stop.await;
+}
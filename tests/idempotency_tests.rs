@@ -0,0 +1,51 @@
+use axum::http::Method;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{header, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_put_retries_with_token_are_replayed() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/uploads", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let token = [("x-fily-idempotency-token", "upload-42"), ("content-type", "text/plain")];
+    let first = send(addr, Method::PUT, "/uploads/report.txt", &token, b"first").await;
+    assert!(first.starts_with("HTTP/1.1 200"), "{}", first);
+    assert_eq!(header(&first, "x-fily-idempotent-replay"), None);
+
+    // Another writer replaces the object; a retry of the first PUT must not write it back
+    let response = send(addr, Method::PUT, "/uploads/report.txt", &[], b"second").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let retry = send(addr, Method::PUT, "/uploads/report.txt", &token, b"first").await;
+    assert!(retry.starts_with("HTTP/1.1 200"), "{}", retry);
+    assert_eq!(header(&retry, "x-fily-idempotent-replay"), Some("true"));
+    assert_eq!(header(&retry, "etag"), header(&first, "etag"));
+    assert_eq!(header(&retry, "content-type"), Some("text/plain"));
+    let response = send(addr, Method::GET, "/uploads/report.txt", &[], b"").await;
+    assert!(response.ends_with("\r\n\r\nsecond"), "{}", response);
+
+    let response = send(addr, Method::PUT, "/uploads/other.txt", &token, b"first").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert!(response.contains("InvalidRequest"), "{}", response);
+    let response = send(addr, Method::GET, "/uploads/other.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    let bad_token = [("x-fily-idempotency-token", "")];
+    let response = send(addr, Method::PUT, "/uploads/report.txt", &bad_token, b"third").await;
+    assert!(response.contains("InvalidArgument"), "{}", response);
+
+    stop.await;
+}
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use axum::http::Method;
+use fily::fily::etag::generate_etag;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{body, encryption, header, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_concurrent_puts_keep_data_and_metadata_together() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir(temp_dir.path().join("photos")).unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let bodies: Vec<Vec<u8>> = (0..16).map(|i| format!("version {}", i).repeat(1000 + i).into_bytes()).collect();
+    let puts = bodies.iter().cloned().map(|body| {
+        tokio::spawn(async move { send(addr, Method::PUT, "/photos/a.jpg", &[], &body).await })
+    });
+    for put in puts.collect::<Vec<_>>() {
+        let response = put.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    // Whichever write won, its data is served with its own ETag and passes verification
+    let response = send(addr, Method::GET, "/photos/a.jpg", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let (_, data) = response.split_once("\r\n\r\n").unwrap();
+    assert!(bodies.iter().any(|body| body == data.as_bytes()));
+    assert_eq!(header(&response, "etag"), Some(generate_etag(data.as_bytes()).as_str()));
+
+    stop.await;
+}
+
+#[tokio::test]
+async fn test_gets_during_puts_return_the_etag_of_their_body() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir(temp_dir.path().join("photos")).unwrap();
+    // Unencrypted, so nothing but the key lock ties the data read to its metadata
+    let (addr, stop) = serve(test_config(temp_dir.path().to_str().unwrap())).await;
+    let response = send(addr, Method::PUT, "/photos/b.jpg", &[], b"initial").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // Readers keep reading for as long as the writers are writing
+    let writing = Arc::new(AtomicBool::new(true));
+    let gets: Vec<_> = (0..8)
+        .map(|_| {
+            let writing = writing.clone();
+            tokio::spawn(async move {
+                let mut responses = Vec::new();
+                while writing.load(Ordering::Relaxed) {
+                    responses.push(send(addr, Method::GET, "/photos/b.jpg", &[], b"").await);
+                }
+                responses
+            })
+        })
+        .collect();
+    let puts: Vec<_> = (0..16)
+        .map(|i| {
+            let body = format!("version {}", i).repeat(1000 + i).into_bytes();
+            tokio::spawn(async move { send(addr, Method::PUT, "/photos/b.jpg", &[], &body).await })
+        })
+        .collect();
+    for put in puts {
+        let response = put.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+    writing.store(false, Ordering::Relaxed);
+
+    for get in gets {
+        for response in get.await.unwrap() {
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            let data = body(&response);
+            assert_eq!(header(&response, "etag"), Some(generate_etag(data.as_bytes()).as_str()));
+        }
+    }
+
+    stop.await;
+}
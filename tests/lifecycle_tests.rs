@@ -0,0 +1,129 @@
+use fily::fily::timeouts::TimeoutConfig;
+use fily::fily::{Config, Server};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        location: location.to_string(),
+        address: "127.0.0.1".to_string(),
+        port: "0".to_string(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_embedded_server_shuts_down_on_request() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::init(create_test_config(temp_dir.path().to_str().unwrap()))
+        .await
+        .unwrap()
+        .without_signal_handlers();
+    let shutdown = server.shutdown_handle();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let running = tokio::spawn(server.serve_with_listener(listener));
+
+    // The server accepts connections until it is asked to stop
+    tokio::net::TcpStream::connect(addr).await.unwrap();
+
+    shutdown.shutdown();
+    let result = tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .expect("server did not shut down");
+    assert!(result.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_init_rejects_unusable_storage() {
+    let temp_dir = TempDir::new().unwrap();
+    let file = temp_dir.path().join("not-a-directory");
+    std::fs::write(&file, b"data").unwrap();
+
+    let result = Server::init(create_test_config(file.to_str().unwrap())).await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_stalled_clients_are_timed_out() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = create_test_config(temp_dir.path().to_str().unwrap());
+    config.timeouts = TimeoutConfig {
+        request: None,
+        header_read: Some(Duration::from_millis(200)),
+        body_idle: Some(Duration::from_millis(200)),
+    };
+    let server = Server::init(config).await.unwrap().without_signal_handlers();
+    let shutdown = server.shutdown_handle();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let running = tokio::spawn(server.serve_with_listener(listener));
+
+    // Headers that never finish: the connection is closed without a response
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("header read timeout was not enforced")
+        .ok();
+    assert!(response.is_empty());
+
+    // An upload that stops sending its body gets RequestTimeout
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"PUT /bucket/key HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\npartial")
+        .await
+        .unwrap();
+    let mut response = vec![0u8; 4096];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut response))
+        .await
+        .expect("body idle timeout was not enforced")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert!(response.contains("RequestTimeout"), "{}", response);
+
+    shutdown.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_self_test_detects_changed_master_key() {
+    use fily::fily::self_test::{SelfTestConfig, SelfTestFailureMode};
+    use fily::fily::EncryptionConfig;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = |key: &str, on_failure| {
+        let mut config = create_test_config(temp_dir.path().to_str().unwrap());
+        config.encryption = Some(EncryptionConfig {
+            enabled: true,
+            master_key: Some(key.to_string()),
+        });
+        config.self_test = SelfTestConfig {
+            enabled: true,
+            on_failure,
+            ntp_server: None,
+            ..Default::default()
+        };
+        config
+    };
+    let key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+    let other_key = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+
+    // The first start seals the key canary, later starts with the same key open it
+    assert!(Server::init(config(key, SelfTestFailureMode::Refuse)).await.is_ok());
+    assert!(temp_dir.path().join(".fily-system/encryption-canary").is_file());
+    assert!(Server::init(config(key, SelfTestFailureMode::Refuse)).await.is_ok());
+
+    let err = Server::init(config(other_key, SelfTestFailureMode::Refuse)).await.err().unwrap();
+    assert!(err.to_string().contains("encryption"), "{}", err);
+    assert!(Server::init(config(other_key, SelfTestFailureMode::Warn)).await.is_ok());
+
+    // The storage canary is cleaned up and the system directory is not a bucket
+    let entries = std::fs::read_dir(temp_dir.path().join(".fily-system")).unwrap().count();
+    assert_eq!(entries, 1);
+}
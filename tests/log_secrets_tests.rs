@@ -0,0 +1,147 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use axum::http::Method;
+use chrono::Utc;
+use fily::fily::auth::{AwsCredentials, AwsSignatureV4Validator, PresignParams};
+use fily::fily::manifest::ManifestConfig;
+use fily::fily::notifications::NotificationConfig;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{ACCESS_KEY_ID, MASTER_KEY, SECRET_ACCESS_KEY, encryption, send_raw, serve, test_config};
+
+const SYNC_SECRET_ACCESS_KEY: &str = "je7MtGbClwBF/2Zp9Utk/h3yCo8nvbEXAMPLEKEY";
+const WEBHOOK_TOKEN: &str = "T0000000/B0000000/WEBHOOKTOKENEXAMPLE";
+const MANIFEST_SIGNING_KEY: &str = "manifest-signing-key-EXAMPLEKEY-0123";
+
+/// Collects every formatted log line
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        log_level: "trace".to_string(),
+        encryption: encryption(),
+        sync: vec![serde_json::from_value(serde_json::json!({
+            "endpoint": "http://127.0.0.1:9",
+            "bucket": "mirror",
+            "access_key_id": "AKIAI44QH8DHBEXAMPLE",
+            "secret_access_key": SYNC_SECRET_ACCESS_KEY,
+            "interval_secs": 3600,
+        }))
+        .unwrap()],
+        buckets: vec![serde_json::from_value(serde_json::json!({ "name": "mirror" })).unwrap()],
+        manifest: ManifestConfig {
+            signing_key: Some(MANIFEST_SIGNING_KEY.to_string()),
+        },
+        notifications: NotificationConfig {
+            webhook_url: Some(format!("http://127.0.0.1:9/services/{}", WEBHOOK_TOKEN)),
+            events: vec![],
+        },
+        ..test_config(location)
+    }
+}
+
+/// Runs signed, forged and encrypted requests with every log level enabled and fails if a
+/// configured secret shows up in any log line
+#[tokio::test]
+async fn test_logs_never_contain_configured_secrets() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .init();
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = create_test_config(temp_dir.path().to_str().unwrap());
+    tracing::debug!("effective config {:?}", config);
+    let (addr, stop) = serve(config).await;
+
+    let mut validator = AwsSignatureV4Validator::new();
+    let credentials = AwsCredentials::new(
+        ACCESS_KEY_ID.to_string(),
+        SECRET_ACCESS_KEY.to_string(),
+        "us-east-1".to_string(),
+    )
+    .unwrap();
+    tracing::debug!("client credentials {:?}", credentials);
+    validator.add_credentials(ACCESS_KEY_ID.to_string(), credentials).unwrap();
+    let endpoint = url::Url::parse(&format!("http://{}", addr)).unwrap();
+    let presign = |method: Method, path: &str| {
+        let url = validator
+            .generate_presigned_url(&PresignParams {
+                access_key_id: ACCESS_KEY_ID,
+                method: &method,
+                endpoint: &endpoint,
+                path,
+                expires_in: 600,
+                extra_params: vec![],
+                scope: None,
+                now: Utc::now(),
+            })
+            .unwrap();
+        url.strip_prefix(endpoint.as_str().trim_end_matches('/')).unwrap().to_string()
+    };
+
+    let response = send_raw(addr, "PUT", &presign(Method::PUT, "/photos"), &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = send_raw(addr, "PUT", &presign(Method::PUT, "/photos/a.txt"), &[], b"encrypted at rest").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = send_raw(addr, "GET", &presign(Method::GET, "/photos/a.txt"), &[], b"").await;
+    assert!(response.ends_with("encrypted at rest"), "{}", response);
+    let response = send_raw(addr, "GET", &presign(Method::GET, "/_fily/admin/manifests/photos"), &[], b"").await;
+    assert!(response.contains("\"signature\""), "{}", response);
+
+    // A forged signature walks through the whole verification path before being refused
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        ACCESS_KEY_ID,
+        &amz_date[..8],
+        "0".repeat(64)
+    );
+    let response = send_raw(
+        addr,
+        "GET",
+        "/photos/a.txt",
+        &[
+            ("Authorization", &authorization),
+            ("x-amz-date", &amz_date),
+            ("x-amz-content-sha256", "UNSIGNED-PAYLOAD"),
+        ],
+        b"",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+    stop.await;
+
+    let logs = logs.contents();
+    assert!(logs.contains("Signature verification completed successfully"), "debug logs were not captured");
+    for secret in [SECRET_ACCESS_KEY, SYNC_SECRET_ACCESS_KEY, MASTER_KEY, MANIFEST_SIGNING_KEY, WEBHOOK_TOKEN] {
+        let leaked: Vec<&str> = logs.lines().filter(|line| line.contains(secret)).collect();
+        assert!(leaked.is_empty(), "secret leaked into logs: {:#?}", leaked);
+    }
+}
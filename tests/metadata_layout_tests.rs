@@ -0,0 +1,57 @@
+use axum::http::Method;
+use fily::fily::path_security::MetadataLayout;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{header, send, serve, test_config};
+
+/// Serves the store until the returned future is awaited
+async fn start(
+    location: &std::path::Path,
+    metadata_layout: MetadataLayout,
+) -> (std::net::SocketAddr, impl std::future::Future<Output = ()>) {
+    let config = Config {
+        metadata_layout,
+        ..test_config(location.to_str().unwrap())
+    };
+    serve(config).await
+}
+
+#[tokio::test]
+async fn test_metadata_moves_out_of_the_buckets() {
+    let temp_dir = TempDir::new().unwrap();
+    let storage = temp_dir.path().join("data");
+    let metadata_root = temp_dir.path().join("metadata");
+    std::fs::create_dir_all(storage.join("photos")).unwrap();
+    std::fs::create_dir_all(storage.join("docs")).unwrap();
+
+    let (addr, stop) = start(&storage, MetadataLayout::default()).await;
+    let response = send(addr, Method::PUT, "/photos/a.txt", &[("content-type", "text/csv")], b"hello").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    stop.await;
+    assert!(storage.join("photos/.fily-metadata/a.txt.json").exists());
+
+    // Restarting with a metadata root moves the existing metadata there
+    let (addr, stop) = start(&storage, MetadataLayout::External(metadata_root.clone())).await;
+    assert!(!storage.join("photos/.fily-metadata").exists());
+    assert!(metadata_root.join("photos/a.txt.json").exists());
+    let response = send(addr, Method::GET, "/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(header(&response, "content-type"), Some("text/csv"));
+
+    // The old metadata directory name is free for keys now
+    let response = send(addr, Method::PUT, "/docs/.fily-metadata%2Fnotes.txt", &[], b"mine").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = send(addr, Method::GET, "/docs", &[], b"").await;
+    assert!(response.contains("<Key>.fily-metadata/notes.txt</Key>"), "{}", response);
+    assert!(metadata_root.join("docs/.fily-metadata_notes.txt.json").exists());
+
+    // Deleting the bucket takes the metadata kept for it along
+    send(addr, Method::DELETE, "/photos/a.txt", &[], b"").await;
+    let response = send(addr, Method::DELETE, "/photos", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert!(!metadata_root.join("photos").exists());
+    stop.await;
+}
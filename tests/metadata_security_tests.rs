@@ -17,6 +17,14 @@ async fn test_metadata_path_injection_protection() {
         last_modified: "Mon, 01 Jan 2024 00:00:00 GMT".to_string(),
         user_metadata,
         content_sha256: Some("abc123def456".to_string()),
+        checksum_algorithm: None,
+        checksum_value: None,
+        tags: HashMap::new(),
+        part_sizes: vec![],
+        encryption_id: None,
+        created_by: None,
+        created_at: None,
+        modified_by: None,
     };
 
     // Test that path traversal attempts in object names are rejected
@@ -61,6 +69,14 @@ async fn test_metadata_bucket_name_injection_protection() {
         last_modified: "Mon, 01 Jan 2024 00:00:00 GMT".to_string(),
         user_metadata,
         content_sha256: Some("abc123def456".to_string()),
+        checksum_algorithm: None,
+        checksum_value: None,
+        tags: HashMap::new(),
+        part_sizes: vec![],
+        encryption_id: None,
+        created_by: None,
+        created_at: None,
+        modified_by: None,
     };
 
     // Test that path traversal attempts in bucket names are rejected
@@ -105,6 +121,14 @@ async fn test_metadata_secure_path_construction() {
         last_modified: "Tue, 02 Jan 2024 12:00:00 GMT".to_string(),
         user_metadata,
         content_sha256: Some("def456abc123".to_string()),
+        checksum_algorithm: None,
+        checksum_value: None,
+        tags: HashMap::new(),
+        part_sizes: vec![],
+        encryption_id: None,
+        created_by: None,
+        created_at: None,
+        modified_by: None,
     };
 
     // Test that valid names work correctly
@@ -156,6 +180,14 @@ async fn test_metadata_file_isolation() {
         last_modified: "Wed, 03 Jan 2024 18:30:00 GMT".to_string(),
         user_metadata,
         content_sha256: Some("ghi789abc123".to_string()),
+        checksum_algorithm: None,
+        checksum_value: None,
+        tags: HashMap::new(),
+        part_sizes: vec![],
+        encryption_id: None,
+        created_by: None,
+        created_at: None,
+        modified_by: None,
     };
 
     // Create metadata for a legitimate file
@@ -0,0 +1,80 @@
+#![cfg(feature = "client")]
+
+use bytes::Bytes;
+use chrono::Utc;
+use fily::fily::client::{self, Credentials, EMPTY_PAYLOAD_SHA256};
+use fily::fily::mfa_delete::{MfaDeleteConfig, MFA_HEADER};
+use fily::fily::Config;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::{Request, StatusCode};
+use hyper_util::client::legacy::Client as HttpClient;
+use hyper_util::rt::TokioExecutor;
+use sha1::Sha1;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{ACCESS_KEY_ID, SECRET_ACCESS_KEY, serve, test_config};
+
+// Base32 of the RFC 6238 test secret "12345678901234567890"
+const MFA_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+fn create_test_config(location: &str, mfa_delete: MfaDeleteConfig) -> Config {
+    Config {
+        mfa_delete,
+        ..test_config(location)
+    }
+}
+
+/// Current six-digit code of the RFC 6238 test secret
+fn current_code() -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(b"12345678901234567890").unwrap();
+    mac.update(&((Utc::now().timestamp() as u64) / 30).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0x0f) as usize;
+    let code = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:06}", code % 1_000_000)
+}
+
+/// Signed DELETE of `path`, with an `x-amz-mfa` code when given
+async fn delete(endpoint: &str, path: &str, code: Option<&str>) -> StatusCode {
+    let mut request = Request::delete(format!("{}{}", endpoint, path));
+    if let Some(code) = code {
+        request = request.header(MFA_HEADER, format!("arn:aws:iam::123456789012:mfa/ops {}", code));
+    }
+    let mut request = request.body(Full::new(Bytes::new())).unwrap();
+    let credentials = Credentials {
+        access_key_id: ACCESS_KEY_ID.to_string(),
+        secret_access_key: SECRET_ACCESS_KEY.to_string(),
+        region: "us-east-1".to_string(),
+    };
+    client::sign(&mut request, &credentials, EMPTY_PAYLOAD_SHA256, Utc::now()).unwrap();
+    let http: HttpClient<_, Full<Bytes>> = HttpClient::builder(TokioExecutor::new()).build_http();
+    http.request(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn test_bucket_delete_needs_mfa_code() {
+    let dir = TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("photos")).unwrap();
+    std::fs::create_dir_all(dir.path().join("scratch")).unwrap();
+    std::fs::write(dir.path().join("photos/a.jpg"), b"a").unwrap();
+    let mfa_delete = MfaDeleteConfig {
+        secret: Some(MFA_SECRET.to_string()),
+        objects: false,
+    };
+    let (addr, stop) = serve(create_test_config(dir.path().to_str().unwrap(), mfa_delete)).await;
+    let endpoint = format!("http://{}", addr);
+
+    assert_eq!(delete(&endpoint, "/scratch", None).await, StatusCode::FORBIDDEN);
+    assert_eq!(delete(&endpoint, "/photos?prefix=", None).await, StatusCode::FORBIDDEN);
+    assert!(dir.path().join("photos/a.jpg").exists());
+
+    // Object deletes are only protected with FILY_MFA_DELETE_OBJECTS
+    assert_eq!(delete(&endpoint, "/photos/a.jpg", None).await, StatusCode::NO_CONTENT);
+    assert_eq!(delete(&endpoint, "/scratch", Some(&current_code())).await, StatusCode::NO_CONTENT);
+    assert!(!dir.path().join("scratch").exists());
+
+    stop.await;
+}
@@ -12,14 +12,10 @@ fn create_test_config() -> Arc<Config> {
     Arc::new(Config {
         location: "./test_data".to_string(),
         address: "127.0.0.1".to_string(),
-        port: "8333".to_string(),
-        log_level: "info".to_string(),
-        aws_credentials: vec![],
-        encryption: None,
+        ..Default::default()
     })
 }
 
-
 #[tokio::test]
 async fn test_auth_middleware_creation() {
     let validator = Arc::new(AwsSignatureV4Validator::new());
@@ -134,7 +130,7 @@ fn test_s3_error_code_mappings() {
     
     for code in s3_error_codes {
         assert!(!code.is_empty());
-        assert!(code.chars().all(|c| c.is_ascii()));
+        assert!(code.is_ascii());
     }
 }
 
@@ -0,0 +1,95 @@
+use chrono::Utc;
+use fily::fily::auth_lockout::AuthLockoutConfig;
+use fily::fily::notifications::{EventKind, NotificationConfig};
+use fily::fily::Config;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+
+use common::{ACCESS_KEY_ID, send_raw, serve, test_config};
+
+fn create_test_config(location: &str, webhook_url: String) -> Config {
+    Config {
+        auth_lockout: AuthLockoutConfig {
+            enabled: true,
+            ip_threshold: 2,
+            ..Default::default()
+        },
+        notifications: NotificationConfig {
+            webhook_url: Some(webhook_url),
+            events: vec![EventKind::AuthLockout],
+        },
+        ..test_config(location)
+    }
+}
+
+/// Sends a request with a well-formed but forged signature
+async fn send_forged(addr: std::net::SocketAddr) -> String {
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        ACCESS_KEY_ID,
+        &amz_date[..8],
+        "0".repeat(64)
+    );
+    let headers = [
+        ("Authorization", authorization.as_str()),
+        ("x-amz-date", amz_date.as_str()),
+        ("x-amz-content-sha256", "UNSIGNED-PAYLOAD"),
+    ];
+    send_raw(addr, "GET", "/photos/a.txt", &headers, b"").await
+}
+
+/// Accepts one webhook call, answers 204 and returns its request line and JSON body
+async fn receive_webhook(listener: &tokio::net::TcpListener) -> (String, serde_json::Value) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        let Some((head, body)) = text.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(str::to_string))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0);
+        if body.len() >= length {
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            let request_line = head.lines().next().unwrap_or_default().to_string();
+            return (request_line, serde_json::from_str(body).unwrap());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_auth_lockout_is_posted_to_webhook() {
+    let webhook = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook_url = format!("http://{}/services/fily", webhook.local_addr().unwrap());
+
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap(), webhook_url)).await;
+
+    for _ in 0..2 {
+        let response = send_forged(addr).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    }
+    let response = send_forged(addr).await;
+    assert!(response.contains("SlowDown"), "{}", response);
+
+    let (request_line, event) = tokio::time::timeout(std::time::Duration::from_secs(10), receive_webhook(&webhook))
+        .await
+        .expect("no webhook call");
+    assert_eq!(request_line, "POST /services/fily HTTP/1.1");
+    assert_eq!(event["event"], "auth_lockout");
+    assert_eq!(event["severity"], "warning");
+    assert_eq!(event["details"]["kind"], "ip");
+    assert_eq!(event["details"]["subject"], "127.0.0.1");
+    assert!(event["text"].as_str().unwrap().contains("source IP 127.0.0.1 locked out"), "{}", event);
+
+    stop.await;
+}
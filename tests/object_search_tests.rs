@@ -0,0 +1,80 @@
+use axum::http::Method;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, send, send_with_query, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+fn search_body(response: &str) -> serde_json::Value {
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+}
+
+fn keys(result: &serde_json::Value) -> Vec<&str> {
+    result["objects"].as_array().unwrap().iter().map(|o| o["key"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn test_search_filters_and_pages() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/photos", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let jpeg = ("content-type", "image/jpeg");
+    // Keys are one path segment, with their slashes encoded
+    send(addr, Method::PUT, "/photos/2024%2Fa.jpg", &[jpeg, ("x-amz-tagging", "album=summer")], b"0123456789").await;
+    send(addr, Method::PUT, "/photos/2024%2Fb.jpg", &[jpeg], b"01234").await;
+    send(addr, Method::PUT, "/photos/2024%2Fnotes.txt", &[("content-type", "text/plain")], b"hello").await;
+    send(addr, Method::PUT, "/photos/2023%2Fc.jpg", &[jpeg, ("x-amz-tagging", "album=summer")], b"01").await;
+
+    let search = |query: &[(&str, &str)]| {
+        let query = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        send_with_query(addr, Method::GET, "/_fily/search/photos", query, &[], b"")
+    };
+
+    let result = search_body(&search(&[("prefix", "2024/"), ("content-type", "image/*")]).await);
+    assert_eq!(keys(&result), ["2024/a.jpg", "2024/b.jpg"]);
+    assert_eq!(result["objects"][0]["size"], 10);
+    assert_eq!(result["is_truncated"], false);
+
+    let result = search_body(&search(&[("tag", "album=summer"), ("min-size", "5")]).await);
+    assert_eq!(keys(&result), ["2024/a.jpg"]);
+    assert_eq!(result["objects"][0]["tags"]["album"], "summer");
+
+    let result = search_body(&search(&[("key-regex", "^20\\d{2}/[a-c]\\.jpg$"), ("max-size", "5")]).await);
+    assert_eq!(keys(&result), ["2023/c.jpg", "2024/b.jpg"]);
+
+    // Pages resume from the token with the same filters
+    let mut found = vec![];
+    let mut token: Option<String> = None;
+    loop {
+        let mut query = vec![("suffix", ".jpg"), ("max-keys", "1")];
+        if let Some(token) = &token {
+            query.push(("continuation-token", token.as_str()));
+        }
+        let result = search_body(&search(&query).await);
+        found.extend(keys(&result).into_iter().map(str::to_string));
+        match result["next_continuation_token"].as_str() {
+            Some(next) => token = Some(next.to_string()),
+            None => break,
+        }
+    }
+    assert_eq!(found, ["2023/c.jpg", "2024/a.jpg", "2024/b.jpg"]);
+
+    let response = search(&[("key-regex", "(")]).await;
+    assert!(response.contains("InvalidArgument"), "{}", response);
+    let response = send(addr, Method::GET, "/_fily/search/missing", &[], b"").await;
+    assert!(response.contains("NoSuchBucket"), "{}", response);
+
+    stop.await;
+}
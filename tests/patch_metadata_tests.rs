@@ -0,0 +1,53 @@
+use axum::http::Method;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, header, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_patch_changes_tags_and_metadata_in_place() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/assets", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let headers = [("x-amz-tagging", "label=draft&team=dam"), ("x-amz-meta-owner", "ops")];
+    let put = send(addr, Method::PUT, "/assets/photos%2Fa.jpg", &headers, b"jpeg data").await;
+    assert!(put.starts_with("HTTP/1.1 200"), "{}", put);
+
+    let patch = br#"{"tags": {"label": "final", "team": null}, "metadata": {"Reviewer": "kim"}}"#;
+    let response = send(addr, Method::PATCH, "/_fily/metadata/assets/photos%2Fa.jpg", &[], patch).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let result: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(result["key"], "photos/a.jpg");
+    assert_eq!(result["tags"], serde_json::json!({"label": "final"}));
+    assert_eq!(result["metadata"], serde_json::json!({"owner": "ops", "reviewer": "kim"}));
+    assert_eq!(Some(result["etag"].as_str().unwrap()), header(&put, "etag"));
+
+    // The patch was saved, and the data is untouched
+    let response = send(addr, Method::PATCH, "/_fily/metadata/assets/photos%2Fa.jpg", &[], b"{}").await;
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let result: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(result["tags"], serde_json::json!({"label": "final"}));
+    let response = send(addr, Method::GET, "/assets/photos%2Fa.jpg", &[], b"").await;
+    assert!(response.ends_with("\r\n\r\njpeg data"), "{}", response);
+    assert_eq!(header(&response, "etag"), header(&put, "etag"));
+
+    let response = send(addr, Method::PATCH, "/_fily/metadata/assets/missing.jpg", &[], b"{}").await;
+    assert!(response.contains("NoSuchKey"), "{}", response);
+    let response = send(addr, Method::PATCH, "/_fily/metadata/assets/photos%2Fa.jpg", &[], b"{\"tags\": 1}").await;
+    assert!(response.contains("InvalidArgument"), "{}", response);
+
+    stop.await;
+}
@@ -0,0 +1,64 @@
+use axum::http::Method;
+use fily::fily::replica::ReplicaConfig;
+use fily::fily::Config;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{body, header, send, serve, test_config};
+
+/// Serves the store until the returned future is awaited
+async fn start(
+    location: &std::path::Path,
+    replica: ReplicaConfig,
+) -> (std::net::SocketAddr, impl std::future::Future<Output = ()>) {
+    let config = Config {
+        replica,
+        ..test_config(location.to_str().unwrap())
+    };
+    serve(config).await
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_replica_serves_reads_of_a_shared_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let storage = temp_dir.path().join("data");
+    std::fs::create_dir_all(storage.join("photos")).unwrap();
+    // A second path to the same directory, standing in for the replica's mount of the store
+    let mount = temp_dir.path().join("mount");
+    std::os::unix::fs::symlink(&storage, &mount).unwrap();
+
+    let (primary, stop_primary) = start(&storage, ReplicaConfig::default()).await;
+    let response = send(primary, Method::PUT, "/photos/a.txt", &[("content-type", "text/plain")], b"one").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let replica_config = ReplicaConfig {
+        enabled: true,
+        max_staleness: Duration::from_millis(200),
+    };
+    let (replica, stop_replica) = start(&mount, replica_config).await;
+    let response = send(replica, Method::GET, "/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(body(&response), "one");
+    assert_eq!(header(&response, "content-type"), Some("text/plain"));
+
+    // Writes are refused and leave the store untouched
+    let response = send(replica, Method::PUT, "/photos/b.txt", &[], b"two").await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    let response = send(replica, Method::DELETE, "/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    assert!(!storage.join("photos/b.txt").exists());
+    assert!(storage.join("photos/a.txt").exists());
+
+    // Changes made by the primary show up once the cached metadata is revalidated
+    let response = send(primary, Method::PUT, "/photos/a.txt", &[("content-type", "text/csv")], b"one").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let response = send(replica, Method::GET, "/photos/a.txt", &[], b"").await;
+    assert_eq!(header(&response, "content-type"), Some("text/csv"));
+
+    stop_replica.await;
+    stop_primary.await;
+}
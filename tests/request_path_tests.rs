@@ -0,0 +1,42 @@
+use axum::http::Method;
+use fily::fily::bootstrap::BucketDeclaration;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{encryption, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        buckets: vec![BucketDeclaration {
+            name: "archive".to_string(),
+            owner: None,
+            grants: None,
+            object_ownership: None,
+            public: None,
+            read_only: true,
+            versioning: None,
+            quota: None,
+            lifecycle: None,
+        }],
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_encoded_bucket_names_reach_bucket_checks() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/archive/a.txt", &[], b"x").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    // The handler would decode `%61rchive` to `archive`, so the read-only check must too
+    let response = send(addr, Method::PUT, "/%61rchive/a.txt", &[], b"x").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    assert!(!temp_dir.path().join("archive/a.txt").exists());
+
+    stop.await;
+}
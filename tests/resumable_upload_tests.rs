@@ -0,0 +1,95 @@
+use axum::http::Method;
+use fily::fily::resumable_upload::ResumableUploadConfig;
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{header, send, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        resumable_uploads: ResumableUploadConfig {
+            enabled: true,
+            max_size: 16,
+            ..Default::default()
+        },
+        ..test_config(location)
+    }
+}
+
+#[tokio::test]
+async fn test_resumable_upload_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    let response = send(addr, Method::PUT, "/photos", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let response = send(addr, Method::POST, "/_fily/uploads/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    assert_eq!(header(&response, "x-fily-upload-offset"), Some("0"));
+    let upload = format!("/_fily/uploads/photos/a.txt/{}", header(&response, "x-fily-upload-id").unwrap());
+
+    let response = send(addr, Method::PATCH, &upload, &[("x-fily-upload-offset", "0")], b"hello ").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert_eq!(header(&response, "x-fily-upload-offset"), Some("6"));
+
+    // A retried append the server already received is refused instead of duplicating data
+    let response = send(addr, Method::PATCH, &upload, &[("x-fily-upload-offset", "0")], b"hello ").await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+    assert!(response.contains("OperationAborted"), "{}", response);
+
+    let response = send(addr, Method::HEAD, &upload, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(header(&response, "x-fily-upload-offset"), Some("6"));
+
+    let response = send(addr, Method::PATCH, &upload, &[("x-fily-upload-offset", "6")], b"world, and more").await;
+    assert!(response.contains("EntityTooLarge"), "{}", response);
+    let response = send(addr, Method::PATCH, &upload, &[("x-fily-upload-offset", "6")], b"world").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+
+    // Nothing is visible under the key until the upload is committed
+    let response = send(addr, Method::GET, "/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    let response = send(addr, Method::POST, &upload, &[("content-type", "text/plain")], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(header(&response, "etag").is_some(), "{}", response);
+
+    let response = send(addr, Method::GET, "/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(header(&response, "content-type"), Some("text/plain"));
+    assert!(response.ends_with("hello world"), "{}", response);
+
+    let response = send(addr, Method::HEAD, &upload, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    // Aborted uploads are gone as well
+    let response = send(addr, Method::POST, "/_fily/uploads/photos/b.txt", &[], b"").await;
+    let upload = format!("/_fily/uploads/photos/b.txt/{}", header(&response, "x-fily-upload-id").unwrap());
+    let response = send(addr, Method::DELETE, &upload, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    let response = send(addr, Method::PATCH, &upload, &[("x-fily-upload-offset", "0")], b"data").await;
+    assert!(response.contains("NoSuchUpload"), "{}", response);
+
+    // Uploads cannot be started in buckets that do not exist
+    let response = send(addr, Method::POST, "/_fily/uploads/missing/a.txt", &[], b"").await;
+    assert!(response.contains("NoSuchBucket"), "{}", response);
+
+    stop.await;
+}
+
+#[tokio::test]
+async fn test_resumable_uploads_are_disabled_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = create_test_config(temp_dir.path().to_str().unwrap());
+    config.resumable_uploads = Default::default();
+    let (addr, stop) = serve(config).await;
+
+    send(addr, Method::PUT, "/photos", &[], b"").await;
+    let response = send(addr, Method::POST, "/_fily/uploads/photos/a.txt", &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    stop.await;
+}
@@ -113,7 +113,7 @@ fn test_io_error_conversion() {
     let s3_err = S3AppError::from(permission_err);
     assert!(matches!(s3_err.code, S3ErrorCode::AccessDenied));
 
-    let other_err = std::io::Error::new(std::io::ErrorKind::Other, "Other error");
+    let other_err = std::io::Error::other("Other error");
     let s3_err = S3AppError::from(other_err);
     assert!(matches!(s3_err.code, S3ErrorCode::InternalError));
 }
@@ -23,6 +23,14 @@ async fn test_metadata_hash_caching_performance() {
         last_modified: "Mon, 01 Jan 2024 00:00:00 GMT".to_string(),
         user_metadata: HashMap::new(),
         content_sha256: Some(body_hash.clone()),
+        checksum_algorithm: None,
+        checksum_value: None,
+        tags: HashMap::new(),
+        part_sizes: vec![],
+        encryption_id: None,
+        created_by: None,
+        created_at: None,
+        modified_by: None,
     };
     
     // Save metadata
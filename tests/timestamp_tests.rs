@@ -0,0 +1,68 @@
+#![cfg(feature = "client")]
+
+use bytes::Bytes;
+use chrono::{NaiveDateTime, Utc};
+use fily::fily::client::{Client, Credentials};
+use fily::fily::timestamp::{http_date, parse_http_date};
+use hyper::{Method, StatusCode};
+use tempfile::TempDir;
+
+mod common;
+
+use common::{ACCESS_KEY_ID, SECRET_ACCESS_KEY, serve, test_config};
+
+fn credentials() -> Credentials {
+    Credentials {
+        access_key_id: ACCESS_KEY_ID.to_string(),
+        secret_access_key: SECRET_ACCESS_KEY.to_string(),
+        region: "us-east-1".to_string(),
+    }
+}
+
+/// Text of every `<tag>` element in an XML body
+fn elements(xml: &[u8], tag: &str) -> Vec<String> {
+    let xml = String::from_utf8_lossy(xml);
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split_once(close.as_str()).map(|(text, _)| text.to_string()))
+        .collect()
+}
+
+/// S3's XML timestamps always carry milliseconds, e.g. `2024-10-15T10:00:00.000Z`
+fn assert_iso8601_millis(value: &str) {
+    assert_eq!(value.len(), 24, "{}", value);
+    assert!(NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.3fZ").is_ok(), "{}", value);
+}
+
+#[tokio::test]
+async fn test_timestamps_use_s3_formats() {
+    let dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(test_config(dir.path().to_str().unwrap())).await;
+    let endpoint = format!("http://{}", addr);
+
+    let client = Client::new(&endpoint, credentials()).unwrap();
+    let before = Utc::now() - chrono::Duration::seconds(1);
+    let response = client.send(Method::PUT, "/photos", &[], Bytes::new()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    client.put_object("photos", "a.jpg", Bytes::from_static(b"sand")).await.unwrap();
+
+    // Last-Modified is an RFC 1123 date
+    let get = client.get_object("photos", "a.jpg").await.unwrap();
+    let last_modified = get.headers()["last-modified"].to_str().unwrap();
+    let modified = parse_http_date(last_modified).unwrap();
+    assert_eq!(http_date(modified), last_modified);
+    assert!(modified >= before && modified <= Utc::now());
+
+    let list = client.send(Method::GET, "/photos", &[("list-type", "2")], Bytes::new()).await.unwrap();
+    let listed = elements(list.body(), "LastModified");
+    assert_eq!(listed.len(), 1);
+    assert_iso8601_millis(&listed[0]);
+
+    let buckets = client.send(Method::GET, "/", &[], Bytes::new()).await.unwrap();
+    let created = elements(buckets.body(), "CreationDate");
+    assert_eq!(created.len(), 1);
+    assert_iso8601_millis(&created[0]);
+
+    stop.await;
+}
@@ -0,0 +1,127 @@
+use axum::http::Method;
+use base64::{engine::general_purpose, Engine as _};
+use fily::fily::webdav::{WebDavConfig, WebDavUser};
+use fily::fily::Config;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{ACCESS_KEY_ID, encryption, send, send_raw, serve, test_config};
+
+fn create_test_config(location: &str) -> Config {
+    Config {
+        encryption: encryption(),
+        verify_on_get: true,
+        webdav: WebDavConfig {
+            enabled: true,
+            users: vec![WebDavUser {
+                username: "alice".to_string(),
+                password: "correct horse".to_string(),
+                access_key_id: ACCESS_KEY_ID.to_string(),
+            }],
+        },
+        ..test_config(location)
+    }
+}
+
+/// Sends a WebDAV request logged in as `user:password`, or anonymously
+async fn send_dav(
+    addr: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    login: Option<&str>,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> String {
+    let authorization = login.map(|login| format!("Basic {}", general_purpose::STANDARD.encode(login)));
+    let mut headers = headers.to_vec();
+    if let Some(authorization) = &authorization {
+        headers.push(("authorization", authorization));
+    }
+    send_raw(addr, method, path, &headers, body).await
+}
+
+const LOGIN: Option<&str> = Some("alice:correct horse");
+
+#[tokio::test]
+async fn test_webdav_collections_and_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, stop) = serve(create_test_config(temp_dir.path().to_str().unwrap())).await;
+
+    // Clients learn the auth scheme from the first, anonymous request
+    let response = send_dav(addr, "OPTIONS", "/_fily/dav/", None, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    assert!(response.contains("www-authenticate: Basic realm=\"fily\""), "{}", response);
+    let response = send_dav(addr, "OPTIONS", "/_fily/dav/", Some("alice:wrong"), &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    let response = send_dav(addr, "OPTIONS", "/_fily/dav/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("dav: 1"), "{}", response);
+
+    // Buckets are top-level collections, directories below them nested ones
+    let response = send_dav(addr, "MKCOL", "/_fily/dav/docs/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    let response = send_dav(addr, "MKCOL", "/_fily/dav/docs/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    let response = send_dav(addr, "MKCOL", "/_fily/dav/docs/drafts/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    let response = send_dav(addr, "MKCOL", "/_fily/dav/docs/a/b/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+
+    let text = [("content-type", "text/plain")];
+    let response = send_dav(addr, "PUT", "/_fily/dav/docs/notes/to%20do.txt", LOGIN, &text, b"hello").await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    let response = send_dav(addr, "PUT", "/_fily/dav/docs/notes/to%20do.txt", LOGIN, &text, b"hello world").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    let response = send_dav(addr, "PUT", "/_fily/dav/missing/a.txt", LOGIN, &text, b"x").await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+
+    // Objects written over S3 show up too
+    let response = send(addr, Method::PUT, "/docs/readme.md", &[], b"# docs").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let depth_1 = [("depth", "1")];
+    let response = send_dav(addr, "PROPFIND", "/_fily/dav/", LOGIN, &depth_1, b"").await;
+    assert!(response.starts_with("HTTP/1.1 207"), "{}", response);
+    assert!(response.contains("<D:href>/_fily/dav/docs/</D:href>"), "{}", response);
+
+    let response = send_dav(addr, "PROPFIND", "/_fily/dav/docs/", LOGIN, &depth_1, b"").await;
+    assert!(response.starts_with("HTTP/1.1 207"), "{}", response);
+    for href in ["/_fily/dav/docs/", "/_fily/dav/docs/drafts/", "/_fily/dav/docs/notes/", "/_fily/dav/docs/readme.md"] {
+        assert!(response.contains(&format!("<D:href>{}</D:href>", href)), "{} in {}", href, response);
+    }
+    assert!(!response.contains(".fily-metadata"), "{}", response);
+
+    // Sizes are of the plaintext, not of the encrypted file
+    let response = send_dav(addr, "PROPFIND", "/_fily/dav/docs/notes/to%20do.txt", LOGIN, &[("depth", "0")], b"").await;
+    assert!(response.starts_with("HTTP/1.1 207"), "{}", response);
+    assert!(response.contains("<D:href>/_fily/dav/docs/notes/to%20do.txt</D:href>"), "{}", response);
+    assert!(response.contains("<D:getcontentlength>11</D:getcontentlength>"), "{}", response);
+    assert!(response.contains("<D:getcontenttype>text/plain</D:getcontenttype>"), "{}", response);
+
+    let response = send_dav(addr, "PROPFIND", "/_fily/dav/docs/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    assert!(response.contains("propfind-finite-depth"), "{}", response);
+
+    let response = send_dav(addr, "GET", "/_fily/dav/docs/notes/to%20do.txt", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("hello world"), "{}", response);
+    let response = send_dav(addr, "GET", "/_fily/dav/docs/readme.md", LOGIN, &[], b"").await;
+    assert!(response.ends_with("# docs"), "{}", response);
+
+    // A bucket can only be deleted once its collections are gone
+    let response = send_dav(addr, "DELETE", "/_fily/dav/docs/", LOGIN, &[], b"").await;
+    assert!(response.contains("<Code>BucketNotEmpty</Code>"), "{}", response);
+    for path in ["/_fily/dav/docs/notes/", "/_fily/dav/docs/drafts/", "/_fily/dav/docs/readme.md"] {
+        let response = send_dav(addr, "DELETE", path, LOGIN, &[], b"").await;
+        assert!(response.starts_with("HTTP/1.1 204"), "{}: {}", path, response);
+    }
+    let response = send_dav(addr, "GET", "/_fily/dav/docs/notes/to%20do.txt", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let response = send_dav(addr, "DELETE", "/_fily/dav/docs/", LOGIN, &[], b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    let response = send_dav(addr, "PROPFIND", "/_fily/dav/docs/", LOGIN, &[("depth", "0")], b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    stop.await;
+}